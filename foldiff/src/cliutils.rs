@@ -1,19 +1,41 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use std::fs::File;
 use dialoguer::Confirm;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use std::sync::LazyLock;
-use libfoldiff::reporting::{CanBeWrappedBy, Reporter, ReporterSized, ReportingMultiWrapper};
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, OnceLock};
+use std::time::Duration;
+use libfoldiff::reporting::{CanBeWrappedBy, JsonReporter, Reporter, ReporterSized, ReportingMultiWrapper};
 
 pub fn confirm(msg: &str) -> Result<bool> {
 	Ok(Confirm::new().with_prompt(msg).interact()?)
 }
 
+/// opens a file descriptor that was passed in by a parent process.
+/// works on a duplicate of it, so fails cleanly if it isn't actually open.
+#[cfg(unix)]
+pub fn open_fd(fd: i32) -> Result<File> {
+	use std::os::fd::BorrowedFd;
+
+	// safety: if fd isn't open, dup() just fails with EBADF
+	let owned = unsafe { BorrowedFd::borrow_raw(fd) }
+		.try_clone_to_owned()
+		.with_context(|| format!("File descriptor {fd} is not open"))?;
+	Ok(File::from(owned))
+}
+
+#[cfg(not(unix))]
+pub fn open_fd(_: i32) -> Result<File> {
+	anyhow::bail!("--progress-fd is only supported on unix");
+}
+
 static SPINNER_TEMPLATE_COUNT: &str = "{spinner} [{pos}] {msg}";
 static SPINNER_TEMPLATE_SIMPLE: &str = "{spinner} {msg}";
 static SPINNER_TICKS: &[&str] = &["⠙","⠸","⢰","⣠","⣄","⡆","⠇","⠋","✓"];
 // default: "⠁⠁⠉⠙⠚⠒⠂⠂⠒⠲⠴⠤⠄⠄⠤⠠⠠⠤⠦⠖⠒⠐⠐⠒⠓⠋⠉⠈⠈ "
 
-static PROGRESS_TEMPLATE: &str = "{spinner} [{percent:>3}% {pos:>3}/{len:3}] {msg} {wide_bar}";
+// bytes_per_sec is our own key, filled in from set_speed_hint, as pos counts files and not bytes
+static PROGRESS_TEMPLATE: &str = "{spinner} [{percent:>3}% {pos:>3}/{len:3}] {msg}{bytes_per_sec} {wide_bar} {eta}";
 static PROGRESS_TEMPLATE_FINISHED: &str = "{spinner} [{percent:>3}% {pos:>3}/{len:3}] {msg}";
 //static PROGRESS_TICKS: &[&str] = &[" ", "✓"];
 
@@ -35,9 +57,31 @@ static SPINNER_STYLE_FINISHED_SIMPLE: LazyLock<ProgressStyle> = LazyLock::new(||
 	).unwrap().tick_strings(SPINNER_TICKS)
 });
 
-static PROGRESS_STYLE: LazyLock<ProgressStyle> = LazyLock::new(|| {
-	ProgressStyle::with_template(PROGRESS_TEMPLATE).unwrap().tick_strings(SPINNER_TICKS)
-});
+/// how long the rest of `len` items should take, going at the average rate of the first `pos` over `elapsed`
+fn eta(pos: u64, len: u64, elapsed: Duration) -> Duration {
+	if pos == 0 {
+		return Duration::ZERO;
+	}
+	elapsed.mul_f64(len.saturating_sub(pos) as f64 / pos as f64)
+}
+
+/// each bar needs its own style, so it can show its own speed
+fn progress_style(speed: Arc<AtomicU64>) -> ProgressStyle {
+	ProgressStyle::with_template(PROGRESS_TEMPLATE)
+		.unwrap()
+		.tick_strings(SPINNER_TICKS)
+		// replaces indicatif's own {eta}, so it can be tested without waiting around
+		.with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| {
+			let eta = eta(state.pos(), state.len().unwrap_or(0), state.elapsed());
+			let _ = write!(w, "{:#}", HumanDuration(eta));
+		})
+		.with_key("bytes_per_sec", move |_: &ProgressState, w: &mut dyn std::fmt::Write| {
+			let speed = f64::from_bits(speed.load(Ordering::Relaxed));
+			if speed > 0.0 {
+				let _ = write!(w, " ({}/s)", HumanBytes(speed as u64));
+			}
+		})
+}
 
 static PROGRESS_STYLE_FINISHED: LazyLock<ProgressStyle> = LazyLock::new(|| {
 	ProgressStyle::with_template(
@@ -45,22 +89,40 @@ static PROGRESS_STYLE_FINISHED: LazyLock<ProgressStyle> = LazyLock::new(|| {
 	).unwrap().tick_strings(SPINNER_TICKS)
 });
 
+/// if it's been long enough since `last` to show a new current file on `pb`, and updates `last` if so.
+/// current files can change thousands of times a second, which would just be flicker.
+fn message_due(pb: &ProgressBar, last: &AtomicU64) -> bool {
+	let now = pb.elapsed().as_millis() as u64;
+	let prev = last.load(Ordering::Relaxed);
+	now >= prev + 100 && last.compare_exchange(prev, now, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+}
+
 // implement libfoldiff::reporting for indicatif
+// everything is also sent to a JsonReporter, which only does anything if --progress-fd was passed
+// progress always goes to stderr, so that stdout only has results in it and can be piped into other things
 
-pub struct Spinner<const COUNT: bool>(ProgressBar);
+// the String is the message without the speed or current file on the end,
+// the AtomicU64 is when the current file was last shown, in ms since the spinner was created,
+// and the Duration is how long it took, once it's done
+pub struct Spinner<const COUNT: bool>(ProgressBar, JsonReporter, String, AtomicU64, OnceLock<Duration>);
 
 impl<const COUNT: bool> Reporter for Spinner<COUNT> {
 	fn new(msg: &str) -> Self {
-		Self(ProgressBar::new_spinner()
+		Self(ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr())
 			.with_message(msg.to_string())
 			.with_style(
 				if COUNT { SPINNER_STYLE_COUNT.clone() } else { SPINNER_STYLE_SIMPLE.clone() }
-			))
+			),
+			<JsonReporter as Reporter>::new(msg),
+			msg.to_string(),
+			AtomicU64::new(0),
+			OnceLock::new())
 	}
 
 	fn incr(&self, n: usize) {
 		// TODO: retain normal steady-tick implementation of incr() not ticking it
 		self.0.inc(n as u64);
+		self.1.incr(n);
 	}
 
 	fn count(&self) -> usize {
@@ -69,35 +131,57 @@ impl<const COUNT: bool> Reporter for Spinner<COUNT> {
 
 	fn tick(&self) {
 		self.0.tick();
+		self.1.tick();
 	}
 
 	fn done_clear(&self) {
+		let _ = self.4.set(self.0.elapsed());
 		self.0.finish_and_clear();
+		self.1.done_clear();
 	}
 
 	fn done(&self) {
+		let _ = self.4.set(self.0.elapsed());
 		self.0.set_style(
 			if COUNT { SPINNER_STYLE_FINISHED_COUNT.clone() } else { SPINNER_STYLE_FINISHED_SIMPLE.clone() }
 		);
+		// don't leave the last file showing
+		self.0.set_message(self.2.clone());
 		self.0.abandon();
+		self.1.done();
 	}
 
 	fn suspend<F: FnOnce() -> R, R>(&self, f: F) -> R {
 		self.0.suspend(f)
 	}
+
+	fn set_speed_hint(&self, bytes_per_sec: f64) {
+		self.0.set_message(format!("{} ({}/s)", self.2, HumanBytes(bytes_per_sec as u64)));
+	}
+
+	fn set_message(&self, msg: &str) {
+		if message_due(&self.0, &self.3) {
+			self.0.set_message(format!("{}: {msg}", self.2));
+		}
+	}
+
+	fn stage_elapsed(&self) -> Option<Duration> {
+		self.4.get().copied()
+	}
 }
 
-pub struct Bar(ProgressBar);
+// the first AtomicU64 is the speed in bytes/s, as f64 bits.
+// the String, second AtomicU64, and Duration work like they do for Spinner
+pub struct Bar(ProgressBar, JsonReporter, Arc<AtomicU64>, String, AtomicU64, OnceLock<Duration>);
 
 impl Reporter for Bar {
 	fn new(msg: &str) -> Self {
-		Self(ProgressBar::new(0)
-			.with_message(msg.to_string())
-			.with_style(PROGRESS_STYLE.clone()))
+		<Self as ReporterSized>::new(msg, 0)
 	}
 
 	fn incr(&self, n: usize) {
 		self.0.inc(n as u64);
+		self.1.incr(n);
 	}
 
 	fn count(&self) -> usize {
@@ -106,31 +190,58 @@ impl Reporter for Bar {
 
 	fn tick(&self) {
 		self.0.tick();
+		self.1.tick();
 	}
 
 	fn done_clear(&self) {
+		let _ = self.5.set(self.0.elapsed());
 		self.0.finish_and_clear();
+		self.1.done_clear();
 	}
 
 	fn done(&self) {
+		let _ = self.5.set(self.0.elapsed());
 		self.0.set_style(PROGRESS_STYLE_FINISHED.clone());
+		self.0.set_message(self.3.clone());
 		self.0.abandon();
+		self.1.done();
 	}
 
 	fn suspend<F: FnOnce() -> R, R>(&self, f: F) -> R {
 		self.0.suspend(f)
 	}
+
+	fn set_speed_hint(&self, bytes_per_sec: f64) {
+		self.2.store(bytes_per_sec.to_bits(), Ordering::Relaxed);
+	}
+
+	fn set_message(&self, msg: &str) {
+		if message_due(&self.0, &self.4) {
+			self.0.set_message(format!("{}: {msg}", self.3));
+		}
+	}
+
+	fn stage_elapsed(&self) -> Option<Duration> {
+		self.5.get().copied()
+	}
 }
 
 impl ReporterSized for Bar {
 	fn new(msg: &str, len: usize) -> Self {
-		Self(ProgressBar::new(len as u64)
+		let speed = Arc::new(AtomicU64::new(0));
+		Self(ProgressBar::with_draw_target(Some(len as u64), ProgressDrawTarget::stderr())
 			.with_message(msg.to_string())
-			.with_style(PROGRESS_STYLE.clone()))
+			.with_style(progress_style(speed.clone())),
+			<JsonReporter as ReporterSized>::new(msg, len),
+			speed,
+			msg.to_string(),
+			AtomicU64::new(0),
+			OnceLock::new())
 	}
 
 	fn set_len(&self, len: usize) {
 		self.0.set_length(len as u64);
+		self.1.set_len(len);
 	}
 
 	fn length(&self) -> usize {
@@ -143,22 +254,63 @@ pub struct MultiWrapper(MultiProgress);
 
 impl ReportingMultiWrapper for MultiWrapper {
 	fn new() -> Self {
-		MultiWrapper(MultiProgress::new())
+		MultiWrapper(MultiProgress::with_draw_target(ProgressDrawTarget::stderr()))
 	}
 	
 	fn suspend<F: FnOnce() -> R, R>(&self, f: F) -> R {
 		self.0.suspend(f)
 	}
+
+	fn add_overall_bar(&self, len: usize) -> impl ReporterSized + Sync {
+		let bar = <Bar as ReporterSized>::new("Total files", len);
+		// at the top, whatever was added before it
+		Bar(self.0.insert(0, bar.0), bar.1, bar.2, bar.3, bar.4, bar.5)
+	}
 }
 
 impl<const COUNT: bool> CanBeWrappedBy<MultiWrapper> for Spinner<COUNT> {
 	fn add_to(self, w: &MultiWrapper) -> Self {
-		Spinner(w.0.add(self.0))
+		Spinner(w.0.add(self.0), self.1, self.2, self.3, self.4)
 	}
 }
 
 impl CanBeWrappedBy<MultiWrapper> for Bar {
 	fn add_to(self, w: &MultiWrapper) -> Self {
-		Bar(w.0.add(self.0))
+		Bar(w.0.add(self.0), self.1, self.2, self.3, self.4, self.5)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+
+	#[test]
+	fn test_bar_eta() {
+		// 20 items in 200ms is 100 items/s, so the other 80 should take 800ms
+		assert_eq!(eta(20, 100, Duration::from_millis(200)), Duration::from_millis(800));
+		assert_eq!(eta(50, 100, Duration::from_secs(30)), Duration::from_secs(30));
+		// nothing done yet, so no idea, and nothing left to do
+		assert_eq!(eta(0, 100, Duration::from_secs(5)), Duration::ZERO);
+		assert_eq!(eta(100, 100, Duration::from_secs(5)), Duration::ZERO);
+		// the length can be set after items are already done
+		assert_eq!(eta(10, 5, Duration::from_secs(5)), Duration::ZERO);
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn test_spinner_message_rate_limit() {
+		let spn = <Spinner<true> as Reporter>::new("Scanning");
+		spn.0.set_draw_target(ProgressDrawTarget::hidden());
+
+		std::thread::sleep(Duration::from_millis(110));
+		spn.set_message("a");
+		assert_eq!(spn.0.message(), "Scanning: a");
+
+		// too soon after the last one
+		spn.set_message("b");
+		assert_eq!(spn.0.message(), "Scanning: a");
+
+		spn.done();
+		assert_eq!(spn.0.message(), "Scanning");
+	}
+}
@@ -1,19 +1,29 @@
 use std::fs::File;
+use std::io::BufReader;
+use std::time::{Duration, Instant};
 use anyhow::{bail, ensure, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 use clap::{Parser, Subcommand};
-use libfoldiff::FoldiffCfg;
-use libfoldiff::manifest::DiffManifest;
+use libfoldiff::{DiffStrategy, EncryptionCfg, FoldiffCfg, IoAccounting, PhaseConcurrency, SymlinkMode, VerifyMode};
+use libfoldiff::manifest::{DiffManifest, ManifestEntry};
+use libfoldiff::applying::ApplyingDiff;
+use libfoldiff::diffing::{DiffingDiff, WriteStats, WriteTimings};
+use libfoldiff::reporting::JsonReporter;
+use libfoldiff::verify::{VerifyMismatch, VerifyOptions, VerifyResult};
+use libfoldiff::vfs::StdVfs;
+use libfoldiff::upgrade::CompatibilityMatrix;
+use indicatif::HumanBytes;
 
 mod cliutils;
+mod watch;
 
 #[derive(Parser, Debug)]
 #[command(
 	version = "v1.3.1",
 	about,
 	long_version = "v1.3.1
-   writing fldf v1.1.0
-   reading fldf 1.0.0-r, v1.1.0"
+   writing fldf v1.3.0
+   reading fldf 1.0.0-r, v1.1.0, v1.2.0, v1.3.0"
 )]
 struct Cli {
 	#[command(subcommand)]
@@ -23,7 +33,13 @@ struct Cli {
 	force: bool,
 	/// How many threads to use ("-T 0" = number of logical processors)
 	#[arg(short = 'T', long, default_value_t = 0)]
-	threads: usize
+	threads: usize,
+	/// Also write progress to this file descriptor, as one JSON object per line (unix only)
+	#[arg(long)]
+	progress_fd: Option<i32>,
+	/// Append a line of JSON to this file for every file that is diffed or applied
+	#[arg(long)]
+	log: Option<String>,
 }
 
 // picking the default value for -Z:
@@ -41,18 +57,80 @@ struct Cli {
 enum Commands {
 	/// Create a diff from two similar folders
 	Diff {
-		/// Path to the source / "old" folder
-		old: String,
+		/// Path to the source / "old" folder. When diffing tar archives, this is where to create the diff file instead
+		old: Option<String>,
 		/// Path to the "new" folder
-		new: String,
+		new: Option<String>,
 		/// Path to where to create the diff file
-		diff: String,
+		diff: Option<String>,
+		/// Diff the contents of this tar archive instead of an old folder. Needs --tar-new, and only the diff path
+		#[arg(long, value_name = "OLD.TAR", requires = "tar_new")]
+		tar_old: Option<String>,
+		/// Diff against the contents of this tar archive instead of a new folder. Needs --tar-old
+		#[arg(long, value_name = "NEW.TAR", requires = "tar_old")]
+		tar_new: Option<String>,
 		/// Zstd compression level to use for compressing new files (1 = weakest, 19 = strongest)
 		#[arg(short = 'Z', long, default_value_t = 7)]
 		level_new: u8,
 		/// Zstd compression level to use for diffing (1 = weakest, 19 = strongest)
 		#[arg(short = 'D', long, default_value_t = 3)]
-		level_diff: u8
+		level_diff: u8,
+		/// What to do with symlinks: "deny" to fail, "follow" to diff what they point to, or "preserve" to store them in the diff
+		#[arg(long, default_value = "deny")]
+		symlinks: SymlinkMode,
+		/// Treat paths that only differ in case as the same file (always on for Windows)
+		#[arg(long, default_value_t = false)]
+		case_insensitive: bool,
+		/// Fail instead of warning if a file changes while it is being scanned
+		#[arg(long, default_value_t = false)]
+		strict_scan: bool,
+		/// Print how well each file compressed once the diff is written
+		#[arg(long, default_value_t = false)]
+		stats: bool,
+		/// Zstd dictionary to use when diffing changed files. It is stored in the diff
+		#[arg(long)]
+		diff_dict: Option<String>,
+		/// Zstd dictionary to use when compressing new files. It is stored in the diff
+		#[arg(long)]
+		new_dict: Option<String>,
+		/// Print what the diff would contain and how big it would be instead of writing it. This takes as long as creating the diff
+		#[arg(long, visible_alias = "stats-only", default_value_t = false)]
+		estimate: bool,
+		/// Use the strongest compression level that should finish within this many seconds, instead of --level-new and --level-diff
+		#[arg(long, value_name = "SECONDS")]
+		time_budget: Option<f64>,
+		/// Split changed files bigger than 1GiB where the old and new data line up, instead of into even chunks.
+		/// Gives much smaller diffs when data was inserted or removed near the start, but reads those files an extra time
+		#[arg(long, default_value_t = false)]
+		cdc: bool,
+		/// Give each changed file the end of the one before it as extra context, putting files with the same name next to each other.
+		/// Much smaller diffs when lots of changed files share the same changes, but they then have to be applied one at a time
+		#[arg(long, default_value_t = false)]
+		delta_chain: bool,
+		/// How far back zstd can look when diffing changed files, as a power of two from 10 to 31. Applying needs this much memory for each file.
+		/// By default, just enough to cover each file
+		#[arg(long, value_parser = clap::value_parser!(u8).range(10..=31))]
+		window_log: Option<u8>,
+		/// Don't look for matches far back in the old file, which is otherwise done for changed files of 32MiB or more.
+		/// Faster, but misses data that moved far
+		#[arg(long, default_value_t = false)]
+		no_ldm: bool,
+		/// How big the table used to look for matches far back is, as a power of two from 6 to 30. By default, picked from the window log
+		#[arg(long, value_parser = clap::value_parser!(u8).range(6..=30))]
+		ldm_hash_log: Option<u8>,
+		/// Save file hashes in a .foldiff-cache file in each folder, and skip hashing files in dirs where nothing's size or modification time changed since
+		#[arg(long, default_value_t = false)]
+		scan_cache: bool,
+		/// How to store files of a mime type: "zstd" to compress them, "raw" to store them uncompressed, or "text" to match up unchanged lines first.
+		/// By default, already-compressed formats are raw and text formats are text. Can be given more than once
+		#[arg(long, value_name = "MIME=STRATEGY", value_parser = parse_strategy)]
+		strategy: Vec<(String, DiffStrategy)>,
+		/// Encrypt the diff with the 32-byte key in this file, so it can only be read or applied with the same key
+		#[arg(long, value_name = "PATH")]
+		key_file: Option<String>,
+		/// Print how long each stage took, how much was read and written, and how much memory was used once the diff is written
+		#[arg(short, long, default_value_t = false)]
+		verbose: bool,
 	},
 	/// Apply a diff to a folder
 	Apply {
@@ -62,22 +140,351 @@ enum Commands {
 		diff: String,
 		/// Path to where to create the "new" folder
 		new: String,
+		/// Restore the modification times of files from when the diff was created
+		#[arg(long, default_value_t = false)]
+		preserve_timestamps: bool,
+		/// Give up after this many files fail to apply. 0 means never give up
+		#[arg(long, default_value_t = 0)]
+		max_errors: usize,
+		/// Decrypt the diff with the 32-byte key in this file
+		#[arg(long, value_name = "PATH")]
+		key_file: Option<String>,
+		/// Decompress new files smaller than this many bytes in parallel, then write them one at a time.
+		/// Faster when writing from many threads is slow, e.g. on a network filesystem. 0 turns it off
+		#[arg(long, value_name = "BYTES", default_value_t = 1 << 20)]
+		predecompress_threshold: u64,
+		/// Copy unchanged and duplicated files with this many threads instead of sharing them with the other phases
+		#[arg(long)]
+		copy_threads: Option<usize>,
+		/// Write new files with this many threads instead of sharing them with the other phases
+		#[arg(long)]
+		new_threads: Option<usize>,
+		/// Apply patches with this many threads instead of sharing them with the other phases
+		#[arg(long)]
+		patch_threads: Option<usize>,
+		/// Don't check there is enough free space for the new folder before starting
+		#[arg(long, default_value_t = false)]
+		skip_space_check: bool,
+		/// Print how much was read and written, and how much memory was used, once the diff is applied
+		#[arg(short, long, default_value_t = false)]
+		verbose: bool,
+	},
+	/// Apply a diff, writing the "new" folder into a tar archive instead of onto disk
+	ApplyTar {
+		/// Path to the source / "old" folder
+		old: String,
+		/// Path to the diff file
+		diff: String,
+		/// Path to where to create the tar archive
+		output: String,
+		/// Give files the modification times from when the diff was created, instead of the current time
+		#[arg(long, default_value_t = false)]
+		preserve_timestamps: bool,
 	},
 	/// Check that two folders are identical, or that they match a given diff file
+	///
+	/// Exit codes: 0 = all good, 1 = failed to read the folders or diff, 2 = files have the wrong contents or are symlinks,
+	/// 3 = files are missing, 4 = there are extra files. If there are multiple kinds of problem, the highest code is used.
+	/// With --strict, any problem is an error instead, and exits with 1.
 	Verify {
 		/// Path to the source / "old" folder
 		old: String,
 		/// Path to the "new" folder
 		new: String,
 		/// If supplied, the path to the diff to verify against. If not supplied, just checks if the folders are identical
-		diff: Option<String>
+		diff: Option<String>,
+		/// Only compare file sizes instead of hashing every file. Much faster, but can miss changes
+		#[arg(long)]
+		fast: bool,
+		/// Stop checking after finding this many problems. 0 means check everything
+		#[arg(long, default_value_t = 0)]
+		max_errors: usize,
+		/// Fail with an error listing every problem found, including extra files
+		#[arg(long)]
+		strict: bool,
+		/// What to do with symlinks when comparing two folders: "deny" to report them, "follow" to compare what they point to,
+		/// or "preserve" to compare where they point
+		#[arg(long, default_value = "deny")]
+		symlinks: SymlinkMode,
+	},
+	/// Create a diff from two similar folders, and re-create it every time the new folder changes
+	Watch {
+		/// Path to the source / "old" folder, which is assumed not to change
+		old: String,
+		/// Path to the "new" folder to watch
+		new: String,
+		/// Path to where to create the diff file
+		diff: String,
+		/// Zstd compression level to use for compressing new files (1 = weakest, 19 = strongest)
+		#[arg(short = 'Z', long, default_value_t = 7)]
+		level_new: u8,
+		/// Zstd compression level to use for diffing (1 = weakest, 19 = strongest)
+		#[arg(short = 'D', long, default_value_t = 3)]
+		level_diff: u8,
+		/// What to do with symlinks: "deny" to fail, "follow" to diff what they point to, or "preserve" to store them in the diff
+		#[arg(long, default_value = "deny")]
+		symlinks: SymlinkMode,
+		/// Treat paths that only differ in case as the same file (always on for Windows)
+		#[arg(long, default_value_t = false)]
+		case_insensitive: bool,
+		/// Fail instead of warning if a file changes while it is being scanned
+		#[arg(long, default_value_t = false)]
+		strict_scan: bool,
+		/// How long to wait for changes to stop before re-creating the diff
+		#[arg(long, default_value_t = 500)]
+		debounce_ms: u64
 	},
 	/// Upgrade a diff from an old file format to the current version
 	Upgrade {
 		/// Path to the old diff
 		old: String,
-		/// Path to the destination location
+		/// Path to the destination location. If not given, the old diff is replaced
+		new: Option<String>,
+	},
+	/// Upgrade every diff in a folder that is in an old file format, backing each one up first
+	UpgradeAll {
+		/// Folder containing the diffs (.fldf files). Subfolders are not searched
+		dir: String,
+		/// Where to copy old diffs to before upgrading them [default: <DIR>/.foldiff-backup]
+		#[arg(long)]
+		backup_dir: Option<String>,
+	},
+	/// Print which versions of the diff file format can read which, and how old diffs are upgraded
+	CompatMatrix,
+	/// List what a diff will do to each file
+	Inspect {
+		/// Path to the diff file
+		diff: String,
+		/// Print how many files are in each category instead of listing every file
+		#[arg(long, default_value_t = false)]
+		summary: bool,
+		/// List every stored blob and how much space it takes up
+		#[arg(long, default_value_t = false, conflicts_with = "summary")]
+		blobs: bool,
+	},
+	/// Get the contents of one new file out of a diff, without reading the rest of it
+	Extract {
+		/// Path to the diff file
+		diff: String,
+		/// Path of the file in the new folder
+		path: String,
+		/// Where to write the file to, instead of printing it
+		output: Option<String>,
+	},
+	/// Combine an A->B diff and a B->C diff into a single A->C diff
+	Compose {
+		/// Path to the A->B diff
+		ab: String,
+		/// Path to the B->C diff
+		bc: String,
+		/// Path to where to create the A->C diff
+		ac: String,
+		/// Zstd compression level to use for new files, which are all recompressed (1 = weakest, 19 = strongest)
+		#[arg(short = 'Z', long, default_value_t = 7)]
+		level_new: u8,
+	},
+	/// Create a diff that undoes an existing diff, from the folders it was made from
+	Invert {
+		/// Path to the diff to undo
+		ab: String,
+		/// Path to the source / "old" folder the diff was made from
+		old: String,
+		/// Path to the "new" folder the diff was made from
 		new: String,
+		/// Path to where to create the inverted diff
+		ba: String,
+		/// Zstd compression level to use for compressing new files (1 = weakest, 19 = strongest)
+		#[arg(short = 'Z', long, default_value_t = 7)]
+		level_new: u8,
+		/// Zstd compression level to use for diffing (1 = weakest, 19 = strongest)
+		#[arg(short = 'D', long, default_value_t = 3)]
+		level_diff: u8,
+		/// What to do with symlinks: "deny" to fail, "follow" to diff what they point to, or "preserve" to store them in the diff
+		#[arg(long, default_value = "deny")]
+		symlinks: SymlinkMode,
+	},
+	/// Check that a diff file is intact without applying it
+	Validate {
+		/// Path to the diff file
+		diff: String,
+	}
+}
+
+/// `--max-errors 0` means there is no limit
+/// what `foldiff diff` reads the old and new files from
+enum DiffSource<'a> {
+	Folders(Utf8PathBuf, Utf8PathBuf),
+	Tars(&'a str, &'a str),
+}
+
+impl DiffSource<'_> {
+	fn scan(&self, cfg: &FoldiffCfg) -> Result<DiffingDiff> {
+		match self {
+			DiffSource::Folders(old_root, new_root) =>
+				libfoldiff::diffing::scan_to_diff::<cliutils::Spinner<true>>(old_root.clone(), new_root.clone(), cfg, &StdVfs),
+			DiffSource::Tars(old_tar, new_tar) => {
+				let open = |p: &str| File::open(p).map(BufReader::new).with_context(|| format!("Failed to open tar archive {p}"));
+				libfoldiff::diffing::scan_to_diff_from_tar::<cliutils::Spinner<true>>(open(old_tar)?, open(new_tar)?, cfg)
+			}
+		}
+	}
+}
+
+/// parses a `--strategy` like `text/html=zstd`
+fn parse_strategy(s: &str) -> Result<(String, DiffStrategy)> {
+	let (mime, strategy) = s.split_once('=').context("Expected a mime type and a strategy, like text/html=zstd")?;
+	Ok((mime.to_string(), strategy.parse()?))
+}
+
+fn max_errors_or_unlimited(n: usize) -> usize {
+	if n == 0 { usize::MAX } else { n }
+}
+
+/// picks the exit code for `foldiff verify`, see the help text for what they mean.
+/// errors exit with 1 by returning from main, so that is not handled here.
+fn verify_exit_code(res: &VerifyResult) -> i32 {
+	res.mismatches
+		.iter()
+		.map(|m| match m {
+			VerifyMismatch::HashMismatch(_) | VerifyMismatch::TypeMismatch(..) | VerifyMismatch::SymlinkFound(_) => 2,
+			VerifyMismatch::Missing(_) | VerifyMismatch::OnlyInFirst(_) => 3,
+			VerifyMismatch::Unexpected(_) | VerifyMismatch::OnlyInSecond(_) => 4,
+		})
+		.max()
+		.unwrap_or(0)
+}
+
+/// prints a table of how well each file compressed, with a total at the end
+fn print_write_stats(stats: &WriteStats) {
+	let row = |kind: &str, input: u64, output: u64, secs: f64, path: &str| {
+		let ratio = if input == 0 { 100.0 } else { output as f64 / input as f64 * 100.0 };
+		// the total row has no path, so don't leave padding at the end of it
+		let line = format!("{kind:<8} {input:>14} {output:>14} {ratio:>6.1}% {secs:>8.2}s  {path}");
+		println!("{}", line.trim_end());
+	};
+
+	println!("{:<8} {:>14} {:>14} {:>7} {:>9}  path", "kind", "size", "stored", "ratio", "time");
+	let (mut total_in, mut total_out, mut total_secs) = (0, 0, 0.0);
+	for (kind, files) in [("new", &stats.new_files), ("patched", &stats.patched_files)] {
+		for (p, s) in files {
+			row(kind, s.input_bytes, s.output_bytes, s.elapsed.as_secs_f64(), p.as_str());
+			total_in += s.input_bytes;
+			total_out += s.output_bytes;
+			total_secs += s.elapsed.as_secs_f64();
+		}
+	}
+	row("total", total_in, total_out, total_secs, "");
+}
+
+/// warns about files that were left out of the diff, as they would clash on a case-insensitive filesystem
+fn print_case_collisions(paths: &[Utf8PathBuf]) {
+	for p in paths {
+		eprintln!("Warning: {p:?} only differs in case from another file, so it was left out of the diff");
+	}
+}
+
+/// prints how long each stage of writing the diff took
+fn print_write_timings(t: &WriteTimings) {
+	for (stage, d) in [
+		("Scanning old folder", t.scan_old),
+		("Scanning new folder", t.scan_new),
+		("Generating manifest", t.manifest_gen),
+		("Compressing new files", t.compress_new),
+		("Diffing changed files", t.diff_patch),
+	] {
+		println!("{stage:<22} {:>8.2}s", d.as_secs_f64());
+	}
+}
+
+/// prints how much was read and written, and how fast that was overall
+fn print_io(io: &IoAccounting, elapsed: Duration) {
+	let speed = (io.bytes_read + io.bytes_written) as f64 / elapsed.as_secs_f64().max(1e-9);
+	println!("Read {}, wrote {} at {}/s", HumanBytes(io.bytes_read), HumanBytes(io.bytes_written), HumanBytes(speed as u64));
+}
+
+fn print_peak_rss(bytes: Option<u64>) {
+	match bytes {
+		Some(b) => println!("Peak memory use: {:.1} MiB", b as f64 / (1 << 20) as f64),
+		None if cfg!(feature = "memory-tracking") => println!("Peak memory use: unknown"),
+		None => println!("Peak memory use: unknown, foldiff was built without the memory-tracking feature"),
+	}
+}
+
+/// prints a table of which format versions can read diffs of which, and the upgrade path for each
+fn print_compat_matrix() {
+	let versions = CompatibilityMatrix::VERSIONS;
+
+	print!("{:<10} read by:", "diff");
+	for r in versions {
+		print!(" {:>8}", r.display_name());
+	}
+	println!("  upgrades through");
+
+	for w in versions {
+		print!("{:<18}", w.display_name());
+		for r in versions {
+			print!(" {:>8}", if CompatibilityMatrix::can_read(w, r) { "yes" } else { "no" });
+		}
+		let path: Vec<_> = CompatibilityMatrix::upgrade_path(w).iter().map(|v| v.display_name()).collect();
+		println!("  {}", if path.is_empty() { "(latest)".to_string() } else { path.join(" -> ") });
+	}
+}
+
+/// prints a table of the blobs stored in the diff, with a total at the end
+fn print_blob_table(diff: &ApplyingDiff) {
+	let manifest = diff.manifest();
+	let sizes = manifest.size_lookup();
+	let (new_sizes, patch_sizes) = (diff.new_blob_sizes(), diff.patch_blob_sizes());
+
+	println!("{:<8} {:>14} {:>14}  path", "kind", "stored", "size");
+	let mut total_new = 0;
+	for nf in &manifest.new_files {
+		let stored = new_sizes[nf.index as usize];
+		let size = sizes.get(&nf.hash).map_or("?".to_string(), |s| s.to_string());
+		println!("{:<8} {stored:>14} {size:>14}  {}", "new", nf.path);
+		total_new += stored;
+	}
+
+	println!();
+	println!("{:<8} {:>14} {:>16} {:>16}  path", "kind", "stored", "old hash", "new hash");
+	let mut total_patch = 0;
+	for pf in &manifest.patched_files {
+		let stored = patch_sizes[pf.index as usize];
+		println!("{:<8} {stored:>14} {:016x} {:016x}  {}", "patched", pf.old_hash, pf.new_hash, pf.path);
+		total_patch += stored;
+	}
+
+	println!();
+	println!("{:<8} {:>14}", "total", total_new + total_patch);
+}
+
+/// prints every entry of the manifest, one per line
+fn print_manifest_table(manifest: &DiffManifest) {
+	for entry in manifest.iter_all_files() {
+		match entry {
+			ManifestEntry::Untouched(p) => println!("untouched   {p}"),
+			ManifestEntry::Deleted(p) => println!("deleted     {p}"),
+			ManifestEntry::New(nf) => println!("new         {}", nf.path),
+			ManifestEntry::Patched(pf) => println!("patched     {}", pf.path),
+			ManifestEntry::Duplicated { old, new } => {
+				let from = if old.is_empty() { "(new data)".to_string() } else { old.join(", ") };
+				for p in new {
+					println!("duplicated  {p} <- {from}");
+				}
+			},
+		}
+	}
+	for sl in &manifest.symlinks {
+		println!("symlink     {} -> {}", sl.path, sl.target);
+	}
+	for hl in &manifest.hardlinked_files {
+		println!("hard link   {} -> {}", hl.path, hl.target);
+	}
+	for d in &manifest.empty_dirs_new {
+		println!("new dir     {d}");
+	}
+	for d in &manifest.empty_dirs_deleted {
+		println!("deleted dir {d}");
 	}
 }
 
@@ -97,22 +504,62 @@ fn main() -> Result<()> {
 
 	libfoldiff::set_num_threads(threads)?;
 
+	if let Some(fd) = cli.progress_fd {
+		JsonReporter::set_target(cliutils::open_fd(fd)?);
+	}
+
+	let log_path = cli.log.as_deref().map(Utf8PathBuf::from);
+
 	match &cli.command {
-		Commands::Diff { diff, new, old, level_diff, level_new } => {
+		Commands::Diff { diff, new, old, tar_old, tar_new, level_diff, level_new, symlinks, case_insensitive, strict_scan, stats, diff_dict, new_dict, estimate, time_budget, cdc, delta_chain, window_log, no_ldm, ldm_hash_log, scan_cache, strategy, key_file, verbose } => {
+			let read_dict = |p: &Option<String>| -> Result<Option<Vec<u8>>> {
+				p.as_ref()
+					.map(|p| std::fs::read(p).with_context(|| format!("Failed to read dictionary {p}")))
+					.transpose()
+			};
+
 			let cfg = FoldiffCfg {
 				threads,
 				level_new: *level_new,
-				level_diff: *level_diff
+				level_diff: *level_diff,
+				symlinks: *symlinks,
+				case_insensitive_paths: *case_insensitive,
+				strict_scan: *strict_scan,
+				diff_dict: read_dict(diff_dict)?,
+				new_dict: read_dict(new_dict)?,
+				log_path: log_path.clone(),
+				time_budget_secs: *time_budget,
+				cdc_chunking: *cdc,
+				use_scan_cache: *scan_cache,
+				strategy_overrides: strategy.iter().cloned().collect(),
+				encryption: key_file.as_deref().map(|p| EncryptionCfg::from_key_file(p.into())).transpose()?,
+				delta_chain: *delta_chain,
+				window_log: *window_log,
+				long_distance_matching: !*no_ldm,
+				ldm_hash_log: *ldm_hash_log,
+				..FoldiffCfg::default()
 			};
 
-			let old_root: Utf8PathBuf = old.into();
-			let new_root: Utf8PathBuf = new.into();
-			// check both exist
-			ensure!(std::fs::metadata(&old_root).context("old path must exist")?.is_dir(), "old path must be a directory");
-			ensure!(std::fs::metadata(&new_root).context("new path must exist")?.is_dir(), "new path must be a directory");
+			if let Some(t) = time_budget {
+				ensure!(t.is_finite() && *t > 0.0, "Time budget must be a positive number of seconds");
+			}
+
+			// with tars, the only path given is the diff, which clap puts in `old`
+			let (source, diff) = match (tar_old.as_deref().zip(tar_new.as_deref()), old, new, diff) {
+				(None, Some(old), Some(new), Some(diff)) => (DiffSource::Folders(old.into(), new.into()), diff),
+				(Some((old_tar, new_tar)), Some(diff), None, None) => (DiffSource::Tars(old_tar, new_tar), diff),
+				(None, ..) => bail!("Expected an old folder, a new folder, and where to create the diff"),
+				(Some(_), ..) => bail!("Only expected where to create the diff when using --tar-old and --tar-new"),
+			};
+
+			if let DiffSource::Folders(old_root, new_root) = &source {
+				// check both exist
+				ensure!(std::fs::metadata(old_root).context("old path must exist")?.is_dir(), "old path must be a directory");
+				ensure!(std::fs::metadata(new_root).context("new path must exist")?.is_dir(), "new path must be a directory");
+			}
 
 			// check for diff file existence and possibly delete it
-			if std::fs::exists(diff).context("Failed to check for output existence")? {
+			if !*estimate && std::fs::exists(diff).context("Failed to check for output existence")? {
 				let meta = std::fs::symlink_metadata(diff).context("Failed to check existing output file type")?;
 				if meta.is_dir() {
 					bail!("Output diff file exists but is a directory");
@@ -131,15 +578,66 @@ fn main() -> Result<()> {
 				std::fs::remove_file(diff).context("Failed to remove file")?;
 			}
 
-			// scan the file system
-			let mut diff_state = libfoldiff::diffing::scan_to_diff::<cliutils::Spinner<true>>(old_root, new_root)?;
-			//println!("{diff_state:?}");
+			if *estimate {
+				// scan the file system
+				let mut diff_state = source.scan(&cfg)?;
+				print_case_collisions(diff_state.case_collisions());
+
+				let manifest = diff_state.generate_manifest::<cliutils::Spinner<false>>()?;
+				print!("{manifest}");
+				println!();
 
-			// emit the diff to disk
-			diff_state.write_to_file::<cliutils::Bar, cliutils::Spinner<false>>(Utf8Path::new(diff), &cfg)?;
+				let est = diff_state.estimate_size::<cliutils::Bar, cliutils::Spinner<false>>(&cfg)?;
+				let ratio = if est.input_bytes == 0 { 100.0 } else { est.total as f64 / est.input_bytes as f64 * 100.0 };
+				println!("Manifest:      {:>14} bytes", est.manifest_bytes);
+				println!("New files:     {:>14} bytes", est.new_blob_bytes);
+				println!("Patched files: {:>14} bytes", est.patch_blob_bytes);
+				println!("Total:         {:>14} bytes", est.total);
+				println!("Ratio:         {ratio:>13.1}% of {} bytes of new and changed files", est.input_bytes);
+				return Ok(());
+			}
+
+			let start = Instant::now();
+			let write_stats = match source {
+				// scan the file system and emit the diff to disk, compressing new files as they're found
+				DiffSource::Folders(old_root, new_root) =>
+					libfoldiff::diffing::scan_and_write_pipeline::<cliutils::Bar, cliutils::Spinner<true>>(old_root, new_root, Utf8Path::new(diff), &cfg)?,
+				// the pipeline wants folders to scan, so extract and scan first
+				DiffSource::Tars(..) =>
+					source.scan(&cfg)?.write_to_file::<cliutils::Bar, cliutils::Spinner<false>>(Utf8Path::new(diff), &cfg)?,
+			};
+
+			print_case_collisions(&write_stats.case_collisions);
+			if let Some(level) = write_stats.budget_level {
+				println!("Used compression level {level} to fit in the time budget");
+			}
+			if *stats {
+				print_write_stats(&write_stats);
+			}
+			if *verbose {
+				print_write_timings(&write_stats.timings);
+				print_io(&write_stats.io, start.elapsed());
+				print_peak_rss(write_stats.peak_rss_bytes);
+			}
 
 		}
-		Commands::Apply { old, diff, new } => {
+		Commands::Apply { old, diff, new, preserve_timestamps, max_errors, key_file, predecompress_threshold, copy_threads, new_threads, patch_threads, skip_space_check, verbose } => {
+			let cfg = FoldiffCfg {
+				threads,
+				preserve_timestamps: *preserve_timestamps,
+				log_path: log_path.clone(),
+				max_errors: max_errors_or_unlimited(*max_errors),
+				predecompress_threshold: *predecompress_threshold,
+				// phases without their own count get as many as everything would have shared
+				phase_threads: (copy_threads.is_some() || new_threads.is_some() || patch_threads.is_some()).then(|| PhaseConcurrency {
+					copy_threads: copy_threads.unwrap_or(threads),
+					patch_threads: patch_threads.unwrap_or(threads),
+					new_threads: new_threads.unwrap_or(threads),
+				}),
+				skip_space_check: *skip_space_check,
+				..FoldiffCfg::default()
+			};
+
 			let old_root: Utf8PathBuf = old.into();
 			let new_root: Utf8PathBuf = new.into();
 			// check existence
@@ -158,24 +656,130 @@ fn main() -> Result<()> {
 				std::fs::remove_dir_all(new).context("Failed to remove folder")?;
 			}
 
-			let mut diff_state = libfoldiff::applying::read_diff_from_file(&Utf8PathBuf::from(diff))?;
-			diff_state.apply::<
+			let start = Instant::now();
+			let mut diff_state = match key_file {
+				Some(key_file) => libfoldiff::applying::read_encrypted_diff_from_file(&Utf8PathBuf::from(diff), &EncryptionCfg::from_key_file(key_file.as_str().into())?)?,
+				None => libfoldiff::applying::read_diff_from_file(&Utf8PathBuf::from(diff))?,
+			};
+			let res = diff_state.apply::<
 				cliutils::MultiWrapper,
 				cliutils::Spinner<false>,
 				cliutils::Bar
-			>(old_root, new_root)?;
+			>(old_root, new_root, &cfg, &StdVfs, None)?;
+
+			if *verbose {
+				print_io(&res.io, start.elapsed());
+				print_peak_rss(res.peak_rss_bytes);
+			}
+			if !res.is_ok() {
+				let m = diff_state.manifest();
+				let total = m.iter_new_paths().count() + m.symlinks.len() + m.hardlinked_files.len();
+				if res.failed.len() >= cfg.max_errors {
+					println!("Aborting: max error count ({max_errors}) reached. Subsequent errors may exist.");
+				}
+				println!("Applied {}/{total} files. Errors:", res.succeeded);
+				for e in &res.failed {
+					println!("{e}: {:#}", e.inner);
+				}
+				bail!("Failed to apply {} files", res.failed.len());
+			}
 		},
-		Commands::Verify { new, old, diff } => {
-			if let Some(diff) = diff {
-				let f = File::open(diff).context("Failed to open diff file to verify with")?;
-				let manifest = DiffManifest::read_from(f).context("Failed to read diff file to verify with")?;
-				libfoldiff::verify::verify_against_diff::<cliutils::Spinner<true>>(old.as_str().into(), new.as_str().into(), &manifest)?;
+		Commands::ApplyTar { old, diff, output, preserve_timestamps } => {
+			let cfg = FoldiffCfg {
+				threads,
+				preserve_timestamps: *preserve_timestamps,
+				..FoldiffCfg::default()
+			};
+
+			ensure!(std::fs::metadata(old).context("old path must exist")?.is_dir(), "old path must be a directory");
+			ensure!(std::fs::metadata(diff).context("diff must exist")?.is_file(), "diff must be a file");
+
+			if std::fs::exists(output).context("Failed to check for output existence")? {
+				ensure!(std::fs::metadata(output)?.is_file(), "Output tar exists but is not a file");
+
+				if !cli.force {
+					let cont = cliutils::confirm("Output tar exists, overwrite it?")?;
+
+					if !cont { bail!("Output tar already exists"); }
+				}
 			}
-			else {
-				libfoldiff::verify::test_dir_equality::<cliutils::Spinner<true>>(old.as_str().into(), new.as_str().into())?;
+
+			let diff_state = libfoldiff::applying::read_diff_from_file(&Utf8PathBuf::from(diff))?;
+			let f = File::create(output).context("Failed to create output tar")?;
+			let res = diff_state.apply_to_tar(Utf8Path::new(old), std::io::BufWriter::new(f), &cfg, &StdVfs);
+			if res.is_err() {
+				// a partial archive is no use to anyone
+				let _ = std::fs::remove_file(output);
+			}
+			res?;
+		},
+		Commands::Verify { new, old, diff, fast, max_errors, strict, symlinks } => {
+			let cfg = FoldiffCfg {
+				threads,
+				symlinks: *symlinks,
+				verify_mode: if *fast { VerifyMode::Fast } else { VerifyMode::Full },
+				log_path: log_path.clone(),
+				max_errors: max_errors_or_unlimited(*max_errors),
+				..FoldiffCfg::default()
+			};
+			let opts = VerifyOptions { strict: *strict };
+
+			let res =
+				if let Some(diff) = diff {
+					let f = File::open(diff).context("Failed to open diff file to verify with")?;
+					let manifest = DiffManifest::read_from(f).context("Failed to read diff file to verify with")?;
+					libfoldiff::verify::verify_against_diff::<cliutils::Spinner<true>>(old.as_str().into(), new.as_str().into(), &manifest, &cfg, opts)?
+				}
+				else {
+					libfoldiff::verify::test_dir_equality::<cliutils::Spinner<true>>(old.as_str().into(), new.as_str().into(), &cfg, opts)?
+				};
+
+			if res.mismatches.len() >= cfg.max_errors {
+				println!("Aborting: max error count ({max_errors}) reached. Subsequent errors may exist.");
+			}
+			print!("{res}");
+
+			let code = verify_exit_code(&res);
+			if code != 0 {
+				std::process::exit(code);
+			}
+		},
+		Commands::Watch { old, new, diff, level_new, level_diff, symlinks, case_insensitive, strict_scan, debounce_ms } => {
+			let cfg = FoldiffCfg {
+				threads,
+				level_new: *level_new,
+				level_diff: *level_diff,
+				symlinks: *symlinks,
+				case_insensitive_paths: *case_insensitive,
+				strict_scan: *strict_scan,
+				log_path: log_path.clone(),
+				..FoldiffCfg::default()
+			};
+
+			let old_root: Utf8PathBuf = old.into();
+			let new_root: Utf8PathBuf = new.into();
+			ensure!(std::fs::metadata(&old_root).context("old path must exist")?.is_dir(), "old path must be a directory");
+			ensure!(std::fs::metadata(&new_root).context("new path must exist")?.is_dir(), "new path must be a directory");
+
+			if std::fs::exists(diff).context("Failed to check for output existence")? {
+				ensure!(std::fs::metadata(diff)?.is_file(), "Output diff file exists but is not a file");
+
+				if !cli.force {
+					let cont = cliutils::confirm("Output diff file exists, overwrite it?")?;
+
+					if !cont { bail!("Output diff file already exists"); }
+				}
 			}
+
+			watch::watch(old_root, new_root, diff.into(), &cfg, Duration::from_millis(*debounce_ms))?;
+		},
+		Commands::Upgrade { old, .. } if !libfoldiff::upgrade::needs_upgrade(File::open(old).context("Failed to open old diff file")?)? => {
+			println!("Already up to date.");
 		},
-		Commands::Upgrade { new, old } => {
+		Commands::Upgrade { new: None, old } => {
+			libfoldiff::upgrade::auto_upgrade_in_place::<cliutils::Spinner<false>>(old)?;
+		},
+		Commands::Upgrade { new: Some(new), old } => {
 			if std::fs::exists(new).context("Failed to check for destination existence")? {
 				if !cli.force {
 					let cont = cliutils::confirm("Destination file exists, overwrite it?")?;
@@ -192,7 +796,135 @@ fn main() -> Result<()> {
 
 			libfoldiff::upgrade::auto_upgrade::<cliutils::Spinner<false>>(fold, fnew)?;
 		},
+		Commands::UpgradeAll { dir, backup_dir } => {
+			let res = libfoldiff::upgrade::upgrade_directory::<cliutils::Bar>(Utf8Path::new(dir), backup_dir.as_deref().map(Utf8Path::new))?;
+
+			println!("Upgraded {} diffs, {} were already up to date", res.upgraded.len(), res.up_to_date.len());
+			if !res.failed.is_empty() {
+				for (p, e) in &res.failed {
+					println!("{p}: {e:#}");
+				}
+				bail!("Failed to upgrade {} diffs", res.failed.len());
+			}
+		},
+		Commands::CompatMatrix => print_compat_matrix(),
+		Commands::Inspect { diff, summary, blobs } => {
+			let ver = libfoldiff::upgrade::detect_version_from_file(Utf8Path::new(diff))?;
+			println!("FLDF {}{}", ver.display_name(), if ver.is_latest() { "" } else { " (can be upgraded)" });
+
+			if *blobs {
+				// need the blob section too, not just the manifest
+				let diff = libfoldiff::applying::read_diff_from_file(&Utf8PathBuf::from(diff))
+					.context("Failed to read diff file to inspect")?;
+				print_blob_table(&diff);
+				return Ok(());
+			}
+
+			let f = File::open(diff).context("Failed to open diff file to inspect")?;
+			let manifest = DiffManifest::read_from(f).context("Failed to read diff file to inspect")?;
+
+			if *summary {
+				print!("{manifest}");
+			}
+			else {
+				print_manifest_table(&manifest);
+			}
+		},
+		Commands::Extract { diff, path, output } => {
+			let mut f = BufReader::new(File::open(diff).context("Failed to open diff file to extract from")?);
+			let mut contents = libfoldiff::catalog::seek_to_file(&mut f, path)?;
+			if let Some(output) = output {
+				let mut out = File::create(output).context("Failed to create output file")?;
+				std::io::copy(&mut contents, &mut out).context("Failed to extract file")?;
+			}
+			else {
+				std::io::copy(&mut contents, &mut std::io::stdout().lock()).context("Failed to extract file")?;
+			}
+		},
+		Commands::Compose { ab, bc, ac, level_new } => {
+			let cfg = FoldiffCfg {
+				threads,
+				level_new: *level_new,
+				log_path: log_path.clone(),
+				..FoldiffCfg::default()
+			};
+
+			if std::fs::exists(ac).context("Failed to check for output existence")? {
+				ensure!(std::fs::metadata(ac)?.is_file(), "Output diff file exists but is not a file");
+
+				if !cli.force {
+					let cont = cliutils::confirm("Output diff file exists, overwrite it?")?;
+
+					if !cont { bail!("Output diff file already exists"); }
+				}
+
+				std::fs::remove_file(ac).context("Failed to remove file")?;
+			}
+
+			libfoldiff::diffing::compose_diffs(Utf8Path::new(ab), Utf8Path::new(bc), Utf8Path::new(ac), &cfg)?;
+		},
+		Commands::Invert { ab, old, new, ba, level_new, level_diff, symlinks } => {
+			let cfg = FoldiffCfg {
+				threads,
+				level_new: *level_new,
+				level_diff: *level_diff,
+				symlinks: *symlinks,
+				log_path: log_path.clone(),
+				..FoldiffCfg::default()
+			};
+
+			ensure!(std::fs::metadata(old).context("old path must exist")?.is_dir(), "old path must be a directory");
+			ensure!(std::fs::metadata(new).context("new path must exist")?.is_dir(), "new path must be a directory");
+
+			if std::fs::exists(ba).context("Failed to check for output existence")? {
+				ensure!(std::fs::metadata(ba)?.is_file(), "Output diff file exists but is not a file");
+
+				if !cli.force {
+					let cont = cliutils::confirm("Output diff file exists, overwrite it?")?;
+
+					if !cont { bail!("Output diff file already exists"); }
+				}
+
+				std::fs::remove_file(ba).context("Failed to remove file")?;
+			}
+
+			libfoldiff::diffing::invert_diff::<cliutils::Bar, cliutils::Spinner<true>>(Utf8Path::new(ab), Utf8Path::new(ba), Utf8Path::new(old), Utf8Path::new(new), &cfg)?;
+		},
+		Commands::Validate { diff } => {
+			let report = libfoldiff::verify::validate_diff(Utf8Path::new(diff))?;
+			print!("{report}");
+			ensure!(report.ok, "Diff is not valid");
+			println!("Diff is valid");
+		},
 	}
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_cfg_default_matches_cli() {
+		let cli = Cli::try_parse_from(["foldiff", "diff", "old", "new", "diff.fldf"]).unwrap();
+		let Commands::Diff { level_new, level_diff, symlinks, case_insensitive, strict_scan, cdc, delta_chain, no_ldm, .. } = cli.command
+		else { unreachable!() };
+
+		let cfg = FoldiffCfg::default();
+		assert_eq!(cfg.level_new, level_new);
+		assert_eq!(cfg.level_diff, level_diff);
+		assert_eq!(cfg.symlinks, symlinks);
+		assert_eq!(cfg.case_insensitive_paths, case_insensitive);
+		assert_eq!(cfg.strict_scan, strict_scan);
+		assert_eq!(cfg.cdc_chunking, cdc);
+		assert_eq!(cfg.delta_chain, delta_chain);
+		assert_eq!(cfg.long_distance_matching, !no_ldm);
+
+		let cli = Cli::try_parse_from(["foldiff", "apply", "old", "diff.fldf", "new"]).unwrap();
+		let Commands::Apply { predecompress_threshold, skip_space_check, .. } = cli.command
+		else { unreachable!() };
+		assert_eq!(cfg.predecompress_threshold, predecompress_threshold);
+		assert_eq!(cfg.skip_space_check, skip_space_check);
+	}
+}
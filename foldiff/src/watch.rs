@@ -0,0 +1,93 @@
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::Duration;
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use libfoldiff::FoldiffCfg;
+use libfoldiff::vfs::StdVfs;
+use crate::cliutils;
+
+/// diffs into `tmp`, then renames it over `diff` so the old diff stays intact if anything fails
+fn rebuild(old_root: &Utf8Path, new_root: &Utf8Path, diff: &Utf8Path, tmp: &Utf8Path, cfg: &FoldiffCfg) -> Result<()> {
+	// a previous run may have died and left this behind
+	if std::fs::exists(tmp).context("Failed to check for leftover temporary diff")? {
+		std::fs::remove_file(tmp).context("Failed to remove leftover temporary diff")?;
+	}
+
+	let res = libfoldiff::diffing::scan_to_diff::<cliutils::Spinner<true>>(old_root.to_path_buf(), new_root.to_path_buf(), cfg, &StdVfs)
+		.and_then(|mut diff_state| diff_state.write_to_file::<cliutils::Bar, cliutils::Spinner<false>>(tmp, cfg));
+
+	if let Err(e) = res {
+		let _ = std::fs::remove_file(tmp);
+		return Err(e);
+	}
+
+	std::fs::rename(tmp, diff).context("Failed to replace diff file with the rebuilt one")
+}
+
+/// checks if an event should trigger a rebuild
+fn is_relevant(ev: &Event, ignored: &[PathBuf]) -> bool {
+	// we read every file while diffing, so access events would have us rebuilding forever
+	if let EventKind::Access(_) = ev.kind {
+		return false;
+	}
+
+	// writing the diff itself into the watched folder is not a change we care about
+	!ev.paths.iter().all(|p| ignored.contains(p))
+}
+
+/// waits for a relevant event, giving up after `timeout` if one is passed.
+/// returns false if we timed out.
+fn wait_for_change(rx: &Receiver<notify::Result<Event>>, ignored: &[PathBuf], timeout: Option<Duration>) -> Result<bool> {
+	loop {
+		let ev = match timeout {
+			Some(t) => match rx.recv_timeout(t) {
+				Ok(ev) => ev,
+				Err(RecvTimeoutError::Timeout) => return Ok(false),
+				Err(RecvTimeoutError::Disconnected) => anyhow::bail!("Filesystem watcher stopped unexpectedly"),
+			},
+			None => rx.recv().context("Filesystem watcher stopped unexpectedly")?,
+		};
+
+		match ev {
+			Ok(ev) if is_relevant(&ev, ignored) => return Ok(true),
+			Ok(_) => {},
+			Err(e) => eprintln!("Filesystem watcher reported an error: {e}"),
+		}
+	}
+}
+
+/// Diffs `old_root` against `new_root`, then re-diffs every time `new_root` changes.
+/// `old_root` is assumed not to change. Runs until the process is killed.
+pub fn watch(old_root: Utf8PathBuf, new_root: Utf8PathBuf, diff: Utf8PathBuf, cfg: &FoldiffCfg, debounce: Duration) -> Result<()> {
+	let tmp = Utf8PathBuf::from(format!("{diff}.tmp"));
+
+	// event paths are absolute, so make ours match
+	let ignored = [
+		std::path::absolute(&diff).context("Failed to resolve diff path")?,
+		std::path::absolute(&tmp).context("Failed to resolve temporary diff path")?,
+	];
+
+	// start watching before the first build so we don't miss anything that happens during it
+	let (tx, rx) = channel();
+	let mut watcher = notify::recommended_watcher(tx).context("Failed to create filesystem watcher")?;
+	watcher
+		.watch(&std::path::absolute(&new_root)?, RecursiveMode::Recursive)
+		.context("Failed to watch new folder")?;
+
+	loop {
+		if let Err(e) = rebuild(&old_root, &new_root, &diff, &tmp, cfg) {
+			eprintln!("Failed to rebuild diff, keeping the previous one: {e:?}");
+		}
+		else {
+			eprintln!("Wrote diff to {diff}");
+		}
+
+		eprintln!("Watching {new_root} for changes...");
+		wait_for_change(&rx, &ignored, None)?;
+
+		// let things settle before rebuilding
+		while wait_for_change(&rx, &ignored, Some(debounce))? {}
+	}
+}
@@ -0,0 +1,116 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+fn foldiff() -> Command {
+	Command::new(env!("CARGO_BIN_EXE_foldiff"))
+}
+
+/// sets up an old and new folder, and a diff between them
+fn setup() -> tempfile::TempDir {
+	let dir = tempfile::tempdir().unwrap();
+	let old = dir.path().join("old");
+	let new = dir.path().join("new");
+	fs::create_dir_all(&old).unwrap();
+	fs::create_dir_all(&new).unwrap();
+
+	fs::write(old.join("same.txt"), "unchanged").unwrap();
+	fs::write(new.join("same.txt"), "unchanged").unwrap();
+	fs::write(old.join("edited.txt"), "before").unwrap();
+	fs::write(new.join("edited.txt"), "after").unwrap();
+
+	let out = foldiff()
+		.arg("diff")
+		.args([&old, &new, &dir.path().join("diff.fldf")])
+		.output()
+		.unwrap();
+	assert!(out.status.success());
+
+	dir
+}
+
+fn verify(dir: &Path) -> i32 {
+	foldiff()
+		.arg("verify")
+		.args([dir.join("old"), dir.join("new"), dir.join("diff.fldf")])
+		.output()
+		.unwrap()
+		.status
+		.code()
+		.unwrap()
+}
+
+#[test]
+fn test_verify_ok() {
+	let dir = setup();
+	assert_eq!(verify(dir.path()), 0);
+}
+
+#[test]
+fn test_verify_bad_diff() {
+	let dir = setup();
+	fs::write(dir.path().join("diff.fldf"), "not a diff").unwrap();
+	assert_eq!(verify(dir.path()), 1);
+}
+
+#[test]
+fn test_verify_hash_mismatch() {
+	let dir = setup();
+	fs::write(dir.path().join("new/edited.txt"), "oops").unwrap();
+	assert_eq!(verify(dir.path()), 2);
+}
+
+#[test]
+fn test_verify_missing() {
+	let dir = setup();
+	fs::remove_file(dir.path().join("new/edited.txt")).unwrap();
+	assert_eq!(verify(dir.path()), 3);
+}
+
+#[test]
+fn test_verify_extra() {
+	let dir = setup();
+	fs::write(dir.path().join("new/extra.txt"), "surprise").unwrap();
+	assert_eq!(verify(dir.path()), 4);
+}
+
+#[test]
+fn test_verify_highest_code_wins() {
+	let dir = setup();
+	fs::write(dir.path().join("new/edited.txt"), "oops").unwrap();
+	fs::write(dir.path().join("new/extra.txt"), "surprise").unwrap();
+	assert_eq!(verify(dir.path()), 4);
+}
+
+#[test]
+fn test_verify_max_errors() {
+	let dir = setup();
+	for i in 0..20 {
+		fs::write(dir.path().join(format!("new/extra{i}.txt")), "surprise").unwrap();
+	}
+
+	let out = foldiff()
+		.args(["verify", "--max-errors", "5"])
+		.args([dir.path().join("old"), dir.path().join("new"), dir.path().join("diff.fldf")])
+		.output()
+		.unwrap();
+	let stdout = String::from_utf8(out.stdout).unwrap();
+	assert!(stdout.starts_with("Aborting: max error count (5) reached. Subsequent errors may exist.\n"), "{stdout}");
+	assert!(stdout.lines().count() < 20, "{stdout}");
+	assert_eq!(out.status.code(), Some(4));
+}
+
+#[test]
+fn test_verify_strict() {
+	let dir = setup();
+	fs::write(dir.path().join("new/extra.txt"), "surprise").unwrap();
+
+	let out = foldiff()
+		.args(["verify", "--strict"])
+		.args([dir.path().join("old"), dir.path().join("new"), dir.path().join("diff.fldf")])
+		.output()
+		.unwrap();
+	let stderr = String::from_utf8(out.stderr).unwrap();
+	assert!(stderr.contains("extra.txt\" exists, but is not in the diff"), "{stderr}");
+	assert_eq!(out.status.code(), Some(1));
+}
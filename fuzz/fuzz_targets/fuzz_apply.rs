@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+
+// the input is a u32 length, then that many bytes of the old file, then the diff.
+// errors are fine, but libfuzzer counts any panic or running out of memory as a crash
+fuzz_target!(|data: &[u8]| {
+	let Some((len, rest)) = data.split_first_chunk::<4>()
+	else { return };
+	let old_len = (u32::from_be_bytes(*len) as usize).min(rest.len());
+	let (old, diff) = rest.split_at(old_len);
+
+	let _ = libfoldiff::zstddiff::apply(&mut &old[..], &mut Cursor::new(diff), &mut std::io::sink(), old.len() as u64);
+});
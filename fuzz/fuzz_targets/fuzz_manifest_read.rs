@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+
+// the input is a whole diff file, of which only the header and manifest are read
+fuzz_target!(|data: &[u8]| {
+	let _ = libfoldiff::manifest::DiffManifest::read_from(Cursor::new(data));
+});
@@ -0,0 +1,156 @@
+// applying a diff of many files, run with `cargo bench -p libfoldiff --bench apply`.
+// the mixed diff has 300 untouched, 200 patched, and 500 new files, and each phase is also applied on its own
+
+use std::io::Write;
+use std::time::Duration;
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use libfoldiff::applying::read_diff_from_file;
+use libfoldiff::diffing::scan_to_diff;
+use libfoldiff::reporting::{NoopMultiWrapper, NoopReporter};
+use libfoldiff::vfs::{StdVfs, VfsMetadata, VfsProvider};
+use libfoldiff::{FoldiffCfg, PhaseConcurrency};
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use tempfile::TempDir;
+
+const FILE_SIZE: usize = 64 << 10;
+
+/// takes this long to create each file, like a network filesystem
+struct SlowVfs(Duration);
+
+impl VfsProvider for SlowVfs {
+	fn open_read(&self, path: &Utf8Path) -> Result<Box<dyn std::io::Read>> {
+		StdVfs.open_read(path)
+	}
+
+	fn create_write(&self, path: &Utf8Path) -> Result<Box<dyn Write>> {
+		std::thread::sleep(self.0);
+		StdVfs.create_write(path)
+	}
+
+	fn exists(&self, path: &Utf8Path) -> bool {
+		StdVfs.exists(path)
+	}
+
+	fn read_dir(&self, path: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+		StdVfs.read_dir(path)
+	}
+
+	fn metadata(&self, path: &Utf8Path) -> Result<VfsMetadata> {
+		StdVfs.metadata(path)
+	}
+}
+
+/// an old folder and a diff to apply to it
+struct Workload {
+	old: Utf8PathBuf,
+	diff: Utf8PathBuf,
+	files: usize,
+}
+
+fn write_random(path: &Utf8Path, seed: u64) {
+	let mut data = vec![0; FILE_SIZE];
+	StdRng::seed_from_u64(seed).fill_bytes(&mut data);
+	std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+	std::fs::write(path, data).unwrap();
+}
+
+/// makes a diff with this many of each kind of file in `root/name`, the same every run
+fn make_workload(root: &Utf8Path, name: &str, untouched: usize, patched: usize, new: usize, cfg: &FoldiffCfg) -> Workload {
+	let (old, new_root) = (root.join(name).join("old"), root.join(name).join("new"));
+	std::fs::create_dir_all(&old).unwrap();
+	std::fs::create_dir_all(&new_root).unwrap();
+
+	for i in 0..untouched {
+		let path = format!("untouched/{}/{i}", i % 10);
+		write_random(&old.join(&path), i as u64);
+		write_random(&new_root.join(&path), i as u64);
+	}
+	for i in 0..patched {
+		let path = format!("patched/{}/{i}", i % 10);
+		write_random(&old.join(&path), 1_000_000 + i as u64);
+		write_random(&new_root.join(&path), 1_000_000 + i as u64);
+		// a few bytes added on the end
+		let mut f = std::fs::OpenOptions::new().append(true).open(new_root.join(&path)).unwrap();
+		f.write_all(b"changed").unwrap();
+	}
+	for i in 0..new {
+		write_random(&new_root.join(format!("new/{}/{i}", i % 10)), 2_000_000 + i as u64);
+	}
+
+	let diff = root.join(name).join("diff.fldf");
+	scan_to_diff::<NoopReporter>(old.clone(), new_root, cfg, &StdVfs).unwrap()
+		.write_to_file::<NoopReporter, NoopReporter>(&diff, cfg).unwrap();
+	Workload { old, diff, files: untouched + patched + new }
+}
+
+/// applies into a new folder next to the workload, which is deleted after it's timed
+fn apply_into(w: &Workload, out: &Utf8Path, cfg: &FoldiffCfg, vfs: &dyn VfsProvider) {
+	let res = read_diff_from_file(&w.diff).unwrap()
+		.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(w.old.clone(), out.to_path_buf(), cfg, vfs, None)
+		.unwrap();
+	assert!(res.is_ok());
+}
+
+fn fresh_dir(root: &Utf8Path) -> (TempDir, Utf8PathBuf) {
+	let dir = tempfile::tempdir_in(root).unwrap();
+	let out = Utf8PathBuf::try_from(dir.path().join("out")).unwrap();
+	(dir, out)
+}
+
+fn cfg_with_threads(threads: usize) -> FoldiffCfg {
+	FoldiffCfg {
+		threads,
+		phase_threads: Some(PhaseConcurrency { copy_threads: threads, patch_threads: threads, new_threads: threads }),
+		..FoldiffCfg::default()
+	}
+}
+
+fn bench_apply(c: &mut Criterion) {
+	let dir = tempfile::tempdir().unwrap();
+	let root = Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap();
+	let cfg = cfg_with_threads(4);
+	let mixed = make_workload(&root, "mixed", 300, 200, 500, &cfg);
+
+	// each phase on its own, to see which one is holding the others up
+	let mut group = c.benchmark_group("apply phases");
+	for (name, untouched, patched, new) in [("untouched", 300, 0, 0), ("patched", 0, 200, 0), ("new", 0, 0, 500)] {
+		let w = make_workload(&root, name, untouched, patched, new, &cfg);
+		group.throughput(Throughput::Elements(w.files as u64));
+		group.bench_function(name, |b| b.iter_batched(|| fresh_dir(&root), |(dir, out)| {
+			apply_into(&w, &out, &cfg, &StdVfs);
+			dir
+		}, BatchSize::PerIteration));
+	}
+	group.finish();
+
+	let mut group = c.benchmark_group("apply mixed");
+	group.throughput(Throughput::Elements(mixed.files as u64));
+	for threads in [1, 2, 4, 8] {
+		let cfg = cfg_with_threads(threads);
+		group.bench_with_input(BenchmarkId::new("threads", threads), &cfg, |b, cfg| b.iter_batched(|| fresh_dir(&root), |(dir, out)| {
+			apply_into(&mixed, &out, cfg, &StdVfs);
+			dir
+		}, BatchSize::PerIteration));
+	}
+
+	// 1ms per new or patched file, which is more about how many are in flight at once than how fast the disk is
+	group.bench_function("slow filesystem", |b| b.iter_batched(|| fresh_dir(&root), |(dir, out)| {
+		apply_into(&mixed, &out, &cfg, &SlowVfs(Duration::from_millis(1)));
+		dir
+	}, BatchSize::PerIteration));
+
+	// writing into a temporary folder then moving it into place, so the output never exists half written
+	group.bench_function("temp folder then rename", |b| b.iter_batched(|| fresh_dir(&root), |(dir, out)| {
+		let tmp = out.with_file_name("out.tmp");
+		apply_into(&mixed, &tmp, &cfg, &StdVfs);
+		std::fs::rename(&tmp, &out).unwrap();
+		dir
+	}, BatchSize::PerIteration));
+	group.finish();
+}
+
+criterion_group!(benches, bench_apply);
+criterion_main!(benches);
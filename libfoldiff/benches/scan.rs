@@ -0,0 +1,82 @@
+// scanning folders into a diff, run with `cargo bench -p libfoldiff --bench scan`.
+// each tree is scanned against an identical copy, where every file is untouched,
+// and against a copy with 10% of its files changed
+
+use std::io::Write;
+use camino::Utf8PathBuf;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use libfoldiff::diffing::scan_to_diff;
+use libfoldiff::reporting::NoopReporter;
+use libfoldiff::vfs::StdVfs;
+use libfoldiff::FoldiffCfg;
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use tempfile::TempDir;
+
+/// an old folder and two new ones, deleted when dropped
+struct Trees {
+	_dir: TempDir,
+	old: Utf8PathBuf,
+	same: Utf8PathBuf,
+	changed: Utf8PathBuf,
+}
+
+/// `count` files of `size` random bytes, the same every run
+fn setup(count: usize, size: usize) -> Trees {
+	let dir = tempfile::tempdir().unwrap();
+	let root = Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap();
+	let (old, same, changed) = (root.join("old"), root.join("same"), root.join("changed"));
+
+	let mut buf = vec![0; size.min(64 << 20)];
+	for i in 0..count {
+		// spread over a few dirs, like a real folder
+		let path = format!("{}/{i}.bin", i % 10);
+		for root in [&old, &same, &changed] {
+			std::fs::create_dir_all(root.join(&path).parent().unwrap()).unwrap();
+		}
+		let mut files = [&old, &same, &changed].map(|r| std::fs::File::create(r.join(&path)).unwrap());
+
+		let mut rng = StdRng::seed_from_u64(i as u64);
+		let mut left = size;
+		while left > 0 {
+			let n = left.min(buf.len());
+			rng.fill_bytes(&mut buf[..n]);
+			for f in &mut files {
+				f.write_all(&buf[..n]).unwrap();
+			}
+			left -= n;
+		}
+		// one byte is enough for the whole file to hash differently
+		if i % 10 == 0 {
+			files[2].write_all(b"!").unwrap();
+		}
+	}
+
+	Trees { _dir: dir, old, same, changed }
+}
+
+fn bench_scan(c: &mut Criterion) {
+	let cfg = FoldiffCfg::default();
+
+	for (name, count, size) in [("1000x1KiB", 1000, 1 << 10), ("100x1MiB", 100, 1 << 20), ("1x500MiB", 1, 500 << 20)] {
+		let trees = setup(count, size);
+		let mut group = c.benchmark_group(format!("scan {name}"));
+		group.sample_size(10);
+		// small files are about how much each file costs, and big ones about how fast they're read and hashed.
+		// both folders are scanned, so that's twice the files
+		group.throughput(
+			if size < 1 << 20 { Throughput::Elements(2 * count as u64) }
+			else { Throughput::Bytes(2 * (count * size) as u64) }
+		);
+
+		for (variant, new) in [("unchanged", &trees.same), ("10% changed", &trees.changed)] {
+			group.bench_with_input(BenchmarkId::from_parameter(variant), new, |b, new| b.iter(|| {
+				scan_to_diff::<NoopReporter>(trees.old.clone(), new.clone(), &cfg, &StdVfs).unwrap()
+			}));
+		}
+		group.finish();
+	}
+}
+
+criterion_group!(benches, bench_scan);
+criterion_main!(benches);
@@ -0,0 +1,63 @@
+// diffing and applying single files, run with `cargo bench -p libfoldiff`.
+// old is random, and new is old with 1% of it overwritten in 100 places
+
+use std::io::Cursor;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use libfoldiff::zstddiff;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+
+const LEVELS: [u8; 3] = [3, 7, 15];
+
+fn make_files(size: usize) -> (Vec<u8>, Vec<u8>) {
+	let mut rng = StdRng::seed_from_u64(size as u64);
+	let mut old = vec![0; size];
+	rng.fill_bytes(&mut old);
+
+	let mut new = old.clone();
+	let run = size / 100 / 100;
+	for _ in 0..100 {
+		let at = rng.gen_range(0..size - run);
+		rng.fill_bytes(&mut new[at..at + run]);
+	}
+	(old, new)
+}
+
+fn make_diff(old: &[u8], new: &[u8], level: u8) -> Vec<u8> {
+	let mut diff = Cursor::new(Vec::new());
+	zstddiff::diff(&mut Cursor::new(old), &mut Cursor::new(new), &mut diff, Some(level), None, None, None).unwrap();
+	diff.into_inner()
+}
+
+fn bench_zstddiff(c: &mut Criterion) {
+	for (name, size) in [("1MiB", 1 << 20), ("100MiB", 100 << 20)] {
+		let (old, new) = make_files(size);
+		let mut group = c.benchmark_group(format!("zstddiff {name}"));
+		// throughput is of the new file, as that is what is read when diffing and written when applying
+		group.throughput(Throughput::Bytes(size as u64));
+		if size > 1 << 20 {
+			group.sample_size(10);
+		}
+
+		for level in LEVELS {
+			let diff = make_diff(&old, &new, level);
+			// criterion can't measure sizes, but they're half of what matters
+			eprintln!("{name} at level {level}: {} byte diff", diff.len());
+
+			group.bench_with_input(BenchmarkId::new("diff", level), &level, |b, &level| b.iter(|| make_diff(&old, &new, level)));
+			group.bench_with_input(BenchmarkId::new("apply", level), &diff, |b, diff| b.iter(|| {
+				let mut out = Vec::with_capacity(size);
+				zstddiff::apply(&mut &old[..], &mut Cursor::new(diff), &mut out, size as u64).unwrap();
+				out
+			}));
+		}
+
+		// what diffing is up against: compressing the new file without the old one
+		eprintln!("{name} without the old file: {} bytes", zstd::encode_all(&new[..], 3).unwrap().len());
+		group.bench_function("zstd without old", |b| b.iter(|| zstd::encode_all(&new[..], 3).unwrap()));
+		group.finish();
+	}
+}
+
+criterion_group!(benches, bench_zstddiff);
+criterion_main!(benches);
@@ -1,14 +1,139 @@
-use crate::common::{copy_rl, copy_rl_hash, create_file};
-use crate::manifest::DiffManifest;
+use crate::encryption::EncryptionCfg;
+use crate::common::{check_hash, padding_to, copy_rl_hash, create_symlink, set_mode, to_unix_time, ApplyOperation, ErrorAction, FoldiffCfg, FoldiffError, HashAlgorithm, Phase, PhaseError, DEFAULT_FILE_MODE};
+use crate::manifest::{DiffManifest, FileAttributes, NewFile, PatchedFile};
 use crate::reporting::{AutoSpin, CanBeWrappedBy, Reporter, ReporterSized, ReportingMultiWrapper};
-use crate::{aggregate_errors, handle_res_async, handle_res_parit, hash, throw_err_async, zstddiff};
-use anyhow::{anyhow, Context};
-use memmap2::Mmap;
+use crate::oplog::OpLog;
+use crate::iocount::{IoAccounting, IoCounters};
+use crate::threading::{is_cancelled, CancellationToken};
+use crate::vfs::VfsProvider;
+use crate::{handle_res_parit, hash, memory, throw_err_async, zstddiff};
+use anyhow::{anyhow, ensure, Context};
+use memmap2::{Mmap, MmapMut};
+use zstd::dict::DecoderDictionary;
 use rayon::prelude::*;
+use std::borrow::Cow;
 use std::fs::File;
-use std::io::{Cursor, Read, Seek};
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom, Write};
 use camino::{Utf8Path, Utf8PathBuf};
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Instant, SystemTime};
+
+/// copies the contents of a new blob, decompressing it unless it was stored as-is.
+/// returns how many bytes were written.
+fn copy_blob(read: &mut impl Read, write: &mut impl Write, raw: bool, dict: Option<&DecoderDictionary>) -> std::io::Result<u64> {
+	if raw {
+		std::io::copy(read, write)
+	}
+	else if let Some(dict) = dict {
+		let mut dec = zstd::Decoder::with_prepared_dictionary(BufReader::new(read), dict)?;
+		std::io::copy(&mut dec, write)
+	}
+	else {
+		let mut dec = zstd::Decoder::new(read)?;
+		std::io::copy(&mut dec, write)
+	}
+}
+
+/// how many bytes of small new files to decompress into memory at once, before writing them
+const PREDECOMPRESS_BATCH: u64 = 64 << 20;
+
+/// runs `f` in `pool`, or in the current pool if there isn't one
+fn in_pool<R: Send>(pool: Option<&rayon::ThreadPool>, f: impl FnOnce() -> R + Send) -> R {
+	match pool {
+		Some(pool) => pool.install(f),
+		None => f(),
+	}
+}
+
+/// ties an error to the file it came from
+fn in_phase(phase: Phase, path: &str, err: Option<anyhow::Error>) -> Option<PhaseError> {
+	err.map(|inner| PhaseError { phase, path: path.to_string(), inner })
+}
+
+/// sorts the errors from a phase into `failed`.
+/// anything that isn't tied to a file is returned, as then we can't carry on.
+fn collect_failures(errs: Vec<anyhow::Error>, failed: &mut Vec<PhaseError>) -> anyhow::Result<()> {
+	for e in errs {
+		failed.push(e.downcast::<PhaseError>()?);
+	}
+	Ok(())
+}
+
+/// permissions only mean anything for files that actually have them
+fn has_modes(vfs: &dyn VfsProvider, path: &Utf8Path) -> bool {
+	vfs.metadata(path).is_ok_and(|m| m.mode.is_some())
+}
+
+/// fails with FoldiffError::InsufficientDiskSpace if the new folder won't fit with 5% to spare.
+/// diffs that don't know the size of every file, and filesystems that can't say how much space is free, aren't checked
+fn check_disk_space(manifest: &DiffManifest, new_root: &Utf8Path) -> anyhow::Result<()> {
+	let Some(needed) = manifest.required_disk_bytes()
+	else { return Ok(()) };
+	let required = needed.saturating_add(needed / 20);
+
+	// the new folder usually doesn't exist yet, nor might its parents
+	let existing = new_root.ancestors().find(|p| p.exists()).unwrap_or(Utf8Path::new("."));
+	let Ok(available) = fs2::available_space(existing)
+	else { return Ok(()) };
+	ensure!(available >= required, FoldiffError::InsufficientDiskSpace { available, required });
+	Ok(())
+}
+
+/// writes out a new blob to `path`, checking it hashes to `hash`. returns how many bytes were written.
+fn write_new_blob(blob: &[u8], dest: &mut impl Write, raw: bool, dict: Option<&DecoderDictionary>, path: &str, hash: u64, algo: HashAlgorithm) -> anyhow::Result<u64> {
+	let mut wrt = hash::XXHashStreamer::with_algorithm(&mut *dest, algo);
+	let written = copy_blob(&mut Cursor::new(blob), &mut wrt, raw, dict).context("Failed to decompress")?;
+
+	let rh = wrt.finish();
+	// writes may not have happened yet, and errors would be lost when dropped
+	dest.flush().context("Failed to write")?;
+	check_hash(path, ApplyOperation::Decompress, hash, rh)?;
+	Ok(written)
+}
+
+/// applies the patch blob for `pf` to `src`, checking both the source and the result hash as expected
+fn write_patched(src: &mut impl Read, src_len: u64, patch: &mut impl Read, dest: &mut impl Write, dict: Option<&[u8]>, pf: &PatchedFile, algo: HashAlgorithm) -> anyhow::Result<()> {
+	let mut src = hash::XXHashStreamer::with_algorithm(src, algo);
+	let mut dst = hash::XXHashStreamer::with_algorithm(&mut *dest, algo);
+
+	zstddiff::apply_with_dict(&mut src, patch, &mut dst, src_len, dict)?;
+
+	let src_rh = src.finish();
+	let dst_rh = dst.finish();
+	dest.flush().context("Failed to write patched file")?;
+	check_hash(&pf.path, ApplyOperation::CheckOld, pf.old_hash, src_rh)?;
+	check_hash(&pf.path, ApplyOperation::Patch, pf.new_hash, dst_rh)?;
+	Ok(())
+}
+
+/// patched files in the order their patches were made, which delta chained ones have to be applied in
+fn patches_in_order(manifest: &DiffManifest) -> Vec<&PatchedFile> {
+	let mut patches: Vec<_> = manifest.patched_files.iter().collect();
+	patches.sort_by_key(|pf| pf.index);
+	patches
+}
+
+/// How applying a diff went, when it got far enough to try every file
+#[derive(Debug, Default)]
+pub struct ApplyResult {
+	/// how many files, symlinks, and hard links were created
+	pub succeeded: usize,
+	/// every file that went wrong. each one's `inner` may be an ApplyError.
+	pub failed: Vec<PhaseError>,
+	/// how much was read from the old folder and the diff, and written to the new folder
+	pub io: IoAccounting,
+	/// the most memory in use at once while applying, with the `memory-tracking` feature on linux or windows.
+	/// on windows this is the peak for the whole process so far
+	pub peak_rss_bytes: Option<u64>,
+}
+
+impl ApplyResult {
+	pub fn is_ok(&self) -> bool {
+		self.failed.is_empty()
+	}
+}
 
 /// An in-memory representation of a diff, used for the applying process
 #[derive(Debug, Default)]
@@ -16,27 +141,186 @@ pub struct ApplyingDiff {
 	manifest: DiffManifest,
 	blobs_new: Vec<u64>,   // offset into diff file
 	blobs_patch: Vec<u64>, // offset into diff file
+	blob_sizes_new: Vec<u64>,   // stored length, excluding the length prefix
+	blob_sizes_patch: Vec<u64>, // total length of all chunks, excluding the chunk headers
+	dict_new: Option<(u64, u64)>, // offset into diff file, length
+	dict_patch: Option<(u64, u64)>, // offset into diff file, length
 	read: Option<Mmap>, // the diff file map
+	key: Option<EncryptionCfg>, // set if the diff is encrypted
 	old_root: Utf8PathBuf,
 	new_root: Utf8PathBuf,
 }
 
 impl ApplyingDiff {
+	pub fn manifest(&self) -> &DiffManifest {
+		&self.manifest
+	}
+
+	/// how many bytes each new blob takes up in the diff, by blob index
+	pub fn new_blob_sizes(&self) -> &[u64] {
+		&self.blob_sizes_new
+	}
+
+	/// how many bytes each patch blob takes up in the diff, by blob index
+	pub fn patch_blob_sizes(&self) -> &[u64] {
+		&self.blob_sizes_patch
+	}
+
+	/// the whole diff file, to read blobs out of
+	fn map(&self) -> anyhow::Result<&[u8]> {
+		Ok(&**self.read.as_ref().ok_or(anyhow!("Cannot read blobs from a state without a set `read` prop"))?)
+	}
+
+	/// decrypts a new blob or dictionary if the diff is encrypted
+	fn open_blob<'a>(&self, blob: &'a [u8]) -> anyhow::Result<Cow<'a, [u8]>> {
+		match &self.key {
+			Some(key) => Ok(Cow::Owned(key.open(blob)?)),
+			None => Ok(Cow::Borrowed(blob)),
+		}
+	}
+
+	/// a dictionary from the diff, decrypted if need be
+	fn dict(&self, dict: Option<(u64, u64)>) -> anyhow::Result<Option<Cow<'_, [u8]>>> {
+		let map = self.map()?;
+		dict.map(|(o, l)| self.open_blob(&map[o as usize..(o + l) as usize]).context("Failed to decrypt dictionary")).transpose()
+	}
+
+	/// decompresses new blob `idx` for `path`, checking it hashes to `hash`
+	pub(crate) fn read_new_blob(&self, idx: u64, path: &str, hash: u64) -> anyhow::Result<Vec<u8>> {
+		let map = self.map()?;
+		let start = *self.blobs_new.get(idx as usize).ok_or(anyhow!("New blob {idx} is out of range"))? as usize + 8; // skip length
+		let len = self.blob_sizes_new[idx as usize] as usize;
+		let dict = self.dict(self.dict_new)?.as_deref().map(DecoderDictionary::copy);
+		let blob = self.open_blob(&map[start..(start + len)]).with_context(|| format!("Failed to decrypt new file {path}"))?;
+
+		let mut data = Vec::new();
+		write_new_blob(&blob, &mut data, self.manifest.uncompressed_blobs.contains(&idx), dict.as_ref(), path, hash, self.manifest.hash_algorithm)?;
+		Ok(data)
+	}
+
+	/// patch blob `idx` as it is stored, chunk headers and all, but decrypted if the diff is encrypted
+	pub(crate) fn patch_blob(&self, idx: u64) -> anyhow::Result<Cow<'_, [u8]>> {
+		let map = self.map()?;
+		let start = *self.blobs_patch.get(idx as usize).ok_or(anyhow!("Patch blob {idx} is out of range"))? as usize;
+		// blob_sizes_patch doesn't count the chunk count, the length of each chunk, or stored old chunk lengths
+		let (count, stored) = zstddiff::split_chunk_count(u64::from_be_bytes(*map[start..].first_chunk().unwrap()));
+		let headers = if stored { 2 } else { 1 };
+		let len = 8 + count * 8 * headers + self.blob_sizes_patch[idx as usize];
+		let blob = &map[start..(start + len as usize)];
+		match &self.key {
+			Some(key) => Ok(Cow::Owned(key.open_patch_blob(blob).with_context(|| format!("Failed to decrypt patch blob {idx}"))?)),
+			None => Ok(Cow::Borrowed(blob)),
+		}
+	}
+
+	/// the dictionary that patch blobs were made with, if any
+	pub(crate) fn patch_dict(&self) -> anyhow::Result<Option<Cow<'_, [u8]>>> {
+		self.dict(self.dict_patch)
+	}
+
+	/// applies the patch blob for `pf` to `src`, checking both hashes.
+	/// `prev` is the new contents of the patched file before it, which is only used in delta chained diffs
+	pub(crate) fn patch_in_memory(&self, pf: &PatchedFile, src: &[u8], prev: &[u8]) -> anyhow::Result<Vec<u8>> {
+		let dict = self.patch_dict()?;
+		let chained = self.manifest.delta_chain.then(|| zstddiff::chain_dict(prev, dict.as_deref()));
+		let mut data = Vec::new();
+		write_patched(&mut &src[..], src.len() as u64, &mut &self.patch_blob(pf.index)?[..], &mut data, chained.as_deref().or(dict.as_deref()), pf, self.manifest.hash_algorithm)?;
+		Ok(data)
+	}
+
+	/// checks the manifest is consistent with the blobs we found
+	fn validate(&self) -> anyhow::Result<()> {
+		self.manifest
+			.validate(self.blobs_new.len() as u64, self.blobs_patch.len() as u64)
+			.map_err(|problems| anyhow!("Diff is inconsistent:\n{}", problems.join("\n")))
+	}
+
+	/// writes out the new folder. if `cancel` is cancelled, files that haven't been started yet are skipped,
+	/// and FoldiffError::Cancelled is returned, leaving the new folder partially written.
+	/// new and patched files are written through `vfs`, but unchanged files and links still use std::fs,
+	/// and attributes are only set on files that `vfs` reports permissions for.
+	/// files that fail don't stop the others from being written, and are listed in the result instead of returning Err.
+	/// once `cfg.max_errors` files have failed, the rest are skipped.
 	pub fn apply<
 		TWrap: ReportingMultiWrapper,
 		TSpin: Reporter + CanBeWrappedBy<TWrap> + Sync,
 		TBar: ReporterSized + CanBeWrappedBy<TWrap> + Sync
-	>(&mut self, old_root: Utf8PathBuf, new_root: Utf8PathBuf) -> anyhow::Result<()> {
+	>(&mut self, old_root: Utf8PathBuf, new_root: Utf8PathBuf, cfg: &FoldiffCfg, vfs: &dyn VfsProvider, cancel: Option<&CancellationToken>) -> anyhow::Result<ApplyResult> {
+		self.apply_with_recovery::<TWrap, TSpin, TBar>(old_root, new_root, cfg, vfs, cancel, |_| ErrorAction::Skip)
+	}
+
+	/// Same as `apply`, but calls `on_error` as soon as each file fails, from whichever thread it failed on.
+	/// If it returns `ErrorAction::Abort`, the rest of the files are skipped as if `cfg.max_errors` had been reached.
+	/// Either way, the file is listed in the result.
+	/// Hash mismatches can be told apart by downcasting the error's `inner` to an ApplyError.
+	pub fn apply_with_recovery<
+		TWrap: ReportingMultiWrapper,
+		TSpin: Reporter + CanBeWrappedBy<TWrap> + Sync,
+		TBar: ReporterSized + CanBeWrappedBy<TWrap> + Sync
+	>(
+		&mut self,
+		old_root: Utf8PathBuf,
+		new_root: Utf8PathBuf,
+		cfg: &FoldiffCfg,
+		vfs: &dyn VfsProvider,
+		cancel: Option<&CancellationToken>,
+		on_error: impl Fn(&PhaseError) -> ErrorAction + Sync,
+	) -> anyhow::Result<ApplyResult> {
+		// check this before doing anything, instead of after writing everything
+		ensure!(!cfg.preserve_timestamps || cfg!(feature = "timestamps"), "Preserving timestamps requires libfoldiff's `timestamps` feature");
+
+		self.validate()?;
+		if !cfg.skip_space_check {
+			check_disk_space(&self.manifest, &new_root)?;
+		}
+		let log = OpLog::open(cfg)?;
+		memory::reset_peak_rss();
+
 		self.old_root = old_root;
 		self.new_root = new_root;
 
 		let diff_map = &**self.read.as_ref().ok_or(anyhow!("Cannot call apply() on a state without a set `read` prop"))?;
 
+		let raw_blobs: HashSet<u64> = self.manifest.uncompressed_blobs.iter().copied().collect();
+		let (dict_new, dict_patch) = (self.dict(self.dict_new)?, self.patch_dict()?);
+		// digested once, instead of for every new file
+		let dict_new = dict_new.as_deref().map(DecoderDictionary::copy);
+		let (dict_new, dict_patch) = (dict_new.as_ref(), dict_patch.as_deref());
+
 		let num_duped_copy: usize = self.manifest.duplicated_files.iter().filter(|d| d.idx == u64::MAX).map(|d| d.new_paths.len()).sum();
 		let num_duped_create: usize = self.manifest.duplicated_files.iter().filter(|d| d.idx != u64::MAX).map(|d| d.new_paths.len()).sum();
 
+		let succeeded = AtomicUsize::new(0);
+		let mut failed = Vec::new();
+
+		// once too many files have failed, or the caller gives up, we stop starting new ones just like when cancelled,
+		// but still return what went wrong
+		let num_failed = AtomicUsize::new(0);
+		let aborted = CancellationToken::new();
+		let stopped = || is_cancelled(cancel) || aborted.is_cancelled();
+		let fail = |phase: Phase, path: &str, res: Option<anyhow::Error>| -> Option<anyhow::Error> {
+			let err = in_phase(phase, path, res)?;
+			let too_many = num_failed.fetch_add(1, Ordering::Relaxed) + 1 >= cfg.max_errors;
+			if too_many || on_error(&err) == ErrorAction::Abort {
+				aborted.cancel();
+			}
+			Some(err.into())
+		};
+
+		// progress reporting
+		let wrap = TWrap::new();
+		let overall = wrap.add_overall_bar(
+			self.manifest.untouched_files.len() + num_duped_copy
+				+ self.manifest.new_files.len() + num_duped_create
+				+ self.manifest.patched_files.len()
+				+ self.manifest.symlinks.len()
+				+ self.manifest.hardlinked_files.len()
+		);
+
 		// incr bar and finish if done
 		let inc_n = |n: usize, b: &TBar| {
+			succeeded.fetch_add(n, Ordering::Relaxed);
+			overall.incr(n);
 			b.incr(n);
 			if b.count() == b.length() {
 				b.done();
@@ -44,26 +328,49 @@ impl ApplyingDiff {
 		};
 		let inc = |b: &TBar| inc_n(1, b);
 
-		// progress reporting
-		let wrap = TWrap::new();
 		let spn = TSpin::new("Applying diff").add_to(&wrap);
 		let bar_untouched = <TBar as ReporterSized>::new("Copying unchanged files", self.manifest.untouched_files.len() + num_duped_copy).add_to(&wrap);
 		let bar_new = <TBar as ReporterSized>::new("Creating new files", self.manifest.new_files.len() + num_duped_create).add_to(&wrap);
 		let bar_patched = <TBar as ReporterSized>::new("Applying patched files", self.manifest.patched_files.len()).add_to(&wrap);
+		let bar_symlinks = <TBar as ReporterSized>::new("Creating symlinks", self.manifest.symlinks.len()).add_to(&wrap);
+		let bar_hardlinks = <TBar as ReporterSized>::new("Creating hard links", self.manifest.hardlinked_files.len()).add_to(&wrap);
 
+		let as0 = AutoSpin::spin(&overall);
 		let as1 = AutoSpin::spin(&spn);
 		let as2 = AutoSpin::spin(&bar_untouched);
 		let as3 = AutoSpin::spin(&bar_new);
 		let as4 = AutoSpin::spin(&bar_patched);
+		let as5 = AutoSpin::spin(&bar_symlinks);
+		let as6 = AutoSpin::spin(&bar_hardlinks);
+
+		// these have no files in them to create them for us
+		for dir in &self.manifest.empty_dirs_new {
+			std::fs::create_dir_all(self.new_root.join(dir)).with_context(|| format!("Failed to create empty dir {dir}"))?;
+		}
+
+		let io = IoCounters::default();
+
+		// for working out how quickly new files are being written
+		let new_start = Instant::now();
+		let new_bytes = AtomicU64::new(0);
+
+		// each phase can have a pool of its own, otherwise they all share the one we're in
+		let pool = |n: usize| rayon::ThreadPoolBuilder::new().num_threads(n).build().context("Failed to create thread pool");
+		let (copy_pool, new_pool, patch_pool) = match cfg.phase_threads {
+			Some(p) => (Some(pool(p.copy_threads)?), Some(pool(p.new_threads)?), Some(pool(p.patch_threads)?)),
+			None => (None, None, None),
+		};
 
 		// let's spawn some threads!
+		// every phase's files are split up with par_iter, so without phase_threads they all go into the same work-stealing
+		// pool, and a thread that finishes one phase's files helps with the others instead of sitting idle
 		let errs = Mutex::new(Vec::new());
 		rayon::scope(|s| {
 			if self.manifest.untouched_files.is_empty() && self.manifest.duplicated_files.is_empty() {
 				bar_untouched.done_clear();
 			}
 			else {
-				s.spawn(|_| {
+				s.spawn(|_| in_pool(copy_pool.as_ref(), || {
 					// handle untouched files
 					// use a parallel iterator so we can use as MANY threads as possible,
 					// or for if the other tasks are all done first.
@@ -71,42 +378,76 @@ impl ApplyingDiff {
 						self.manifest.untouched_files
 							.par_iter()
 							.filter_map(|(h, p)| {
-								let h = *h;
-								let old_path = self.old_root.join(p);
-								let new_path = self.new_root.join(p);
+								if stopped() {
+									return None;
+								}
+								let res = log.wrap("copy", p, Some(*h), || {
+									let h = *h;
+									let old_path = self.old_root.join(p);
+									let new_path = self.new_root.join(p);
 								
-								let real_hash = handle_res_parit!(copy_rl_hash(old_path, new_path));
+									let real_hash = handle_res_parit!(copy_rl_hash(old_path, new_path, self.manifest.hash_algorithm, &io));
 								
-								if real_hash != h {
-									return Some(anyhow!("Found {p} was different to expected (hash was {real_hash}, not {})", h));
+									handle_res_parit!(check_hash(p, ApplyOperation::Copy, h, real_hash));
+
+									inc(&bar_untouched);
+									None
+								});
+								fail(Phase::Copy, p, res)
+							})
+							.collect();
+
+					if !checks.is_empty() {
+						errs.lock().unwrap().extend(checks.drain(..));
+					}
+				}));
+				s.spawn(|_| in_pool(copy_pool.as_ref(), || {
+					// files that moved with a renamed dir have one place to come from and go to, just like unchanged files,
+					// so copy and check them in one go and all in parallel, instead of reading each one twice
+					let (renamed, duplicated): (Vec<_>, Vec<_>) = self.manifest.duplicated_files.iter().partition(|d| self.manifest.in_renamed_dir(d));
+					let mut checks: Vec<_> =
+						renamed
+							.par_iter()
+							.filter_map(|d| {
+								if stopped() {
+									return None;
 								}
+								let p = &d.new_paths[0];
+								let res = log.wrap("duplicate", p, Some(d.hash), || {
+									let real_hash = handle_res_parit!(copy_rl_hash(self.old_root.join(&d.old_paths[0]), self.new_root.join(p), self.manifest.hash_algorithm, &io));
+									handle_res_parit!(check_hash(&d.old_paths[0], ApplyOperation::CheckOld, d.hash, real_hash));
 
-								inc(&bar_untouched);
-								None
+									inc(&bar_untouched);
+									None
+								});
+								fail(Phase::Duplicate, p, res)
 							})
 							.collect();
 
 					if !checks.is_empty() {
 						errs.lock().unwrap().extend(checks.drain(..));
 					}
-				});
-				s.spawn(|_| {
-					// handle duplicated files
+
+					// handle the rest of the duplicated files
 					// could be further parallelized by turning this loop into a par_iter,
 					// but seems unnecessary to me due to this already being pretty parallelized.
-					for d in &self.manifest.duplicated_files {
+					for d in duplicated {
+						if stopped() {
+							return;
+						}
 						// check all the hashes match
 						let mut checks: Vec<_> =
 							d.old_paths
 								.par_iter()
 								.filter_map(|p| {
-									let mut f = handle_res_parit!(File::open(self.old_root.join(p)), "Failed to open old file {p} to verify hash");
-									let h = handle_res_parit!(hash::hash_stream(&mut f), "Failed to hash old file {p} to verify it");
+									let res = (|| {
+										let f = handle_res_parit!(File::open(self.old_root.join(p)), "Failed to open old file {p} to verify hash");
+										let h = handle_res_parit!(hash::hash_stream_with(&mut io.reader(f), self.manifest.hash_algorithm), "Failed to hash old file {p} to verify it");
 
-									if h != d.hash {
-										Some(anyhow!("Old file {p} was not as expected."));
-									}
-									None
+										handle_res_parit!(check_hash(p, ApplyOperation::CheckOld, d.hash, h));
+										None
+									})();
+									fail(Phase::Duplicate, p, res)
 								})
 								.collect();
 
@@ -116,63 +457,62 @@ impl ApplyingDiff {
 						}
 
 						// okay, now copy to all the new places then
-						// if we have a file on disk, then perform an in-kernel copy for speed
+						// each copy is checked as it is made, as the old file could have changed since it was checked
 						let mut checks: Vec<_> =
 							if d.idx == u64::MAX {
 								d.new_paths
 									.par_iter()
 									.filter_map(|p| {
-										// ensure we have a parent directory
-										let dest_path = self.new_root.join(p);
-										if let Some(par) = dest_path.parent() {
-											handle_res_parit!(std::fs::create_dir_all(par), "Failed to create parent dir to copy file {p}");
-										}
-
-										handle_res_parit!(copy_rl(self.old_root.join(&d.old_paths[0]), dest_path), "Failed to copy file {p}");
-										None
+										let res = log.wrap("duplicate", p, Some(d.hash), || {
+											let real_hash = handle_res_parit!(copy_rl_hash(self.old_root.join(&d.old_paths[0]), self.new_root.join(p), self.manifest.hash_algorithm, &io), "Failed to copy file {p}");
+											handle_res_parit!(check_hash(p, ApplyOperation::Copy, d.hash, real_hash));
+											None
+										});
+										fail(Phase::Duplicate, p, res)
 									})
 									.collect()
 							}
 							else {
 								// we need to copy out of ourself
-								let blob = if let Some(t) = self.blobs_new.get(d.idx as usize) {
-									*t as usize
-								}
-								else {
-									throw_err_async!(errs, anyhow!("new file {} had an out-of-range index pointing to its data", d.new_paths[0]));
-								};
-
-								// read length
-								let len = u64::from_be_bytes(*diff_map[blob..].first_chunk().unwrap()) as usize;
-								let blob = blob + 8; // advance past length
-								
 								// copy one out
 								let p = &d.new_paths[0];
-								let mut read = Cursor::new(&diff_map[blob..(blob + len)]);
-								let f = handle_res_async!(errs, create_file(&self.new_root.join(p)), "Failed to create new file {p} to write to");
-								let mut writer = hash::XXHashStreamer::new(f);
+								let res = log.wrap("duplicate", p, Some(d.hash), || {
+									let blob = if let Some(t) = self.blobs_new.get(d.idx as usize) {
+										*t as usize
+									}
+									else {
+										return Some(anyhow!("new file {p} had an out-of-range index pointing to its data"));
+									};
 
-								handle_res_async!(errs, std::io::copy(&mut read, &mut writer));
+									// read length
+									let len = u64::from_be_bytes(*diff_map[blob..].first_chunk().unwrap()) as usize;
+									let blob = blob + 8; // advance past length
 
-								// check hash
-								let rh = writer.finish();
-								if rh != d.hash {
-									throw_err_async!(errs, anyhow!("Newly created file {p} does not match expected data"));
+									let mut f = io.writer(handle_res_parit!(vfs.create_write(&self.new_root.join(p)), "Failed to create new file {p} to write to"));
+									io.add_read(len as u64 + 8);
+									let data = handle_res_parit!(self.open_blob(&diff_map[blob..(blob + len)]), "Failed to decrypt new file {p}");
+									handle_res_parit!(write_new_blob(&data, &mut f, raw_blobs.contains(&d.idx), dict_new, p, d.hash, self.manifest.hash_algorithm), "Failed to write new file {p}");
+									drop(f);
+									if has_modes(vfs, &self.new_root.join(p)) {
+										handle_res_parit!(set_mode(self.new_root.join(p), DEFAULT_FILE_MODE), "Failed to set permissions of {p}");
+									}
+									None
+								});
+								if let Some(e) = fail(Phase::Duplicate, p, res) {
+									throw_err_async!(errs, e);
 								}
 								
-								// copy to the rest
+								// copy to the rest, from the one we just wrote, as there is nothing in the old folder
 								d.new_paths
 									.par_iter()
 									.skip(1)
 									.filter_map(|p| {
-										// ensure we have a parent directory
-										let dest_path = self.new_root.join(p);
-										if let Some(par) = dest_path.parent() {
-											handle_res_parit!(std::fs::create_dir_all(par), "Failed to create parent dir to copy file {p}");
-										}
-
-										handle_res_parit!(copy_rl(self.old_root.join(&d.old_paths[0]), dest_path), "Failed to copy file {p}");
-										None
+										let res = log.wrap("duplicate", p, Some(d.hash), || {
+											let real_hash = handle_res_parit!(copy_rl_hash(self.new_root.join(&d.new_paths[0]), self.new_root.join(p), self.manifest.hash_algorithm, &io), "Failed to copy file {p}");
+											handle_res_parit!(check_hash(p, ApplyOperation::CopyNew, d.hash, real_hash));
+											None
+										});
+										fail(Phase::Duplicate, p, res)
 									})
 									.collect()
 							};
@@ -184,98 +524,219 @@ impl ApplyingDiff {
 
 						inc_n(d.new_paths.len(), if d.idx == u64::MAX { &bar_untouched } else { &bar_new });
 					}
-				});
+				}));
 			}
 			if self.manifest.new_files.is_empty() {
 				bar_new.done_clear();
 			}
 			else {
-				s.spawn(|_| {
+				s.spawn(|_| in_pool(new_pool.as_ref(), || {
 					// handle new files
-					let mut checks: Vec<_> = self.manifest.new_files
-						.par_iter()
-						.filter_map(|nf| {
-							let blob = if let Some(t) = self.blobs_new.get(nf.index as usize) {
-								*t as usize
-							}
-							else {
-								return Some(anyhow!("new file {} had an out-of-range index pointing to its data", nf.path));
-							};
+					// the stored blob for a new file, decrypted if need be
+					let blob_of = |nf: &NewFile| -> anyhow::Result<Cow<'_, [u8]>> {
+						let blob = *self.blobs_new.get(nf.index as usize)
+							.ok_or_else(|| anyhow!("new file {} had an out-of-range index pointing to its data", nf.path))? as usize;
 
-							// create new file
-							let mut dest = handle_res_parit!(create_file(&self.new_root.join(&nf.path)), "Failed to create {} to write new file", &nf.path);
-							let mut wrt = hash::XXHashStreamer::new(&mut dest);
+						// read length
+						let len = u64::from_be_bytes(*diff_map[blob..].first_chunk().unwrap()) as usize;
+						let blob = blob + 8; // advance past length
+						io.add_read(len as u64 + 8);
+
+						self.open_blob(&diff_map[blob..(blob + len)]).with_context(|| format!("Failed to decrypt new file {}", nf.path))
+					};
+
+					let finish = |nf: &NewFile, written: u64| {
+						if has_modes(vfs, &self.new_root.join(&nf.path)) {
+							handle_res_parit!(set_mode(self.new_root.join(&nf.path), DEFAULT_FILE_MODE), "Failed to set permissions of {}", nf.path);
+						}
 
-							// read length
-							let len = u64::from_be_bytes(*diff_map[blob..].first_chunk().unwrap()) as usize;
-							let blob = blob + 8; // advance past length
+						// average over everything so far, so it doesn't jump around with each file's size
+						let total = new_bytes.fetch_add(written, Ordering::Relaxed) + written;
+						bar_new.set_speed_hint(total as f64 / new_start.elapsed().as_secs_f64());
+						inc(&bar_new);
 
-							// copy and decompress
-							let mut read = Cursor::new(&diff_map[blob..(blob + len)]);
+						None
+					};
 
-							handle_res_parit!(zstd::stream::copy_decode(&mut read, &mut wrt), "Failed to decompress file {}", &nf.path);
+					// small files are decompressed in parallel, and written one at a time while the next batch is decompressed
+					let sizes = self.manifest.size_lookup();
+					let (small, large): (Vec<_>, Vec<_>) = self.manifest.new_files
+						.iter()
+						.partition(|nf| sizes.get(&nf.hash).is_some_and(|s| *s < cfg.predecompress_threshold));
 
-							let rh = wrt.finish();
-							if rh != nf.hash {
-								return Some(anyhow!("Written {} was different to expected (hash was {rh}, not {})", nf.path, nf.hash));
+					let write_small = || {
+						let mut batches = vec![Vec::new()];
+						let mut batch_size = 0;
+						for nf in small {
+							if batch_size >= PREDECOMPRESS_BATCH {
+								batches.push(Vec::new());
+								batch_size = 0;
 							}
+							batch_size += sizes[&nf.hash];
+							batches.last_mut().unwrap().push(nf);
+						}
 
-							inc(&bar_new);
+						let mut checks = Vec::new();
+						let mut ready = Vec::new();
+						for batch in batches.into_iter().map(Some).chain([None]) {
+							let (errs, next) = rayon::join(
+								|| std::mem::take(&mut ready)
+									.into_iter()
+									.filter_map(|(nf, data): (&NewFile, anyhow::Result<Vec<u8>>)| {
+										if stopped() {
+											return None;
+										}
+										bar_new.set_message(&nf.path);
+										let res = log.wrap("new", &nf.path, Some(nf.hash), || {
+											let data = handle_res_parit!(data);
+											let mut dest = io.writer(handle_res_parit!(vfs.create_write(&self.new_root.join(&nf.path)), "Failed to create {} to write new file", &nf.path));
+											handle_res_parit!(dest.write_all(&data).and_then(|_| dest.flush()), "Failed to write new file {}", &nf.path);
+											drop(dest);
+											finish(nf, data.len() as u64)
+										});
+										fail(Phase::New, &nf.path, res)
+									})
+									.collect::<Vec<_>>(),
+								|| batch.unwrap_or_default()
+									.into_par_iter()
+									.filter(|_| !stopped())
+									.map(|nf| {
+										let data = blob_of(nf).and_then(|blob| {
+											let mut data = Vec::with_capacity(sizes[&nf.hash] as usize);
+											write_new_blob(&blob, &mut data, raw_blobs.contains(&nf.index), dict_new, &nf.path, nf.hash, self.manifest.hash_algorithm)
+												.with_context(|| format!("Failed to write new file {}", nf.path))?;
+											Ok(data)
+										});
+										(nf, data)
+									})
+									.collect(),
+							);
+							checks.extend(errs);
+							ready = next;
+						}
+						checks
+					};
 
-							None
-						})
-						.collect();
+					let write_large = || {
+						large
+							.par_iter()
+							.filter_map(|nf| {
+								if stopped() {
+									return None;
+								}
+								bar_new.set_message(&nf.path);
+								let res = log.wrap("new", &nf.path, Some(nf.hash), || {
+									let data = handle_res_parit!(blob_of(nf));
+
+									// create new file, then copy and decompress
+									let mut dest = io.writer(handle_res_parit!(vfs.create_write(&self.new_root.join(&nf.path)), "Failed to create {} to write new file", &nf.path));
+									let written = handle_res_parit!(write_new_blob(&data, &mut dest, raw_blobs.contains(&nf.index), dict_new, &nf.path, nf.hash, self.manifest.hash_algorithm), "Failed to write new file {}", &nf.path);
+									drop(dest);
+									finish(nf, written)
+								});
+								fail(Phase::New, &nf.path, res)
+							})
+							.collect::<Vec<_>>()
+					};
+
+					let (mut checks, large_checks) = rayon::join(write_small, write_large);
+					checks.extend(large_checks);
 
 					if !checks.is_empty() {
 						errs.lock().unwrap().extend(checks.drain(..));
 						return;
 					}
-				});
+				}));
 			}
 			if self.manifest.patched_files.is_empty() {
 				bar_patched.done_clear();
 			}
 			else {
-				s.spawn(|_| {
+				s.spawn(|_| in_pool(patch_pool.as_ref(), || {
 					// handle patched files
-					let mut checks: Vec<_> =
-						self.manifest.patched_files
-							.par_iter()
-							.filter_map(|pf| {
-								let mut src = handle_res_parit!(File::open(self.old_root.join(&pf.path)), "Failed to open file to patch from {}", pf.path);
-								let mut dst = handle_res_parit!(create_file(&self.new_root.join(&pf.path)), "Failed to create file to patch to {}", pf.path);
+					// `tail` is where to keep the end of the new file for the next one, when delta chained
+					let patch_one = |pf: &PatchedFile, dict: Option<&[u8]>, tail: Option<&mut Vec<u8>>| {
+						if stopped() {
+							return None;
+						}
+						bar_patched.set_message(&pf.path);
+						let res = log.wrap("patch", &pf.path, Some(pf.new_hash), || {
+							let mut src = io.reader(handle_res_parit!(vfs.open_read(&self.old_root.join(&pf.path)), "Failed to open file to patch from {}", pf.path));
+							let dst = io.writer(handle_res_parit!(vfs.create_write(&self.new_root.join(&pf.path)), "Failed to create file to patch to {}", pf.path));
+							let mut dst = zstddiff::TailWriter::new(dst, if tail.is_some() { zstddiff::CHAIN_WINDOW } else { 0 });
 
-								// get length of src
-								let src_len = handle_res_parit!(src.metadata(), "Couldn't get length of patch source file {}", pf.path).len();
+							// get length of src
+							let src_len = handle_res_parit!(vfs.metadata(&self.old_root.join(&pf.path)), "Couldn't get length of patch source file {}", pf.path).len;
 
-								let mut src = hash::XXHashStreamer::new(&mut src);
-								let mut dst = hash::XXHashStreamer::new(&mut dst);
+							if pf.index as usize >= self.blobs_patch.len() {
+								return Some(anyhow!("patched file {} had an out-of-range index pointing to its data", pf.path));
+							}
 
-								let blob = if let Some(t) = self.blobs_patch.get(pf.index as usize) {
-									*t as usize
-								}
-								else {
-									return Some(anyhow!("patched file {} had an out-of-range index pointing to its data", pf.path));
-								};
+							// get diff blob ready
+							let patch = handle_res_parit!(self.patch_blob(pf.index), "Failed to read patch for {}", pf.path);
+							let mut diff = io.reader(Cursor::new(&patch[..]));
 
-								// get diff blob ready
-								let mut diff = Cursor::new(&diff_map[blob..]);
+							// apply!
+							handle_res_parit!(write_patched(&mut src, src_len, &mut diff, &mut dst, dict, pf, self.manifest.hash_algorithm), "Failed to apply diff for {}", pf.path);
+							if let Some(tail) = tail {
+								*tail = dst.into_tail();
+							}
 
-								// apply!
-								handle_res_parit!(zstddiff::apply(&mut src, &mut diff, &mut dst, src_len), "Failed to apply diff for {}", pf.path);
+							inc(&bar_patched);
 
-								let src_rh = src.finish();
-								let dst_rh = dst.finish();
-								if src_rh != pf.old_hash {
-									return Some(anyhow!("Source {} was different to expected (hash was {src_rh}, not {})", pf.path, pf.old_hash));
-								}
-								if dst_rh != pf.new_hash {
-									return Some(anyhow!("Written {} was different to expected (hash was {dst_rh}, not {})", pf.path, pf.new_hash));
+							None
+						});
+						let res = res.map(|e| e.context(format!("patch blob {}, old hash {:016x}, new hash {:016x}", pf.index, pf.old_hash, pf.new_hash)));
+						fail(Phase::Patch, &pf.path, res)
+					};
+
+					let mut checks: Vec<_> =
+						if self.manifest.delta_chain {
+							// each patch needs the one before it to be done
+							let mut tail = Vec::new();
+							patches_in_order(&self.manifest)
+								.into_iter()
+								.filter_map(|pf| patch_one(pf, Some(&zstddiff::chain_dict(&tail, dict_patch)), Some(&mut tail)))
+								.collect()
+						}
+						else {
+							self.manifest.patched_files
+								.par_iter()
+								.filter_map(|pf| patch_one(pf, dict_patch, None))
+								.collect()
+						};
+
+					if !checks.is_empty() {
+						errs.lock().unwrap().extend(checks.drain(..));
+					}
+				}));
+			}
+			if self.manifest.symlinks.is_empty() {
+				bar_symlinks.done_clear();
+			}
+			else {
+				s.spawn(|_| {
+					// handle symlinks
+					let mut checks: Vec<_> =
+						self.manifest.symlinks
+							.par_iter()
+							.filter_map(|sl| {
+								if stopped() {
+									return None;
 								}
+								let res = log.wrap("symlink", &sl.path, None, || {
+									let dest_path = self.new_root.join(&sl.path);
+									if let Some(par) = dest_path.parent() {
+										handle_res_parit!(std::fs::create_dir_all(par), "Failed to create parent dir for symlink {}", sl.path);
+									}
 
-								inc(&bar_patched);
+									handle_res_parit!(create_symlink(&sl.target, dest_path), "Failed to create symlink {}", sl.path);
 
-								None
+									inc(&bar_symlinks);
+
+									None
+								});
+								fail(Phase::Symlink, &sl.path, res)
 							})
 							.collect();
 
@@ -286,16 +747,246 @@ impl ApplyingDiff {
 			}
 		});
 
-		aggregate_errors!(errs.into_inner()?);
+		// anything that failed because we stopped halfway through isn't worth reporting
+		ensure!(!is_cancelled(cancel), FoldiffError::Cancelled);
+		collect_failures(errs.into_inner()?, &mut failed)?;
+
+		// hard links can only be created once the files they point to exist
+		if self.manifest.hardlinked_files.is_empty() {
+			bar_hardlinks.done_clear();
+		}
+		else {
+			let errs: Vec<_> =
+				self.manifest.hardlinked_files
+					.par_iter()
+					.filter_map(|hl| {
+						if stopped() {
+							return None;
+						}
+						let res = log.wrap("hardlink", &hl.path, None, || {
+							let dest_path = self.new_root.join(&hl.path);
+							if let Some(par) = dest_path.parent() {
+								handle_res_parit!(std::fs::create_dir_all(par), "Failed to create parent dir for hard link {}", hl.path);
+							}
 
-		as1.all_good();
+							handle_res_parit!(std::fs::hard_link(self.new_root.join(&hl.target), dest_path), "Failed to create hard link {} to {}", hl.path, hl.target);
+
+							inc(&bar_hardlinks);
+
+							None
+						});
+						fail(Phase::HardLink, &hl.path, res)
+					})
+					.collect();
+
+			ensure!(!is_cancelled(cancel), FoldiffError::Cancelled);
+			collect_failures(errs, &mut failed)?;
+		}
+
+		// this has to happen last, so nothing writes to the files after
+		let errs: Vec<_> =
+			self.manifest.file_attributes
+				.par_iter()
+				.filter_map(|fa| {
+					if stopped() {
+						return None;
+					}
+					let res = (|| {
+						let path = self.new_root.join(&fa.path);
+						if let (Some(mode), true) = (fa.mode, has_modes(vfs, &path)) {
+							handle_res_parit!(set_mode(&path, mode), "Failed to set permissions of {}", fa.path);
+						}
+
+						#[cfg(feature = "timestamps")]
+						if let (true, Some((secs, nanos))) = (cfg.preserve_timestamps, fa.mtime) {
+							let mtime = filetime::FileTime::from_unix_time(secs, nanos);
+							handle_res_parit!(filetime::set_file_mtime(&path, mtime), "Failed to set modification time of {}", fa.path);
+						}
+
+						None
+					})();
+					fail(Phase::Attributes, &fa.path, res)
+				})
+				.collect();
+
+		ensure!(!is_cancelled(cancel), FoldiffError::Cancelled);
+		collect_failures(errs, &mut failed)?;
+
+		if failed.is_empty() {
+			as0.all_good();
+			as1.all_good();
+		}
 		drop(as2);
 		drop(as3);
 		drop(as4);
+		drop(as5);
+		drop(as6);
+		Ok(ApplyResult { succeeded: succeeded.into_inner(), failed, io: io.totals(), peak_rss_bytes: memory::peak_rss_bytes() })
+	}
+
+	/// Writes what the new folder would be into a tar archive, instead of onto the filesystem.
+	/// The old folder is read through `vfs`, and everything is checked against its hash like `apply` does.
+	/// New and patched files are built in memory before being added, as tar needs to know their size up front.
+	/// If this fails, whatever was written to `tar_writer` is not a complete archive.
+	pub fn apply_to_tar(&self, old_root: &Utf8Path, tar_writer: impl Write, cfg: &FoldiffCfg, vfs: &dyn VfsProvider) -> anyhow::Result<()> {
+		self.validate()?;
+		let algo = self.manifest.hash_algorithm;
+		let attrs: HashMap<&str, &FileAttributes> = self.manifest.file_attributes.iter().map(|fa| (fa.path.as_str(), fa)).collect();
+		let now = to_unix_time(SystemTime::now()).0;
+		let mut tar = tar::Builder::new(tar_writer);
+
+		// everything but the path, which the tar crate fills in so it can handle long ones
+		let header = |path: &str, kind: tar::EntryType, len: u64| {
+			let fa = attrs.get(path);
+			let mut h = tar::Header::new_gnu();
+			h.set_entry_type(kind);
+			h.set_size(len);
+			h.set_mode(match kind {
+				tar::EntryType::Symlink => 0o777,
+				tar::EntryType::Directory => 0o755,
+				_ => fa.and_then(|fa| fa.mode).unwrap_or(DEFAULT_FILE_MODE),
+			});
+			let mtime = fa.and_then(|fa| fa.mtime).filter(|_| cfg.preserve_timestamps).map_or(now, |(secs, _)| secs);
+			h.set_mtime(mtime.max(0) as u64);
+			h
+		};
+		let read_old = |p: &str| -> anyhow::Result<Vec<u8>> {
+			let mut data = Vec::new();
+			vfs.open_read(&old_root.join(p))?.read_to_end(&mut data).with_context(|| format!("Failed to read old file {p}"))?;
+			Ok(data)
+		};
+
+		for (h, p) in &self.manifest.untouched_files {
+			// these can be big, so stream them in and check the hash afterwards
+			let len = vfs.metadata(&old_root.join(p))?.len;
+			let mut src = hash::XXHashStreamer::with_algorithm(vfs.open_read(&old_root.join(p))?, algo);
+			tar.append_data(&mut header(p, tar::EntryType::Regular, len), p, &mut src).with_context(|| format!("Failed to add {p} to tar"))?;
+			check_hash(p, ApplyOperation::Copy, *h, src.finish())?;
+		}
+
+		for d in &self.manifest.duplicated_files {
+			let data =
+				if d.idx == u64::MAX {
+					for p in &d.old_paths {
+						check_hash(p, ApplyOperation::CheckOld, d.hash, hash::hash_stream_with(&mut &read_old(p)?[..], algo)?)?;
+					}
+					read_old(&d.old_paths[0])?
+				}
+				else {
+					self.read_new_blob(d.idx, &d.new_paths[0], d.hash)?
+				};
+
+			for p in &d.new_paths {
+				tar.append_data(&mut header(p, tar::EntryType::Regular, data.len() as u64), p, &data[..]).with_context(|| format!("Failed to add {p} to tar"))?;
+			}
+		}
+
+		for nf in &self.manifest.new_files {
+			let data = self.read_new_blob(nf.index, &nf.path, nf.hash)?;
+			tar.append_data(&mut header(&nf.path, tar::EntryType::Regular, data.len() as u64), &nf.path, &data[..])
+				.with_context(|| format!("Failed to add {} to tar", nf.path))?;
+		}
+
+		let mut prev = Vec::new();
+		for pf in patches_in_order(&self.manifest) {
+			let data = self.patch_in_memory(pf, &read_old(&pf.path)?, &prev).with_context(|| format!("Failed to apply diff for {}", pf.path))?;
+			tar.append_data(&mut header(&pf.path, tar::EntryType::Regular, data.len() as u64), &pf.path, &data[..])
+				.with_context(|| format!("Failed to add {} to tar", pf.path))?;
+			prev = data;
+		}
+
+		for sl in &self.manifest.symlinks {
+			tar.append_link(&mut header(&sl.path, tar::EntryType::Symlink, 0), &sl.path, &sl.target)
+				.with_context(|| format!("Failed to add symlink {} to tar", sl.path))?;
+		}
+
+		// like on disk, these have to come after the files they point to
+		for hl in &self.manifest.hardlinked_files {
+			tar.append_link(&mut header(&hl.path, tar::EntryType::Link, 0), &hl.path, &hl.target)
+				.with_context(|| format!("Failed to add hard link {} to tar", hl.path))?;
+		}
+
+		for dir in &self.manifest.empty_dirs_new {
+			tar.append_data(&mut header(dir, tar::EntryType::Directory, 0), dir, std::io::empty())
+				.with_context(|| format!("Failed to add empty dir {dir} to tar"))?;
+		}
+
+		tar.into_inner().context("Failed to finish tar")?.flush().context("Failed to finish tar")?;
 		Ok(())
 	}
 }
 
+/// Applies a diff without touching the filesystem, for testing manifest logic.
+/// `old_files` is the old folder, `blobs_new` the contents of each new blob (without its length),
+/// and `blobs_patch` each patch as written by zstddiff. Returns the new folder.
+/// Uses the same decompressing, patching, and hash checks as `apply`, but everything happens on one thread,
+/// and symlinks, hard links, file attributes, and dictionaries are not supported.
+pub fn apply_in_memory(
+	manifest: &DiffManifest,
+	old_files: HashMap<String, Vec<u8>>,
+	blobs_new: Vec<Vec<u8>>,
+	blobs_patch: Vec<Vec<u8>>,
+) -> anyhow::Result<HashMap<String, Vec<u8>>> {
+	manifest
+		.validate(blobs_new.len() as u64, blobs_patch.len() as u64)
+		.map_err(|problems| anyhow!("Diff is inconsistent:\n{}", problems.join("\n")))?;
+	ensure!(manifest.symlinks.is_empty() && manifest.hardlinked_files.is_empty(), "apply_in_memory does not support links");
+	// the blobs passed in don't include the dictionaries
+	ensure!(!manifest.has_dictionaries, "apply_in_memory does not support diffs with dictionaries");
+
+	let raw_blobs: HashSet<u64> = manifest.uncompressed_blobs.iter().copied().collect();
+	let algo = manifest.hash_algorithm;
+	let old = |p: &str| old_files.get(p).with_context(|| format!("Old file {p} does not exist"));
+	let mut new_files = HashMap::new();
+
+	for (h, p) in &manifest.untouched_files {
+		let data = old(p)?;
+		let real_hash = hash::hash_stream_with(&mut &data[..], algo)?;
+		check_hash(p, ApplyOperation::Copy, *h, real_hash)?;
+		new_files.insert(p.clone(), data.clone());
+	}
+
+	for d in &manifest.duplicated_files {
+		let data =
+			if d.idx == u64::MAX {
+				for p in &d.old_paths {
+					check_hash(p, ApplyOperation::CheckOld, d.hash, hash::hash_stream_with(&mut &old(p)?[..], algo)?)?;
+				}
+				old(&d.old_paths[0])?.clone()
+			}
+			else {
+				let mut data = Vec::new();
+				write_new_blob(&blobs_new[d.idx as usize], &mut data, raw_blobs.contains(&d.idx), None, &d.new_paths[0], d.hash, algo)
+					.with_context(|| format!("Failed to write new file {}", d.new_paths[0]))?;
+				data
+			};
+
+		for p in &d.new_paths {
+			new_files.insert(p.clone(), data.clone());
+		}
+	}
+
+	for nf in &manifest.new_files {
+		let mut data = Vec::new();
+		write_new_blob(&blobs_new[nf.index as usize], &mut data, raw_blobs.contains(&nf.index), None, &nf.path, nf.hash, algo)
+			.with_context(|| format!("Failed to write new file {}", nf.path))?;
+		new_files.insert(nf.path.clone(), data);
+	}
+
+	let mut prev = Vec::new();
+	for pf in patches_in_order(manifest) {
+		let src = old(&pf.path)?;
+		let chained = manifest.delta_chain.then(|| zstddiff::chain_dict(&prev, None));
+		let mut data = Vec::new();
+		write_patched(&mut &src[..], src.len() as u64, &mut &blobs_patch[pf.index as usize][..], &mut data, chained.as_deref(), pf, algo)
+			.with_context(|| format!("Failed to apply diff for {}", pf.path))?;
+		new_files.insert(pf.path.clone(), data.clone());
+		prev = data;
+	}
+
+	Ok(new_files)
+}
+
 /// handles initialising an in-memory applying state from disk
 pub fn read_diff_from_file(path: &Utf8Path) -> anyhow::Result<ApplyingDiff> {
 	let f = File::open(path).context("Failed to open file to read diff")?;
@@ -309,62 +1000,721 @@ pub fn read_diff_from_file(path: &Utf8Path) -> anyhow::Result<ApplyingDiff> {
 	Ok(res)
 }
 
+/// Reads a diff that is already in memory, such as one from `DiffingDiff::write_to_bytes`.
+/// The data is copied, so it doesn't need to outlive the result.
+pub fn read_diff_from_bytes(data: &[u8]) -> anyhow::Result<ApplyingDiff> {
+	let mut res = read_diff_from(&mut Cursor::new(data))?;
+
+	// blobs are always read out of a map, so copy the diff into an anonymous one
+	let mut map = MmapMut::map_anon(data.len()).context("Failed to allocate memory for diff")?;
+	map.copy_from_slice(data);
+	res.read = Some(map.make_read_only()?);
+	Ok(res)
+}
+
+/// where we are in the diff, so that running out of file can say what was being read
+struct ParseContext {
+	/// what kind of thing we're reading the items of, e.g. "new blob"
+	phase: &'static str,
+	index: u64,
+	count: u64,
+	/// how long the whole diff is
+	len: u64,
+}
+
+impl ParseContext {
+	fn new(reader: &mut impl Seek) -> anyhow::Result<Self> {
+		let pos = reader.stream_position()?;
+		let len = reader.seek(SeekFrom::End(0))?;
+		reader.seek(SeekFrom::Start(pos))?;
+		Ok(Self { phase: "", index: 0, count: 0, len })
+	}
+
+	fn start(&mut self, phase: &'static str, count: u64) {
+		self.phase = phase;
+		self.count = count;
+		self.index = 0;
+	}
+
+	fn eof(&self, offset: u64, what: &str) -> anyhow::Error {
+		if self.phase.is_empty() {
+			anyhow!("Unexpected EOF at offset {offset:#x} while reading {what}")
+		}
+		else {
+			anyhow!("Unexpected EOF at offset {offset:#x} while reading {what} of {} #{} of {}", self.phase, self.index, self.count)
+		}
+	}
+
+	fn read_u64(&self, reader: &mut (impl Read + Seek), what: &str) -> anyhow::Result<u64> {
+		let offset = reader.stream_position()?;
+		let mut buf = [0u8; 8];
+		reader.read_exact(&mut buf).map_err(|e| match e.kind() {
+			std::io::ErrorKind::UnexpectedEof => self.eof(offset, what),
+			_ => anyhow::Error::new(e).context(format!("Failed to read {what}")),
+		})?;
+		Ok(u64::from_be_bytes(buf))
+	}
+
+	/// seeking past the end isn't an error by itself, so check for it here
+	fn skip(&self, reader: &mut (impl Read + Seek), len: u64, what: &str) -> anyhow::Result<()> {
+		let offset = reader.stream_position()?;
+		if offset.checked_add(len).is_none_or(|end| end > self.len) {
+			return Err(self.eof(self.len, what));
+		}
+		reader.seek_relative(len.try_into()?).with_context(|| format!("Failed to seek past {what}"))
+	}
+}
+
 pub fn read_diff_from(reader: &mut (impl Read + Seek)) -> anyhow::Result<ApplyingDiff> {
+	read_diff_from_with_key(reader, None)
+}
+
+/// Reads a diff that was encrypted with `key`, from a file. Diffs that aren't encrypted are read as normal.
+pub fn read_encrypted_diff_from_file(path: &Utf8Path, key: &EncryptionCfg) -> anyhow::Result<ApplyingDiff> {
+	let f = File::open(path).context("Failed to open file to read diff")?;
+
+	// safety: see read_diff_from_file
+	let map = unsafe { Mmap::map(&f) }?;
+
+	let mut res = read_diff_from_with_key(&mut Cursor::new(&map), Some(key))?;
+	res.read = Some(map);
+	Ok(res)
+}
+
+fn read_diff_from_with_key(reader: &mut (impl Read + Seek), key: Option<&EncryptionCfg>) -> anyhow::Result<ApplyingDiff> {
+	let mut ctx = ParseContext::new(reader)?;
+	let start = reader.stream_position()?;
+
 	// checks magic bytes and version too
-	let manifest = DiffManifest::read_from(&mut *reader)?;
+	let (manifest, encrypted) = DiffManifest::read_from_with_key(&mut *reader, key)?;
+	let padding = padding_to(reader.stream_position()? - start, manifest.blob_alignment);
+	ctx.skip(reader, padding, "the padding before the blobs")?;
 
 	// create self
 	let mut new_self = ApplyingDiff::default();
 	new_self.manifest = manifest;
+	new_self.key = key.filter(|_| encrypted).cloned();
 
-	let mut new_blob_count = [0u8; 8];
-	reader
-		.read_exact(&mut new_blob_count)
-		.context("Failed to read new file count")?;
-	let new_blob_count = u64::from_be_bytes(new_blob_count);
+	let new_blob_count = ctx.read_u64(reader, "the new file count")?;
+	ctx.start("new blob", new_blob_count);
 
-	for _ in 0..new_blob_count {
+	for i in 0..new_blob_count {
+		ctx.index = i;
 		// keep track of the offset
 		new_self.blobs_new.push(reader.stream_position()?);
 
-		// read blob length
-		let mut len = [0u8; 8];
-		reader
-			.read_exact(&mut len)
-			.context("Failed to read new file length")?;
-		let len = u64::from_be_bytes(len);
+		let len = ctx.read_u64(reader, "the length")?;
+		new_self.blob_sizes_new.push(len);
 
 		// jump to next file
-		reader
-			.seek_relative(len.try_into()?)
-			.context("Failed to seek while skipping new file")?;
+		ctx.skip(reader, len, "the data")?;
 	}
 
-	let mut patched_blob_count = [0u8; 8];
-	reader
-		.read_exact(&mut patched_blob_count)
-		.context("Failed to read patched file count")?;
-	let patched_blob_count = u64::from_be_bytes(patched_blob_count);
+	let patched_blob_count = ctx.read_u64(reader, "the patched file count")?;
+	ctx.start("patch blob", patched_blob_count);
 
-	for _ in 0..patched_blob_count {
+	for i in 0..patched_blob_count {
+		ctx.index = i;
 		// keep track of the offset
 		new_self.blobs_patch.push(reader.stream_position()?);
 
 		// read through array
-		// read chunk count
-		let mut count = [0u8; 8];
-		reader.read_exact(&mut count).context("Failed to read diff chunk count")?;
-		let count = u64::from_be_bytes(count);
+		let (count, stored) = zstddiff::split_chunk_count(ctx.read_u64(reader, "the chunk count")?);
+		if stored {
+			ctx.skip(reader, count.saturating_mul(8), "the old chunk lengths")?;
+		}
 
+		let mut total = 0;
 		for _ in 0..count {
-			// read chunk length
-			let mut len = [0u8; 8];
-			reader.read_exact(&mut len).context("Failed to read diff chunk length")?;
-			let len = u64::from_be_bytes(len);
+			let len = zstddiff::chunk_data_len(ctx.read_u64(reader, "a chunk length")?);
+			total += len;
 			// advance reader through it
-			reader.seek_relative(len as i64).context("Failed to seek through diff")?;
+			ctx.skip(reader, len, "a chunk")?;
 		}
+		new_self.blob_sizes_patch.push(total);
+	}
+
+	if new_self.manifest.has_dictionaries {
+		ctx.start("dictionary", 2);
+		let mut read_dict = |i| -> anyhow::Result<Option<(u64, u64)>> {
+			ctx.index = i;
+			let len = ctx.read_u64(reader, "the length")?;
+			let offset = reader.stream_position()?;
+			ctx.skip(reader, len, "the data")?;
+			// empty means there isn't one
+			Ok((len != 0).then_some((offset, len)))
+		};
+		new_self.dict_new = read_dict(0)?;
+		new_self.dict_patch = read_dict(1)?;
+	}
+
+	// only used by catalog::seek_to_file, but a cut off one means the diff is too
+	if new_self.manifest.has_catalog {
+		ctx.start("catalog", 1);
+		ctx.skip(reader, 4, "the magic bytes")?;
+		let count = ctx.read_u64(reader, "the entry count")?;
+		ctx.skip(reader, 16 + count.saturating_mul(24), "the entries")?;
+		ctx.read_u64(reader, "the start offset")?;
 	}
 
+	new_self.validate()?;
+
 	Ok(new_self)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::reporting::{CountingReporter, NoopMultiWrapper, NoopReporter};
+	use crate::common::{create_file, ApplyError};
+	use crate::testing::{assert_folders_equal, generate_test_folder, root_of, FolderSpec};
+	use crate::vfs::StdVfs;
+	use tempfile::tempdir;
+
+	/// writes `n` small files spread over a few shared directories into `new`, and diffs them against an empty `old`
+	fn diff_many_files(root: &Utf8Path, n: usize, cfg: &FoldiffCfg) -> (Utf8PathBuf, Utf8PathBuf, Utf8PathBuf) {
+		let (old_root, new_root) = (root.join("old"), root.join("new"));
+		std::fs::create_dir(&old_root).unwrap();
+
+		// lots of files sharing a few directories, so threads race to create the same parents
+		for i in 0..n {
+			let p = new_root.join(format!("a{}/b{}/c{}/f{i}", i % 10, i % 7, i % 3));
+			create_file(&p).unwrap();
+			std::fs::write(&p, format!("file number {i}")).unwrap();
+		}
+
+		let diff_path = root.join("diff.fldf");
+		let mut diff = crate::diffing::scan_to_diff::<NoopReporter>(old_root.clone(), new_root.clone(), cfg, &StdVfs).unwrap();
+		diff.write_to_file::<NoopReporter, NoopReporter>(&diff_path, cfg).unwrap();
+
+		(old_root, new_root, diff_path)
+	}
+
+	#[test]
+	fn test_apply_many_nested_files() {
+		let dir = tempdir().unwrap();
+		let root = Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap();
+		let cfg = FoldiffCfg::builder().threads(4).level_new(3).build();
+		let (old_root, new_root, diff_path) = diff_many_files(&root, 10_000, &cfg);
+		let out_root = root.join("out");
+
+		let mut applying = read_diff_from_file(&diff_path).unwrap();
+		let applied = applying.apply::<NoopMultiWrapper, NoopReporter, CountingReporter>(old_root, out_root.clone(), &cfg, &StdVfs, None).unwrap();
+		assert!(applied.is_ok());
+		assert_eq!(applied.succeeded, 10_000);
+
+		assert_folders_equal(&new_root, &out_root);
+	}
+
+	#[test]
+	fn test_phase_threads() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("old/same", "unchanged")
+			.file("old/edited", "the quick brown fox")
+			.file("old/copied", "copy me")
+			.file("new/same", "unchanged")
+			.file("new/edited", "the quick red fox")
+			.file("new/copied", "copy me")
+			.file("new/d/copied", "copy me")
+			.file("new/new", "brand new")
+		);
+		let root = root_of(&dir);
+		let cfg = FoldiffCfg {
+			phase_threads: Some(crate::PhaseConcurrency { copy_threads: 1, patch_threads: 2, new_threads: 3 }),
+			..FoldiffCfg::default()
+		};
+		crate::diffing::scan_to_diff::<NoopReporter>(root.join("old"), root.join("new"), &cfg, &StdVfs).unwrap()
+			.write_to_file::<NoopReporter, NoopReporter>(&root.join("diff.fldf"), &cfg).unwrap();
+
+		let res = read_diff_from_file(&root.join("diff.fldf")).unwrap()
+			.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(root.join("old"), root.join("out"), &cfg, &StdVfs, None)
+			.unwrap();
+		assert!(res.is_ok(), "{:?}", res.failed);
+		assert_eq!(res.succeeded, 5);
+
+		assert_folders_equal(&root.join("new"), &root.join("out"));
+
+		// phases run in the pool they're given
+		let pool = rayon::ThreadPoolBuilder::new().num_threads(3).build().unwrap();
+		assert_eq!(in_pool(Some(&pool), rayon::current_num_threads), 3);
+		assert_eq!(in_pool(None, rayon::current_num_threads), rayon::current_num_threads());
+	}
+
+	#[test]
+	fn test_predecompress() {
+		let dir = tempdir().unwrap();
+		let root = Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap();
+		let cfg = FoldiffCfg::builder().threads(4).level_new(3).build();
+		let (old_root, new_root, _) = diff_many_files(&root, 1000, &cfg);
+		let diff_path = root.join("mixed.fldf");
+		for (i, f) in ["big", "d/bigger"].iter().enumerate() {
+			create_file(&new_root.join(f)).unwrap();
+			std::fs::write(new_root.join(f), format!("big file {i}").repeat(1000)).unwrap();
+		}
+		let cfg = FoldiffCfg { predecompress_threshold: 1000, ..cfg };
+		crate::diffing::scan_to_diff::<NoopReporter>(old_root.clone(), new_root.clone(), &cfg, &StdVfs).unwrap()
+			.write_to_file::<NoopReporter, NoopReporter>(&diff_path, &cfg).unwrap();
+
+		// both with and without predecompressing, and with none of the files small enough
+		for threshold in [1000, 0, u64::MAX] {
+			let cfg = FoldiffCfg { predecompress_threshold: threshold, ..cfg.clone() };
+			let out_root = root.join(format!("out-{threshold}"));
+			let applied = read_diff_from_file(&diff_path).unwrap()
+				.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(old_root.clone(), out_root.clone(), &cfg, &StdVfs, None)
+				.unwrap();
+			assert!(applied.is_ok(), "{:?}", applied.failed);
+			assert_eq!(applied.succeeded, 1002);
+
+			assert_folders_equal(&new_root, &out_root);
+		}
+	}
+
+	#[test]
+	fn test_predecompress_large() {
+		// not really a test, but a benchmark: lots of small files that take a while to decompress
+		let dir = tempdir().unwrap();
+		let root = Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap();
+		let (old_root, new_root) = (root.join("old"), root.join("new"));
+		std::fs::create_dir(&old_root).unwrap();
+		for i in 0..20_000 {
+			let p = new_root.join(format!("d{}/f{i}", i % 100));
+			create_file(&p).unwrap();
+			std::fs::write(&p, (0..4000).map(|j| format!("{} ", (i * j) % 997)).collect::<String>()).unwrap();
+		}
+		let cfg = FoldiffCfg::builder().threads(8).level_new(19).build();
+		crate::diffing::scan_to_diff::<NoopReporter>(old_root.clone(), new_root, &cfg, &StdVfs).unwrap()
+			.write_to_file::<NoopReporter, NoopReporter>(&root.join("diff.fldf"), &cfg).unwrap();
+
+		for threshold in [0, 1 << 20] {
+			let cfg = FoldiffCfg { predecompress_threshold: threshold, ..cfg.clone() };
+			let start = Instant::now();
+			let applied = read_diff_from_file(&root.join("diff.fldf")).unwrap()
+				.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(old_root.clone(), root.join(format!("out-{threshold}")), &cfg, &StdVfs, None)
+				.unwrap();
+			assert!(applied.is_ok());
+			eprintln!("predecompress threshold {threshold}: {:?}", start.elapsed());
+		}
+	}
+
+	#[test]
+	fn test_apply_cancel() {
+		let dir = tempdir().unwrap();
+		let root = Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap();
+		let cfg = FoldiffCfg::builder().threads(4).level_new(3).build();
+		let (old_root, _, diff_path) = diff_many_files(&root, 10_000, &cfg);
+		let out_root = root.join("out");
+
+		let token = CancellationToken::new();
+		let handle = {
+			let (token, out_root, cfg) = (token.clone(), out_root.clone(), cfg.clone());
+			std::thread::spawn(move || {
+				let mut applying = read_diff_from_file(&diff_path).unwrap();
+				applying.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(old_root, out_root, &cfg, &StdVfs, Some(&token))
+			})
+		};
+		token.cancel();
+
+		let err = handle.join().unwrap().unwrap_err();
+		assert!(matches!(err.downcast_ref(), Some(FoldiffError::Cancelled)), "{err:?}");
+
+		// it should have stopped well before writing everything
+		let missing = (0..10_000).any(|i| !out_root.join(format!("a{}/b{}/c{}/f{i}", i % 10, i % 7, i % 3)).exists());
+		assert!(missing);
+	}
+
+	#[test]
+	fn test_apply_space_check() {
+		let dir = generate_test_folder(FolderSpec::new().file("old/a", "same").file("new/a", "same").file("new/b", "new"));
+		let root = root_of(&dir);
+		let cfg = FoldiffCfg::default();
+		crate::diffing::scan_to_diff::<NoopReporter>(root.join("old"), root.join("new"), &cfg, &StdVfs).unwrap()
+			.write_to_file::<NoopReporter, NoopReporter>(&root.join("diff.fldf"), &cfg).unwrap();
+
+		// far more than any disk has
+		let mut applying = read_diff_from_file(&root.join("diff.fldf")).unwrap();
+		for (_, size) in &mut applying.manifest.uncompressed_sizes {
+			*size = 1 << 60;
+		}
+		let err = applying.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(root.join("old"), root.join("out"), &cfg, &StdVfs, None).unwrap_err();
+		assert!(matches!(err.downcast_ref(), Some(FoldiffError::InsufficientDiskSpace { required, .. }) if *required > 2 << 60), "{err:?}");
+		assert!(!root.join("out").exists());
+
+		let cfg = FoldiffCfg { skip_space_check: true, ..cfg };
+		assert!(applying.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(root.join("old"), root.join("out"), &cfg, &StdVfs, None).unwrap().is_ok());
+	}
+
+	#[test]
+	fn test_apply_in_memory() {
+		let h = |d: &[u8]| hash::hash_stream(&mut &*d).unwrap();
+		let old_files = HashMap::from([
+			("same".to_string(), b"unchanged".to_vec()),
+			("gone".to_string(), b"deleted".to_vec()),
+			("edited".to_string(), b"the quick brown fox".to_vec()),
+			("copied".to_string(), b"copy me".to_vec()),
+		]);
+
+		let mut patch = Cursor::new(Vec::new());
+		let edited = b"the quick red fox".to_vec();
+		zstddiff::diff(&mut Cursor::new(&old_files["edited"]), &mut Cursor::new(&edited), &mut patch, None, None, None, None).unwrap();
+
+		let new_data = b"brand new".to_vec();
+		let manifest = crate::manifest::DiffManifestBuilder::new()
+			.add_untouched(h(b"unchanged"), "same")
+			.add_deleted(h(b"deleted"), "gone")
+			.add_patched(h(b"the quick brown fox"), h(&edited), "edited", 0)
+			.add_new(h(&new_data), "new", 0)
+			.add_duplicated(h(b"copy me"), vec!["copied".into()], vec!["copied".into(), "copied2".into()], u64::MAX)
+			.build();
+
+		let res = apply_in_memory(&manifest, old_files, vec![zstd::encode_all(&new_data[..], 3).unwrap()], vec![patch.into_inner()]).unwrap();
+
+		assert_eq!(res.len(), 5);
+		assert_eq!(res["same"], b"unchanged");
+		assert_eq!(res["edited"], edited);
+		assert_eq!(res["new"], new_data);
+		assert_eq!(res["copied"], b"copy me");
+		assert_eq!(res["copied2"], b"copy me");
+		assert!(!res.contains_key("gone"));
+
+		let mut with_dicts = crate::manifest::DiffManifestBuilder::new().add_new(h(&new_data), "new", 0).build();
+		with_dicts.has_dictionaries = true;
+		let err = apply_in_memory(&with_dicts, HashMap::new(), vec![zstd::encode_all(&new_data[..], 3).unwrap()], vec![]).unwrap_err();
+		assert!(err.to_string().contains("dictionaries"), "{err}");
+	}
+
+	#[test]
+	fn test_apply_in_memory_bad_hash() {
+		let old_files = HashMap::from([("same".to_string(), b"unchanged".to_vec())]);
+		let manifest = crate::manifest::DiffManifestBuilder::new().add_untouched(1234, "same").build();
+		let err = apply_in_memory(&manifest, old_files, vec![], vec![]).unwrap_err();
+		assert!(matches!(
+			err.downcast_ref(),
+			Some(crate::ApplyError::HashMismatch { operation: ApplyOperation::Copy, expected: 1234, .. })
+		));
+	}
+
+	#[test]
+	fn test_apply_hash_mismatch_message() {
+		let dir = generate_test_folder(FolderSpec::new().file("old/same", "unchanged").file("new/same", "unchanged"));
+		let root = root_of(&dir);
+		let cfg = FoldiffCfg::default();
+		crate::diffing::scan_to_diff::<NoopReporter>(root.join("old"), root.join("new"), &cfg, &StdVfs).unwrap()
+			.write_to_file::<NoopReporter, NoopReporter>(&root.join("diff.fldf"), &cfg).unwrap();
+
+		std::fs::write(root.join("old/same"), "changed").unwrap();
+		let res = read_diff_from_file(&root.join("diff.fldf")).unwrap()
+			.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(root.join("old"), root.join("out"), &cfg, &StdVfs, None)
+			.unwrap();
+		assert_eq!(res.succeeded, 0);
+		let [err] = &res.failed[..] else { panic!("expected one failure, got {:?}", res.failed) };
+		assert!(matches!(err, crate::PhaseError { phase: crate::Phase::Copy, path, .. } if path == "same"));
+
+		let msg = format!("{err}: {:#}", err.inner);
+		assert!(msg.starts_with("While copying same: "), "{msg}");
+		assert!(msg.contains("same was wrong after copying it from the old folder"), "{msg}");
+		assert!(msg.contains("may not be the one the diff was made from"), "{msg}");
+	}
+
+	#[test]
+	fn test_apply_renamed_dir() {
+		let mut spec = FolderSpec::new();
+		for (side, prefix) in [("old", "src"), ("new", "source")] {
+			for f in ["a", "b", "c/d"] {
+				spec.file(&format!("{side}/{prefix}/{f}"), format!("contents of {f}"));
+			}
+		}
+		let dir = generate_test_folder(&spec);
+		let root = root_of(&dir);
+		let cfg = FoldiffCfg::default();
+		crate::diffing::scan_to_diff::<NoopReporter>(root.join("old"), root.join("new"), &cfg, &StdVfs).unwrap()
+			.write_to_file::<NoopReporter, NoopReporter>(&root.join("diff.fldf"), &cfg).unwrap();
+
+		let mut applying = read_diff_from_file(&root.join("diff.fldf")).unwrap();
+		assert_eq!(applying.manifest().renamed_dirs, [("src".to_string(), "source".to_string())]);
+		let res = applying.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(root.join("old"), root.join("out"), &cfg, &StdVfs, None).unwrap();
+		assert!(res.is_ok());
+		assert_eq!(res.succeeded, 3);
+
+		assert_folders_equal(&root.join("new"), &root.join("out"));
+
+		// the old folder is still checked
+		std::fs::write(root.join("old/src/c/d"), "changed").unwrap();
+		let res = applying.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(root.join("old"), root.join("out2"), &cfg, &StdVfs, None).unwrap();
+		let [err] = &res.failed[..] else { panic!("expected one failure, got {:?}", res.failed) };
+		assert!(matches!(err, crate::PhaseError { phase: crate::Phase::Duplicate, path, .. } if path == "source/c/d"));
+	}
+
+	#[test]
+	fn test_apply_duplicates_checked() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("old/a", "copy me")
+			.file("new/x/a", "copy me")
+			.file("new/y/z/b", "copy me")
+			.file("new/n1", "new twice")
+			.file("new/d/n2", "new twice")
+		);
+		let root = root_of(&dir);
+		let cfg = FoldiffCfg::default();
+		crate::diffing::scan_to_diff::<NoopReporter>(root.join("old"), root.join("new"), &cfg, &StdVfs).unwrap()
+			.write_to_file::<NoopReporter, NoopReporter>(&root.join("diff.fldf"), &cfg).unwrap();
+
+		let res = read_diff_from_file(&root.join("diff.fldf")).unwrap()
+			.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(root.join("old"), root.join("out"), &cfg, &StdVfs, None)
+			.unwrap();
+		assert!(res.is_ok(), "{:?}", res.failed);
+		assert_eq!(res.succeeded, 4);
+
+		assert_folders_equal(&root.join("new"), &root.join("out"));
+
+		// the hash is of what was written, even when the parent dir didn't exist yet
+		let h = copy_rl_hash(root.join("old/a"), root.join("out2/deep/er/a"), HashAlgorithm::default(), &IoCounters::default()).unwrap();
+		assert_eq!(h, hash::hash_stream(&mut &b"copy me"[..]).unwrap());
+		assert_eq!(std::fs::read(root.join("out2/deep/er/a")).unwrap(), b"copy me");
+	}
+
+	#[test]
+	fn test_apply_copied_then_edited() {
+		// "a" is copied to "b" and then edited, so it's both duplicated and patched on the old side
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("old/a", "the original ".repeat(50))
+			.file("new/b", "the original ".repeat(50))
+			.file("new/a", "the edited one ".repeat(50))
+		);
+		let root = root_of(&dir);
+		let cfg = FoldiffCfg::default();
+		crate::diffing::scan_to_diff::<NoopReporter>(root.join("old"), root.join("new"), &cfg, &StdVfs).unwrap()
+			.write_to_file::<NoopReporter, NoopReporter>(&root.join("diff.fldf"), &cfg).unwrap();
+
+		let mut diff = read_diff_from_file(&root.join("diff.fldf")).unwrap();
+		assert_eq!(diff.manifest().patched_files.len(), 1);
+		assert_eq!(diff.manifest().duplicated_files[0].old_paths, ["a"]);
+
+		let res = diff.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(root.join("old"), root.join("out"), &cfg, &StdVfs, None).unwrap();
+		assert!(res.is_ok(), "{:?}", res.failed);
+		assert_folders_equal(&root.join("new"), &root.join("out"));
+	}
+
+	#[test]
+	fn test_apply_max_errors() {
+		let dir = tempdir().unwrap();
+		let root = Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap();
+		for side in ["old", "new", "wrong"] {
+			for i in 0..1000 {
+				let p = root.join(side).join(format!("d{}/f{i}", i % 10));
+				create_file(&p).unwrap();
+				std::fs::write(&p, if side == "wrong" { format!("not file {i}") } else { format!("file {i}") }).unwrap();
+			}
+		}
+		let cfg = FoldiffCfg { threads: 4, max_errors: 10, ..FoldiffCfg::default() };
+		crate::diffing::scan_to_diff::<NoopReporter>(root.join("old"), root.join("new"), &cfg, &StdVfs).unwrap()
+			.write_to_file::<NoopReporter, NoopReporter>(&root.join("diff.fldf"), &cfg).unwrap();
+
+		let res = read_diff_from_file(&root.join("diff.fldf")).unwrap()
+			.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(root.join("wrong"), root.join("out"), &cfg, &StdVfs, None)
+			.unwrap();
+
+		// files already being copied when we hit the limit still get reported, so allow a few extra
+		assert!((10..100).contains(&res.failed.len()), "{} files failed", res.failed.len());
+		assert_eq!(res.succeeded, 0);
+	}
+
+	#[test]
+	fn test_apply_with_recovery() {
+		let dir = tempdir().unwrap();
+		let root = Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap();
+		for side in ["old", "new", "wrong"] {
+			for i in 0..1000 {
+				let p = root.join(side).join(format!("d{}/f{i}", i % 10));
+				create_file(&p).unwrap();
+				// every 10th file is wrong
+				std::fs::write(&p, if side == "wrong" && i % 10 == 0 { format!("not file {i}") } else { format!("file {i}") }).unwrap();
+			}
+		}
+		let cfg = FoldiffCfg { threads: 4, ..FoldiffCfg::default() };
+		crate::diffing::scan_to_diff::<NoopReporter>(root.join("old"), root.join("new"), &cfg, &StdVfs).unwrap()
+			.write_to_file::<NoopReporter, NoopReporter>(&root.join("diff.fldf"), &cfg).unwrap();
+
+		let apply = |out: &str, action: ErrorAction| {
+			let seen = Mutex::new(Vec::new());
+			let res = read_diff_from_file(&root.join("diff.fldf")).unwrap()
+				.apply_with_recovery::<NoopMultiWrapper, NoopReporter, NoopReporter>(root.join("wrong"), root.join(out), &cfg, &StdVfs, None, |e| {
+					assert!(e.inner.downcast_ref::<ApplyError>().is_some(), "{e:?}");
+					seen.lock().unwrap().push(e.path.clone());
+					action
+				})
+				.unwrap();
+			assert_eq!(seen.into_inner().unwrap().len(), res.failed.len());
+			res
+		};
+
+		let res = apply("skipped", ErrorAction::Skip);
+		assert_eq!((res.succeeded, res.failed.len()), (900, 100));
+
+		// files already being copied when we abort still get reported, so allow a few extra
+		let res = apply("aborted", ErrorAction::Abort);
+		assert!((1..50).contains(&res.failed.len()), "{} files failed", res.failed.len());
+		assert!(res.succeeded < 900);
+	}
+
+	#[test]
+	fn test_apply_to_tar() {
+		let old_files = [("same", "unchanged"), ("edited", "the quick brown fox"), ("deleted", "gone soon"), ("dup", "copy me")];
+		let new_files = [("same", "unchanged"), ("edited", "the quick brown cat"), ("sub/new", "brand new"), ("dup", "copy me"), ("dup2", "copy me")];
+		let mut spec = FolderSpec::new();
+		for (side, files) in [("old", &old_files[..]), ("new", &new_files[..])] {
+			for (p, data) in files {
+				spec.file(&format!("{side}/{p}"), data);
+			}
+		}
+		let dir = generate_test_folder(spec.dir("new/empty"));
+		let root = root_of(&dir);
+
+		let cfg = FoldiffCfg::default();
+		crate::diffing::scan_to_diff::<NoopReporter>(root.join("old"), root.join("new"), &cfg, &StdVfs).unwrap()
+			.write_to_file::<NoopReporter, NoopReporter>(&root.join("diff.fldf"), &cfg).unwrap();
+		let diff = read_diff_from_file(&root.join("diff.fldf")).unwrap();
+
+		let mut out = Vec::new();
+		diff.apply_to_tar(&root.join("old"), &mut out, &cfg, &StdVfs).unwrap();
+
+		let mut entries = HashMap::new();
+		for e in tar::Archive::new(&out[..]).entries().unwrap() {
+			let mut e = e.unwrap();
+			let path = e.path().unwrap().to_string_lossy().trim_end_matches('/').to_string();
+			let mut data = String::new();
+			e.read_to_string(&mut data).unwrap();
+			entries.insert(path, (e.header().entry_type(), data));
+		}
+
+		assert_eq!(entries.len(), new_files.len() + 1, "{entries:?}");
+		for (p, data) in new_files {
+			assert_eq!(entries[p], (tar::EntryType::Regular, data.to_string()));
+		}
+		assert_eq!(entries["empty"].0, tar::EntryType::Directory);
+
+		// a wrong old folder is caught
+		std::fs::write(root.join("old/same"), "changed").unwrap();
+		assert!(diff.apply_to_tar(&root.join("old"), std::io::sink(), &cfg, &StdVfs).is_err());
+	}
+
+	#[test]
+	#[cfg(feature = "encryption")]
+	fn test_apply_encrypted() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("old/a", "the quick brown fox".repeat(50))
+			.file("new/a", "the quick brown cat".repeat(50))
+			.file("new/b", "jumps over the lazy dog".repeat(50))
+		);
+		let root = root_of(&dir);
+
+		let key = EncryptionCfg::new([7; 32]);
+		let cfg = FoldiffCfg {
+			encryption: Some(key.clone()),
+			new_dict: Some(b"jumps over the lazy dog".to_vec()),
+			diff_dict: Some(b"the quick brown cat".to_vec()),
+			..Default::default()
+		};
+		let diff_path = root.join("diff.fldf");
+		let mut diff = crate::diffing::scan_to_diff::<NoopReporter>(root.join("old"), root.join("new"), &cfg, &StdVfs).unwrap();
+		diff.write_to_file::<NoopReporter, NoopReporter>(&diff_path, &cfg).unwrap();
+
+		// no paths or contents in the clear
+		let data = std::fs::read(&diff_path).unwrap();
+		for needle in [&b"lazy"[..], b"new/b", b"quick"] {
+			assert!(!data.windows(needle.len()).any(|w| w == needle));
+		}
+
+		let err = read_diff_from_file(&diff_path).unwrap_err();
+		assert!(err.to_string().contains("encrypted"), "{err}");
+		assert!(read_encrypted_diff_from_file(&diff_path, &EncryptionCfg::new([8; 32])).is_err());
+
+		let mut applying = read_encrypted_diff_from_file(&diff_path, &key).unwrap();
+		let applied = applying.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(root.join("old"), root.join("out"), &cfg, &StdVfs, None).unwrap();
+		assert!(applied.is_ok());
+		assert_folders_equal(&root.join("new"), &root.join("out"));
+	}
+
+	#[test]
+	#[cfg(feature = "timestamps")]
+	fn test_preserve_timestamps() {
+		use std::time::{Duration, SystemTime};
+
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("old/same", "same")
+			.file("old/patched", "before ".repeat(50))
+			.file("new/same", "same")
+			.file("new/patched", "after ".repeat(50))
+			.file("new/sub/added", "new file")
+		);
+		let root = root_of(&dir);
+		let files = ["same", "patched", "sub/added"];
+		let mtime = |p: &Utf8Path| std::fs::metadata(p).unwrap().modified().unwrap();
+
+		// well in the past, with nanoseconds so they get checked too
+		for (i, p) in files.iter().enumerate() {
+			let t = SystemTime::UNIX_EPOCH + Duration::new(1_600_000_000 + i as u64, 123_456_789);
+			File::options().write(true).open(root.join("new").join(p)).unwrap().set_modified(t).unwrap();
+		}
+
+		let cfg = FoldiffCfg { preserve_timestamps: true, ..FoldiffCfg::default() };
+		let diff_path = root.join("diff.fldf");
+		crate::diffing::scan_to_diff::<NoopReporter>(root.join("old"), root.join("new"), &cfg, &StdVfs).unwrap()
+			.write_to_file::<NoopReporter, NoopReporter>(&diff_path, &cfg).unwrap();
+
+		for (preserve, out) in [(true, "out"), (false, "out-now")] {
+			let cfg = FoldiffCfg { preserve_timestamps: preserve, ..FoldiffCfg::default() };
+			let applied = read_diff_from_file(&diff_path).unwrap()
+				.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(root.join("old"), root.join(out), &cfg, &StdVfs, None)
+				.unwrap();
+			assert!(applied.is_ok());
+
+			for p in files {
+				let (expected, got) = (mtime(&root.join("new").join(p)), mtime(&root.join(out).join(p)));
+				assert_eq!(expected == got, preserve, "{out}/{p}: {expected:?} vs {got:?}");
+			}
+		}
+	}
+
+	#[test]
+	fn test_blob_alignment() {
+		let dir = tempdir().unwrap();
+		let root = Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap();
+		let (old_root, new_root, _) = diff_many_files(&root, 3, &FoldiffCfg::default());
+
+		for alignment in [1, 8, 4096] {
+			let cfg = FoldiffCfg { blob_alignment: alignment, ..Default::default() };
+			let mut diff = crate::diffing::scan_to_diff::<NoopReporter>(old_root.clone(), new_root.clone(), &cfg, &StdVfs).unwrap();
+			let bytes = diff.write_to_bytes::<NoopReporter, NoopReporter>(&cfg).unwrap();
+
+			// the first new blob is right after the count at the start of the blobs
+			let mut applying = read_diff_from_bytes(&bytes).unwrap();
+			let blobs = applying.blobs_new[0] - 8;
+			assert_eq!(blobs % alignment as u64, 0);
+			// after the magic, version, manifest length, and manifest, with as little zero padding as possible
+			let header = 16 + u64::from_be_bytes(bytes[8..16].try_into().unwrap());
+			assert!(blobs >= header && blobs - header < alignment as u64);
+			assert!(bytes[header as usize..blobs as usize].iter().all(|&b| b == 0));
+			let out = root.join(format!("out{alignment}"));
+			assert!(applying.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(old_root.clone(), out.clone(), &cfg, &StdVfs, None).unwrap().is_ok());
+			assert_folders_equal(&new_root, &out);
+		}
+
+		let cfg = FoldiffCfg { blob_alignment: 3, ..Default::default() };
+		let mut diff = crate::diffing::scan_to_diff::<NoopReporter>(old_root, new_root, &cfg, &StdVfs).unwrap();
+		assert!(diff.write_to_bytes::<NoopReporter, NoopReporter>(&cfg).is_err());
+	}
+
+	#[test]
+	fn test_read_truncated_diff() {
+		let dir = tempdir().unwrap();
+		let root = Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap();
+		let (_, _, diff_path) = diff_many_files(&root, 3, &FoldiffCfg::default());
+
+		// cut the last new blob short, along with everything after it
+		let mut data = std::fs::read(&diff_path).unwrap();
+		let catalog_start = u64::from_be_bytes(*data.last_chunk().unwrap());
+		data.truncate(catalog_start as usize - 12);
+		let err = read_diff_from(&mut Cursor::new(&data)).unwrap_err();
+		assert_eq!(err.to_string(), format!("Unexpected EOF at offset {:#x} while reading the data of new blob #2 of 3", data.len()));
+	}
+}
@@ -0,0 +1,171 @@
+// a table at the end of a diff saying where each new and patched file's blob is, so one file can be read
+// without reading the manifest or walking through every blob before it.
+//
+// layout: magic, entry count, new file dictionary offset and length (both 0 if there isn't one),
+// then (path hash, blob offset, flags) for each file sorted by path hash, and lastly where the catalog starts.
+// offsets are from the start of the diff, and blob offsets point at a new blob's length or a patch blob's chunk count.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use anyhow::{bail, ensure, Context, Result};
+use xxhash_rust::xxh3::xxh3_64;
+
+const CATALOG_MAGIC: [u8; 4] = *b"FLDC";
+// magic, count, dictionary offset and length
+const HEADER_LEN: u64 = 4 + 8 * 3;
+const ENTRY_LEN: u64 = 8 * 3;
+
+// set in an entry's flags if it is a patch blob instead of a new blob
+const FLAG_PATCH: u64 = 1;
+// set if a new blob is stored without compression
+const FLAG_RAW: u64 = 2;
+
+/// where one file's blob is in the diff
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CatalogEntry {
+	pub path_hash: u64,
+	pub offset: u64,
+	flags: u64,
+}
+
+impl CatalogEntry {
+	pub fn new_blob(path: &str, offset: u64, raw: bool) -> Self {
+		Self { path_hash: path_hash(path), offset, flags: if raw { FLAG_RAW } else { 0 } }
+	}
+
+	pub fn patch_blob(path: &str, offset: u64) -> Self {
+		Self { path_hash: path_hash(path), offset, flags: FLAG_PATCH }
+	}
+}
+
+fn path_hash(path: &str) -> u64 {
+	xxh3_64(path.as_bytes())
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+	let mut buf = [0u8; 8];
+	reader.read_exact(&mut buf)?;
+	Ok(u64::from_be_bytes(buf))
+}
+
+/// writes the catalog at the current position, which must be `start` bytes from the start of the diff
+pub(crate) fn write_catalog(writer: &mut impl Write, start: u64, mut entries: Vec<CatalogEntry>, new_dict: Option<(u64, u64)>) -> Result<()> {
+	entries.sort_unstable_by_key(|e| e.path_hash);
+
+	writer.write_all(&CATALOG_MAGIC)?;
+	writer.write_all(&(entries.len() as u64).to_be_bytes())?;
+	let (dict_offset, dict_len) = new_dict.unwrap_or_default();
+	writer.write_all(&dict_offset.to_be_bytes())?;
+	writer.write_all(&dict_len.to_be_bytes())?;
+	for e in entries {
+		for n in [e.path_hash, e.offset, e.flags] {
+			writer.write_all(&n.to_be_bytes())?;
+		}
+	}
+	writer.write_all(&start.to_be_bytes())?;
+	Ok(())
+}
+
+/// finds the catalog from the end of the diff, returning where it starts and how many entries it has
+fn find_catalog(reader: &mut (impl Read + Seek)) -> Result<(u64, u64)> {
+	let len = reader.seek(SeekFrom::End(0))?;
+	ensure!(len >= HEADER_LEN + 8, "Diff is too small to have a catalog");
+	reader.seek(SeekFrom::End(-8))?;
+	let start = read_u64(reader)?;
+
+	// older diffs end with the last blob, so make sure this really is a catalog
+	let no_catalog = || "Diff has no catalog, it was made by an older version of foldiff or by composing diffs";
+	ensure!(start <= len - HEADER_LEN - 8, no_catalog());
+	reader.seek(SeekFrom::Start(start))?;
+	let mut magic = [0u8; 4];
+	reader.read_exact(&mut magic)?;
+	ensure!(magic == CATALOG_MAGIC, no_catalog());
+	let count = read_u64(reader)?;
+	ensure!(count.checked_mul(ENTRY_LEN).and_then(|l| l.checked_add(start + HEADER_LEN + 8)) == Some(len), no_catalog());
+	Ok((start, count))
+}
+
+/// binary searches the catalog for `path`
+fn find_entry(reader: &mut (impl Read + Seek), start: u64, count: u64, path: &str) -> Result<Option<CatalogEntry>> {
+	let hash = path_hash(path);
+	let mut read_entry = |i: u64| -> Result<CatalogEntry> {
+		reader.seek(SeekFrom::Start(start + HEADER_LEN + i * ENTRY_LEN))?;
+		Ok(CatalogEntry { path_hash: read_u64(reader)?, offset: read_u64(reader)?, flags: read_u64(reader)? })
+	};
+
+	let (mut lo, mut hi) = (0, count);
+	while lo < hi {
+		let mid = lo + (hi - lo) / 2;
+		let e = read_entry(mid)?;
+		match e.path_hash.cmp(&hash) {
+			std::cmp::Ordering::Equal => return Ok(Some(e)),
+			std::cmp::Ordering::Less => lo = mid + 1,
+			std::cmp::Ordering::Greater => hi = mid,
+		}
+	}
+	Ok(None)
+}
+
+/// Reads the contents of the new file at `path` straight out of a diff, using the catalog at the end of it,
+/// instead of reading the manifest and every blob before it. Files are found by a hash of their path, which isn't checked.
+/// Patched files can't be read this way, as they need the old file.
+pub fn seek_to_file<'a, R: Read + Seek>(reader: &'a mut R, path: &str) -> Result<Box<dyn Read + 'a>> {
+	let (start, count) = find_catalog(reader)?;
+	let Some(entry) = find_entry(reader, start, count, path)?
+	else { bail!("{path:?} is not a new or patched file in the diff") };
+	if entry.flags & FLAG_PATCH != 0 {
+		bail!("{path:?} is patched by the diff, so can't be read without the old file");
+	}
+
+	reader.seek(SeekFrom::Start(start + 4 + 8))?;
+	let (dict_offset, dict_len) = (read_u64(reader)?, read_u64(reader)?);
+	let dict = if dict_len != 0 {
+		reader.seek(SeekFrom::Start(dict_offset))?;
+		let mut dict = vec![0u8; dict_len.try_into()?];
+		reader.read_exact(&mut dict).context("Failed to read the new file dictionary")?;
+		Some(dict)
+	}
+	else {
+		None
+	};
+
+	reader.seek(SeekFrom::Start(entry.offset))?;
+	let len = read_u64(reader)?;
+	let blob = reader.take(len);
+	if entry.flags & FLAG_RAW != 0 {
+		return Ok(Box::new(blob));
+	}
+	let decoder = match dict {
+		Some(dict) => zstd::Decoder::with_dictionary(std::io::BufReader::new(blob), &dict)?,
+		None => zstd::Decoder::with_buffer(std::io::BufReader::new(blob))?,
+	};
+	Ok(Box::new(decoder))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Cursor;
+
+	#[test]
+	fn test_catalog_lookup() {
+		// some blob-ish data before the catalog
+		let mut diff = b"not really blobs".to_vec();
+		let start = diff.len() as u64;
+		let entries = (0..100).map(|i| CatalogEntry::new_blob(&format!("file{i}"), i * 10, false)).chain([CatalogEntry::patch_blob("patched", 5)]).collect();
+		write_catalog(&mut diff, start, entries, None).unwrap();
+
+		let mut cursor = Cursor::new(&diff[..]);
+		let (found_start, count) = find_catalog(&mut cursor).unwrap();
+		assert_eq!((found_start, count), (start, 101));
+		for i in 0..100 {
+			assert_eq!(find_entry(&mut cursor, start, count, &format!("file{i}")).unwrap().unwrap().offset, i * 10);
+		}
+		assert_eq!(find_entry(&mut cursor, start, count, "missing").unwrap(), None);
+		assert!(seek_to_file(&mut cursor, "patched").is_err());
+
+		// anything else at the end isn't mistaken for a catalog
+		assert!(find_catalog(&mut Cursor::new(&diff[..diff.len() - 1])).is_err());
+		assert!(find_catalog(&mut Cursor::new(b"way too short")).is_err());
+		assert!(find_catalog(&mut Cursor::new(vec![0xff; 1000])).is_err());
+	}
+}
@@ -1,22 +1,392 @@
+use std::collections::HashMap;
 use std::fs::File;
-use anyhow::Context;
-use camino::Utf8Path;
+use std::io::Write;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Context};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::encryption::EncryptionCfg;
 use crate::hash;
+use crate::iocount::IoCounters;
+use crate::zstddiff::DiffTuning;
 
 pub const MAGIC_BYTES: [u8; 4] = *b"FLDF";
 pub const VERSION_NUMBER_1_0_0_R: [u8; 4] = [1, 0, 0, b'r']; // v1.0.0-r
 pub const VERSION_NUMBER_1_1_0: [u8; 4] = [0, 1, 1, 0]; // v1.1.0
-pub const VERSION_NUMBER_LATEST: [u8; 4] = VERSION_NUMBER_1_1_0;
+pub const VERSION_NUMBER_1_2_0: [u8; 4] = [0, 1, 2, 0]; // v1.2.0
+pub const VERSION_NUMBER_1_3_0: [u8; 4] = [0, 1, 3, 0]; // v1.3.0
+pub const VERSION_NUMBER_LATEST: [u8; 4] = VERSION_NUMBER_1_3_0;
+
+/// the most that blobs can be aligned to, so a corrupt manifest can't ask for huge padding
+pub const MAX_BLOB_ALIGNMENT: usize = 1 << 16;
+
+/// unix permissions given to files created from the diff that have no stored mode
+pub const DEFAULT_FILE_MODE: u32 = 0o644;
 
 /// internal configuration struct passed into foldiff to control its operation
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct FoldiffCfg {
 	pub threads: usize,
 	pub level_new: u8,
 	pub level_diff: u8,
+	pub symlinks: SymlinkMode,
+	pub preserve_timestamps: bool,
+	/// treat paths that only differ in case as the same path. always on for windows.
+	pub case_insensitive_paths: bool,
+	/// fail instead of warning if a file changes while it is being scanned
+	pub strict_scan: bool,
+	pub verify_mode: VerifyMode,
+	/// zstd dictionary used when compressing patches, stored in the diff
+	pub diff_dict: Option<Vec<u8>>,
+	/// zstd dictionary used when compressing new files, stored in the diff
+	pub new_dict: Option<Vec<u8>>,
+	/// if set, a line of JSON is appended to this file for every file handled
+	pub log_path: Option<Utf8PathBuf>,
+	/// stop applying once this many files have failed, so a wrong old folder doesn't pile up errors forever
+	pub max_errors: usize,
+	/// if set, ignore level_new and level_diff and use the strongest level that should compress everything in this many seconds
+	pub time_budget_secs: Option<f64>,
+	/// split files bigger than 1GiB where the old and new data line up, instead of into even chunks.
+	/// much better for files with data inserted near the start, but reads them an extra time while diffing
+	pub cdc_chunking: bool,
+	/// save the hash of every scanned file in a `.foldiff-cache` file in the root of each folder, and trust them next time
+	/// for dirs where no file has changed name, size, or modification time. a file changed without any of those changing
+	/// would be missed, so only use this for folders that are only changed normally
+	pub use_scan_cache: bool,
+	/// how to store files of each mime type, instead of the default for it. see `DiffStrategy`
+	pub strategy_overrides: HashMap<String, DiffStrategy>,
+	/// if set, encrypt the manifest and every blob of written diffs with this key. needs the `encryption` feature
+	pub encryption: Option<EncryptionCfg>,
+	/// pad the end of the manifest so the blobs start at a multiple of this many bytes into the diff,
+	/// which is faster to read out of when the diff is memory mapped. must be a power of two up to MAX_BLOB_ALIGNMENT,
+	/// and 4096 lines them up with pages
+	pub blob_alignment: usize,
+	/// diff each changed file with the end of the changed file before it as extra context, with files of the same name
+	/// next to each other. much smaller diffs when many changed files share the same changes, e.g. versions of one binary,
+	/// but changed files then have to be applied one at a time
+	pub delta_chain: bool,
+	/// how far back zstd can look when diffing, as a power of two from 10 to 31. applying needs a buffer this big for
+	/// each file being patched. if None, the smallest that covers each chunk of the old and new file is used
+	pub window_log: Option<u8>,
+	/// let zstd look for matches far back in the old file, for chunks of at least 32MiB. slower, but finds moved data
+	pub long_distance_matching: bool,
+	/// how big long distance matching's hash table is, as a power of two from 6 to 30. if None, zstd picks from the window log
+	pub ldm_hash_log: Option<u8>,
+	/// when applying, new files smaller than this many bytes are decompressed into memory in parallel, then written one
+	/// at a time while the next batch is decompressed. much faster when writing from many threads is slow, e.g. on a
+	/// network filesystem. 0 writes every new file straight from its blob
+	pub predecompress_threshold: u64,
+	/// when applying, give each phase a thread pool of its own instead of sharing the global one
+	pub phase_threads: Option<PhaseConcurrency>,
+	/// don't check there is enough free space for the new folder before applying
+	pub skip_space_check: bool,
+}
+
+/// how many threads each phase of applying a diff gets. 0 means one per logical processor
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PhaseConcurrency {
+	/// copying unchanged and duplicated files
+	pub copy_threads: usize,
+	/// patching changed files
+	pub patch_threads: usize,
+	/// writing new files
+	pub new_threads: usize,
+}
+
+impl Default for FoldiffCfg {
+	/// matches the defaults of the foldiff cli, except for threads
+	fn default() -> Self {
+		Self {
+			threads: 1,
+			level_new: 7,
+			level_diff: 3,
+			symlinks: SymlinkMode::default(),
+			preserve_timestamps: false,
+			case_insensitive_paths: false,
+			strict_scan: false,
+			verify_mode: VerifyMode::default(),
+			diff_dict: None,
+			new_dict: None,
+			log_path: None,
+			max_errors: usize::MAX,
+			time_budget_secs: None,
+			cdc_chunking: false,
+			use_scan_cache: false,
+			strategy_overrides: HashMap::new(),
+			encryption: None,
+			blob_alignment: 8,
+			delta_chain: false,
+			window_log: None,
+			long_distance_matching: true,
+			ldm_hash_log: None,
+			predecompress_threshold: 1 << 20,
+			phase_threads: None,
+			skip_space_check: false,
+		}
+	}
+}
+
+impl FoldiffCfg {
+	pub fn builder() -> FoldiffCfgBuilder {
+		FoldiffCfgBuilder::default()
+	}
+
+	/// the parts of this that are passed on to zstddiff
+	pub(crate) fn diff_tuning(&self) -> DiffTuning {
+		DiffTuning { window_log: self.window_log, long_distance_matching: self.long_distance_matching, ldm_hash_log: self.ldm_hash_log }
+	}
+}
+
+/// builds a FoldiffCfg, starting from the defaults
+#[derive(Clone, Debug, Default)]
+pub struct FoldiffCfgBuilder {
+	cfg: FoldiffCfg,
+}
+
+impl FoldiffCfgBuilder {
+	pub fn threads(&mut self, threads: usize) -> &mut Self {
+		self.cfg.threads = threads;
+		self
+	}
+
+	pub fn level_new(&mut self, level: u8) -> &mut Self {
+		self.cfg.level_new = level;
+		self
+	}
+
+	pub fn level_diff(&mut self, level: u8) -> &mut Self {
+		self.cfg.level_diff = level;
+		self
+	}
+
+	pub fn build(&self) -> FoldiffCfg {
+		self.cfg.clone()
+	}
+}
+
+/// errors that callers might want to handle specially, found by downcasting the returned anyhow::Error
+#[derive(Debug)]
+pub enum FoldiffError {
+	/// the operation was stopped with a CancellationToken
+	Cancelled,
+	/// there isn't enough free space to apply the diff, with some headroom. both are in bytes
+	InsufficientDiskSpace { available: u64, required: u64 },
+}
+
+impl std::fmt::Display for FoldiffError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Cancelled => write!(f, "Operation was cancelled"),
+			Self::InsufficientDiskSpace { available, required } =>
+				write!(f, "Not enough free space to apply the diff: {required} bytes are needed, but only {available} are free"),
+		}
+	}
+}
+
+impl std::error::Error for FoldiffError {}
+
+/// what apply was doing when a file turned out to be wrong
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ApplyOperation {
+	/// copying an unchanged file from the old folder
+	Copy,
+	/// decompressing a new file out of the diff
+	Decompress,
+	/// patching a file from the old folder
+	Patch,
+	/// checking a file in the old folder before using it
+	CheckOld,
+	/// copying a new file from another copy of it that was just written to the new folder
+	CopyNew,
+}
+
+impl std::fmt::Display for ApplyOperation {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Copy => write!(f, "copying it from the old folder"),
+			Self::Decompress => write!(f, "decompressing it from the diff"),
+			Self::Patch => write!(f, "patching it"),
+			Self::CheckOld => write!(f, "reading it from the old folder"),
+			Self::CopyNew => write!(f, "copying it from another copy of it in the new folder"),
+		}
+	}
+}
+
+/// errors from applying a diff, found by downcasting the returned anyhow::Error
+#[derive(Debug)]
+pub enum ApplyError {
+	/// a file had different contents to what the diff expected
+	HashMismatch {
+		path: String,
+		operation: ApplyOperation,
+		expected: u64,
+		actual: u64,
+	},
+}
+
+impl std::fmt::Display for ApplyError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::HashMismatch { path, operation, expected, actual } => {
+				write!(f, "{path} was wrong after {operation} (expected hash {expected:016x}, got {actual:016x}). ")?;
+				match operation {
+					ApplyOperation::Copy | ApplyOperation::CheckOld => write!(f, "The old folder may not be the one the diff was made from."),
+					ApplyOperation::Decompress | ApplyOperation::Patch => write!(f, "The diff may be corrupted, try downloading it again."),
+					ApplyOperation::CopyNew => write!(f, "Something else may have changed the new folder while applying."),
+				}
+			},
+		}
+	}
+}
+
+impl std::error::Error for ApplyError {}
+
+/// which part of applying a diff a file was being handled in
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+	Copy,
+	Duplicate,
+	New,
+	Patch,
+	Symlink,
+	HardLink,
+	Attributes,
+}
+
+impl std::fmt::Display for Phase {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Copy => write!(f, "copying"),
+			Self::Duplicate => write!(f, "duplicating"),
+			Self::New => write!(f, "creating"),
+			Self::Patch => write!(f, "patching"),
+			Self::Symlink => write!(f, "creating symlink"),
+			Self::HardLink => write!(f, "creating hard link"),
+			Self::Attributes => write!(f, "setting attributes of"),
+		}
+	}
+}
+
+/// what `ApplyingDiff::apply_with_recovery` should do after a file fails
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorAction {
+	/// note it in the result, and carry on with the other files
+	Skip,
+	/// stop starting new files, and return what went wrong so far
+	Abort,
+}
+
+/// An error from applying a diff, tied to the file that caused it. Found by downcasting.
+#[derive(Debug)]
+pub struct PhaseError {
+	pub phase: Phase,
+	pub path: String,
+	pub inner: anyhow::Error,
+}
+
+impl std::fmt::Display for PhaseError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "While {} {}", self.phase, self.path)
+	}
+}
+
+impl std::error::Error for PhaseError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		Some(self.inner.as_ref())
+	}
+}
+
+/// checks a hash, returning an ApplyError if it's wrong
+pub(crate) fn check_hash(path: &str, operation: ApplyOperation, expected: u64, actual: u64) -> Result<(), ApplyError> {
+	if expected == actual {
+		Ok(())
+	}
+	else {
+		Err(ApplyError::HashMismatch { path: path.to_string(), operation, expected, actual })
+	}
+}
+
+/// what to do when a symlink is found while scanning
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkMode {
+	/// fail the scan
+	#[default]
+	Deny,
+	/// resolve the symlink and treat it as whatever it points to
+	Follow,
+	/// store the symlink itself in the diff, and recreate it when applying
+	Preserve,
+}
+
+/// which hash every hash in a diff was made with
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+	/// XXH64, used by all diffs before FLDF v1.3.0
+	XxHash64,
+	/// XXH3 (64 bit), which uses SIMD where the cpu has it
+	#[default]
+	Xxh3,
+}
+
+impl HashAlgorithm {
+	/// what manifests that don't say which algorithm they use were hashed with
+	pub fn legacy() -> Self {
+		Self::XxHash64
+	}
+}
+
+/// how thoroughly to check file contents when verifying
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum VerifyMode {
+	/// hash every file
+	#[default]
+	Full,
+	/// only compare file sizes, falling back to hashing when the size is unknown
+	Fast,
+}
+
+/// how new and changed files are stored in a diff, picked from the mime type inferred from their contents.
+/// by default, already-compressed formats are Raw, text formats are TextDelta, and everything else is Zstd
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DiffStrategy {
+	/// compress with zstd, against the old file for changed files
+	#[default]
+	Zstd,
+	/// store without compression. changed files are stored whole, as compressed data rarely has much in common with its old version
+	Raw,
+	/// match up unchanged lines with the old file first, then compress the rest with zstd
+	TextDelta,
 }
 
-/// creates a file and all necessary parent directories
+impl FromStr for DiffStrategy {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Ok(match s {
+			"zstd" => Self::Zstd,
+			"raw" => Self::Raw,
+			"text" => Self::TextDelta,
+			_ => bail!("Unknown diff strategy '{s}', expected one of zstd, raw, text"),
+		})
+	}
+}
+
+impl FromStr for SymlinkMode {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Ok(match s {
+			"deny" => Self::Deny,
+			"follow" => Self::Follow,
+			"preserve" => Self::Preserve,
+			_ => bail!("Unknown symlink mode '{s}', expected one of deny, follow, preserve"),
+		})
+	}
+}
+
+/// creates a file and all necessary parent directories.
+/// safe to call from many threads at once, as create_dir_all is fine with another thread creating the same dirs.
 pub fn create_file(p: &Utf8Path) -> std::io::Result<File> {
 	if let Some(p) = p.parent() {
 		std::fs::create_dir_all(p)?;
@@ -24,39 +394,105 @@ pub fn create_file(p: &Utf8Path) -> std::io::Result<File> {
 	File::create(p)
 }
 
-// Reflinks or copies a file and hashes it
-pub fn copy_rl_hash(src_p: impl AsRef<Utf8Path>, dst_p: impl AsRef<Utf8Path>) -> anyhow::Result<u64> {
+// Reflinks or copies a file, creating its parent dirs, and hashes what was written
+pub fn copy_rl_hash(src_p: impl AsRef<Utf8Path>, dst_p: impl AsRef<Utf8Path>, algo: HashAlgorithm, io: &IoCounters) -> anyhow::Result<u64> {
 	let src_p = src_p.as_ref();
 	let dst_p = dst_p.as_ref();
-	
+
+	// reflinking won't make the parent dir for us, and would always fall back to copying
+	if let Some(par) = dst_p.parent() {
+		std::fs::create_dir_all(par).context(format!("Failed to create parent dir to copy to {dst_p:?}"))?;
+	}
+
 	// if we're on *nix, try reflinking
-	if cfg!(unix) && reflink::reflink(&src_p, &dst_p).is_ok() {
-		// reflinked, check the hash
-		hash::hash_file(&src_p).context(format!("Failed to hash file copied from {src_p:?}"))
+	let hash = if cfg!(unix) && reflink::reflink(src_p, dst_p).is_ok() {
+		// reflinked, check the hash of the copy. that's the only time the data is actually read
+		let hash = hash::hash_file_with(dst_p, algo).context(format!("Failed to hash file copied from {src_p:?}"))?;
+		io.add_read(std::fs::metadata(dst_p)?.len());
+		hash
 	}
 	else {
 		// reflink failed or we're on windows, copy
 		// copying in kernel space would be slightly faster but we have to check the hash
 		let mut src = File::open(&src_p).context(format!("Failed to open file to copy from {src_p:?}"))?;
-		let mut dst = create_file(&dst_p).context(format!("Failed to create file to copy to {dst_p:?}"))?;
+		let dst = create_file(&dst_p).context(format!("Failed to create file to copy to {dst_p:?}"))?;
+		#[cfg(all(feature = "uring", target_os = "linux"))]
+		let mut dst = crate::uring::UringWriter::new(dst);
+		#[cfg(not(all(feature = "uring", target_os = "linux")))]
+		let mut dst = dst;
 
-		let mut hw = hash::XXHashStreamer::new(&mut dst);
-		std::io::copy(&mut src, &mut hw).context(format!("Failed to copy file {src_p:?}"))?;
+		let mut hw = hash::XXHashStreamer::with_algorithm(&mut dst, algo);
+		let n = std::io::copy(&mut src, &mut hw).context(format!("Failed to copy file {src_p:?}"))?;
+		io.add_read(n);
+		io.add_written(n);
+		let hash = hw.finish();
+		dst.flush().context(format!("Failed to copy file {src_p:?}"))?;
 
-		Ok(hw.finish())
-	}
+		hash
+	};
+
+	copy_permissions(src_p, dst_p).context(format!("Failed to copy permissions of {src_p:?}"))?;
+
+	Ok(hash)
 }
 
-pub fn copy_rl(src_p: impl AsRef<Utf8Path>, dst_p: impl AsRef<Utf8Path>) -> std::io::Result<()> {
-	let src_p = src_p.as_ref();
-	let dst_p = dst_p.as_ref();
-	
-	// if we're on *nix, try reflinking
-	if cfg!(unix) && reflink::reflink(&src_p, &dst_p).is_ok() {
+fn copy_permissions(src_p: &Utf8Path, dst_p: &Utf8Path) -> std::io::Result<()> {
+	std::fs::set_permissions(dst_p, std::fs::metadata(src_p)?.permissions())
+}
+
+/// sets the unix permission bits of a file, does nothing on other platforms
+pub fn set_mode(p: impl AsRef<Utf8Path>, mode: u32) -> std::io::Result<()> {
+	#[cfg(unix)]
+	return {
+		use std::os::unix::fs::PermissionsExt;
+		std::fs::set_permissions(p.as_ref(), std::fs::Permissions::from_mode(mode))
+	};
+
+	#[cfg(not(unix))]
+	return {
+		let _ = (p, mode);
 		Ok(())
-	}
-	else {
-		std::fs::copy(src_p, dst_p).map(|_| ())
+	};
+}
+
+/// creates a symlink at `link` pointing to `target`
+pub fn create_symlink(target: impl AsRef<Utf8Path>, link: impl AsRef<Utf8Path>) -> std::io::Result<()> {
+	#[cfg(unix)]
+	return std::os::unix::fs::symlink(target.as_ref(), link.as_ref());
+
+	// windows needs to know if the target is a file or a dir, which we don't store
+	#[cfg(not(unix))]
+	return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Restoring symlinks is only supported on unix"));
+}
+
+/// checks that blobs can be aligned to `alignment`, whether it came from a cfg or a manifest
+pub(crate) fn check_blob_alignment(alignment: u64) -> anyhow::Result<()> {
+	anyhow::ensure!(
+		alignment.is_power_of_two() && alignment <= MAX_BLOB_ALIGNMENT as u64,
+		"Blob alignment must be a power of two up to {MAX_BLOB_ALIGNMENT}, not {alignment}"
+	);
+	Ok(())
+}
+
+/// how much padding is needed after the first `pos` bytes of a diff to reach a multiple of `alignment`
+pub(crate) fn padding_to(pos: u64, alignment: u64) -> u64 {
+	pos.next_multiple_of(alignment) - pos
+}
+
+/// converts a time to seconds and nanoseconds since the unix epoch, as stored in the manifest
+pub fn to_unix_time(t: SystemTime) -> (i64, u32) {
+	match t.duration_since(UNIX_EPOCH) {
+		Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+		Err(e) => {
+			// before the epoch, the nanoseconds still need to count forwards
+			let d = e.duration();
+			if d.subsec_nanos() == 0 {
+				(-(d.as_secs() as i64), 0)
+			}
+			else {
+				(-(d.as_secs() as i64) - 1, 1_000_000_000 - d.subsec_nanos())
+			}
+		}
 	}
 }
 
@@ -64,9 +500,18 @@ pub fn copy_rl(src_p: impl AsRef<Utf8Path>, dst_p: impl AsRef<Utf8Path>) -> std:
 #[macro_export]
 macro_rules! aggregate_errors {
 	($e:expr) => {{
-		let e = $e;
+		let mut e = $e;
+		// a single error can be passed on as it is, so it can still be downcast
+		if e.len() == 1 {
+			return Err(e.pop().unwrap());
+		}
 		if !e.is_empty() {
-			anyhow::bail!("Failed with multiple errors:\n{}", e.into_iter().map(|e| format!("{e}")).collect::<Vec<_>>().join("\n"));
+			// hash mismatches already say everything there is to know, anything else needs its causes too
+			let describe = |e: anyhow::Error| match e.chain().find_map(|c| c.downcast_ref::<$crate::ApplyError>()) {
+				Some(ae) => ae.to_string(),
+				None => format!("{e:#}"),
+			};
+			anyhow::bail!("Failed with multiple errors:\n{}", e.into_iter().map(describe).collect::<Vec<_>>().join("\n"));
 		}
 	}};
 }
\ No newline at end of file
@@ -0,0 +1,464 @@
+// the hand-written binary format that manifests are stored in from FLDF v1.3.0, instead of messagepack.
+// it is a list of sections, each a u8 type and the u64 length of the rest of it, ended by a lone 0 byte.
+// empty sections are left out, and ones of a type we don't know are skipped, so fields can be added later.
+// inside sections, integers are 8 byte big-endian, and strings are a u64 length followed by that much UTF-8.
+// every section except the info one is a u64 count followed by that many entries.
+
+use std::io::{Read, Write};
+use anyhow::{bail, ensure, Context, Result};
+use crate::common::{check_blob_alignment, HashAlgorithm};
+use crate::manifest::{DiffManifest, DuplicatedFile, FileAttributes, HardLinkedFile, NewFile, PatchedFile, SymlinkEntry};
+
+const SECTION_END: u8 = 0;
+const SECTION_INFO: u8 = 1;
+const SECTION_UNTOUCHED: u8 = 2;
+const SECTION_DELETED: u8 = 3;
+const SECTION_NEW: u8 = 4;
+const SECTION_DUPLICATED: u8 = 5;
+const SECTION_PATCHED: u8 = 6;
+const SECTION_SYMLINKS: u8 = 7;
+const SECTION_HARDLINKS: u8 = 8;
+const SECTION_ATTRIBUTES: u8 = 9;
+const SECTION_EMPTY_DIRS_NEW: u8 = 10;
+const SECTION_EMPTY_DIRS_DELETED: u8 = 11;
+const SECTION_UNCOMPRESSED_SIZES: u8 = 12;
+const SECTION_UNCOMPRESSED_BLOBS: u8 = 13;
+const SECTION_RENAMED_DIRS: u8 = 14;
+
+// set in the info section's flags
+const INFO_HAS_DICTIONARIES: u64 = 1;
+const INFO_HAS_CATALOG: u64 = 2;
+const INFO_DELTA_CHAIN: u64 = 4;
+
+// set in a file attribute's flags for each of its fields that is there
+const ATTR_MTIME: u64 = 1;
+const ATTR_MODE: u64 = 2;
+
+/// builds up the contents of one section
+#[derive(Default)]
+struct SectionWriter(Vec<u8>);
+
+impl SectionWriter {
+	fn u64(&mut self, n: u64) {
+		self.0.extend_from_slice(&n.to_be_bytes());
+	}
+
+	fn str(&mut self, s: &str) {
+		self.u64(s.len() as u64);
+		self.0.extend_from_slice(s.as_bytes());
+	}
+
+	fn strs(&mut self, strs: &[String]) {
+		self.u64(strs.len() as u64);
+		for s in strs {
+			self.str(s);
+		}
+	}
+
+	fn write(self, w: &mut impl Write, section: u8) -> Result<()> {
+		w.write_all(&[section])?;
+		w.write_all(&(self.0.len() as u64).to_be_bytes())?;
+		w.write_all(&self.0)?;
+		Ok(())
+	}
+}
+
+/// writes `items` as a section, unless there are none
+fn write_list<T>(w: &mut impl Write, section: u8, items: &[T], f: impl Fn(&mut SectionWriter, &T)) -> Result<()> {
+	if items.is_empty() {
+		return Ok(());
+	}
+	let mut s = SectionWriter::default();
+	s.u64(items.len() as u64);
+	for item in items {
+		f(&mut s, item);
+	}
+	s.write(w, section)
+}
+
+/// reads values out of one section
+struct SectionReader<'a> {
+	data: &'a [u8],
+	section: u8,
+}
+
+impl<'a> SectionReader<'a> {
+	fn take(&mut self, len: u64) -> Result<&'a [u8]> {
+		ensure!(len <= self.data.len() as u64, "Section {} of the manifest is cut off", self.section);
+		let (head, tail) = self.data.split_at(len as usize);
+		self.data = tail;
+		Ok(head)
+	}
+
+	fn u64(&mut self) -> Result<u64> {
+		Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+	}
+
+	fn str(&mut self) -> Result<String> {
+		let len = self.u64()?;
+		let s = std::str::from_utf8(self.take(len)?).with_context(|| format!("Section {} of the manifest has a string that isn't UTF-8", self.section))?;
+		Ok(s.to_string())
+	}
+
+	fn strs(&mut self) -> Result<Vec<String>> {
+		self.list(Self::str)
+	}
+
+	fn list<T>(&mut self, f: impl Fn(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+		let count = self.u64()?;
+		// every entry is at least 8 bytes, so a corrupt count can't make us allocate more than the section's size
+		let mut items = Vec::with_capacity(count.min(self.data.len() as u64 / 8) as usize);
+		for _ in 0..count {
+			items.push(f(self)?);
+		}
+		Ok(items)
+	}
+}
+
+// compression levels are 1-22, so 0 can mean there isn't one
+fn level_to_u64(level: Option<u8>) -> u64 {
+	level.map_or(0, u64::from)
+}
+
+fn level_from_u64(n: u64) -> Result<Option<u8>> {
+	Ok(if n == 0 { None } else { Some(n.try_into().context("Manifest has an impossible compression level")?) })
+}
+
+impl DiffManifest {
+	/// Writes the manifest in the compact format used from FLDF v1.3.0, uncompressed
+	pub fn write_compact(&self, mut w: impl Write) -> Result<()> {
+		let w = &mut w;
+
+		let mut info = SectionWriter::default();
+		info.u64(
+			if self.has_dictionaries { INFO_HAS_DICTIONARIES } else { 0 }
+			| if self.has_catalog { INFO_HAS_CATALOG } else { 0 }
+			| if self.delta_chain { INFO_DELTA_CHAIN } else { 0 }
+		);
+		info.u64(match self.hash_algorithm {
+			HashAlgorithm::XxHash64 => 0,
+			HashAlgorithm::Xxh3 => 1,
+		});
+		info.u64(self.blob_alignment);
+		info.write(w, SECTION_INFO)?;
+
+		write_list(w, SECTION_UNTOUCHED, &self.untouched_files, |s, (hash, path)| {
+			s.u64(*hash);
+			s.str(path);
+		})?;
+		write_list(w, SECTION_DELETED, &self.deleted_files, |s, (hash, path)| {
+			s.u64(*hash);
+			s.str(path);
+		})?;
+		write_list(w, SECTION_NEW, &self.new_files, |s, nf| {
+			s.u64(nf.hash);
+			s.u64(nf.index);
+			s.str(&nf.path);
+			s.u64(level_to_u64(nf.compression_level));
+		})?;
+		write_list(w, SECTION_DUPLICATED, &self.duplicated_files, |s, d| {
+			s.u64(d.hash);
+			s.u64(d.idx);
+			s.strs(&d.old_paths);
+			s.strs(&d.new_paths);
+		})?;
+		write_list(w, SECTION_PATCHED, &self.patched_files, |s, pf| {
+			s.u64(pf.old_hash);
+			s.u64(pf.new_hash);
+			s.u64(pf.index);
+			s.str(&pf.path);
+			s.u64(level_to_u64(pf.compression_level));
+		})?;
+		write_list(w, SECTION_SYMLINKS, &self.symlinks, |s, sl| {
+			s.str(&sl.path);
+			s.str(&sl.target);
+		})?;
+		write_list(w, SECTION_HARDLINKS, &self.hardlinked_files, |s, hl| {
+			s.str(&hl.path);
+			s.str(&hl.target);
+		})?;
+		write_list(w, SECTION_ATTRIBUTES, &self.file_attributes, |s, fa| {
+			s.str(&fa.path);
+			s.u64(if fa.mtime.is_some() { ATTR_MTIME } else { 0 } | if fa.mode.is_some() { ATTR_MODE } else { 0 });
+			if let Some((secs, nanos)) = fa.mtime {
+				s.u64(secs as u64);
+				s.u64(nanos.into());
+			}
+			if let Some(mode) = fa.mode {
+				s.u64(mode.into());
+			}
+		})?;
+		write_list(w, SECTION_EMPTY_DIRS_NEW, &self.empty_dirs_new, |s, d| s.str(d))?;
+		write_list(w, SECTION_EMPTY_DIRS_DELETED, &self.empty_dirs_deleted, |s, d| s.str(d))?;
+		write_list(w, SECTION_UNCOMPRESSED_SIZES, &self.uncompressed_sizes, |s, (hash, size)| {
+			s.u64(*hash);
+			s.u64(*size);
+		})?;
+		write_list(w, SECTION_UNCOMPRESSED_BLOBS, &self.uncompressed_blobs, |s, i| s.u64(*i))?;
+		write_list(w, SECTION_RENAMED_DIRS, &self.renamed_dirs, |s, (old, new)| {
+			s.str(old);
+			s.str(new);
+		})?;
+
+		w.write_all(&[SECTION_END])?;
+		Ok(())
+	}
+
+	/// Reads a manifest written by `write_compact`
+	pub fn read_compact(mut r: impl Read) -> Result<Self> {
+		let mut manifest = DiffManifest::default();
+		let mut body = Vec::new();
+		loop {
+			let mut section = [0u8];
+			r.read_exact(&mut section).context("Manifest is cut off")?;
+			let section = section[0];
+			if section == SECTION_END {
+				break;
+			}
+
+			let mut len = [0u8; 8];
+			r.read_exact(&mut len).context("Manifest is cut off")?;
+			let len = u64::from_be_bytes(len);
+			body.clear();
+			(&mut r).take(len).read_to_end(&mut body)?;
+			ensure!(body.len() as u64 == len, "Section {section} of the manifest is cut off");
+
+			let mut s = SectionReader { data: &body, section };
+			match section {
+				SECTION_INFO => {
+					let flags = s.u64()?;
+					manifest.has_dictionaries = flags & INFO_HAS_DICTIONARIES != 0;
+					manifest.has_catalog = flags & INFO_HAS_CATALOG != 0;
+					manifest.delta_chain = flags & INFO_DELTA_CHAIN != 0;
+					manifest.hash_algorithm = match s.u64()? {
+						0 => HashAlgorithm::XxHash64,
+						1 => HashAlgorithm::Xxh3,
+						n => bail!("Manifest uses unknown hash algorithm {n}"),
+					};
+					manifest.blob_alignment = s.u64()?;
+					check_blob_alignment(manifest.blob_alignment)?;
+				},
+				SECTION_UNTOUCHED => manifest.untouched_files = s.list(|s| Ok((s.u64()?, s.str()?)))?,
+				SECTION_DELETED => manifest.deleted_files = s.list(|s| Ok((s.u64()?, s.str()?)))?,
+				SECTION_NEW => manifest.new_files = s.list(|s| Ok(NewFile {
+					hash: s.u64()?,
+					index: s.u64()?,
+					path: s.str()?,
+					compression_level: level_from_u64(s.u64()?)?,
+				}))?,
+				SECTION_DUPLICATED => manifest.duplicated_files = s.list(|s| Ok(DuplicatedFile {
+					hash: s.u64()?,
+					idx: s.u64()?,
+					old_paths: s.strs()?,
+					new_paths: s.strs()?,
+				}))?,
+				SECTION_PATCHED => manifest.patched_files = s.list(|s| Ok(PatchedFile {
+					old_hash: s.u64()?,
+					new_hash: s.u64()?,
+					index: s.u64()?,
+					path: s.str()?,
+					compression_level: level_from_u64(s.u64()?)?,
+				}))?,
+				SECTION_SYMLINKS => manifest.symlinks = s.list(|s| Ok(SymlinkEntry { path: s.str()?, target: s.str()? }))?,
+				SECTION_HARDLINKS => manifest.hardlinked_files = s.list(|s| Ok(HardLinkedFile { path: s.str()?, target: s.str()? }))?,
+				SECTION_ATTRIBUTES => manifest.file_attributes = s.list(|s| {
+					let path = s.str()?;
+					let flags = s.u64()?;
+					let mtime = if flags & ATTR_MTIME != 0 {
+						Some((s.u64()? as i64, s.u64()?.try_into().context("Manifest has an impossible modification time")?))
+					}
+					else {
+						None
+					};
+					let mode = if flags & ATTR_MODE != 0 {
+						Some(s.u64()?.try_into().context("Manifest has impossible permissions")?)
+					}
+					else {
+						None
+					};
+					Ok(FileAttributes { path, mtime, mode })
+				})?,
+				SECTION_EMPTY_DIRS_NEW => manifest.empty_dirs_new = s.strs()?,
+				SECTION_EMPTY_DIRS_DELETED => manifest.empty_dirs_deleted = s.strs()?,
+				SECTION_UNCOMPRESSED_SIZES => manifest.uncompressed_sizes = s.list(|s| Ok((s.u64()?, s.u64()?)))?,
+				SECTION_UNCOMPRESSED_BLOBS => manifest.uncompressed_blobs = s.list(SectionReader::u64)?,
+				SECTION_RENAMED_DIRS => manifest.renamed_dirs = s.list(|s| Ok((s.str()?, s.str()?)))?,
+				// from a newer version of foldiff, and nothing we need
+				_ => continue,
+			}
+			ensure!(s.data.is_empty(), "Section {section} of the manifest has unexpected data at the end");
+		}
+		Ok(manifest)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::common::{MAGIC_BYTES, MAX_BLOB_ALIGNMENT, VERSION_NUMBER_1_3_0};
+	use crate::manifest::DiffManifestBuilder;
+	use proptest::collection::vec;
+	use proptest::option;
+	use proptest::prelude::*;
+
+	fn make_manifest() -> DiffManifest {
+		let mut manifest = DiffManifestBuilder::new()
+			.add_untouched(1, "same")
+			.add_deleted(2, "gone")
+			.add_new(3, "new", 0)
+			.add_patched(4, 5, "changed", 0)
+			.add_duplicated(6, vec!["a".into()], vec!["b".into(), "c".into()], u64::MAX)
+			.build();
+		manifest.new_files[0].compression_level = Some(19);
+		manifest.symlinks.push(SymlinkEntry { path: "link".into(), target: "../ünïcödé".into() });
+		manifest.hardlinked_files.push(HardLinkedFile { path: "hard".into(), target: "new".into() });
+		manifest.file_attributes.push(FileAttributes { path: "new".into(), mtime: Some((-5, 999_999_999)), mode: Some(0o755) });
+		manifest.file_attributes.push(FileAttributes { path: "changed".into(), mtime: None, mode: None });
+		manifest.empty_dirs_new.push("empty".into());
+		manifest.empty_dirs_deleted.push("was empty".into());
+		manifest.uncompressed_sizes.push((3, 100));
+		manifest.uncompressed_blobs.push(0);
+		manifest.renamed_dirs.push(("src".into(), "source".into()));
+		manifest.has_catalog = true;
+		manifest.delta_chain = true;
+		manifest.hash_algorithm = HashAlgorithm::XxHash64;
+		manifest
+	}
+
+	#[test]
+	fn test_compact_round_trip() {
+		let manifest = make_manifest();
+		let mut data = Vec::new();
+		manifest.write_compact(&mut data).unwrap();
+		let read = DiffManifest::read_compact(&data[..]).unwrap();
+		// the messagepack encoding covers every field, and all its other users are gone
+		assert_eq!(rmp_serde::to_vec(&read).unwrap(), rmp_serde::to_vec(&manifest).unwrap());
+
+		// sections from the future are skipped
+		let mut future = vec![99];
+		future.extend(3u64.to_be_bytes());
+		future.extend(b"new");
+		future.extend(&data);
+		let read = DiffManifest::read_compact(&future[..]).unwrap();
+		assert_eq!(rmp_serde::to_vec(&read).unwrap(), rmp_serde::to_vec(&manifest).unwrap());
+
+		for len in [0, 1, data.len() / 2, data.len() - 1] {
+			assert!(DiffManifest::read_compact(&data[..len]).is_err());
+		}
+		// a count far bigger than the section can hold
+		let mut huge = Vec::new();
+		DiffManifestBuilder::new().add_untouched(1, "same").build().write_compact(&mut huge).unwrap();
+		let count = huge.iter().position(|&b| b == SECTION_UNTOUCHED).unwrap() + 9;
+		huge[count..count + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+		assert!(DiffManifest::read_compact(&huge[..]).is_err());
+	}
+
+	// a manifest with something in every field. the compact format stores level 0 as None, but it isn't a real level anyway
+	fn any_manifest() -> impl Strategy<Value = DiffManifest> {
+		let s = any::<String>;
+		let level = || option::of(1..=22u8);
+		let files = (
+			vec((any::<u64>(), s()), 1..4),
+			vec((any::<u64>(), s()), 1..4),
+			vec((any::<u64>(), any::<u64>(), s(), level()), 1..4),
+			vec((any::<u64>(), any::<u64>(), vec(s(), 1..3), vec(s(), 1..3)), 1..4),
+			vec((any::<u64>(), any::<u64>(), any::<u64>(), s(), level()), 1..4),
+		);
+		let extras = (
+			vec((s(), s()), 1..4),
+			vec((s(), s()), 1..4),
+			vec((s(), option::of(any::<(i64, u32)>()), option::of(any::<u32>())), 1..4),
+			vec(s(), 1..4),
+			vec(s(), 1..4),
+			vec((s(), s()), 1..4),
+		);
+		let info = (
+			vec(any::<(u64, u64)>(), 1..4),
+			vec(any::<u64>(), 1..4),
+			any::<[bool; 4]>(),
+			0..=MAX_BLOB_ALIGNMENT.ilog2(),
+		);
+
+		(files, extras, info).prop_map(|((untouched, deleted, new, duplicated, patched), (symlinks, hardlinks, attrs, dirs_new, dirs_deleted, renamed), (sizes, blobs, flags, alignment))| {
+			let mut manifest = DiffManifest::default();
+			manifest.untouched_files = untouched;
+			manifest.deleted_files = deleted;
+			manifest.new_files = new.into_iter().map(|(hash, index, path, compression_level)| NewFile { hash, index, path, compression_level }).collect();
+			manifest.duplicated_files = duplicated.into_iter().map(|(hash, idx, old_paths, new_paths)| DuplicatedFile { hash, idx, old_paths, new_paths }).collect();
+			manifest.patched_files = patched.into_iter().map(|(old_hash, new_hash, index, path, compression_level)| PatchedFile { old_hash, new_hash, index, path, compression_level }).collect();
+			manifest.symlinks = symlinks.into_iter().map(|(path, target)| SymlinkEntry { path, target }).collect();
+			manifest.hardlinked_files = hardlinks.into_iter().map(|(path, target)| HardLinkedFile { path, target }).collect();
+			manifest.file_attributes = attrs.into_iter().map(|(path, mtime, mode)| FileAttributes { path, mtime, mode }).collect();
+			manifest.empty_dirs_new = dirs_new;
+			manifest.empty_dirs_deleted = dirs_deleted;
+			manifest.renamed_dirs = renamed;
+			manifest.uncompressed_sizes = sizes;
+			manifest.uncompressed_blobs = blobs;
+			[manifest.has_dictionaries, manifest.has_catalog, manifest.delta_chain] = [flags[0], flags[1], flags[2]];
+			manifest.hash_algorithm = if flags[3] { HashAlgorithm::Xxh3 } else { HashAlgorithm::XxHash64 };
+			manifest.blob_alignment = 1 << alignment;
+			manifest
+		})
+	}
+
+	proptest! {
+		// compressing at level 19 is slow, so fewer cases than usual
+		#![proptest_config(ProptestConfig::with_cases(64))]
+		#[test]
+		fn test_manifest_round_trip(manifest in any_manifest()) {
+			let expected = rmp_serde::to_vec(&manifest).unwrap();
+
+			let mut data = Vec::new();
+			manifest.write_compact(&mut data).unwrap();
+			let read = DiffManifest::read_compact(&data[..]).unwrap();
+			prop_assert_eq!(rmp_serde::to_vec(&read).unwrap(), expected.clone());
+
+			// and compressed behind a header, as it is in a diff
+			let mut diff = MAGIC_BYTES.to_vec();
+			diff.extend(VERSION_NUMBER_1_3_0);
+			let compressed = manifest.to_compressed().unwrap();
+			diff.extend((compressed.len() as u64).to_be_bytes());
+			diff.extend(compressed);
+			let read = DiffManifest::read_from(std::io::Cursor::new(diff)).unwrap();
+			prop_assert_eq!(rmp_serde::to_vec(&read).unwrap(), expected.clone());
+
+			// messagepack, which older diffs use
+			let read: DiffManifest = rmp_serde::from_slice(&expected).unwrap();
+			prop_assert_eq!(rmp_serde::to_vec(&read).unwrap(), expected);
+		}
+	}
+
+	#[test]
+	fn test_compact_manifest_large() {
+		// not really a test, but a benchmark: compare against messagepack on 100k files
+		let mut builder = DiffManifestBuilder::new();
+		for i in 0..100_000u64 {
+			let path = format!("some/fairly/deep/dir{}/file{i}.txt", i % 100);
+			match i % 4 {
+				0 => builder.add_new(i, path, i),
+				1 => builder.add_patched(i, i ^ 1, path, i),
+				_ => builder.add_untouched(i, path),
+			};
+		}
+		let manifest = builder.build();
+
+		let start = std::time::Instant::now();
+		let msgpack = rmp_serde::to_vec(&manifest).unwrap();
+		let msgpack_write = start.elapsed();
+		let start = std::time::Instant::now();
+		rmp_serde::from_slice::<DiffManifest>(&msgpack).unwrap();
+		let msgpack_read = start.elapsed();
+
+		let start = std::time::Instant::now();
+		let mut compact = Vec::new();
+		manifest.write_compact(&mut compact).unwrap();
+		let compact_write = start.elapsed();
+		let start = std::time::Instant::now();
+		DiffManifest::read_compact(&compact[..]).unwrap();
+		let compact_read = start.elapsed();
+
+		let zstd_len = |d: &[u8]| zstd::encode_all(d, 19).unwrap().len();
+		eprintln!("messagepack: {} bytes ({} compressed), write {msgpack_write:?}, read {msgpack_read:?}", msgpack.len(), zstd_len(&msgpack));
+		eprintln!("compact:     {} bytes ({} compressed), write {compact_write:?}, read {compact_read:?}", compact.len(), zstd_len(&compact));
+	}
+}
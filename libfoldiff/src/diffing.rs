@@ -1,18 +1,254 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fs::File;
-use std::io::{copy, Seek, Write};
+use std::io::{copy, Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use camino::{Utf8Path, Utf8PathBuf};
-use anyhow::{anyhow, bail, Context};
-use rmp_serde::Serializer;
-use serde::Serialize;
-use zstd::Encoder;
-use crate::common::{FoldiffCfg, MAGIC_BYTES, VERSION_NUMBER_LATEST};
-use crate::manifest::{DiffManifest, DuplicatedFile, NewFile, PatchedFile};
-use crate::{hash, zstddiff};
+use anyhow::{anyhow, bail, ensure, Context};
+use derivative::Derivative;
+use fs2::FileExt;
+use rayon::prelude::*;
+use tempfile::TempDir;
+use zstd::dict::EncoderDictionary;
+use crate::common::{check_blob_alignment, padding_to, to_unix_time, DiffStrategy, FoldiffCfg, FoldiffError, HashAlgorithm, SymlinkMode, MAGIC_BYTES, VERSION_NUMBER_LATEST};
+use crate::oplog::OpLog;
+use crate::threading::{is_cancelled, CancellationToken};
+use crate::applying::ApplyingDiff;
+use crate::manifest::{find_renamed_dirs, ENCRYPTED_FLAG, DiffManifest, DuplicatedFile, FileAttributes, HardLinkedFile, NewFile, PatchedFile, Source, SymlinkEntry};
+use crate::{hash, memory, zstddiff};
+use crate::catalog::{write_catalog, CatalogEntry};
+use crate::encryption::EncryptionCfg;
+use crate::iocount::{IoAccounting, IoCounters};
+use crate::scancache::{self, ScanCache};
+use crate::zstddiff::CompressionStats;
 use crate::reporting::{AutoSpin, Reporter, ReporterSized};
+use crate::vfs::{StdVfs, VfsMetadata, VfsProvider};
 
-/// An in-memory representation of a diff, used for the diff creation process
+/// mime types of already-compressed formats, which are stored as-is instead of compressed again
+const INCOMPRESSIBLE_TYPES: &[&str] = &[
+	// images
+	"image/jpeg", "image/png", "image/gif", "image/webp", "image/avif", "image/heif",
+	// video & audio
+	"video/mp4", "video/webm", "video/x-matroska", "video/quicktime", "audio/mpeg", "audio/ogg", "audio/x-flac", "audio/aac", "audio/m4a",
+	// archives
+	"application/zip", "application/gzip", "application/x-xz", "application/x-bzip2", "application/zstd",
+	"application/x-7z-compressed", "application/vnd.rar", "application/x-brotli",
+];
+
+/// how to store a file with this inferred mime type
+fn strategy_for(overrides: &HashMap<String, DiffStrategy>, mime: Option<&str>) -> DiffStrategy {
+	if let Some(s) = mime.and_then(|m| overrides.get(m)) {
+		return *s;
+	}
+	match mime {
+		Some(m) if INCOMPRESSIBLE_TYPES.contains(&m) => DiffStrategy::Raw,
+		Some(m) if m.starts_with("text/") => DiffStrategy::TextDelta,
+		_ => DiffStrategy::Zstd,
+	}
+}
+
+/// How well each file compressed, returned by `write_to`
 #[derive(Clone, Debug, Default)]
+pub struct WriteStats {
+	/// size of the compressed manifest
+	pub manifest_bytes: u64,
+	pub new_files: Vec<(Utf8PathBuf, CompressionStats)>,
+	pub patched_files: Vec<(Utf8PathBuf, CompressionStats)>,
+	/// the level picked to fit in `time_budget_secs`, if there was one and anything to compress
+	pub budget_level: Option<u8>,
+	/// how long each stage took
+	pub timings: WriteTimings,
+	/// how much was read from both folders and written to the diff, including while scanning
+	pub io: IoAccounting,
+	/// the most memory in use at once while scanning and writing, with the `memory-tracking` feature on linux or windows.
+	/// on windows this is the peak for the whole process so far
+	pub peak_rss_bytes: Option<u64>,
+	/// where each part of the diff was written
+	pub sections: DiffSections,
+	/// files left out because they only differ in case from another file, see `DiffingDiff::case_collisions`
+	pub case_collisions: Vec<Utf8PathBuf>,
+}
+
+/// Where each part of a written diff starts, in bytes from the start of the diff. Part of `WriteStats`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiffSections {
+	/// the new blob count, after the header and its padding
+	pub new_blobs: u64,
+	/// the patch blob count
+	pub patch_blobs: u64,
+	/// the dictionaries if there are any, otherwise the catalog
+	pub after_blobs: u64,
+	/// the end of the diff
+	pub end: u64,
+}
+
+/// How long each stage of creating a diff took, part of `WriteStats`
+#[derive(Copy, Clone, Debug, Default)]
+pub struct WriteTimings {
+	/// zero if the folders weren't scanned by this DiffingDiff
+	pub scan_old: Duration,
+	/// when pipelining, this includes compressing new files
+	pub scan_new: Duration,
+	pub manifest_gen: Duration,
+	pub compress_new: Duration,
+	pub diff_patch: Duration,
+}
+
+/// How big a diff would be, returned by `estimate_size`
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SizeEstimate {
+	pub manifest_bytes: u64,
+	/// the whole new blob section, including the count and the length of each blob
+	pub new_blob_bytes: u64,
+	/// the whole patch blob section, including the count and every chunk header
+	pub patch_blob_bytes: u64,
+	/// everything, including headers
+	pub total: u64,
+	/// how big the new and patched files are before compressing them
+	pub input_bytes: u64,
+}
+
+/// a writer that throws data away but keeps track of where it is, like a file would
+#[derive(Debug, Default)]
+struct NullSeeker {
+	pos: u64,
+	len: u64,
+}
+
+impl Write for NullSeeker {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.pos += buf.len() as u64;
+		self.len = self.len.max(self.pos);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}
+
+impl Seek for NullSeeker {
+	fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+		let new_pos = match pos {
+			SeekFrom::Start(p) => Some(p),
+			SeekFrom::End(o) => self.len.checked_add_signed(o),
+			SeekFrom::Current(o) => self.pos.checked_add_signed(o),
+		};
+		self.pos = new_pos.ok_or(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seeked to a negative position"))?;
+		Ok(self.pos)
+	}
+}
+
+/// a new file to compress ahead of time: hash, full path, whether to store it as-is
+type SpoolJob = (u64, Utf8PathBuf, bool);
+
+/// how many files, and how much of each, are compressed to time levels when picking one for a time budget
+const BUDGET_SAMPLE_FILES: usize = 5;
+const BUDGET_SAMPLE_BYTES: u64 = 1 << 20;
+
+/// the strongest level that should compress `total` bytes within `budget` seconds, given how many bytes per second
+/// levels 1 and 9 got through. zstd gets slower roughly exponentially as the level goes up, so this interpolates in log space.
+fn level_for_budget(total: u64, speed_1: f64, speed_9: f64, budget: f64) -> u8 {
+	let per_level = (speed_9 / speed_1).ln() / 8.0;
+	(1..=19u8)
+		.rev()
+		.find(|l| total as f64 / (speed_1 * (per_level * (l - 1) as f64).exp()) <= budget)
+		.unwrap_or(1)
+}
+
+/// `cfg.new_dict`, digested by zstd once for each level it is used at, instead of again for every file
+struct PreparedNewDict<'a> {
+	dict: Option<&'a [u8]>,
+	levels: Mutex<HashMap<u8, Arc<EncoderDictionary<'static>>>>,
+}
+
+impl<'a> PreparedNewDict<'a> {
+	fn new(cfg: &'a FoldiffCfg) -> Self {
+		Self { dict: cfg.new_dict.as_deref(), levels: Mutex::default() }
+	}
+
+	fn get(&self, level: u8) -> Option<Arc<EncoderDictionary<'static>>> {
+		let dict = self.dict?;
+		let mut levels = self.levels.lock().unwrap();
+		Some(levels.entry(level).or_insert_with(|| Arc::new(EncoderDictionary::copy(dict, level as i32))).clone())
+	}
+}
+
+/// compresses a new file into a blob, without the length prefix
+fn compress_new_blob(src: &mut impl Read, len: u64, dest: &mut impl Write, raw: bool, level: u8, cfg: &FoldiffCfg, dict: &PreparedNewDict) -> anyhow::Result<()> {
+	if raw {
+		copy(src, dest)?;
+		return Ok(());
+	}
+
+	let dict = dict.get(level);
+	let mut enc =
+		if let Some(dict) = &dict {
+			zstd::Encoder::with_prepared_dictionary(dest, dict)?
+		}
+		else {
+			zstd::Encoder::new(dest, level as i32)?
+		};
+	enc.set_pledged_src_size(Some(len))?;
+	enc.include_checksum(false)?;
+	enc.include_contentsize(false)?;
+	enc.multithread(cfg.threads as u32)?;
+
+	copy(src, &mut enc)?;
+	enc.finish()?;
+	Ok(())
+}
+
+/// writes the magic bytes, version, and manifest, encrypting the manifest if there is a key,
+/// then pads up to the manifest's blob alignment. returns how big the compressed manifest is.
+fn write_header(writer: &mut impl Write, manifest: &DiffManifest, key: Option<&EncryptionCfg>) -> anyhow::Result<u64> {
+	writer.write_all(&MAGIC_BYTES)?;
+
+	// write version number, includes null byte
+	writer.write_all(&VERSION_NUMBER_LATEST)?;
+
+	let blob_alignment = manifest.blob_alignment;
+	let compressed = manifest.to_compressed()?;
+	let (manifest, flag) = match key {
+		Some(key) => (key.seal(&compressed).context("Failed to encrypt manifest")?, ENCRYPTED_FLAG),
+		None => (compressed, 0),
+	};
+	writer.write_all(&(manifest.len() as u64 | flag).to_be_bytes())?;
+	writer.write_all(&manifest)?;
+
+	// magic, version, and length
+	let padding = padding_to(16 + manifest.len() as u64, blob_alignment);
+	writer.write_all(&vec![0u8; padding as usize])?;
+	Ok(manifest.len() as u64)
+}
+
+/// diffs `old` to `new` into `dest` as a patch blob, however `strategy` says to
+#[allow(clippy::too_many_arguments)]
+fn diff_patch_blob(strategy: DiffStrategy, old: &mut (impl Read + Seek), new: &mut (impl Read + Seek), dest: &mut (impl Write + Seek), level: u8, ol: u64, nl: u64, dict: Option<&[u8]>, cfg: &FoldiffCfg) -> anyhow::Result<CompressionStats> {
+	let diff_fn = match strategy {
+		DiffStrategy::TextDelta => zstddiff::diff_text_with_dict,
+		_ if cfg.cdc_chunking => zstddiff::diff_cdc_with_dict,
+		_ => zstddiff::diff_with_dict,
+	};
+	diff_fn(old, new, dest, Some(level), Some(cfg.threads), Some(ol), Some(nl), dict, &cfg.diff_tuning())
+}
+
+/// reads the last `CHAIN_WINDOW` bytes of `f`, which is `len` long, to give to the next patch in a delta chain
+fn read_chain_tail(f: &mut (impl Read + Seek), len: u64) -> anyhow::Result<Vec<u8>> {
+	f.seek(SeekFrom::Start(len.saturating_sub(zstddiff::CHAIN_WINDOW as u64)))?;
+	let mut tail = Vec::new();
+	f.read_to_end(&mut tail)?;
+	Ok(tail)
+}
+
+/// Decides if a file should be left out of a diff, given its path relative to the folder being scanned
+pub type ExcludeFn = Box<dyn Fn(&Utf8Path, &VfsMetadata) -> bool + Send + Sync>;
+
+/// An in-memory representation of a diff, used for the diff creation process
+#[derive(Clone, Derivative)]
+#[derivative(Debug, Default)]
 pub struct DiffingDiff {
 	blobs_new: Vec<Utf8PathBuf>,
 	blobs_patch: Vec<Utf8PathBuf>,
@@ -20,8 +256,54 @@ pub struct DiffingDiff {
 	new_root: Utf8PathBuf,
 	files: BTreeMap<u64, DiffingFileData>,
 	// for efficient lookups, must be kept in sync
+	// keys are passed through path_key() first
 	file_paths_old: BTreeMap<Utf8PathBuf, u64>,
 	file_paths_new: BTreeMap<Utf8PathBuf, u64>,
+	symlinks: SymlinkMode,
+	case_insensitive: bool,
+	// full paths of files left out because another file's path only differs in case
+	case_collisions: Vec<Utf8PathBuf>,
+	strict_scan: bool,
+	// reuse hashes from the .foldiff-cache in each folder, and save new ones there
+	use_scan_cache: bool,
+	// mime type -> how to store files of that type, instead of the default
+	strategy_overrides: HashMap<String, DiffStrategy>,
+	// path -> target, only tracked for the new side when preserving
+	symlinks_new: BTreeMap<Utf8PathBuf, Utf8PathBuf>,
+	// (device, inode) -> first path seen with it, for new files with multiple links
+	inodes_new: HashMap<(u64, u64), Utf8PathBuf>,
+	// path -> the path it is a hard link to
+	hardlinks_new: BTreeMap<Utf8PathBuf, Utf8PathBuf>,
+	attributes_new: BTreeMap<Utf8PathBuf, ScannedAttributes>,
+	empty_dirs_old: BTreeSet<Utf8PathBuf>,
+	empty_dirs_new: BTreeSet<Utf8PathBuf>,
+	// every directory in the new folder, empty or not
+	dirs_new: BTreeSet<Utf8PathBuf>,
+	// compression levels to use instead of the ones in the cfg, keys are passed through path_key()
+	file_levels: HashMap<Utf8PathBuf, u8>,
+	// when pipelining, new files are sent here as soon as they're found to be compressed early
+	spool_tx: Option<Sender<SpoolJob>>,
+	// file that early-compressed new files were written to, and hash -> (offset, length) in it
+	spool_path: Option<Utf8PathBuf>,
+	spooled_new: HashMap<u64, (u64, u64)>,
+	// files it returns true for are skipped while scanning
+	#[derivative(Debug = "ignore")]
+	exclude_fn: Option<Arc<ExcludeFn>>,
+	// filled in as each stage finishes, and returned from write_to
+	timings: WriteTimings,
+	// set once scanning has reset the peak memory count, so write_to doesn't throw away what scanning used
+	peak_rss_reset: bool,
+	// shared with the thread compressing new files when pipelining
+	io: Arc<IoCounters>,
+	// where scan_to_diff_from_tar extracted to. kept until we're dropped, as write_to reads from them
+	extracted: Vec<Arc<TempDir>>,
+}
+
+/// file attributes of a new file, gathered while scanning
+#[derive(Clone, Debug, Default)]
+struct ScannedAttributes {
+	mtime: Option<SystemTime>,
+	mode: Option<u32>,
 }
 
 /// the looked up value of DiffingDiff::files entries
@@ -30,104 +312,436 @@ struct DiffingFileData {
 	paths_old: Vec<Utf8PathBuf>,
 	paths_new: Vec<Utf8PathBuf>,
 	inferred_mime: Option<&'static str>,
+	size: u64,
 }
 
 
 impl DiffingDiff {
-	pub fn new(old_root: Utf8PathBuf, new_root: Utf8PathBuf) -> Self {
+	pub fn new(old_root: Utf8PathBuf, new_root: Utf8PathBuf, cfg: &FoldiffCfg) -> Self {
 		Self {
 			old_root,
 			new_root,
+			symlinks: cfg.symlinks,
+			// windows' filesystems are case-insensitive by default
+			case_insensitive: cfg.case_insensitive_paths || cfg!(windows),
+			strict_scan: cfg.strict_scan,
+			use_scan_cache: cfg.use_scan_cache,
+			strategy_overrides: cfg.strategy_overrides.clone(),
 			..Default::default()
 		}
 	}
 
+	/// normalizes a path for use as a key in file_paths_old and file_paths_new
+	fn path_key(&self, path: &Utf8Path) -> Utf8PathBuf {
+		if self.case_insensitive {
+			path.as_str().to_lowercase().into()
+		}
+		else {
+			path.to_path_buf()
+		}
+	}
+
+	/// Files that were left out of the diff because their path only differs in case from another file's,
+	/// with the folder they were in. Always empty unless paths are case-insensitive.
+	pub fn case_collisions(&self) -> &[Utf8PathBuf] {
+		&self.case_collisions
+	}
+
+	/// Leaves out every file in either folder that `f` returns true for, as if it didn't exist.
+	/// It gets the path relative to the folder, and the file's metadata, after following symlinks if they are followed.
+	/// Must be set before scanning, so use `new` and `scan` instead of `scan_to_diff`.
+	pub fn set_exclude_fn(&mut self, f: ExcludeFn) {
+		self.exclude_fn = Some(Arc::new(f));
+	}
+
+	/// Scans the old and new folders. Only call this once.
+	pub fn scan<TSpin: Reporter+Sync>(&mut self, vfs: &dyn VfsProvider) -> anyhow::Result<()> {
+		memory::reset_peak_rss();
+		self.peak_rss_reset = true;
+
+		let spn = TSpin::new("Scanning old files");
+		let aspn = AutoSpin::spin(&spn);
+		self.scan_internal(Utf8Path::new(""), false, vfs, &spn)?;
+		self.timings.scan_old = aspn.all_good();
+
+		let spn = TSpin::new("Scanning new files");
+		let aspn = AutoSpin::spin(&spn);
+		self.scan_internal(Utf8Path::new(""), true, vfs, &spn)?;
+		self.timings.scan_new = aspn.all_good();
+		Ok(())
+	}
+
+	/// overrides the compression level for one file in the new folder, which must already have been scanned.
+	/// this is `level_new` for new files and `level_diff` for patched files, and does nothing to files that are neither.
+	pub fn set_file_level(&mut self, path: &Utf8Path, level: u8) -> anyhow::Result<()> {
+		ensure!((1..=22).contains(&level), "Compression level must be between 1 and 22, not {level}");
+
+		let key = self.path_key(path);
+		ensure!(self.file_paths_new.contains_key(&key), "{path} is not a file in the new folder");
+		self.file_levels.insert(key, level);
+		Ok(())
+	}
+
+	/// re-hashes every file that is about to be compressed or diffed, as one that changed since it was scanned would
+	/// make a diff that can't be applied. new files that were already compressed while scanning are left out,
+	/// as they were checked while they were compressed
+	fn check_unchanged<TSpin: Reporter+Sync>(&self, levels_new: &HashMap<u64, u8>) -> anyhow::Result<()> {
+		let spooled = |i: usize, p: &Utf8Path| {
+			self.spool_path.is_some() && !levels_new.contains_key(&(i as u64)) && self.spooled_new.contains_key(&self.file_paths_new[&self.path_key(p)])
+		};
+		let to_check: Vec<_> = self.blobs_new.iter()
+			.enumerate()
+			.filter(|(i, p)| !spooled(*i, p))
+			.map(|(_, p)| (self.new_root.join(p), self.file_paths_new[&self.path_key(p)]))
+			.chain(self.blobs_patch.iter().flat_map(|p| [
+				(self.old_root.join(p), self.file_paths_old[&self.path_key(p)]),
+				(self.new_root.join(p), self.file_paths_new[&self.path_key(p)]),
+			]))
+			.collect();
+
+		let spn = TSpin::new("Checking files haven't changed");
+		let aspn = AutoSpin::spin(&spn);
+		let mut changed: Vec<_> = to_check
+			.par_iter()
+			.filter_map(|(path, expected)| {
+				spn.incr(1);
+				let hash = File::open(path).and_then(|f| hash::hash_stream(&mut self.io.reader(f)));
+				match hash {
+					Ok(h) if h == *expected => None,
+					Ok(_) => Some(format!("{path:?} was changed")),
+					Err(e) => Some(format!("{path:?} couldn't be read: {e}")),
+				}
+			})
+			.collect();
+		aspn.all_good();
+
+		if !changed.is_empty() {
+			changed.sort();
+			let count = changed.len();
+			changed.truncate(10);
+			let more = if count > 10 { format!("\nand {} more", count - 10) } else { String::new() };
+			bail!("{count} files changed after they were scanned, scan them again to make a diff:\n{}{more}", changed.join("\n"));
+		}
+		Ok(())
+	}
+
 	/// handles finalising an in-memory diffing state to disk
-	/// takes mut as it also has to set blobs_new and blobs_patch
-	pub fn write_to<TBar: ReporterSized, TSpin: Reporter+Sync>(&mut self, writer: &mut (impl Write + Seek), cfg: &FoldiffCfg) -> anyhow::Result<()> {
-		writer.write_all(&MAGIC_BYTES)?;
-
-		// write version number, includes null byte
-		writer.write_all(&VERSION_NUMBER_LATEST)?;
-		// leave space for length
-		writer.write_all(&[0u8; 8])?;
-
-		let mut wr = countio::Counter::new(&mut *writer);
-		let mut serializer = Serializer::new(Encoder::new(&mut wr, 19)?.auto_finish());
-		self
-			.generate_manifest::<TSpin>()?
-			.serialize(&mut serializer)
-			.context("Failed to serialize diff format into file")?;
-
-		drop(serializer); // load bearing drop
-		let comp_size = wr.writer_bytes();
-		// write manifest size
-		writer.seek_relative(-(comp_size as i64) - 8)?;
-		writer.write_all(&comp_size.to_be_bytes())?;
-		writer.seek_relative(comp_size as i64)?;
+	/// takes mut as it also has to set blobs_new and blobs_patch.
+	/// if `cancel` is cancelled, stops before the next file and returns FoldiffError::Cancelled.
+	pub fn write_to<TBar: ReporterSized, TSpin: Reporter+Sync>(&mut self, writer: &mut (impl Write + Seek), cfg: &FoldiffCfg, cancel: Option<&CancellationToken>) -> anyhow::Result<WriteStats> {
+		if !self.peak_rss_reset {
+			memory::reset_peak_rss();
+		}
+		let mut stats = WriteStats { timings: self.timings, case_collisions: self.case_collisions.clone(), ..Default::default() };
+		let io = self.io.clone();
+		let writer = &mut io.writer(writer);
+		// catalog offsets are from the start of the diff, wherever that is in `writer`
+		let base = writer.stream_position()?;
+
+		let start = Instant::now();
+		let mut manifest = self.generate_manifest::<TSpin>()?;
+		if cfg.delta_chain {
+			self.order_patches_for_chain(&mut manifest);
+		}
+		manifest.delta_chain = cfg.delta_chain;
+		stats.timings.manifest_gen = start.elapsed();
+		manifest.has_dictionaries = cfg.new_dict.is_some() || cfg.diff_dict.is_some();
+		// the catalog would give away which files are in the diff
+		manifest.has_catalog = cfg.encryption.is_none();
+		check_blob_alignment(cfg.blob_alignment as u64)?;
+		manifest.blob_alignment = cfg.blob_alignment as u64;
+		cfg.diff_tuning().check()?;
+		let uncompressed_blobs: HashSet<_> = manifest.uncompressed_blobs.iter().copied().collect();
+
+		// the level can only be picked once we know everything there is to compress
+		let budget_cfg;
+		let cfg =
+			if let Some(budget) = cfg.time_budget_secs {
+				stats.budget_level = self.pick_budget_level::<TSpin>(budget, &uncompressed_blobs, cfg)?;
+				let level = stats.budget_level.unwrap_or(cfg.level_new);
+				budget_cfg = FoldiffCfg { level_new: level, level_diff: level, ..cfg.clone() };
+				&budget_cfg
+			}
+			else {
+				cfg
+			};
+		// blob index -> level, for files with their own level
+		let levels_new: HashMap<_, _> = manifest.new_files.iter().filter_map(|nf| Some((nf.index, nf.compression_level?))).collect();
+		let levels_patch: HashMap<_, _> = manifest.patched_files.iter().filter_map(|pf| Some((pf.index, pf.compression_level?))).collect();
+
+		// before writing anything, so a stale scan doesn't leave behind half a diff
+		self.check_unchanged::<TSpin>(&levels_new)?;
+
+		let log = OpLog::open(cfg)?;
+		let new_dict = PreparedNewDict::new(cfg);
+
+		stats.manifest_bytes = write_header(&mut *writer, &manifest, cfg.encryption.as_ref())?;
 
 		// write new files
+		stats.sections.new_blobs = writer.stream_position()? - base;
 		writer.write_all(&(self.blobs_new.len() as u64).to_be_bytes())?;
+		let mut spool = self.spool_path.as_ref().map(File::open).transpose().context("Failed to open spooled new files")?;
 
+		let start = Instant::now();
+		let mut offsets_new = Vec::with_capacity(self.blobs_new.len());
 		if !self.blobs_new.is_empty() {
 			let bar = <TBar as ReporterSized>::new("Compressing new files", self.blobs_new.len());
-			for path in &self.blobs_new {
-				let mut f =
-					File::open(self.new_root.join(path)).context("Failed to open file while copying newly added files")?;
+			for (i, path) in self.blobs_new.iter().enumerate() {
+				ensure!(!is_cancelled(cancel), FoldiffError::Cancelled);
+				let hash = self.file_paths_new[&self.path_key(path)];
+				offsets_new.push(writer.stream_position()? - base);
+				let res = (|| -> anyhow::Result<()> {
+					let start = Instant::now();
+					let f =
+						File::open(self.new_root.join(path)).context("Failed to open file while copying newly added files")?;
+					let len = f.metadata()?.len();
+					let mut f = io.reader(f);
 
-				//writer.write_all(&len.to_be_bytes())?;
-				writer.seek_relative(8)?; // space for len
+					//writer.write_all(&len.to_be_bytes())?;
+					writer.seek_relative(8)?; // space for len
 
-				let mut count = countio::Counter::new(&mut *writer);
-				let mut enc = zstd::Encoder::new(&mut count, cfg.level_new as i32)?;
-				enc.set_pledged_src_size(Some(f.metadata()?.len()))?;
-				enc.include_checksum(false)?;
-				enc.include_contentsize(false)?;
-				enc.multithread(cfg.threads as u32)?;
+					// encrypted blobs have to be sealed all at once, so are built up in memory first
+					let mut plain = Vec::new();
+					let mut count = countio::Counter::new(if cfg.encryption.is_some() { &mut plain as &mut dyn Write } else { &mut *writer });
+					let level = levels_new.get(&(i as u64)).copied();
+					match (self.spooled_new.get(&hash), &mut spool) {
+						// already compressed while scanning, unless it was meant to have a different level
+						(Some(&(offset, spooled_len)), Some(spool)) if level.is_none() => {
+							spool.seek(SeekFrom::Start(offset))?;
+							copy(&mut io.reader(spool.take(spooled_len)), &mut count)?;
+						},
+						_ => {
+							let raw = uncompressed_blobs.contains(&(i as u64));
+							compress_new_blob(&mut f, len, &mut count, raw, level.unwrap_or(cfg.level_new), cfg, &new_dict)?;
+						},
+					}
 
-				copy(&mut f, &mut enc)?;
-				enc.finish()?;
+					let mut bytes = count.writer_bytes() as u64;
+					if let Some(key) = &cfg.encryption {
+						let sealed = key.seal(&plain)?;
+						writer.write_all(&sealed)?;
+						bytes = sealed.len() as u64;
+					}
 
-				// write length
-				let bytes = count.writer_bytes() as u64;
-				writer.seek_relative(-(bytes as i64) - 8)?;
-				writer.write_all(&bytes.to_be_bytes())?;
-				writer.seek_relative(bytes as i64)?;
+					// write length
+					writer.seek_relative(-(bytes as i64) - 8)?;
+					writer.write_all(&bytes.to_be_bytes())?;
+					writer.seek_relative(bytes as i64)?;
 
+					stats.new_files.push((path.clone(), CompressionStats::new(1, len, bytes + 8, start.elapsed())));
+					Ok(())
+				})();
+				log.record("compress", path.as_str(), Some(hash), res.as_ref().err());
+				res?;
 				bar.incr(1);
 			}
 			bar.done();
 		}
 
+		stats.timings.compress_new = start.elapsed();
+
 		// write patches
+		let start = Instant::now();
+		stats.sections.patch_blobs = writer.stream_position()? - base;
 		writer.write_all(&(self.blobs_patch.len() as u64).to_be_bytes())?;
 		//writer.write_all(&0u64.to_be_bytes())?;
 
 		// perform diffing
+		let mut offsets_patch = Vec::with_capacity(self.blobs_patch.len());
+		// the end of the last file diffed, when delta chaining
+		let mut chain_tail = Vec::new();
 		if !self.blobs_patch.is_empty() {
 			let bar = <TBar as ReporterSized>::new("Diffing changed files", self.blobs_patch.len());
-			for p in &self.blobs_patch {
-				let mut old = File::open(self.old_root.join(p)).context("Failed to open old file for diffing")?;
-				let mut new = File::open(self.new_root.join(p)).context("Failed to open new file for diffing")?;
+			for (i, p) in self.blobs_patch.iter().enumerate() {
+				ensure!(!is_cancelled(cancel), FoldiffError::Cancelled);
+				let hash = self.file_paths_new[&self.path_key(p)];
+				offsets_patch.push(writer.stream_position()? - base);
+				let res = (|| -> anyhow::Result<()> {
+					let old = File::open(self.old_root.join(p)).context("Failed to open old file for diffing")?;
+					let new = File::open(self.new_root.join(p)).context("Failed to open new file for diffing")?;
 
-				let ol = old.metadata()?.len();
-				let nl = new.metadata()?.len();
+					let ol = old.metadata()?.len();
+					let nl = new.metadata()?.len();
+					let (mut old, mut new) = (io.reader(old), io.reader(new));
 
-				zstddiff::diff(&mut old, &mut new, &mut *writer, Some(cfg.level_diff), Some(cfg.threads), Some(ol), Some(nl))
-					.context("Failed to perform diff")?;
+					let level = levels_patch.get(&(i as u64)).copied().unwrap_or(cfg.level_diff);
+					let strategy = strategy_for(&self.strategy_overrides, self.files[&hash].inferred_mime);
+					let chained = cfg.delta_chain.then(|| zstddiff::chain_dict(&chain_tail, cfg.diff_dict.as_deref()));
+					let dict = chained.as_deref().or(cfg.diff_dict.as_deref());
+					let s = match &cfg.encryption {
+						None => diff_patch_blob(strategy, &mut old, &mut new, &mut *writer, level, ol, nl, dict, cfg).context("Failed to perform diff")?,
+						Some(key) => {
+							let mut plain = Cursor::new(Vec::new());
+							let s = diff_patch_blob(strategy, &mut old, &mut new, &mut plain, level, ol, nl, dict, cfg).context("Failed to perform diff")?;
+							let sealed = key.seal_patch_blob(plain.get_ref())?;
+							writer.write_all(&sealed)?;
+							CompressionStats::new(s.chunks, s.input_bytes, sealed.len() as u64, s.elapsed)
+						},
+					};
+					if cfg.delta_chain {
+						chain_tail = read_chain_tail(&mut new, nl)?;
+					}
+					stats.patched_files.push((p.clone(), s));
+					Ok(())
+				})();
+				log.record("diff", p.as_str(), Some(hash), res.as_ref().err());
+				res?;
 				bar.incr(1);
 			}
 			bar.done();
 		}
 
-		Ok(())
+		stats.timings.diff_patch = start.elapsed();
+		stats.sections.after_blobs = writer.stream_position()? - base;
+
+		// write dictionaries, an empty one means there isn't one
+		let mut new_dict_pos = None;
+		if manifest.has_dictionaries {
+			for dict in [&cfg.new_dict, &cfg.diff_dict] {
+				let dict = match (dict, &cfg.encryption) {
+					(Some(dict), Some(key)) => key.seal(dict)?,
+					(dict, _) => dict.clone().unwrap_or_default(),
+				};
+				writer.write_all(&(dict.len() as u64).to_be_bytes())?;
+				new_dict_pos.get_or_insert((writer.stream_position()? - base, dict.len() as u64));
+				writer.write_all(&dict)?;
+			}
+		}
+
+		// so single files can be found without reading everything before them
+		if manifest.has_catalog {
+			let catalog = manifest.new_files.iter()
+				.map(|nf| CatalogEntry::new_blob(&nf.path, offsets_new[nf.index as usize], uncompressed_blobs.contains(&nf.index)))
+				// every copy of a duplicated new file points at the same blob
+				.chain(manifest.duplicated_files.iter().filter(|df| df.idx != u64::MAX).flat_map(|df| {
+					df.new_paths.iter().map(|p| CatalogEntry::new_blob(p, offsets_new[df.idx as usize], uncompressed_blobs.contains(&df.idx)))
+				}))
+				.chain(manifest.patched_files.iter().map(|pf| CatalogEntry::patch_blob(&pf.path, offsets_patch[pf.index as usize])))
+				.collect();
+			let catalog_start = writer.stream_position()? - base;
+			write_catalog(&mut *writer, catalog_start, catalog, new_dict_pos.filter(|(_, l)| *l != 0))?;
+		}
+
+		stats.sections.end = writer.stream_position()? - base;
+		stats.io = io.totals();
+		stats.peak_rss_bytes = memory::peak_rss_bytes();
+		Ok(stats)
+	}
+
+	/// Works out how big the diff would be, by going through all of `write_to` without keeping the output.
+	/// This does all the same compression work, so takes just as long as actually writing the diff.
+	pub fn estimate_size<TBar: ReporterSized, TSpin: Reporter+Sync>(&mut self, cfg: &FoldiffCfg) -> anyhow::Result<SizeEstimate> {
+		let mut sink = NullSeeker::default();
+		// nothing is really written, so there is nothing to log
+		let cfg = FoldiffCfg { log_path: None, ..cfg.clone() };
+		let stats = self.write_to::<TBar, TSpin>(&mut sink, &cfg, None)?;
+
+		let sections = stats.sections;
+		Ok(SizeEstimate {
+			manifest_bytes: stats.manifest_bytes,
+			new_blob_bytes: sections.patch_blobs - sections.new_blobs,
+			patch_blob_bytes: sections.after_blobs - sections.patch_blobs,
+			total: sink.len,
+			input_bytes: stats.new_files.iter().chain(&stats.patched_files).map(|(_, s)| s.input_bytes).sum(),
+		})
 	}
 
-	pub fn write_to_file<TBar: ReporterSized, TSpin: Reporter+Sync>(&mut self, path: &Utf8Path, cfg: &FoldiffCfg) -> anyhow::Result<()> {
+	pub fn write_to_file<TBar: ReporterSized, TSpin: Reporter+Sync>(&mut self, path: &Utf8Path, cfg: &FoldiffCfg) -> anyhow::Result<WriteStats> {
 		// create file
 		let mut f = File::create_new(path).context("Failed to create file to save diff")?;
 
-		self.write_to::<TBar, TSpin>(&mut f, cfg)
+		// reserve roughly the right amount of space up front, so the filesystem can keep the file in one piece.
+		// this is only a guess, and not every filesystem can do it, so carry on without it if it fails
+		let _ = f.allocate(self.guess_size());
+
+		let stats = self.write_to::<TBar, TSpin>(&mut f, cfg, None)?;
+
+		// then cut off whatever we didn't need
+		let end = f.stream_position()?;
+		f.set_len(end).context("Failed to truncate diff file")?;
+		Ok(stats)
+	}
+
+	/// picks a level for `write_to` to fit in `budget` seconds, by timing levels 1 and 9 on the start of the first few blobs.
+	/// returns None if there is nothing to compress.
+	fn pick_budget_level<TSpin: Reporter+Sync>(&self, budget: f64, raw_blobs: &HashSet<u64>, cfg: &FoldiffCfg) -> anyhow::Result<Option<u8>> {
+		let start = Instant::now();
+		let spn = TSpin::new("Picking a compression level");
+		let spn = AutoSpin::spin(&spn);
+
+		// raw blobs are just copied, so take next to no time
+		let to_compress: Vec<_> = self.blobs_new.iter().enumerate()
+			.filter(|(i, _)| !raw_blobs.contains(&(*i as u64)))
+			.map(|(_, p)| p)
+			.chain(&self.blobs_patch)
+			.collect();
+		let total: u64 = to_compress.iter().map(|p| self.files[&self.file_paths_new[&self.path_key(p)]].size).sum();
+
+		let mut samples = Vec::new();
+		for p in to_compress.iter().take(BUDGET_SAMPLE_FILES) {
+			let f = File::open(self.new_root.join(p)).context("Failed to open file to time compressing it")?;
+			let mut buf = Vec::new();
+			self.io.reader(f).take(BUDGET_SAMPLE_BYTES).read_to_end(&mut buf)?;
+			samples.push(buf);
+		}
+		let sample_bytes: u64 = samples.iter().map(|s| s.len() as u64).sum();
+		if sample_bytes == 0 {
+			spn.all_good();
+			return Ok(None);
+		}
+
+		// bytes per second
+		let new_dict = PreparedNewDict::new(cfg);
+		let speed = |level| -> anyhow::Result<f64> {
+			let start = Instant::now();
+			for s in &samples {
+				compress_new_blob(&mut &s[..], s.len() as u64, &mut std::io::sink(), false, level, cfg, &new_dict)?;
+			}
+			Ok(sample_bytes as f64 / start.elapsed().as_secs_f64().max(1e-9))
+		};
+		let (speed_1, speed_9) = (speed(1)?, speed(9)?);
+
+		spn.all_good();
+		Ok(Some(level_for_budget(total, speed_1, speed_9, budget - start.elapsed().as_secs_f64())))
+	}
+
+	/// Writes the diff into memory instead of a file, and returns all of it.
+	/// The folders are still read from disk.
+	pub fn write_to_bytes<TBar: ReporterSized, TSpin: Reporter+Sync>(&mut self, cfg: &FoldiffCfg) -> anyhow::Result<Vec<u8>> {
+		let mut cursor = Cursor::new(Vec::new());
+		self.write_to::<TBar, TSpin>(&mut cursor, cfg, None)?;
+		Ok(cursor.into_inner())
+	}
+
+	/// a rough guess at how big the diff will be, without compressing anything
+	fn guess_size(&self) -> u64 {
+		let mut new_bytes = 0;
+		let mut patch_bytes = 0;
+		for (key, hash) in &self.file_paths_new {
+			let Some(data) = self.files.get(hash) else { continue };
+			if !data.paths_old.is_empty() {
+				// copied from the old folder, so nothing to store
+				continue;
+			}
+			if self.file_paths_old.contains_key(key) {
+				patch_bytes += data.size;
+			}
+			else {
+				new_bytes += data.size;
+			}
+		}
+
+		(new_bytes as f64 * 0.7 + patch_bytes as f64 * 0.05) as u64
+	}
+
+	/// puts the patches of files with the same name next to each other, as they are the most likely to be alike,
+	/// so each one is good context for the next in a delta chain
+	fn order_patches_for_chain(&mut self, manifest: &mut DiffManifest) {
+		manifest.patched_files.sort_by_cached_key(|pf| pf.path.rsplit('/').map(str::to_string).collect::<Vec<_>>());
+		self.blobs_patch = manifest.patched_files.iter().map(|pf| self.blobs_patch[pf.index as usize].clone()).collect();
+		for (i, pf) in manifest.patched_files.iter_mut().enumerate() {
+			pf.index = i as u64;
+		}
 	}
 
 	/// generates the on-disk manifest format from the in-memory working data
@@ -138,6 +752,10 @@ impl DiffingDiff {
 		// this function will figure out which files fall into which category,
 		// and figure out what blobs must be generated by write_to, and generate the manifest.
 
+		// we may be called more than once, e.g. when estimating the size before writing
+		self.blobs_new.clear();
+		self.blobs_patch.clear();
+
 		// convenience func
 		let path_to_string = |p: &Utf8PathBuf| -> anyhow::Result<String> {
 			Ok(if cfg!(windows) {
@@ -156,6 +774,8 @@ impl DiffingDiff {
 		let spn = AutoSpin::spin(&spn);
 
 		for (hash, entry) in &self.files {
+			manifest.uncompressed_sizes.push((*hash, entry.size));
+
 			// step 1: are we unchanged?
 			if entry.paths_old.len() == 1 && entry.paths_new.len() == 1 && entry.paths_new[0] == entry.paths_old[0] {
 				manifest.untouched_files.push((*hash, path_to_string(&entry.paths_old[0])?));
@@ -179,10 +799,14 @@ impl DiffingDiff {
 				}
 
 				// are we *also* a new file?
+				// if so, all the copies share the one blob
 				let idx =
 					if entry.paths_old.is_empty() {
-						let i = self.blobs_patch.len() as u64;
-						self.blobs_patch.push(entry.paths_new[0].clone());
+						let i = self.blobs_new.len() as u64;
+						if strategy_for(&self.strategy_overrides, entry.inferred_mime) == DiffStrategy::Raw {
+							manifest.uncompressed_blobs.push(i);
+						}
+						self.blobs_new.push(entry.paths_new[0].clone());
 						i
 					}
 					else {
@@ -203,21 +827,29 @@ impl DiffingDiff {
 				debug_assert_eq!(entry.paths_new.len(), 1);
 				// do we need to diff?
 				let path = &entry.paths_new[0];
-				if let Some(old_hash) = self.file_paths_old.get(path) {
+				let strategy = strategy_for(&self.strategy_overrides, entry.inferred_mime);
+				// raw files are stored whole instead, as a diff against the old version would rarely save anything
+				if let Some(old_hash) = self.file_paths_old.get(&self.path_key(path)).filter(|_| strategy != DiffStrategy::Raw) {
 					manifest.patched_files.push(PatchedFile {
 						old_hash: *old_hash,
 						new_hash: *hash,
 						path: path_to_string(path)?,
-						index: self.blobs_patch.len() as u64
+						index: self.blobs_patch.len() as u64,
+						compression_level: self.file_levels.get(&self.path_key(path)).copied(),
 					});
 					self.blobs_patch.push(path.clone());
 				}
 				else {
 					// okay, we *are* a new file
+					// no point spending time compressing something that won't get any smaller
+					if strategy == DiffStrategy::Raw {
+						manifest.uncompressed_blobs.push(self.blobs_new.len() as u64);
+					}
 					manifest.new_files.push(NewFile {
 						hash: *hash,
 						path: path_to_string(path)?,
-						index: self.blobs_new.len() as u64
+						index: self.blobs_new.len() as u64,
+						compression_level: self.file_levels.get(&self.path_key(path)).copied(),
 					});
 					self.blobs_new.push(path.clone());
 				}
@@ -232,7 +864,7 @@ impl DiffingDiff {
 
 				// if path existed in file_paths_new, we'd generate a diff, but then we'd get doubles
 				// as that would be caught in step 3 too, so instead we just ignore in that case
-				if !self.file_paths_new.contains_key(path) {
+				if !self.file_paths_new.contains_key(&self.path_key(path)) {
 					// okay, we *are* a deleted file
 					manifest.deleted_files.push((*hash, path_to_string(path)?));
 				}
@@ -243,18 +875,56 @@ impl DiffingDiff {
 			bail!("All potential scan entry cases should have been handled, but this entry is slipping through the cracks:\n{entry:?}");
 		}
 
+		for (path, target) in &self.symlinks_new {
+			manifest.symlinks.push(SymlinkEntry {
+				path: path_to_string(path)?,
+				target: target.to_string(),
+			});
+		}
+
+		for (path, target) in &self.hardlinks_new {
+			manifest.hardlinked_files.push(HardLinkedFile {
+				path: path_to_string(path)?,
+				target: path_to_string(target)?,
+			});
+		}
+
+		for (path, attrs) in &self.attributes_new {
+			manifest.file_attributes.push(FileAttributes {
+				path: path_to_string(path)?,
+				mtime: attrs.mtime.map(to_unix_time),
+				mode: attrs.mode,
+			});
+		}
+
+		// apply builds the new folder from scratch, so every empty dir needs creating, not just new ones
+		for path in &self.empty_dirs_new {
+			manifest.empty_dirs_new.push(path_to_string(path)?);
+		}
+
+		for path in &self.empty_dirs_old {
+			if !self.dirs_new.contains(path) {
+				manifest.empty_dirs_deleted.push(path_to_string(path)?);
+			}
+		}
+
+		manifest.renamed_dirs = find_renamed_dirs(&manifest.duplicated_files);
+
 		spn.all_good();
 		
 		// we're done!
 		Ok(manifest)
 	}
 
-	/// adds a new file to the diff
+	/// adds a new file to the diff, returning its hash. if `known_hash` is passed, the file isn't hashed again.
 	/// you should not pass a file that is already in the diff - this will return an Err
-	fn add_file(&mut self, in_new: bool, path: &Utf8Path) -> anyhow::Result<()> {
+	fn add_file(&mut self, in_new: bool, path: &Utf8Path, known_hash: Option<u64>, vfs: &dyn VfsProvider, spn: &impl Reporter) -> anyhow::Result<u64> {
 		// check if the path is already there
+		let key = self.path_key(path);
+		// new files at a path that existed before become patches, not new blobs
+		let will_patch = in_new && self.file_paths_old.contains_key(&key);
 		let paths = if in_new { &mut self.file_paths_new } else { &mut self.file_paths_old };
-		if paths.contains_key(path) {
+		if paths.contains_key(&key) {
 			bail!("Attempting to add a file to the diff that already exists")
 		}
 
@@ -262,65 +932,203 @@ impl DiffingDiff {
 
 		// first, hash it
 		let resolved_path = root.join(path);
-		let hash = hash::hash_file(&resolved_path)?;
+		let size = vfs.metadata(&resolved_path).with_context(|| format!("Failed to read metadata of {path:?}"))?.len;
+		let hash = if let Some(hash) = known_hash {
+			hash
+		}
+		else {
+			let mut f = self.io.reader(vfs.open_read(&resolved_path).with_context(|| format!("Failed to open {path:?} to hash it"))?);
+			let mtime = |_: &_| {
+				vfs.metadata(&resolved_path).ok().and_then(|m| m.modified).ok_or(std::io::Error::from(std::io::ErrorKind::Unsupported))
+			};
+			let (hash, changed) = hash::hash_stream_detecting_changes(&mut f, mtime)?;
+			if changed {
+				if self.strict_scan {
+					bail!("{path:?} was modified while it was being scanned");
+				}
+				spn.suspend(|| {
+					eprintln!("Warning: {path:?} was modified while it was being scanned, the diff may be incorrect");
+				});
+			}
+			hash
+		};
 
 		// get working state
 		if let Some(state) = self.files.get_mut(&hash) {
 			// add our path
 			let state_paths = if in_new { &mut state.paths_new } else { &mut state.paths_old };
 			state_paths.push(path.to_path_buf());
-			paths.insert(path.to_path_buf(), hash);
+			paths.insert(key, hash);
 		}
 		else {
-			// perform file type inference
-			let inferred_type = infer::get_from_path(&resolved_path).context("Failed to infer file type")?.map(|t| t.mime_type());
+			// perform file type inference, which only needs the start of the file
+			let mut start = Vec::new();
+			let f = vfs.open_read(&resolved_path).with_context(|| format!("Failed to open {path:?} to infer its type"))?;
+			self.io.reader(f).take(8192).read_to_end(&mut start).context("Failed to infer file type")?;
+			let inferred_type = infer::get(&start).map(|t| t.mime_type());
+
+			// if we're pipelining, and this isn't going to be a patch, we know it'll need a new blob
+			if let Some(tx) = &self.spool_tx {
+				let raw = strategy_for(&self.strategy_overrides, inferred_type) == DiffStrategy::Raw;
+				// raw files are stored whole even if they'd otherwise be patched
+				if in_new && (!will_patch || raw) {
+					tx.send((hash, resolved_path.clone(), raw)).context("Background compression stopped unexpectedly")?;
+				}
+			}
 
 			let new_state = DiffingFileData {
 				inferred_mime: inferred_type,
+				size,
 				paths_old: if !in_new { vec![path.to_path_buf()] } else { vec![] },
 				paths_new: if in_new { vec![path.to_path_buf()] } else { vec![] }
 			};
 
-			paths.insert(path.to_path_buf(), hash);
+			paths.insert(key, hash);
 
 			self.files.insert(hash, new_state);
 		}
 
-		Ok(())
+		Ok(hash)
 	}
 
-	fn scan_internal(&mut self, dir: &Utf8Path, new: bool, spn: &impl Reporter) -> anyhow::Result<()> {
+	fn scan_internal(&mut self, dir: &Utf8Path, new: bool, vfs: &dyn VfsProvider, spn: &impl Reporter) -> anyhow::Result<()> {
 		let root = if new { &self.new_root } else { &self.old_root };
 		// we need to clone this, aw
 		let root = root.clone();
 
-		// read all files in the root
-		let entries = std::fs::read_dir(root.join(dir)).with_context(|| format!("Failed to read dir while scanning {dir:?}"))?;
+		// hashes from the last time this folder was scanned, and the ones from this time to save for next time
+		let prev_cache = self.use_scan_cache.then(|| ScanCache::load(&root, vfs, HashAlgorithm::default()));
+		let mut new_cache = ScanCache::new(HashAlgorithm::default());
+		let cache_path = root.join(scancache::CACHE_FILE_NAME);
+
+		// use a work queue instead of recursing, so very deep trees can't overflow the stack
+		let mut queue = VecDeque::from([dir.to_path_buf()]);
+
+		while let Some(dir) = queue.pop_front() {
+			// read all files in this dir
+			let entries = vfs.read_dir(&root.join(&dir)).with_context(|| format!("Failed to read dir while scanning {dir:?}"))?;
+			let is_empty = entries.is_empty();
+
+			// get everything's metadata first, to see if the files here are the same as when they were cached
+			let entries = entries.into_iter()
+				.filter(|p| !(self.use_scan_cache && *p == cache_path))
+				.map(|p| vfs.metadata(&p).with_context(|| format!("Failed to read metadata of {p:?}")).map(|m| (p, m)))
+				.collect::<anyhow::Result<Vec<_>>>()?;
+			let fingerprint = prev_cache.as_ref()
+				// a followed symlink can point to something that changed without the link changing
+				.filter(|_| self.symlinks != SymlinkMode::Follow || !entries.iter().any(|(_, m)| m.is_symlink))
+				.and_then(|_| scancache::fingerprint(entries.iter().filter(|(_, m)| !m.is_dir).filter_map(|(p, m)| Some((p.file_name()?, m)))));
+			let cached = fingerprint.and_then(|fp| prev_cache.as_ref()?.get(&dir, fp));
+			let mut hashes = HashMap::new();
+
+			for (entry_path, mut meta) in entries {
+				spn.incr(1);
+
+				// are we a directory or a file?
+				let is_link = meta.is_symlink;
+				// strip the root off the front of the path else we get errors
+				let path = entry_path.strip_prefix(&root)?;
+				// so you can see what is taking so long on a slow filesystem
+				spn.set_message(path.as_str());
+
+				// only real filesystems have symlinks, so these can go straight to std::fs
+				if is_link {
+					match self.symlinks {
+						SymlinkMode::Deny => bail!("Entry at '{entry_path:?}' is a symlink, bailing"),
+						SymlinkMode::Follow => {
+							// std::fs::metadata traverses the link for us
+							meta = std::fs::metadata(&entry_path).with_context(|| format!("Failed to resolve symlink {path:?}"))?.into();
+							if meta.is_dir {
+								check_symlink_loop(&root, path)?;
+							}
+						},
+						SymlinkMode::Preserve => {
+							// we only need to know about new symlinks, as old ones never need to be read when applying
+							if new {
+								let target: Utf8PathBuf = std::fs::read_link(&entry_path)
+									.with_context(|| format!("Failed to read symlink {path:?}"))?
+									.try_into()
+									.map_err(|_| anyhow!("Symlink {path:?} has a non-UTF-8 target"))?;
+								self.symlinks_new.insert(path.to_path_buf(), target);
+							}
+							continue;
+						},
+					}
+				}
+
+				if meta.is_dir {
+					// scan it later
+					queue.push_back(path.to_path_buf());
+				}
+				else {
+					// file found!
+					if self.exclude_fn.as_ref().is_some_and(|f| f(path, &meta)) {
+						continue;
+					}
+
+					// on a case-insensitive filesystem these would be the same file, so we can't keep both
+					if self.case_insensitive {
+						let paths = if new { &self.file_paths_new } else { &self.file_paths_old };
+						if paths.contains_key(&self.path_key(path)) {
+							if self.strict_scan {
+								bail!("{path:?} only differs in case from another file, so they can't both be in the diff");
+							}
+							self.case_collisions.push(entry_path.clone());
+							continue;
+						}
+					}
 
-		for entry in entries {
-			let entry = entry.with_context(|| format!("Failed to read entry while scanning {dir:?}"))?;
+					if new {
+						// if it's another link to a file we've already seen, we just need to link it again.
+						// followed symlinks are never treated as hard links.
+						if let (false, Some(id)) = (is_link, meta.hard_link) {
+							match self.inodes_new.entry(id) {
+								std::collections::hash_map::Entry::Occupied(e) => {
+									self.hardlinks_new.insert(path.to_path_buf(), e.get().clone());
+									continue;
+								},
+								std::collections::hash_map::Entry::Vacant(e) => {
+									e.insert(path.to_path_buf());
+								},
+							}
+						}
 
-			spn.incr(1);
-			
-			// are we a directory or a file?
-			let ftype = entry.file_type().context("While reading entry type")?;
-			if ftype.is_symlink() {
-				bail!("Entry at '{:?}' is a symlink, bailing", entry.path());
+						// for followed symlinks, meta is already of the file it points to
+						self.attributes_new.insert(path.to_path_buf(), ScannedAttributes {
+							// not all platforms support this, so just don't store it if we can't get it
+							mtime: meta.modified,
+							mode: meta.mode,
+						});
+					}
+
+					let known_hash = cached.and_then(|c| c.get(path.file_name()?)).copied();
+					let hash = self.add_file(new, path, known_hash, vfs, spn).context("While adding file to diff")?;
+					if let Some(name) = path.file_name() {
+						hashes.insert(name.to_string(), hash);
+					}
+				}
 			}
-			// strip the root off the front of the path else we get errors
-			let path: Utf8PathBuf = match entry.path().try_into()
-			{
-				Ok(p) => p,
-				Err(_) => continue, // just ignore non-UTF-8 paths!
-			};
-			let path = path.strip_prefix(&root)?;
-			if ftype.is_dir() {
-				// recurse
-				self.scan_internal(&path, new, spn)?;
+
+			if let Some(fp) = fingerprint {
+				new_cache.insert(&dir, fp, hashes);
 			}
-			else {
-				// file found!
-				self.add_file(new, path).context("While adding file to diff")?;
+
+			// empty dirs contain no files, so would otherwise be lost. the root always exists anyway.
+			if is_empty && !dir.as_str().is_empty() {
+				let empty_dirs = if new { &mut self.empty_dirs_new } else { &mut self.empty_dirs_old };
+				empty_dirs.insert(dir.clone());
+			}
+			if new {
+				self.dirs_new.insert(dir);
+			}
+		}
+
+		// only speeds things up, so not being able to save it isn't worth failing over
+		if self.use_scan_cache {
+			if let Err(e) = new_cache.save(&root, vfs) {
+				spn.suspend(|| {
+					eprintln!("Warning: failed to save the scan cache for {root:?}: {e:?}");
+				});
 			}
 		}
 
@@ -328,18 +1136,1062 @@ impl DiffingDiff {
 	}
 }
 
-pub fn scan_to_diff<TSpin: Reporter+Sync>(old_root: Utf8PathBuf, new_root: Utf8PathBuf) -> anyhow::Result<DiffingDiff> {
-	let mut new_self = DiffingDiff::new(old_root, new_root);
+/// fails if the symlink at `path` points to a dir it was found inside of, by the path it was found at,
+/// as then following it would go round in circles forever
+fn check_symlink_loop(root: &Utf8Path, path: &Utf8Path) -> anyhow::Result<()> {
+	let target = std::fs::canonicalize(root.join(path)).with_context(|| format!("Failed to resolve symlink {path:?}"))?;
+	for dir in path.ancestors().skip(1) {
+		let dir = root.join(dir);
+		if std::fs::canonicalize(&dir).with_context(|| format!("Failed to resolve {dir:?}"))? == target {
+			bail!("Symlink {path:?} points to {dir:?}, which it is inside of, so following it would never end");
+		}
+	}
+	Ok(())
+}
+
+/// Scans both folders through `vfs`. Writing the diff afterwards still reads the files straight from disk.
+pub fn scan_to_diff<TSpin: Reporter+Sync>(old_root: Utf8PathBuf, new_root: Utf8PathBuf, cfg: &FoldiffCfg, vfs: &dyn VfsProvider) -> anyhow::Result<DiffingDiff> {
+	let mut new_self = DiffingDiff::new(old_root, new_root, cfg);
+	new_self.scan::<TSpin>(vfs)?;
+	Ok(new_self)
+}
 
-	let spn = TSpin::new("Scanning old files");
+/// extracts a tar archive into a new temporary folder
+fn extract_tar<TSpin: Reporter+Sync>(tar: impl Read, side: &str) -> anyhow::Result<(TempDir, Utf8PathBuf)> {
+	let spn = TSpin::new(&format!("Extracting {side} tar"));
 	let aspn = AutoSpin::spin(&spn);
-	new_self.scan_internal(Utf8Path::new(""), false, &spn)?;
-	aspn.all_good();
 
-	let spn = TSpin::new("Scanning new files");
-	let aspn = AutoSpin::spin(&spn);
-	new_self.scan_internal(Utf8Path::new(""), true, &spn)?;
+	let dir = tempfile::tempdir().context("Failed to create temporary folder to extract tar into")?;
+	let path = Utf8PathBuf::try_from(dir.path().to_path_buf()).context("Temporary folder path is not UTF-8")?;
+	// unpack refuses entries that would end up outside the folder
+	tar::Archive::new(tar).unpack(&path).with_context(|| format!("Failed to extract {side} tar"))?;
+
 	aspn.all_good();
+	Ok((dir, path))
+}
+
+/// Like `scan_to_diff`, but diffs what is in two tar archives. They are extracted into temporary folders first,
+/// which last as long as the returned DiffingDiff, so there needs to be enough space for both.
+pub fn scan_to_diff_from_tar<TSpin: Reporter+Sync>(old_tar: impl Read, new_tar: impl Read, cfg: &FoldiffCfg) -> anyhow::Result<DiffingDiff> {
+	let (old_dir, old_root) = extract_tar::<TSpin>(old_tar, "old")?;
+	let (new_dir, new_root) = extract_tar::<TSpin>(new_tar, "new")?;
 
+	let mut new_self = scan_to_diff::<TSpin>(old_root, new_root, cfg, &StdVfs)?;
+	new_self.extracted = vec![Arc::new(old_dir), Arc::new(new_dir)];
 	Ok(new_self)
-}
\ No newline at end of file
+}
+
+/// Like `scan_to_diff` followed by `write_to_file`, but compresses new files on a background thread while
+/// scanning is still going on, instead of waiting for the whole tree to be scanned first.
+/// Compressed files are spooled next to `path` until the manifest can be written.
+/// Per-file compression levels are not known while scanning, so this always uses the levels in `cfg`.
+/// With a time budget, the level isn't known until everything has been scanned, so this just scans and then writes.
+pub fn scan_and_write_pipeline<TBar: ReporterSized, TSpin: Reporter+Sync>(old_root: Utf8PathBuf, new_root: Utf8PathBuf, path: &Utf8Path, cfg: &FoldiffCfg) -> anyhow::Result<WriteStats> {
+	if cfg.time_budget_secs.is_some() {
+		return scan_to_diff::<TSpin>(old_root, new_root, cfg, &StdVfs)?.write_to_file::<TBar, TSpin>(path, cfg);
+	}
+
+	// next to the diff, so it's on a filesystem with room for it. it's deleted when dropped, even if we failed
+	let dir = path.parent().filter(|p| !p.as_str().is_empty()).unwrap_or(Utf8Path::new("."));
+	let name = path.file_name().unwrap_or("diff");
+	let spool = tempfile::Builder::new().prefix(&format!(".{name}.")).suffix(".spool").tempfile_in(dir)
+		.context("Failed to create file to spool new files to")?;
+	let spool_path = Utf8Path::from_path(spool.path()).context("Spool file path is not UTF-8")?;
+	pipeline_internal::<TBar, TSpin>(old_root, new_root, path, spool.as_file().try_clone()?, spool_path, cfg)
+}
+
+fn pipeline_internal<TBar: ReporterSized, TSpin: Reporter+Sync>(old_root: Utf8PathBuf, new_root: Utf8PathBuf, path: &Utf8Path, mut spool: File, spool_path: &Utf8Path, cfg: &FoldiffCfg) -> anyhow::Result<WriteStats> {
+	let (tx, rx) = channel::<SpoolJob>();
+
+	let mut new_self = DiffingDiff::new(old_root, new_root, cfg);
+	memory::reset_peak_rss();
+	new_self.peak_rss_reset = true;
+
+	let io = new_self.io.clone();
+	let spooled = thread::scope(|s| {
+		let writer = s.spawn(move || -> anyhow::Result<HashMap<u64, (u64, u64)>> {
+			let mut spooled = HashMap::new();
+			let mut offset = 0;
+			let new_dict = PreparedNewDict::new(cfg);
+			for (hash, p, raw) in rx {
+				let f = File::open(&p).with_context(|| format!("Failed to open {p} to compress it"))?;
+				let len = f.metadata()?.len();
+				// hash what actually gets compressed, as the file could have changed since it was scanned
+				let mut f = hash::XXHashStreamer::new(io.reader(f));
+				let mut count = countio::Counter::new(io.writer(&mut spool));
+				compress_new_blob(&mut f, len, &mut count, raw, cfg.level_new, cfg, &new_dict)?;
+				ensure!(f.finish() == hash, "{p:?} was changed after it was scanned, scan it again to make a diff");
+
+				let bytes = count.writer_bytes() as u64;
+				spooled.insert(hash, (offset, bytes));
+				offset += bytes;
+			}
+			Ok(spooled)
+		});
+
+		// old files can never be new blobs, so only start sending once we're scanning the new side
+		let spn = TSpin::new("Scanning old files");
+		let aspn = AutoSpin::spin(&spn);
+		new_self.scan_internal(Utf8Path::new(""), false, &StdVfs, &spn)?;
+		new_self.timings.scan_old = aspn.all_good();
+
+		new_self.spool_tx = Some(tx);
+		let spn = TSpin::new("Scanning and compressing new files");
+		let aspn = AutoSpin::spin(&spn);
+		let res = new_self.scan_internal(Utf8Path::new(""), true, &StdVfs, &spn);
+		// hang up so the writer knows it's done
+		new_self.spool_tx = None;
+		// if the writer failed, scanning only saw the channel close, so report the writer's error instead
+		let spooled = writer.join().map_err(|_| anyhow!("Background compression thread panicked"))??;
+		res?;
+		new_self.timings.scan_new = aspn.all_good();
+		anyhow::Ok(spooled)
+	})?;
+
+	new_self.spooled_new = spooled;
+	new_self.spool_path = Some(spool_path.to_path_buf());
+	new_self.write_to_file::<TBar, TSpin>(path, cfg)
+}
+
+/// where a file in a composed diff gets its contents from
+#[derive(Copy, Clone)]
+enum ComposedSource<'a> {
+	/// a file in the first diff's old folder
+	Old(&'a str),
+	/// a new blob of either diff
+	Blob(&'a ApplyingDiff, u64),
+	/// a new blob of the first diff, patched by the second
+	PatchedBlob { ab_idx: u64, ab_hash: u64, bc_patch: &'a PatchedFile },
+	/// a patch from either diff, still applied to the same path in the first diff's old folder
+	Patch(&'a ApplyingDiff, &'a PatchedFile),
+}
+
+/// Combines an A→B diff and a B→C diff into a single A→C diff, without needing any of the folders.
+/// Files that are new in A→B and patched in B→C are patched in memory and stored as new files.
+/// Files that are patched in both diffs can't be composed without A, so this fails if there are any.
+pub fn compose_diffs(ab_path: &Utf8Path, bc_path: &Utf8Path, ac_path: &Utf8Path, cfg: &FoldiffCfg) -> anyhow::Result<()> {
+	let ab = crate::applying::read_diff_from_file(ab_path).with_context(|| format!("Failed to read {ab_path}"))?;
+	let bc = crate::applying::read_diff_from_file(bc_path).with_context(|| format!("Failed to read {bc_path}"))?;
+	ensure!(cfg.encryption.is_none(), "Composed diffs can't be encrypted");
+	// patches are copied over on their own, which would lose the context they were chained with
+	ensure!(!ab.manifest().delta_chain && !bc.manifest().delta_chain, "Cannot compose delta chained diffs");
+	check_blob_alignment(cfg.blob_alignment as u64)?;
+	let (abm, bcm) = (ab.manifest(), bc.manifest());
+	// hashes from different algorithms can't be compared
+	ensure!(abm.hash_algorithm == bcm.hash_algorithm, "{ab_path} and {bc_path} were hashed differently, upgrade both first");
+
+	let ab_state = abm.new_state()?;
+	let ab_links: HashMap<&str, &str> = abm.hardlinked_files.iter().map(|hl| (hl.path.as_str(), hl.target.as_str())).collect();
+	let ab_patched: HashMap<&str, &PatchedFile> = abm.patched_files.iter().map(|pf| (pf.path.as_str(), pf)).collect();
+	let bc_patched: HashMap<&str, &PatchedFile> = bcm.patched_files.iter().map(|pf| (pf.path.as_str(), pf)).collect();
+
+	// what a file in B is, in terms of A
+	let resolve_b = |q: &str| {
+		let q = ab_links.get(q).copied().unwrap_or(q);
+		ab_state.get(q).copied().with_context(|| format!("{q} is used by {bc_path}, but is not in the new folder of {ab_path}"))
+	};
+	let cannot = |p: &str| anyhow!("Cannot compose {p}, as it is changed by both diffs in a way that needs the original folder");
+
+	// sorted so the output is the same every time
+	let bc_state: BTreeMap<_, _> = bcm.new_state()?.into_iter().collect();
+	let mut composed = Vec::new();
+	for (p, (hash, src)) in bc_state {
+		let c = match src {
+			Source::Old(q) => match resolve_b(q)? {
+				(_, Source::Old(r)) => ComposedSource::Old(r),
+				(_, Source::NewBlob(i)) => ComposedSource::Blob(&ab, i),
+				(_, Source::Patch(_)) => match ab_patched.get(q) {
+					Some(pf) if q == p => ComposedSource::Patch(&ab, pf),
+					_ => return Err(cannot(p)),
+				},
+			},
+			Source::NewBlob(i) => ComposedSource::Blob(&bc, i),
+			Source::Patch(_) => match resolve_b(p)? {
+				(_, Source::Old(r)) if r == p => ComposedSource::Patch(&bc, bc_patched[p]),
+				(ab_hash, Source::NewBlob(ab_idx)) => ComposedSource::PatchedBlob { ab_idx, ab_hash, bc_patch: bc_patched[p] },
+				_ => return Err(cannot(p)),
+			},
+		};
+		composed.push((p, hash, c));
+	}
+
+	let mut manifest = DiffManifest::hashed_with(abm.hash_algorithm);
+	// hash -> (paths in A, paths in C), for files that are just copied
+	let mut copies: BTreeMap<u64, (BTreeSet<&str>, Vec<&str>)> = BTreeMap::new();
+	// (source, first path, hash) of each new blob, which are shared between files with the same contents
+	let mut blobs = Vec::new();
+	let mut blob_indices = HashMap::new();
+	let mut patches = Vec::new();
+	let mut patch_dict = None;
+
+	for (p, hash, c) in composed {
+		match c {
+			ComposedSource::Old(r) => {
+				let (from, to) = copies.entry(hash).or_default();
+				from.insert(r);
+				to.push(p);
+			},
+			ComposedSource::Blob(..) | ComposedSource::PatchedBlob { .. } => {
+				let index = *blob_indices.entry(hash).or_insert_with(|| {
+					if let ComposedSource::Blob(d, i) = c {
+						if d.manifest().uncompressed_blobs.contains(&i) {
+							manifest.uncompressed_blobs.push(blobs.len() as u64);
+						}
+					}
+					blobs.push((c, p, hash));
+					blobs.len() as u64 - 1
+				});
+				manifest.new_files.push(NewFile { hash, index, path: p.to_string(), compression_level: None });
+			},
+			ComposedSource::Patch(d, pf) => {
+				// patches are copied as-is, so they all need to have been made with the same dictionary
+				let dict = d.patch_dict()?;
+				ensure!(patches.is_empty() || patch_dict == dict, "Cannot compose diffs that were made with different diff dictionaries");
+				patch_dict = dict;
+
+				manifest.patched_files.push(PatchedFile {
+					old_hash: pf.old_hash,
+					new_hash: pf.new_hash,
+					index: patches.len() as u64,
+					path: p.to_string(),
+					compression_level: pf.compression_level,
+				});
+				patches.push(d.patch_blob(pf.index)?);
+			},
+		}
+	}
+
+	let mut used_old = HashSet::new();
+	for (hash, (from, to)) in copies {
+		used_old.extend(from.iter().copied());
+		if from.len() == 1 && to.len() == 1 && from.first() == to.first() {
+			manifest.untouched_files.push((hash, to[0].to_string()));
+		}
+		else {
+			manifest.duplicated_files.push(DuplicatedFile {
+				hash,
+				idx: u64::MAX,
+				old_paths: from.into_iter().map(String::from).collect(),
+				new_paths: to.into_iter().map(String::from).collect(),
+			});
+		}
+	}
+
+	// anything in A that isn't in C, or copied to somewhere in C
+	let new_paths: HashSet<&str> = bcm.iter_new_paths().chain(bcm.hardlinked_files.iter().map(|hl| hl.path.as_str())).collect();
+	let mut deleted: Vec<_> =
+		abm.old_state()
+			.into_iter()
+			.filter(|(p, _)| !new_paths.contains(p) && !used_old.contains(p))
+			.map(|(p, h)| (h, p.to_string()))
+			.collect();
+	deleted.sort_by(|a, b| a.1.cmp(&b.1));
+	manifest.deleted_files = deleted;
+
+	// links and attributes all describe C, so can just be taken from B→C
+	manifest.symlinks = bcm.symlinks.clone();
+	manifest.hardlinked_files = bcm.hardlinked_files.clone();
+	manifest.file_attributes = bcm.file_attributes.clone();
+	manifest.empty_dirs_new = bcm.empty_dirs_new.clone();
+	manifest.empty_dirs_deleted = abm.empty_dirs_deleted.iter().filter(|d| !bcm.empty_dirs_new.contains(d)).cloned().collect();
+	let sizes: BTreeMap<u64, u64> = abm.uncompressed_sizes.iter().chain(&bcm.uncompressed_sizes).copied().collect();
+	manifest.uncompressed_sizes = sizes.into_iter().collect();
+	manifest.has_dictionaries = cfg.new_dict.is_some() || patch_dict.is_some();
+	manifest.blob_alignment = cfg.blob_alignment as u64;
+	let uncompressed_blobs: HashSet<_> = manifest.uncompressed_blobs.iter().copied().collect();
+
+	let mut f = File::create_new(ac_path).context("Failed to create file to save diff")?;
+	write_header(&mut f, &manifest, None)?;
+
+	// new blobs have to be decompressed to get at their contents, so just recompress them all
+	let new_dict = PreparedNewDict::new(cfg);
+	f.write_all(&(blobs.len() as u64).to_be_bytes())?;
+	for (i, (c, p, hash)) in blobs.into_iter().enumerate() {
+		let data = match c {
+			ComposedSource::Blob(d, idx) => d.read_new_blob(idx, p, hash)?,
+			ComposedSource::PatchedBlob { ab_idx, ab_hash, bc_patch } => {
+				let b = ab.read_new_blob(ab_idx, p, ab_hash)?;
+				bc.patch_in_memory(bc_patch, &b, &[])?
+			},
+			_ => unreachable!(),
+		};
+
+		let mut blob = Vec::new();
+		compress_new_blob(&mut &data[..], data.len() as u64, &mut blob, uncompressed_blobs.contains(&(i as u64)), cfg.level_new, cfg, &new_dict)?;
+		f.write_all(&(blob.len() as u64).to_be_bytes())?;
+		f.write_all(&blob)?;
+	}
+
+	f.write_all(&(patches.len() as u64).to_be_bytes())?;
+	for patch in patches {
+		f.write_all(&patch)?;
+	}
+
+	// write dictionaries, an empty one means there isn't one
+	if manifest.has_dictionaries {
+		for dict in [cfg.new_dict.as_deref(), patch_dict.as_deref()] {
+			let dict = dict.unwrap_or_default();
+			f.write_all(&(dict.len() as u64).to_be_bytes())?;
+			f.write_all(dict)?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Creates a B→A diff that undoes the A→B diff at `ab_path`, where `old_dir` is A and `new_dir` is B.
+/// Both folders are scanned and checked against the A→B diff first, so the result is guaranteed to undo it.
+/// New files become deleted, deleted files become new, patched files are diffed the other way around,
+/// and untouched files stay untouched.
+pub fn invert_diff<TBar: ReporterSized, TSpin: Reporter+Sync>(ab_path: &Utf8Path, ba_path: &Utf8Path, old_dir: &Utf8Path, new_dir: &Utf8Path, cfg: &FoldiffCfg) -> anyhow::Result<WriteStats> {
+	let f = File::open(ab_path).context("Failed to open diff to invert")?;
+	let ab = DiffManifest::read_from(f).context("Failed to read diff to invert")?;
+	// the folders are scanned with the latest hash, so there would be nothing to check them against
+	ensure!(ab.hash_algorithm == HashAlgorithm::default(), "{ab_path} is from an older version of foldiff, upgrade it first");
+
+	// old and new swap places
+	let mut ba = scan_to_diff::<TSpin>(new_dir.to_path_buf(), old_dir.to_path_buf(), cfg, &StdVfs)?;
+
+	let matches = |paths: &BTreeMap<Utf8PathBuf, u64>, p: &str, hash: u64| paths.get(&ba.path_key(Utf8Path::new(p))) == Some(&hash);
+	let old_state = ab.old_state();
+	for (p, hash) in &old_state {
+		ensure!(matches(&ba.file_paths_new, p, *hash), "{p} in {old_dir} does not match the old folder of {ab_path}");
+	}
+	let new_state = ab.new_state()?;
+	for (p, (hash, _)) in &new_state {
+		ensure!(matches(&ba.file_paths_old, p, *hash), "{p} in {new_dir} does not match the new folder of {ab_path}");
+	}
+	// hard links in A were diffed as normal files
+	ensure!(
+		ba.file_paths_new.len() + ba.hardlinks_new.len() == old_state.len() && ba.file_paths_old.len() == new_state.len() + ab.hardlinked_files.len(),
+		"The folders contain files that are not in {ab_path}"
+	);
+
+	ba.write_to_file::<TBar, TSpin>(ba_path, cfg)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::reporting::{CountingReporter, NoopMultiWrapper, NoopReporter};
+	use crate::testing::{assert_folders_equal, generate_test_folder, root_of, FolderSpec};
+	use tempfile::tempdir;
+
+	#[test]
+	fn test_scan_deep_tree() {
+		let old = tempdir().unwrap();
+		let new = tempdir().unwrap();
+		let old_root = Utf8PathBuf::try_from(old.path().to_path_buf()).unwrap();
+		let new_root = Utf8PathBuf::try_from(new.path().to_path_buf()).unwrap();
+
+		// deep enough that recursing would be a bad idea
+		let mut deep = new_root.clone();
+		for _ in 0..1000 {
+			deep.push("d");
+		}
+		std::fs::create_dir_all(&deep).unwrap();
+		std::fs::write(deep.join("f"), b"down here").unwrap();
+
+		let cfg = FoldiffCfg::default();
+		let diff = scan_to_diff::<NoopReporter>(old_root, new_root.clone(), &cfg, &StdVfs).unwrap();
+
+		assert_eq!(diff.file_paths_old.len(), 0);
+		assert_eq!(diff.file_paths_new.len(), 1);
+		assert!(diff.file_paths_new.contains_key(deep.join("f").strip_prefix(&new_root).unwrap()));
+	}
+
+	#[test]
+	fn test_pipeline_matches_normal_diff() {
+		let dir = tempdir().unwrap();
+		let root = Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap();
+		let (old_root, new_root) = (root.join("old"), root.join("new"));
+		std::fs::create_dir_all(&old_root).unwrap();
+		std::fs::create_dir_all(new_root.join("sub")).unwrap();
+
+		std::fs::write(old_root.join("same"), b"unchanged").unwrap();
+		std::fs::write(new_root.join("same"), b"unchanged").unwrap();
+		std::fs::write(old_root.join("changed"), b"before before before").unwrap();
+		std::fs::write(new_root.join("changed"), b"before after before").unwrap();
+		for i in 0..50 {
+			std::fs::write(new_root.join(format!("sub/new{i}")), format!("new file {i}").repeat(i)).unwrap();
+		}
+
+		let cfg = FoldiffCfg::default();
+
+		let normal = root.join("normal.fldf");
+		scan_to_diff::<NoopReporter>(old_root.clone(), new_root.clone(), &cfg, &StdVfs).unwrap()
+			.write_to_file::<NoopReporter, NoopReporter>(&normal, &cfg).unwrap();
+
+		let pipelined = root.join("pipelined.fldf");
+		scan_and_write_pipeline::<NoopReporter, NoopReporter>(old_root, new_root, &pipelined, &cfg).unwrap();
+
+		assert_eq!(std::fs::read(normal).unwrap(), std::fs::read(&pipelined).unwrap());
+		// the spool is gone
+		assert_eq!(std::fs::read_dir(&root).unwrap().count(), 4);
+	}
+
+	#[test]
+	fn test_pipeline_keeps_existing_spool() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("old/a", "old")
+			.file("new/a", "new")
+			.file("x.fldf.spool", "not ours")
+		);
+		let root = root_of(&dir);
+		let cfg = FoldiffCfg::default();
+		let run = || scan_and_write_pipeline::<NoopReporter, NoopReporter>(root.join("old"), root.join("new"), &root.join("x.fldf"), &cfg);
+
+		run().unwrap();
+		assert_eq!(std::fs::read_to_string(root.join("x.fldf.spool")).unwrap(), "not ours");
+
+		// failing, here because the diff already exists, leaves it alone too
+		assert!(run().is_err());
+		assert_eq!(std::fs::read_to_string(root.join("x.fldf.spool")).unwrap(), "not ours");
+		assert_eq!(std::fs::read_dir(&root).unwrap().count(), 4);
+	}
+
+	fn diff_folders(old: &Utf8Path, new: &Utf8Path, out: &Utf8Path) {
+		let cfg = FoldiffCfg::default();
+		scan_to_diff::<NoopReporter>(old.to_path_buf(), new.to_path_buf(), &cfg, &StdVfs).unwrap()
+			.write_to_file::<NoopReporter, NoopReporter>(out, &cfg).unwrap();
+	}
+
+	#[test]
+	fn test_exclude_fn() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("a/kept", "same")
+			.file("a/debug.log", "old log")
+			.file("b/kept", "same")
+			.file("b/debug.log", "new log")
+			.file("b/big", "x".repeat(1000))
+		);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+
+		let cfg = FoldiffCfg::default();
+		let mut diff = DiffingDiff::new(a, b, &cfg);
+		diff.set_exclude_fn(Box::new(|p, meta| p.extension() == Some("log") || meta.len > 100));
+		diff.scan::<NoopReporter>(&StdVfs).unwrap();
+
+		let manifest = diff.generate_manifest::<NoopReporter>().unwrap();
+		assert_eq!(manifest.iter_all_files().count(), 1);
+		assert_eq!(manifest.untouched_files[0].1, "kept");
+	}
+
+	#[test]
+	fn test_scan_from_tar() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("a/same", "same".repeat(50))
+			.file("a/edited", "before".repeat(50))
+			.file("a/gone", "gone")
+			.file("b/same", "same".repeat(50))
+			.file("b/edited", "after".repeat(50))
+			.file("b/new", "new".repeat(50))
+		);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+		let tar_of = |dir: &Utf8Path| {
+			let mut builder = tar::Builder::new(Vec::new());
+			builder.append_dir_all(".", dir).unwrap();
+			builder.into_inner().unwrap()
+		};
+
+		let cfg = FoldiffCfg::default();
+		let mut diff = scan_to_diff_from_tar::<NoopReporter>(&tar_of(&a)[..], &tar_of(&b)[..], &cfg).unwrap();
+		let extracted: Vec<_> = diff.extracted.iter().map(|d| d.path().to_path_buf()).collect();
+
+		let manifest = diff.generate_manifest::<NoopReporter>().unwrap();
+		assert_eq!(manifest.untouched_files.len(), 1);
+		assert_eq!(manifest.patched_files.len(), 1);
+		assert_eq!(manifest.new_files.len(), 1);
+		assert_eq!(manifest.deleted_files.len(), 1);
+
+		// clones share the extracted folders, so they're only gone once every clone is
+		let clone = diff.clone();
+		drop(diff);
+		assert!(extracted.iter().all(|d| d.exists()));
+		drop(clone);
+		assert!(extracted.iter().all(|d| !d.exists()));
+	}
+
+	#[test]
+	fn test_scan_cache() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("a/same", "same".repeat(10000))
+			.file("a/edited", "before".repeat(10000))
+			.file("b/same", "same".repeat(10000))
+			.file("b/edited", "before".repeat(10000))
+		);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+
+		let cfg = FoldiffCfg { use_scan_cache: true, ..Default::default() };
+		let scan = || {
+			let mut diff = scan_to_diff::<NoopReporter>(a.clone(), b.clone(), &cfg, &StdVfs).unwrap();
+			let manifest = diff.generate_manifest::<NoopReporter>().unwrap();
+			(manifest, diff.io.totals().bytes_read)
+		};
+
+		let (manifest, read) = scan();
+		assert_eq!(manifest.untouched_files.len(), 2);
+		assert!(std::fs::exists(a.join(scancache::CACHE_FILE_NAME)).unwrap());
+		assert!(read >= 2 * 100000);
+
+		// nothing needs hashing now, only the start of each file is read to infer its type
+		let (manifest, read) = scan();
+		assert_eq!(manifest.untouched_files.len(), 2);
+		assert!(read <= 2 * 8192);
+
+		// changing the contents but not the size or time is trusted to be the same, which shows the cache is used
+		let edited = b.join("edited");
+		let mtime = std::fs::metadata(&edited).unwrap().modified().unwrap();
+		std::fs::write(&edited, "after!".repeat(10000)).unwrap();
+		File::options().write(true).open(&edited).unwrap().set_modified(mtime).unwrap();
+		assert_eq!(scan().0.untouched_files.len(), 2);
+
+		// but a new time isn't
+		File::options().write(true).open(&edited).unwrap().set_modified(mtime + Duration::from_secs(1)).unwrap();
+		let (manifest, _) = scan();
+		assert_eq!(manifest.untouched_files.len(), 1);
+		assert_eq!(manifest.patched_files.len(), 1);
+	}
+
+	#[test]
+	fn test_diff_strategies() {
+		let page = |body: &str| format!("<!DOCTYPE html>\n<html>\n{}</html>\n", format!("<p>{body}</p>\n").repeat(50));
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("a/page.html", page("old"))
+			.file("b/page.html", page("new"))
+		);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+		let png = |tail: &[u8]| [&b"\x89PNG\r\n\x1a\n"[..], &[0; 500], tail].concat();
+		std::fs::write(a.join("image.png"), png(b"old")).unwrap();
+		std::fs::write(b.join("image.png"), png(b"new")).unwrap();
+
+		let check = |cfg: &FoldiffCfg, patched: &[&str]| {
+			let out = root.join("ab.fldf");
+			let _ = std::fs::remove_file(&out);
+			scan_and_write_pipeline::<NoopReporter, NoopReporter>(a.clone(), b.clone(), &out, cfg).unwrap();
+			let mut diff = crate::applying::read_diff_from_file(&out).unwrap();
+			let m = diff.manifest();
+			assert_eq!(m.patched_files.iter().map(|pf| pf.path.as_str()).collect::<Vec<_>>(), patched);
+			// everything else is stored whole and uncompressed
+			assert_eq!(m.new_files.len() + patched.len(), 2);
+			assert_eq!(m.uncompressed_blobs.len(), m.new_files.len());
+
+			let text = m.patched_files.iter().find(|pf| pf.path == "page.html").map(|pf| pf.index);
+			if let Some(i) = text {
+				let count = u64::from_be_bytes(*diff.patch_blob(i).unwrap().first_chunk().unwrap());
+				assert_ne!(count & zstddiff::TEXT_DELTA_FLAG, 0);
+			}
+
+			let _ = std::fs::remove_dir_all(root.join("out"));
+			assert!(diff.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(a.clone(), root.join("out"), cfg, &StdVfs, None).unwrap().is_ok());
+			assert_eq!(std::fs::read(root.join("out/page.html")).unwrap(), page("new").as_bytes());
+			assert_eq!(std::fs::read(root.join("out/image.png")).unwrap(), png(b"new"));
+		};
+
+		check(&FoldiffCfg::default(), &["page.html"]);
+		let overrides = [("text/html".to_string(), DiffStrategy::Raw), ("image/png".to_string(), DiffStrategy::Zstd)];
+		check(&FoldiffCfg { strategy_overrides: overrides.into(), ..Default::default() }, &["image.png"]);
+	}
+
+	#[test]
+	#[cfg(target_os = "linux")]
+	fn test_case_collisions() {
+		// the temp dir is case-sensitive on linux, so both of these really exist
+		let dir = generate_test_folder(FolderSpec::new()
+			.dir("a")
+			.file("b/Foo.txt", "upper")
+			.file("b/foo.txt", "lower")
+		);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+
+		let cfg = FoldiffCfg { case_insensitive_paths: true, ..FoldiffCfg::default() };
+		let diff = scan_to_diff::<NoopReporter>(a.clone(), b.clone(), &cfg, &StdVfs).unwrap();
+		assert_eq!(diff.case_collisions().len(), 1, "{:?}", diff.case_collisions());
+		assert_eq!(diff.file_paths_new.len(), 1);
+
+		let cfg = FoldiffCfg { strict_scan: true, ..cfg };
+		let err = scan_to_diff::<NoopReporter>(a, b, &cfg, &StdVfs).unwrap_err();
+		assert!(format!("{err:#}").contains("only differs in case"), "{err:#}");
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn test_symlink_loop() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.dir("a")
+			.file("b/file", "contents")
+			.dir("b/x")
+			.dir("b/y")
+			.file("b/z/file", "in z")
+		);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+		// links to other dirs are fine, even to the same one twice
+		std::os::unix::fs::symlink("../z", b.join("x/to_z")).unwrap();
+		std::os::unix::fs::symlink("../z", b.join("y/to_z")).unwrap();
+
+		let cfg = FoldiffCfg { symlinks: SymlinkMode::Follow, ..FoldiffCfg::default() };
+		let diff = scan_to_diff::<NoopReporter>(a.clone(), b.clone(), &cfg, &StdVfs).unwrap();
+		assert!(diff.file_paths_new.contains_key(&diff.path_key(Utf8Path::new("x/to_z/file"))));
+		assert!(diff.file_paths_new.contains_key(&diff.path_key(Utf8Path::new("y/to_z/file"))));
+
+		// but not to a dir it's in, even when that only happens after following another link
+		std::os::unix::fs::symlink("../x", b.join("z/to_x")).unwrap();
+		let err = scan_to_diff::<NoopReporter>(a.clone(), b.clone(), &cfg, &StdVfs).unwrap_err();
+		assert!(format!("{err:#}").contains("would never end"), "{err:#}");
+
+		std::fs::remove_file(b.join("z/to_x")).unwrap();
+		std::os::unix::fs::symlink("..", b.join("z/to_root")).unwrap();
+		let err = scan_to_diff::<NoopReporter>(a, b, &cfg, &StdVfs).unwrap_err();
+		assert!(format!("{err:#}").contains("would never end"), "{err:#}");
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn test_symlinks_follow_roundtrip() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("a/kept", "same")
+			.file("b/kept", "same")
+			.file("b/real/file", "behind a link")
+		);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+		std::os::unix::fs::symlink("real", b.join("dir_link")).unwrap();
+		std::os::unix::fs::symlink("real/file", b.join("file_link")).unwrap();
+
+		let cfg = FoldiffCfg { symlinks: SymlinkMode::Follow, ..FoldiffCfg::default() };
+		let mut diff = scan_to_diff::<NoopReporter>(a.clone(), b.clone(), &cfg, &StdVfs).unwrap();
+		diff.write_to_file::<NoopReporter, NoopReporter>(&root.join("ab.fldf"), &cfg).unwrap();
+		let mut applying = crate::applying::read_diff_from_file(&root.join("ab.fldf")).unwrap();
+		assert!(applying.manifest().symlinks.is_empty());
+		assert!(applying.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(a, root.join("out"), &cfg, &StdVfs, None).unwrap().is_ok());
+
+		// the links become copies of what they pointed at
+		let out = root.join("out");
+		for p in ["dir_link", "file_link"] {
+			assert!(!std::fs::symlink_metadata(out.join(p)).unwrap().is_symlink());
+		}
+		assert_eq!(std::fs::read_to_string(out.join("dir_link/file")).unwrap(), "behind a link");
+		assert_eq!(std::fs::read_to_string(out.join("file_link")).unwrap(), "behind a link");
+		assert!(crate::verify::test_dir_equality::<NoopReporter>(&b, &out, &cfg, Default::default()).unwrap().is_ok());
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn test_symlinks_preserve_roundtrip() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("a/target", "old contents")
+			.file("b/target", "new contents")
+			.dir("b/sub")
+		);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+		// old links are never read, so this one is just replaced
+		std::os::unix::fs::symlink("elsewhere", a.join("link")).unwrap();
+		std::os::unix::fs::symlink("target", b.join("link")).unwrap();
+		std::os::unix::fs::symlink("../target", b.join("sub/up")).unwrap();
+		std::os::unix::fs::symlink("nowhere", b.join("dangling")).unwrap();
+
+		let cfg = FoldiffCfg { symlinks: SymlinkMode::Preserve, ..FoldiffCfg::default() };
+		let mut diff = scan_to_diff::<NoopReporter>(a.clone(), b.clone(), &cfg, &StdVfs).unwrap();
+		diff.write_to_file::<NoopReporter, NoopReporter>(&root.join("ab.fldf"), &cfg).unwrap();
+		let mut applying = crate::applying::read_diff_from_file(&root.join("ab.fldf")).unwrap();
+		let mut links: Vec<_> = applying.manifest().symlinks.iter().map(|sl| (sl.path.clone(), sl.target.clone())).collect();
+		links.sort();
+		assert_eq!(links.iter().map(|(p, t)| (p.as_str(), t.as_str())).collect::<Vec<_>>(), [("dangling", "nowhere"), ("link", "target"), ("sub/up", "../target")]);
+
+		assert!(applying.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(a, root.join("out"), &cfg, &StdVfs, None).unwrap().is_ok());
+		let out = root.join("out");
+		for (path, target) in links {
+			assert_eq!(std::fs::read_link(out.join(path)).unwrap(), Utf8Path::new(&target));
+		}
+		assert_eq!(std::fs::read_to_string(out.join("sub/up")).unwrap(), "new contents");
+		assert!(crate::verify::test_dir_equality::<NoopReporter>(&b, &out, &cfg, Default::default()).unwrap().is_ok());
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn test_hardlinks_roundtrip() {
+		use std::os::unix::fs::MetadataExt;
+
+		let dir = generate_test_folder(FolderSpec::new()
+			.dir("a")
+			.file("b/orig", "linked twice")
+			.file("b/apart", "linked twice")
+			.dir("b/sub")
+		);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+		std::fs::hard_link(b.join("orig"), b.join("sub/link")).unwrap();
+
+		diff_folders(&a, &b, &root.join("ab.fldf"));
+		let mut applying = crate::applying::read_diff_from_file(&root.join("ab.fldf")).unwrap();
+		assert_eq!(applying.manifest().hardlinked_files.len(), 1);
+		assert!(applying.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(a, root.join("out"), &FoldiffCfg::default(), &StdVfs, None).unwrap().is_ok());
+
+		let out = root.join("out");
+		let ino = |p: &str| std::fs::metadata(out.join(p)).unwrap().ino();
+		assert_eq!(ino("orig"), ino("sub/link"));
+		// the same contents alone don't make a link
+		assert_ne!(ino("orig"), ino("apart"));
+		assert_folders_equal(&b, &out);
+	}
+
+	#[test]
+	fn test_identical_new_files() {
+		let files = ["x.gitkeep", "y.gitkeep", "z.gitkeep"];
+		let mut spec = FolderSpec::new();
+		spec.file("a/kept", "kept").file("b/kept", "kept").file("b/one", "same").file("b/two", "same");
+		for p in files {
+			spec.file(&format!("b/{p}"), "");
+		}
+		let dir = generate_test_folder(&spec);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+
+		let cfg = FoldiffCfg::default();
+		let out = root.join("ab.fldf");
+		scan_to_diff::<NoopReporter>(a.clone(), b, &cfg, &StdVfs).unwrap().write_to_file::<NoopReporter, NoopReporter>(&out, &cfg).unwrap();
+
+		// one blob for each distinct file
+		let mut diff = crate::applying::read_diff_from_file(&out).unwrap();
+		let m = diff.manifest();
+		assert!(m.new_files.is_empty() && m.patched_files.is_empty());
+		let mut indices: Vec<_> = m.duplicated_files.iter().map(|df| df.idx).collect();
+		indices.sort();
+		assert_eq!(indices, [0, 1]);
+
+		let mut reader = File::open(&out).unwrap();
+		let mut contents = String::new();
+		crate::catalog::seek_to_file(&mut reader, "two").unwrap().read_to_string(&mut contents).unwrap();
+		assert_eq!(contents, "same");
+
+		assert!(diff.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(a, root.join("out"), &cfg, &StdVfs, None).unwrap().is_ok());
+		for p in files {
+			assert_eq!(std::fs::read(root.join("out").join(p)).unwrap(), b"");
+		}
+		assert_eq!(std::fs::read(root.join("out/one")).unwrap(), b"same");
+		assert_eq!(std::fs::read(root.join("out/two")).unwrap(), b"same");
+	}
+
+	/// `versions` incrementally changed versions of the same binary, each with the same new data spliced into it in the new folder
+	fn make_versions(root: &Utf8Path, versions: usize, len: usize) -> (Utf8PathBuf, Utf8PathBuf) {
+		use rand::{Rng, RngCore};
+		let mut rng = rand::thread_rng();
+		let mut data = vec![0u8; len];
+		rng.fill_bytes(&mut data);
+		let mut update = vec![0u8; len / 4];
+		rng.fill_bytes(&mut update);
+		for v in 0..versions {
+			for _ in 0..10 {
+				data[rng.gen_range(0..len)] = rng.gen();
+			}
+			let updated = [&data[..len / 2], &update, &data[len / 2..]].concat();
+			for (side, contents) in [("a", &data), ("b", &updated)] {
+				let p = root.join(side).join(format!("v{v}"));
+				std::fs::create_dir_all(&p).unwrap();
+				std::fs::write(p.join("app.bin"), contents).unwrap();
+			}
+		}
+		(root.join("a"), root.join("b"))
+	}
+
+	#[test]
+	fn test_delta_chain() {
+		let dir = tempdir().unwrap();
+		let root = Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap();
+		let (a, b) = make_versions(&root, 3, 20_000);
+		std::fs::write(a.join("other"), "the quick brown fox".repeat(50)).unwrap();
+		std::fs::write(b.join("other"), "the quick brown cat".repeat(50)).unwrap();
+
+		let write = |cfg: &FoldiffCfg, name: &str| {
+			let out = root.join(name);
+			scan_to_diff::<NoopReporter>(a.clone(), b.clone(), cfg, &StdVfs).unwrap().write_to_file::<NoopReporter, NoopReporter>(&out, cfg).unwrap();
+			out
+		};
+		let plain = write(&FoldiffCfg::default(), "plain.fldf");
+		let cfg = FoldiffCfg { delta_chain: true, ..Default::default() };
+		let chained = write(&cfg, "chained.fldf");
+		// the update only has to be stored once
+		let len = |p: &Utf8Path| std::fs::metadata(p).unwrap().len();
+		assert!(len(&chained) < len(&plain) / 2, "{} vs {}", len(&chained), len(&plain));
+
+		let mut diff = crate::applying::read_diff_from_file(&chained).unwrap();
+		let m = diff.manifest();
+		assert!(m.delta_chain);
+		assert_eq!(m.patched_files.iter().map(|pf| (pf.path.as_str(), pf.index)).collect::<Vec<_>>(), [("v0/app.bin", 0), ("v1/app.bin", 1), ("v2/app.bin", 2), ("other", 3)]);
+
+		assert!(diff.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(a.clone(), root.join("out"), &cfg, &StdVfs, None).unwrap().is_ok());
+		for p in ["v0/app.bin", "v1/app.bin", "v2/app.bin", "other"] {
+			assert_eq!(std::fs::read(root.join("out").join(p)).unwrap(), std::fs::read(b.join(p)).unwrap(), "{p}");
+		}
+
+		let mut tar = Vec::new();
+		diff.apply_to_tar(&a, &mut tar, &cfg, &StdVfs).unwrap();
+		assert!(compose_diffs(&chained, &plain, &root.join("composed.fldf"), &cfg).is_err());
+	}
+
+	#[test]
+	fn test_delta_chain_large() {
+		// not really a test, but a benchmark: 10 versions of a 4mb binary, which all get the same update
+		let dir = tempdir().unwrap();
+		let root = Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap();
+		let (a, b) = make_versions(&root, 10, 4 << 20);
+
+		for delta_chain in [false, true] {
+			let cfg = FoldiffCfg { delta_chain, ..Default::default() };
+			let out = root.join(format!("{delta_chain}.fldf"));
+			let start = Instant::now();
+			scan_to_diff::<NoopReporter>(a.clone(), b.clone(), &cfg, &StdVfs).unwrap().write_to_file::<NoopReporter, NoopReporter>(&out, &cfg).unwrap();
+			let diffed = start.elapsed();
+
+			let start = Instant::now();
+			let mut diff = crate::applying::read_diff_from_file(&out).unwrap();
+			assert!(diff.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(a.clone(), root.join(format!("out-{delta_chain}")), &cfg, &StdVfs, None).unwrap().is_ok());
+			eprintln!("delta chain {delta_chain}: {} bytes, diff {diffed:?}, apply {:?}", std::fs::metadata(&out).unwrap().len(), start.elapsed());
+		}
+	}
+
+	#[test]
+	fn test_changed_after_scan() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("a/edited", "before".repeat(50))
+			.file("b/edited", "after".repeat(50))
+			.file("b/new", "new".repeat(50))
+		);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+		let cfg = FoldiffCfg::default();
+
+		let mut diff = scan_to_diff::<NoopReporter>(a.clone(), b.clone(), &cfg, &StdVfs).unwrap();
+		std::fs::write(b.join("new"), "changed").unwrap();
+		std::fs::remove_file(a.join("edited")).unwrap();
+		let err = diff.write_to::<NoopReporter, NoopReporter>(&mut Cursor::new(Vec::new()), &cfg, None).unwrap_err();
+		let msg = format!("{err:#}");
+		assert!(msg.starts_with("2 files changed after they were scanned"), "{msg}");
+		assert!(msg.contains("new\" was changed") && msg.contains("edited\" couldn't be read"), "{msg}");
+
+		// nothing was written, not even the header
+		let mut out = Cursor::new(Vec::new());
+		assert!(diff.write_to::<NoopReporter, NoopReporter>(&mut out, &cfg, None).is_err());
+		assert!(out.get_ref().is_empty());
+
+		// fine again once they're back how they were
+		std::fs::write(b.join("new"), "new".repeat(50)).unwrap();
+		std::fs::write(a.join("edited"), "before".repeat(50)).unwrap();
+		diff.write_to::<NoopReporter, NoopReporter>(&mut out, &cfg, None).unwrap();
+	}
+
+	#[test]
+	fn test_write_timings() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("a/edited", "before".repeat(50))
+			.file("b/edited", "after".repeat(50))
+			.file("b/new", "new".repeat(50))
+		);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+
+		let cfg = FoldiffCfg::default();
+		let t = scan_and_write_pipeline::<CountingReporter, CountingReporter>(a, b, &root.join("ab.fldf"), &cfg).unwrap().timings;
+		for (stage, time) in [("scan_old", t.scan_old), ("scan_new", t.scan_new), ("manifest_gen", t.manifest_gen), ("compress_new", t.compress_new), ("diff_patch", t.diff_patch)] {
+			assert!(time > Duration::ZERO, "{stage} took no time");
+		}
+
+		let r = <CountingReporter as Reporter>::new("stage");
+		assert_eq!(r.stage_elapsed(), None);
+		r.done();
+		assert!(r.stage_elapsed().is_some());
+	}
+
+	#[test]
+	fn test_estimate_size() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("a/same", "same".repeat(50))
+			.file("a/edited", "before".repeat(50))
+			.file("b/same", "same".repeat(50))
+			.file("b/edited", "after".repeat(50))
+			.file("b/new", "new".repeat(50))
+		);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+
+		let cfg = FoldiffCfg::default();
+		let mut diff = scan_to_diff::<NoopReporter>(a, b, &cfg, &StdVfs).unwrap();
+		let est = diff.estimate_size::<NoopReporter, NoopReporter>(&cfg).unwrap();
+		diff.write_to_file::<NoopReporter, NoopReporter>(&root.join("ab.fldf"), &cfg).unwrap();
+
+		assert_eq!(est.total, std::fs::metadata(root.join("ab.fldf")).unwrap().len());
+		assert_eq!(est.input_bytes, 250 + 150);
+	}
+
+	#[test]
+	fn test_estimate_size_matches_written() {
+		// incompressible files, and blobs padded out, so every part of the estimate is more than a few bytes
+		let dir = generate_test_folder(FolderSpec::new()
+			.with_random_binary("a/edited", 300_000)
+			.with_random_binary("b/edited", 300_000)
+			.with_random_binary("b/new", 200_000)
+			.file("b/sub/text", "some text ".repeat(1000))
+		);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+
+		let cfg = FoldiffCfg { blob_alignment: 4096, ..FoldiffCfg::default() };
+		let mut diff = scan_to_diff::<NoopReporter>(a, b, &cfg, &StdVfs).unwrap();
+		let est = diff.estimate_size::<NoopReporter, NoopReporter>(&cfg).unwrap();
+		let stats = diff.write_to_file::<NoopReporter, NoopReporter>(&root.join("ab.fldf"), &cfg).unwrap();
+		let written = std::fs::read(root.join("ab.fldf")).unwrap();
+		let u64_at = |pos: u64| u64::from_be_bytes(written[pos as usize..][..8].try_into().unwrap());
+
+		// find where each section really is from the file itself
+		let s = stats.sections;
+		assert_eq!(s.new_blobs % 4096, 0);
+		assert_eq!(u64_at(s.new_blobs), 2);
+		let mut pos = s.new_blobs + 8;
+		for _ in 0..2 {
+			pos += 8 + u64_at(pos);
+		}
+		assert_eq!(pos, s.patch_blobs);
+		assert_eq!(u64_at(s.patch_blobs), 1);
+		// there are no dictionaries, so the catalog comes straight after the patches, and ends by pointing back to its start
+		assert_eq!(u64_at(written.len() as u64 - 8), s.after_blobs);
+		assert_eq!(s.end, written.len() as u64);
+
+		assert_eq!(est.new_blob_bytes, s.patch_blobs - s.new_blobs);
+		assert_eq!(est.patch_blob_bytes, s.after_blobs - s.patch_blobs);
+		assert_eq!(est.manifest_bytes, stats.manifest_bytes);
+		assert_eq!(est.total, written.len() as u64);
+		assert_eq!(est.input_bytes, 300_000 + 200_000 + 10_000);
+	}
+
+	#[test]
+	fn test_level_for_budget() {
+		// level 9 at a quarter of the speed of level 1
+		let (speed_1, speed_9) = (400e6, 100e6);
+		assert_eq!(level_for_budget(400_000_000, speed_1, speed_9, 1.0), 1);
+		assert_eq!(level_for_budget(400_000_000, speed_1, speed_9, 4.01), 9);
+		assert_eq!(level_for_budget(400_000_000, speed_1, speed_9, 0.01), 1);
+		assert_eq!(level_for_budget(400_000_000, speed_1, speed_9, 1e9), 19);
+		let l = level_for_budget(400_000_000, speed_1, speed_9, 2.0);
+		assert!(l > 1 && l < 9, "{l}");
+	}
+
+	#[test]
+	fn test_time_budget() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("a/edited", "before".repeat(500))
+			.file("b/edited", "after".repeat(500))
+			.file("b/new", "new".repeat(500))
+		);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+
+		// plenty of time for something this small
+		let cfg = FoldiffCfg { time_budget_secs: Some(1000.0), ..Default::default() };
+		let stats = scan_and_write_pipeline::<NoopReporter, NoopReporter>(a.clone(), b.clone(), &root.join("ab.fldf"), &cfg).unwrap();
+		assert_eq!(stats.budget_level, Some(19));
+
+		let mut diff = crate::applying::read_diff_from_file(&root.join("ab.fldf")).unwrap();
+		assert!(diff.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(a, root.join("out"), &cfg, &StdVfs, None).unwrap().is_ok());
+		assert_eq!(std::fs::read(root.join("out/new")).unwrap(), "new".repeat(500).as_bytes());
+	}
+
+	#[test]
+	fn test_compose_diffs() {
+		let text = |s: &str| s.repeat(40);
+
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("a/same", text("same all along"))
+			.file("a/patched_ab", text("version a of this file"))
+			.file("a/patched_bc", text("this one changes later"))
+			.file("a/renamed", text("moving somewhere else"))
+			.file("a/deleted", text("gone soon"))
+			.file("b/same", text("same all along"))
+			.file("b/patched_ab", text("version b of this file"))
+			.file("b/patched_bc", text("this one changes later"))
+			.file("b/moved", text("moving somewhere else"))
+			.file("b/new_ab", text("added in b, edited in c"))
+			.file("b/new_ab_kept", text("added in b and kept"))
+			.file("c/same", text("same all along"))
+			.file("c/patched_ab", text("version b of this file"))
+			.file("c/patched_bc", text("this one changed later"))
+			.file("c/moved", text("moving somewhere else"))
+			.file("c/new_ab", text("added in b, edited in C"))
+			.file("c/new_ab_kept", text("added in b and kept"))
+			.file("c/new_bc", text("added in c"))
+		);
+		let root = root_of(&dir);
+		let (a, b, c) = (root.join("a"), root.join("b"), root.join("c"));
+
+		diff_folders(&a, &b, &root.join("ab.fldf"));
+		diff_folders(&b, &c, &root.join("bc.fldf"));
+		compose_diffs(&root.join("ab.fldf"), &root.join("bc.fldf"), &root.join("ac.fldf"), &FoldiffCfg::default()).unwrap();
+
+		let mut ac = crate::applying::read_diff_from_file(&root.join("ac.fldf")).unwrap();
+		assert_eq!(ac.manifest().deleted_files.len(), 1);
+		assert!(ac.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(a, root.join("out"), &FoldiffCfg::default(), &StdVfs, None).unwrap().is_ok());
+
+		for entry in std::fs::read_dir(&c).unwrap() {
+			let name = entry.unwrap().file_name();
+			assert_eq!(std::fs::read(c.join(name.to_str().unwrap())).unwrap(), std::fs::read(root.join("out").join(name.to_str().unwrap())).unwrap());
+		}
+		assert_eq!(std::fs::read_dir(root.join("out")).unwrap().count(), 7);
+	}
+
+	#[test]
+	fn test_compose_patched_twice() {
+
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("a/f", "first version".repeat(40))
+			.file("b/f", "second version".repeat(40))
+			.file("c/f", "third version".repeat(40))
+		);
+		let root = root_of(&dir);
+		let (a, b, c) = (root.join("a"), root.join("b"), root.join("c"));
+		diff_folders(&a, &b, &root.join("ab.fldf"));
+		diff_folders(&b, &c, &root.join("bc.fldf"));
+
+		let res = compose_diffs(&root.join("ab.fldf"), &root.join("bc.fldf"), &root.join("ac.fldf"), &FoldiffCfg::default());
+		assert!(res.is_err());
+	}
+
+	#[test]
+	fn test_invert_diff() {
+		let text = |s: &str| s.repeat(40);
+
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("a/same", text("same all along"))
+			.file("a/patched", text("version a of this file"))
+			.file("a/deleted", text("only in a"))
+			.file("b/same", text("same all along"))
+			.file("b/patched", text("version b of this file"))
+			.file("b/new", text("only in b"))
+		);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+		diff_folders(&a, &b, &root.join("ab.fldf"));
+		invert_diff::<NoopReporter, NoopReporter>(&root.join("ab.fldf"), &root.join("ba.fldf"), &a, &b, &FoldiffCfg::default()).unwrap();
+
+		let mut ba = crate::applying::read_diff_from_file(&root.join("ba.fldf")).unwrap();
+		let m = ba.manifest();
+		assert_eq!(m.deleted_files.iter().map(|(_, p)| p.as_str()).collect::<Vec<_>>(), ["new"]);
+		assert_eq!(m.new_files.iter().map(|nf| nf.path.as_str()).collect::<Vec<_>>(), ["deleted"]);
+		assert_eq!(m.patched_files.iter().map(|pf| pf.path.as_str()).collect::<Vec<_>>(), ["patched"]);
+		assert_eq!(m.untouched_files.iter().map(|(_, p)| p.as_str()).collect::<Vec<_>>(), ["same"]);
+
+		assert!(ba.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(b.clone(), root.join("out"), &FoldiffCfg::default(), &StdVfs, None).unwrap().is_ok());
+		for p in ["same", "patched", "deleted"] {
+			assert_eq!(std::fs::read(a.join(p)).unwrap(), std::fs::read(root.join("out").join(p)).unwrap());
+		}
+
+		// the folders have to be the ones the diff was made from
+		std::fs::write(b.join("new"), "something else").unwrap();
+		assert!(invert_diff::<NoopReporter, NoopReporter>(&root.join("ab.fldf"), &root.join("ba2.fldf"), &a, &b, &FoldiffCfg::default()).is_err());
+	}
+}
@@ -0,0 +1,164 @@
+// encrypting the manifest and every blob of a diff with AES-256-GCM, so only those with the key can read or apply it.
+// each piece is sealed on its own as the 12-byte nonce, then the ciphertext, then the 16-byte tag.
+// the lengths and chunk counts around blobs are left as they are, so diffs can still be walked through without the key.
+
+use std::fmt::{Debug, Formatter};
+use anyhow::{ensure, Context, Result};
+use camino::Utf8Path;
+use crate::zstddiff;
+
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+#[cfg(feature = "encryption")]
+const TAG_LEN: usize = 16;
+
+/// The key to encrypt a diff with, or to decrypt it when reading it.
+/// Actually encrypting or decrypting anything needs libfoldiff's `encryption` feature.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionCfg {
+	key: [u8; 32],
+}
+
+impl Debug for EncryptionCfg {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		// don't leak the key into logs
+		f.write_str("EncryptionCfg { .. }")
+	}
+}
+
+impl EncryptionCfg {
+	pub fn new(key: [u8; 32]) -> Self {
+		Self { key }
+	}
+
+	/// reads a key from a file containing exactly 32 bytes
+	pub fn from_key_file(path: &Utf8Path) -> Result<Self> {
+		let key = std::fs::read(path).with_context(|| format!("Failed to read key file {path}"))?;
+		let key = key.try_into().map_err(|k: Vec<u8>| anyhow::anyhow!("Key file {path} must be exactly 32 bytes, not {}", k.len()))?;
+		Ok(Self::new(key))
+	}
+
+	/// encrypts `data`, returning the nonce, ciphertext, and tag
+	#[cfg(feature = "encryption")]
+	pub(crate) fn seal(&self, data: &[u8]) -> Result<Vec<u8>> {
+		use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+		use aes_gcm::Aes256Gcm;
+
+		let cipher = Aes256Gcm::new(&self.key.into());
+		let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+		let sealed = cipher.encrypt(&nonce, data).map_err(|_| anyhow::anyhow!("Failed to encrypt"))?;
+		Ok([&nonce[..], &sealed].concat())
+	}
+
+	/// decrypts something from `seal`, failing if it was made with a different key or has been tampered with
+	#[cfg(feature = "encryption")]
+	pub(crate) fn open(&self, data: &[u8]) -> Result<Vec<u8>> {
+		use aes_gcm::aead::{Aead, KeyInit};
+		use aes_gcm::{Aes256Gcm, Nonce};
+
+		ensure!(data.len() >= NONCE_LEN + TAG_LEN, "Encrypted data is too short");
+		let (nonce, sealed) = data.split_at(NONCE_LEN);
+		Aes256Gcm::new(&self.key.into())
+			.decrypt(Nonce::from_slice(nonce), sealed)
+			.map_err(|_| anyhow::anyhow!("Failed to decrypt, the key is wrong or the diff is corrupt"))
+	}
+
+	#[cfg(not(feature = "encryption"))]
+	pub(crate) fn seal(&self, _data: &[u8]) -> Result<Vec<u8>> {
+		anyhow::bail!("Encrypting diffs requires libfoldiff's `encryption` feature")
+	}
+
+	#[cfg(not(feature = "encryption"))]
+	pub(crate) fn open(&self, _data: &[u8]) -> Result<Vec<u8>> {
+		anyhow::bail!("Decrypting diffs requires libfoldiff's `encryption` feature")
+	}
+
+	/// seals each chunk of a patch blob as written by zstddiff, leaving the chunk count and old chunk lengths as they are
+	pub(crate) fn seal_patch_blob(&self, blob: &[u8]) -> Result<Vec<u8>> {
+		map_patch_chunks(blob, |c| self.seal(c))
+	}
+
+	/// undoes `seal_patch_blob`
+	pub(crate) fn open_patch_blob(&self, blob: &[u8]) -> Result<Vec<u8>> {
+		map_patch_chunks(blob, |c| self.open(c))
+	}
+}
+
+/// rebuilds a patch blob with `f` applied to the data of each chunk
+fn map_patch_chunks(blob: &[u8], f: impl Fn(&[u8]) -> Result<Vec<u8>>) -> Result<Vec<u8>> {
+	let mut rest = blob;
+	let mut take = |n: u64| -> Result<&[u8]> {
+		ensure!(n <= rest.len() as u64, "Patch blob is cut off");
+		let (head, tail) = rest.split_at(n as usize);
+		rest = tail;
+		Ok(head)
+	};
+	let read_u64 = |b: &[u8]| u64::from_be_bytes(b.try_into().unwrap());
+
+	let count = take(8)?;
+	let (chunks, stored) = zstddiff::split_chunk_count(read_u64(count));
+	let mut out = count.to_vec();
+	if stored {
+		out.extend_from_slice(take(chunks.saturating_mul(8))?);
+	}
+	for _ in 0..chunks {
+		let len = read_u64(take(8)?);
+		// there's nothing to seal in an unchanged chunk
+		if len == zstddiff::IDENTICAL_CHUNK {
+			out.extend_from_slice(&len.to_be_bytes());
+			continue;
+		}
+		let data = f(take(len)?)?;
+		out.extend_from_slice(&(data.len() as u64).to_be_bytes());
+		out.extend_from_slice(&data);
+	}
+	ensure!(rest.is_empty(), "Patch blob has unexpected data at the end");
+	Ok(out)
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+	use super::*;
+	use std::io::Cursor;
+
+	#[test]
+	fn test_seal_open() {
+		let key = EncryptionCfg::new([7; 32]);
+		let sealed = key.seal(b"secret stuff").unwrap();
+		assert_eq!(sealed.len(), NONCE_LEN + 12 + TAG_LEN);
+		assert!(!sealed.windows(6).any(|w| w == b"secret"));
+		assert_eq!(key.open(&sealed).unwrap(), b"secret stuff");
+
+		// a fresh nonce every time
+		assert_ne!(key.seal(b"secret stuff").unwrap(), sealed);
+
+		assert!(EncryptionCfg::new([8; 32]).open(&sealed).is_err());
+		let mut tampered = sealed.clone();
+		tampered[NONCE_LEN] ^= 1;
+		assert!(key.open(&tampered).is_err());
+		assert!(key.open(&sealed[..10]).is_err());
+	}
+
+	#[test]
+	fn test_seal_patch_blob() {
+		let old = b"the quick brown fox jumps over the lazy dog".repeat(100);
+		let new = b"the quick brown cat jumps over the lazy dog".repeat(100);
+		let mut patch = Cursor::new(Vec::new());
+		zstddiff::diff(&mut Cursor::new(&old), &mut Cursor::new(&new), &mut patch, None, None, None, None).unwrap();
+		let patch = patch.into_inner();
+
+		let key = EncryptionCfg::new([7; 32]);
+		let sealed = key.seal_patch_blob(&patch).unwrap();
+		// one chunk, with a nonce and tag added to it
+		assert_eq!(sealed.len(), patch.len() + NONCE_LEN + TAG_LEN);
+		assert_eq!(key.open_patch_blob(&sealed).unwrap(), patch);
+		assert!(key.open_patch_blob(&sealed[..sealed.len() - 1]).is_err());
+
+		// unchanged chunks have nothing to seal
+		let mut patch = Cursor::new(Vec::new());
+		zstddiff::diff(&mut Cursor::new(&old), &mut Cursor::new(&old), &mut patch, None, None, None, None).unwrap();
+		let patch = patch.into_inner();
+		assert_eq!(key.seal_patch_blob(&patch).unwrap(), patch);
+		assert_eq!(key.open_patch_blob(&patch).unwrap(), patch);
+	}
+}
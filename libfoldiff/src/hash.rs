@@ -1,15 +1,35 @@
 use std::fs::File;
 use std::hash::Hasher;
 use std::io::{Read, Write};
+use std::time::SystemTime;
 use camino::Utf8Path;
+use memmap2::Mmap;
 use twox_hash::XxHash64;
+use xxhash_rust::xxh3::Xxh3Default;
+use crate::common::HashAlgorithm;
 
-#[derive(Clone, Default)]
-pub struct XXHasher(XxHash64);
+/// files at least this big are hashed through a memory map by `hash_file`
+pub const MMAP_THRESHOLD: u64 = 4 << 20;
+
+#[derive(Clone)]
+pub enum XXHasher {
+	XxHash64(XxHash64),
+	// xxh3 keeps a buffer in its state, so don't make every hasher that big
+	Xxh3(Box<Xxh3Default>),
+}
+
+impl Default for XXHasher {
+	fn default() -> Self {
+		Self::new(HashAlgorithm::default())
+	}
+}
 
 impl Write for XXHasher {
 	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-		self.0.write(buf);
+		match self {
+			Self::XxHash64(h) => h.write(buf),
+			Self::Xxh3(h) => h.write(buf),
+		}
 		Ok(buf.len())
 	}
 
@@ -19,8 +39,18 @@ impl Write for XXHasher {
 }
 
 impl XXHasher {
+	pub fn new(algo: HashAlgorithm) -> Self {
+		match algo {
+			HashAlgorithm::XxHash64 => Self::XxHash64(XxHash64::default()),
+			HashAlgorithm::Xxh3 => Self::Xxh3(Box::default()),
+		}
+	}
+
 	fn finish(&self) -> u64 {
-		self.0.finish()
+		match self {
+			Self::XxHash64(h) => h.finish(),
+			Self::Xxh3(h) => h.finish(),
+		}
 	}
 }
 
@@ -31,20 +61,69 @@ impl XXHasher {
 }*/
 
 pub fn hash_stream(s: &mut impl Read) -> std::io::Result<u64> {
-	let mut h = XXHasher::default();
+	hash_stream_with(s, HashAlgorithm::default())
+}
+
+pub fn hash_stream_with(s: &mut impl Read, algo: HashAlgorithm) -> std::io::Result<u64> {
+	let mut h = XXHasher::new(algo);
 	std::io::copy(s, &mut h)?;
 	Ok(h.finish())
 }
 
 pub fn hash_file(p: &Utf8Path) -> anyhow::Result<u64> {
-	Ok(hash_stream(&mut File::open(p)?)?)
+	hash_file_with(p, HashAlgorithm::default())
+}
+
+pub fn hash_file_with(p: &Utf8Path, algo: HashAlgorithm) -> anyhow::Result<u64> {
+	hash_file_with_threshold(p, algo, MMAP_THRESHOLD)
+}
+
+/// hashes a file, using a memory map if it is at least `mmap_threshold` bytes.
+/// mapping small files costs more than the copy it saves.
+pub fn hash_file_with_threshold(p: &Utf8Path, algo: HashAlgorithm, mmap_threshold: u64) -> anyhow::Result<u64> {
+	if std::fs::metadata(p)?.len() >= mmap_threshold {
+		hash_file_mmap(p, algo)
+	}
+	else {
+		Ok(hash_stream_with(&mut File::open(p)?, algo)?)
+	}
+}
+
+/// hashes a file by mapping it, so the hash runs straight over the page cache instead of a copy of it
+pub fn hash_file_mmap(p: &Utf8Path, algo: HashAlgorithm) -> anyhow::Result<u64> {
+	let f = File::open(p)?;
+	// can't map nothing on some platforms
+	if f.metadata()?.len() == 0 {
+		return Ok(hash_stream_with(&mut std::io::empty(), algo)?);
+	}
+
+	// safety: if the file is changed while we hash it, we just get the wrong hash, like we would with read()
+	let map = unsafe { Mmap::map(&f) }?;
+	let mut h = XXHasher::new(algo);
+	h.write_all(&map)?;
+	Ok(h.finish())
+}
+
+/// hashes a stream, checking its modification time before and after to detect it changing under us.
+/// returns the hash and if the stream was modified while hashing.
+pub fn hash_stream_detecting_changes<R: Read>(s: &mut R, mtime: impl Fn(&R) -> std::io::Result<SystemTime>) -> std::io::Result<(u64, bool)> {
+	// some platforms don't support mtimes, in which case this check can't do anything
+	let before = mtime(s).ok();
+	let hash = hash_stream(s)?;
+	let after = mtime(s).ok();
+	Ok((hash, before != after))
 }
 
 pub struct XXHashStreamer<S>(XXHasher, S);
 
 impl<S> XXHashStreamer<S> {
+	/// hashes with the latest algorithm. anything reading an existing diff must use `with_algorithm` instead.
 	pub fn new(w: S) -> Self {
-		Self(XXHasher::default(), w)
+		Self::with_algorithm(w, HashAlgorithm::default())
+	}
+
+	pub fn with_algorithm(w: S, algo: HashAlgorithm) -> Self {
+		Self(XXHasher::new(algo), w)
 	}
 
 	pub fn finish(&self) -> u64 {
@@ -75,7 +154,9 @@ impl<R: Read> Read for XXHashStreamer<R> {
 
 #[cfg(test)]
 mod tests {
+	use std::fs::FileTimes;
 	use std::io::Seek;
+	use std::time::Duration;
 	use super::*;
 	use tempfile::tempfile;
 
@@ -108,4 +189,102 @@ mod tests {
 		assert_eq!(hash_real, hash_hs_write);
 		assert_eq!(hash_real, hash_hs_read);
 	}
-}
\ No newline at end of file
+
+	// a file that gets modified by "someone else" as soon as we start reading it
+	struct RacingFile(File, bool);
+
+	impl Read for RacingFile {
+		fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+			if !self.1 {
+				self.1 = true;
+				let t = self.0.metadata()?.modified()? + Duration::from_secs(60);
+				self.0.set_times(FileTimes::new().set_modified(t))?;
+			}
+			self.0.read(buf)
+		}
+	}
+
+	#[test]
+	fn test_hash_detects_changes() {
+		let mut f = tempfile().unwrap();
+		f.write_all(&[1u8; 4096]).unwrap();
+		f.rewind().unwrap();
+
+		let (hash, changed) = hash_stream_detecting_changes(&mut f, |f| f.metadata()?.modified()).unwrap();
+		assert!(!changed);
+
+		f.rewind().unwrap();
+		let mut racing = RacingFile(f, false);
+		let (racing_hash, changed) = hash_stream_detecting_changes(&mut racing, |f| f.0.metadata()?.modified()).unwrap();
+		assert!(changed);
+		assert_eq!(hash, racing_hash);
+	}
+
+	#[test]
+	fn test_hash_file_mmap() {
+		let dir = tempfile::tempdir().unwrap();
+		let dir = Utf8Path::from_path(dir.path()).unwrap();
+		for (name, len) in [("empty", 0), ("small", 1000), ("big", MMAP_THRESHOLD as usize + 1)] {
+			let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+			std::fs::write(dir.join(name), &data).unwrap();
+
+			let expected = hash_stream(&mut &data[..]).unwrap();
+			assert_eq!(hash_file_mmap(&dir.join(name), HashAlgorithm::default()).unwrap(), expected, "{name}");
+			assert_eq!(hash_file(&dir.join(name)).unwrap(), expected, "{name}");
+		}
+	}
+
+	#[test]
+	fn test_hash_file_mmap_large() {
+		// not really a test, but a benchmark: compare against reading the file
+		let dir = tempfile::tempdir().unwrap();
+		let path = Utf8Path::from_path(dir.path()).unwrap().join("big");
+		{
+			let mut f = File::create(&path).unwrap();
+			let buf: Vec<u8> = (0..64 << 20).map(|i: u32| (i % 251) as u8).collect();
+			for _ in 0..16 {
+				f.write_all(&buf).unwrap();
+			}
+		}
+
+		let time = |name: &str, threshold: u64| {
+			let start = std::time::Instant::now();
+			let hash = hash_file_with_threshold(&path, HashAlgorithm::default(), threshold).unwrap();
+			eprintln!("{name}: {:.0} MiB/s", 1024.0 / start.elapsed().as_secs_f64());
+			hash
+		};
+
+		// read it once first, so both get the file from the page cache
+		time("read (cold)", u64::MAX);
+		assert_eq!(time("read", u64::MAX), time("mmap", 0));
+	}
+
+	#[test]
+	fn test_hash_algorithms() {
+		let data: Vec<u8> = (0..100_000).map(|i: u32| (i % 251) as u8).collect();
+		let h64 = hash_stream_with(&mut &data[..], HashAlgorithm::XxHash64).unwrap();
+		let h3 = hash_stream_with(&mut &data[..], HashAlgorithm::Xxh3).unwrap();
+
+		// old diffs must keep hashing the same way
+		let mut legacy = XxHash64::default();
+		legacy.write(&data);
+		assert_eq!(h64, legacy.finish());
+		assert_eq!(h3, xxhash_rust::xxh3::xxh3_64(&data));
+		assert_ne!(h64, h3);
+
+		let mut hs = XXHashStreamer::with_algorithm(std::io::sink(), HashAlgorithm::XxHash64);
+		hs.write_all(&data).unwrap();
+		assert_eq!(hs.finish(), h64);
+	}
+
+	#[test]
+	fn test_hash_algorithms_large() {
+		// not really a test, but a benchmark: compare xxh64 against xxh3
+		let buf: Vec<u8> = (0..1 << 30).map(|i: u32| (i % 251) as u8).collect();
+		for algo in [HashAlgorithm::XxHash64, HashAlgorithm::Xxh3] {
+			let start = std::time::Instant::now();
+			hash_stream_with(&mut &buf[..], algo).unwrap();
+			eprintln!("{algo:?}: {:.0} MiB/s", 1024.0 / start.elapsed().as_secs_f64());
+		}
+	}
+}
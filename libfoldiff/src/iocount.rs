@@ -0,0 +1,106 @@
+// counting how much data goes to and from disk, so it's possible to tell if a diff or apply was held up by I/O.
+// files are read and written from lots of rayon threads at once, so everything adds to shared atomics.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// How much data an operation read and wrote, across all threads
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct IoAccounting {
+	pub bytes_read: u64,
+	pub bytes_written: u64,
+}
+
+/// running totals for an operation, that readers and writers from any thread add to
+#[derive(Debug, Default)]
+pub(crate) struct IoCounters {
+	read: AtomicU64,
+	written: AtomicU64,
+}
+
+impl IoCounters {
+	pub fn reader<R: Read>(&self, inner: R) -> CountingReader<'_, R> {
+		CountingReader(inner, &self.read)
+	}
+
+	pub fn writer<W: Write>(&self, inner: W) -> CountingWriter<'_, W> {
+		CountingWriter(inner, &self.written)
+	}
+
+	/// for data that doesn't go through a reader, like a slice of a memory-mapped diff
+	pub fn add_read(&self, n: u64) {
+		self.read.fetch_add(n, Ordering::Relaxed);
+	}
+
+	/// for data that doesn't go through a writer, like a copy done by the OS
+	pub fn add_written(&self, n: u64) {
+		self.written.fetch_add(n, Ordering::Relaxed);
+	}
+
+	pub fn totals(&self) -> IoAccounting {
+		IoAccounting {
+			bytes_read: self.read.load(Ordering::Relaxed),
+			bytes_written: self.written.load(Ordering::Relaxed),
+		}
+	}
+}
+
+/// adds everything read through it to an IoCounters
+pub(crate) struct CountingReader<'a, R>(R, &'a AtomicU64);
+
+impl<R: Read> Read for CountingReader<'_, R> {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		let n = self.0.read(buf)?;
+		self.1.fetch_add(n as u64, Ordering::Relaxed);
+		Ok(n)
+	}
+}
+
+/// adds everything written through it to an IoCounters
+pub(crate) struct CountingWriter<'a, W>(W, &'a AtomicU64);
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		let n = self.0.write(buf)?;
+		self.1.fetch_add(n as u64, Ordering::Relaxed);
+		Ok(n)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.0.flush()
+	}
+}
+
+// diffing jumps around the old and new files, and the diff writer goes back to fill in lengths.
+// data that gets read or written twice is counted twice, as it really does go to and from disk twice
+impl<R: Read + Seek> Seek for CountingReader<'_, R> {
+	fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+		self.0.seek(pos)
+	}
+}
+
+impl<W: Write + Seek> Seek for CountingWriter<'_, W> {
+	fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+		self.0.seek(pos)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rayon::prelude::*;
+
+	#[test]
+	fn test_counters_across_threads() {
+		let io = IoCounters::default();
+		(0..64).into_par_iter().for_each(|i| {
+			let data = vec![0u8; i * 100];
+			let mut out = Vec::new();
+			std::io::copy(&mut io.reader(&data[..]), &mut io.writer(&mut out)).unwrap();
+		});
+		io.add_read(5);
+
+		let total = (0..64).map(|i| i * 100).sum::<u64>();
+		assert_eq!(io.totals(), IoAccounting { bytes_read: total + 5, bytes_written: total });
+	}
+}
@@ -4,10 +4,24 @@ pub mod diffing;
 pub mod zstddiff;
 mod hash;
 pub mod applying;
+pub mod catalog;
 mod threading;
+mod oplog;
+mod memory;
+mod iocount;
+mod scancache;
+mod encryption;
+mod compact;
 pub mod upgrade;
 pub mod verify;
 pub mod reporting;
+pub mod vfs;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
+#[cfg(test)]
+mod testing;
 
-pub use crate::threading::set_num_threads;
-pub use crate::common::FoldiffCfg;
\ No newline at end of file
+pub use crate::threading::{set_num_threads, CancellationToken};
+pub use crate::iocount::IoAccounting;
+pub use crate::encryption::EncryptionCfg;
+pub use crate::common::{ApplyError, ApplyOperation, DiffStrategy, ErrorAction, FoldiffCfg, FoldiffCfgBuilder, FoldiffError, HashAlgorithm, Phase, PhaseConcurrency, PhaseError, SymlinkMode, VerifyMode};
\ No newline at end of file
@@ -1,10 +1,17 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
 use std::io::{Read, Seek};
-use anyhow::{ensure, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use derivative::Derivative;
 use rmp_serde::Deserializer;
 use serde::{Deserialize, Serialize};
-use zstd::Decoder;
-use crate::common::{MAGIC_BYTES, VERSION_NUMBER_1_0_0_R, VERSION_NUMBER_1_1_0};
+use zstd::{Decoder, Encoder};
+use crate::common::{HashAlgorithm, MAGIC_BYTES, VERSION_NUMBER_1_0_0_R, VERSION_NUMBER_1_1_0, VERSION_NUMBER_1_2_0, VERSION_NUMBER_1_3_0};
+use crate::encryption::EncryptionCfg;
+
+/// v1.3.0+: set in the manifest length if the manifest and every blob are encrypted.
+/// older readers take it as an impossibly long manifest and fail
+pub(crate) const ENCRYPTED_FLAG: u64 = 1 << 63;
 
 /// Messagepack manifest structure stored in the diff file
 #[derive(Clone, Debug, Serialize, Deserialize, Derivative)]
@@ -17,8 +24,52 @@ pub struct DiffManifest {
     pub new_files: Vec<NewFile>,
     pub duplicated_files: Vec<DuplicatedFile>,
     pub patched_files: Vec<PatchedFile>,
+    // v1.2.0+ fields: these must stay at the end and default to empty so older manifests still parse
+    #[serde(default)]
+    pub symlinks: Vec<SymlinkEntry>,
+    #[serde(default)]
+    pub hardlinked_files: Vec<HardLinkedFile>,
+    #[serde(default)]
+    pub file_attributes: Vec<FileAttributes>,
+    #[serde(default)]
+    pub empty_dirs_new: Vec<String>,
+    #[serde(default)]
+    pub empty_dirs_deleted: Vec<String>,
+    // (hash, size) of every file content in the diff, as the same hash means the same size
+    #[serde(default)]
+    pub uncompressed_sizes: Vec<(u64, u64)>,
+    // indices of new blobs that are stored as-is instead of zstd compressed
+    #[serde(default)]
+    pub uncompressed_blobs: Vec<u64>,
+    // if the dictionaries section follows the patch blobs
+    #[serde(default)]
+    pub has_dictionaries: bool,
+    // v1.3.0+: what every hash in this manifest was made with. older diffs all used xxh64
+    #[serde(default = "HashAlgorithm::legacy")]
+    pub hash_algorithm: HashAlgorithm,
+    // v1.3.0+: (old, new) dirs that files were moved between together. the files are still listed in duplicated_files
+    #[serde(default)]
+    pub renamed_dirs: Vec<(String, String)>,
+    // v1.3.0+: if the diff ends with a catalog of where each new and patched file's blob is
+    #[serde(default)]
+    pub has_catalog: bool,
+    // v1.3.0+: the manifest is followed by zeroes up to a multiple of this many bytes from the start of the diff,
+    // where the blobs start. older diffs have no padding
+    #[serde(default = "no_alignment")]
+    #[derivative(Default(value="1"))]
+    pub blob_alignment: u64,
+    // v1.3.0+: if each patch blob was made with the end of the new contents of the patch blob before it as extra context
+    #[serde(default)]
+    pub delta_chain: bool,
+}
+
+fn no_alignment() -> u64 {
+    1
 }
 
+// at least this many files have to move from one dir to another for the dir to count as renamed
+const MIN_RENAMED_DIR_FILES: usize = 3;
+
 type HashAndPath = (u64, String);
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -26,6 +77,9 @@ pub struct NewFile {
     pub hash: u64,
     pub index: u64,
     pub path: String,
+    // v1.2.0+, None if the diff's default level was used
+    #[serde(default)]
+    pub compression_level: Option<u8>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -42,9 +96,175 @@ pub struct PatchedFile {
     pub new_hash: u64,
     pub index: u64,
     pub path: String,
+    // v1.2.0+, None if the diff's default level was used
+    #[serde(default)]
+    pub compression_level: Option<u8>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct SymlinkEntry {
+    pub path: String,
+    pub target: String,
+}
+
+/// a path in the new folder that is a hard link to another file in the new folder
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct HardLinkedFile {
+    pub path: String,
+    pub target: String,
+}
+
+/// metadata of a file in the new folder, restored after its contents are written
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct FileAttributes {
+    pub path: String,
+    pub mtime: Option<(i64, u32)>, // seconds, nanoseconds since the unix epoch
+    #[serde(default)]
+    pub mode: Option<u32>, // unix permission bits
+}
+
+/// writes one line of the summary, listing the first few paths as examples
+fn write_category<'a>(f: &mut Formatter<'_>, name: &str, unit: &str, paths: impl ExactSizeIterator<Item = &'a str>) -> std::fmt::Result {
+    const EXAMPLES: usize = 5;
+
+    let count = paths.len();
+    write!(f, "{name}: {count} {unit}")?;
+    if count != 0 {
+        let examples: Vec<_> = paths.take(EXAMPLES).collect();
+        write!(f, " (e.g. {}", examples.join(", "))?;
+        if count > EXAMPLES {
+            write!(f, ", ... and {} more", count - EXAMPLES)?;
+        }
+        write!(f, ")")?;
+    }
+    writeln!(f)
+}
+
+impl Display for DiffManifest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let dupes: Vec<&str> = self.duplicated_files.iter().flat_map(|d| d.new_paths.iter().map(String::as_str)).collect();
+        let renames: Vec<String> = self.renamed_dirs.iter().map(|(o, n)| format!("{o} -> {n}")).collect();
+
+        write_category(f, "Untouched", "files", self.untouched_files.iter().map(|(_, p)| p.as_str()))?;
+        write_category(f, "Deleted", "files", self.deleted_files.iter().map(|(_, p)| p.as_str()))?;
+        write_category(f, "New", "files", self.new_files.iter().map(|nf| nf.path.as_str()))?;
+        write_category(f, "Patched", "files", self.patched_files.iter().map(|pf| pf.path.as_str()))?;
+        write_category(f, "Duplicated", "files", dupes.into_iter())?;
+        write_category(f, "Renamed dirs", "dirs", renames.iter().map(String::as_str))?;
+        write_category(f, "Symlinks", "links", self.symlinks.iter().map(|sl| sl.path.as_str()))?;
+        write_category(f, "Hard links", "links", self.hardlinked_files.iter().map(|hl| hl.path.as_str()))?;
+        write_category(f, "New empty dirs", "dirs", self.empty_dirs_new.iter().map(String::as_str))?;
+        write_category(f, "Deleted empty dirs", "dirs", self.empty_dirs_deleted.iter().map(String::as_str))
+    }
+}
+
+/// where the contents of a file in the new folder come from when applying
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Source<'a> {
+    /// copied from this path in the old folder
+    Old(&'a str),
+    /// a new blob in the diff
+    NewBlob(u64),
+    /// a patch blob in the diff, applied to the same path in the old folder
+    Patch(u64),
+}
+
+/// if `new` is `old` with the dirs at the start swapped for different ones, which (old, new) dirs those are.
+/// e.g. `src/a/b.rs` and `source/a/b.rs` gives `src` and `source`
+fn dir_rename<'a>(old: &'a str, new: &'a str) -> Option<(&'a str, &'a str)> {
+    let (mut o, mut n) = (old, new);
+    while let (Some((op, oc)), Some((np, nc))) = (o.rsplit_once('/'), n.rsplit_once('/')) {
+        if oc != nc {
+            break;
+        }
+        (o, n) = (op, np);
+    }
+    // we need the file name to match, and a dir on both sides
+    (o.len() != old.len() && o != n).then_some((o, n))
+}
+
+/// finds dirs that enough files moved between, all keeping the same path relative to them
+pub(crate) fn find_renamed_dirs(duplicated_files: &[DuplicatedFile]) -> Vec<(String, String)> {
+    let mut counts: BTreeMap<(&str, &str), usize> = BTreeMap::new();
+    for d in duplicated_files {
+        if let ([old], [new], u64::MAX) = (&d.old_paths[..], &d.new_paths[..], d.idx) {
+            if let Some(rename) = dir_rename(old, new) {
+                *counts.entry(rename).or_default() += 1;
+            }
+        }
+    }
+
+    counts.into_iter()
+        .filter(|(_, n)| *n >= MIN_RENAMED_DIR_FILES)
+        .map(|((o, n), _)| (o.to_string(), n.to_string()))
+        .collect()
+}
+
+fn find_repeats<'a>(paths: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let mut seen = HashSet::new();
+    paths.filter(|p| !seen.insert(*p)).collect()
+}
+
+/// a single entry of the manifest, as returned by [`DiffManifest::iter_all_files`]
+#[derive(Copy, Clone, Debug)]
+pub enum ManifestEntry<'a> {
+    Untouched(&'a str),
+    Deleted(&'a str),
+    New(&'a NewFile),
+    Patched(&'a PatchedFile),
+    Duplicated { old: &'a [String], new: &'a [String] },
 }
 
 impl DiffManifest {
+    /// an empty manifest, for hashes made with `algo`
+    pub(crate) fn hashed_with(algo: HashAlgorithm) -> Self {
+        Self { hash_algorithm: algo, ..Default::default() }
+    }
+
+    /// if `d` is a file that moved along with the rest of a renamed dir
+    pub(crate) fn in_renamed_dir(&self, d: &DuplicatedFile) -> bool {
+        let ([old], [new], u64::MAX) = (&d.old_paths[..], &d.new_paths[..], d.idx)
+        else { return false };
+        dir_rename(old, new).is_some_and(|(o, n)| self.renamed_dirs.iter().any(|(ro, rn)| ro == o && rn == n))
+    }
+
+    /// iterates over every file entry in the manifest, regardless of what happened to it
+    pub fn iter_all_files(&self) -> impl Iterator<Item = ManifestEntry<'_>> {
+        self.untouched_files.iter().map(|(_, p)| ManifestEntry::Untouched(p))
+            .chain(self.deleted_files.iter().map(|(_, p)| ManifestEntry::Deleted(p)))
+            .chain(self.new_files.iter().map(ManifestEntry::New))
+            .chain(self.patched_files.iter().map(ManifestEntry::Patched))
+            .chain(self.duplicated_files.iter().map(|d| ManifestEntry::Duplicated { old: &d.old_paths, new: &d.new_paths }))
+    }
+
+    /// iterates over the path of every file that should exist in the new folder
+    pub fn iter_new_paths(&self) -> impl Iterator<Item = &str> {
+        self.iter_all_files().flat_map(|e| {
+            let paths: Vec<&str> = match e {
+                ManifestEntry::Untouched(p) => vec![p],
+                ManifestEntry::Deleted(_) => vec![],
+                ManifestEntry::New(nf) => vec![&nf.path],
+                ManifestEntry::Patched(pf) => vec![&pf.path],
+                ManifestEntry::Duplicated { new, .. } => new.iter().map(String::as_str).collect(),
+            };
+            paths
+        })
+    }
+
+    /// iterates over the path of every file that should exist in the old folder
+    pub fn iter_old_paths(&self) -> impl Iterator<Item = &str> {
+        self.iter_all_files().flat_map(|e| {
+            let paths: Vec<&str> = match e {
+                ManifestEntry::Untouched(p) => vec![p],
+                ManifestEntry::Deleted(p) => vec![p],
+                ManifestEntry::New(_) => vec![],
+                ManifestEntry::Patched(pf) => vec![&pf.path],
+                ManifestEntry::Duplicated { old, .. } => old.iter().map(String::as_str).collect(),
+            };
+            paths
+        })
+    }
+
     pub(crate) fn read_100r(reader: impl Read) -> Result<Self> {
         let mut deserializer = Deserializer::new(reader);
         let manifest =
@@ -60,16 +280,42 @@ impl DiffManifest {
         Ok(manifest)
     }
 
-    pub(crate) fn read_110(mut reader: impl Read) -> Result<Self> {
+    // v1.1.0 onwards all compress the manifest. v1.2.0 adds fields to the end of the messagepack,
+    // and v1.3.0 switches to the compact format.
+    // also returns if the diff is encrypted
+    pub(crate) fn read_110(mut reader: impl Read, ver: [u8; 4], key: Option<&EncryptionCfg>) -> Result<(Self, bool)> {
         // read compressed data length
         let mut len = [0u8; 8];
         reader.read_exact(&mut len)?;
         let len = u64::from_be_bytes(len);
 
-        let decoder = Decoder::new(reader.take(len))?;
-        let mut deser = Deserializer::new(decoder);
+        let decompress = |r: &mut dyn Read| -> Result<Self> {
+            let decoder = Decoder::new(r)?;
+            if ver == VERSION_NUMBER_1_3_0 {
+                Self::read_compact(decoder)
+            }
+            else {
+                DiffManifest::deserialize(&mut Deserializer::new(decoder)).context("Failed to deserialize diff format")
+            }
+        };
 
-        DiffManifest::deserialize(&mut deser).context("Failed to deserialize diff format")
+        if len & ENCRYPTED_FLAG == 0 {
+            return Ok((decompress(&mut reader.take(len))?, false));
+        }
+
+        let Some(key) = key
+        else { bail!("Diff is encrypted, a key is needed to read it") };
+        let mut sealed = Vec::new();
+        reader.take(len & !ENCRYPTED_FLAG).read_to_end(&mut sealed)?;
+        let compressed = key.open(&sealed).context("Failed to decrypt manifest")?;
+        Ok((decompress(&mut &compressed[..])?, true))
+    }
+
+    /// the manifest in the compact format and zstd compressed, as it is stored in diffs
+    pub(crate) fn to_compressed(&self) -> Result<Vec<u8>> {
+        let mut enc = Encoder::new(Vec::new(), 19)?;
+        self.write_compact(&mut enc).context("Failed to serialize diff format")?;
+        Ok(enc.finish()?)
     }
 
     // checks the magic bytes are valid, reads the version, rewinds by 4 bytes if 1.0.0-r, and returns it.
@@ -92,7 +338,7 @@ impl DiffManifest {
             // null byte, we are using a compressed manifest
             // check version
             ensure!(
-				ver == VERSION_NUMBER_1_1_0,
+				ver == VERSION_NUMBER_1_1_0 || ver == VERSION_NUMBER_1_2_0 || ver == VERSION_NUMBER_1_3_0,
 				"Did not recognise version number {:x?}",
 				ver
 			);
@@ -105,14 +351,436 @@ impl DiffManifest {
         }
     }
 
-    pub fn read_from(mut reader: impl Read+Seek) -> Result<Self> {
+    /// checks that the manifest makes sense given how many blobs the diff has,
+    /// so that applying it can't read the wrong data or panic.
+    /// returns a description of every problem found.
+    pub fn validate(&self, new_blob_count: u64, patch_blob_count: u64) -> std::result::Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+
+        for nf in &self.new_files {
+            if nf.index >= new_blob_count {
+                problems.push(format!("new file {} has index {}, but there are only {new_blob_count} new blobs", nf.path, nf.index));
+            }
+        }
+        for pf in &self.patched_files {
+            if pf.index >= patch_blob_count {
+                problems.push(format!("patched file {} has index {}, but there are only {patch_blob_count} patch blobs", pf.path, pf.index));
+            }
+        }
+        for df in &self.duplicated_files {
+            if df.idx != u64::MAX && df.idx >= new_blob_count {
+                problems.push(format!("duplicated file with hash {:x} has index {}, but there are only {new_blob_count} new blobs", df.hash, df.idx));
+            }
+        }
+
+        // each path can only be one thing on each side
+        let new_paths =
+            self.iter_new_paths()
+                .chain(self.symlinks.iter().map(|sl| sl.path.as_str()))
+                .chain(self.hardlinked_files.iter().map(|hl| hl.path.as_str()));
+        for p in find_repeats(new_paths) {
+            problems.push(format!("{p} appears more than once in the new folder"));
+        }
+        // an old file can be both copied and patched, so it can be listed more than once, but only ever with one hash
+        let mut old_hashes = HashMap::new();
+        let mut conflicting = BTreeSet::new();
+        for (p, h) in self.old_entries() {
+            if old_hashes.insert(p, h).is_some_and(|prev| prev != h) {
+                conflicting.insert(p);
+            }
+        }
+        for p in conflicting {
+            problems.push(format!("{p} has more than one hash in the old folder"));
+        }
+
+        if problems.is_empty() { Ok(()) } else { Err(problems) }
+    }
+
+    /// every path in the old folder with its hash. a path can come up more than once, e.g. if it was copied and patched
+    fn old_entries(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.untouched_files.iter().chain(&self.deleted_files).map(|(h, p)| (p.as_str(), *h))
+            .chain(self.patched_files.iter().map(|pf| (pf.path.as_str(), pf.old_hash)))
+            .chain(self.duplicated_files.iter().flat_map(|df| df.old_paths.iter().map(|p| (p.as_str(), df.hash))))
+    }
+
+    /// maps each path in the old folder to its hash
+    pub(crate) fn old_state(&self) -> HashMap<&str, u64> {
+        self.old_entries().collect()
+    }
+
+    /// maps each file in the new folder to its hash and where it comes from. does not include hard links.
+    pub(crate) fn new_state(&self) -> Result<HashMap<&str, (u64, Source<'_>)>> {
+        let mut state = HashMap::new();
+        for (h, p) in &self.untouched_files {
+            state.insert(p.as_str(), (*h, Source::Old(p)));
+        }
+        for nf in &self.new_files {
+            state.insert(nf.path.as_str(), (nf.hash, Source::NewBlob(nf.index)));
+        }
+        for pf in &self.patched_files {
+            state.insert(pf.path.as_str(), (pf.new_hash, Source::Patch(pf.index)));
+        }
+        for df in &self.duplicated_files {
+            let src =
+                if df.idx == u64::MAX {
+                    let Some(p) = df.old_paths.first() else {
+                        bail!("Duplicated file with hash {:x} has no data and nothing to copy from", df.hash);
+                    };
+                    Source::Old(p)
+                }
+                else {
+                    Source::NewBlob(df.idx)
+                };
+
+            for p in &df.new_paths {
+                state.insert(p.as_str(), (df.hash, src));
+            }
+        }
+        Ok(state)
+    }
+
+    /// Combines a diff from A to B, and a diff from B to C, into one diff from A to C.
+    ///
+    /// The result uses the blobs stored in `bc`, so this fails if any file in C would need data from `ab`,
+    /// such as a file that is new in `ab` and untouched in `bc`, or patched in both.
+    pub fn merge(ab: &DiffManifest, bc: &DiffManifest) -> Result<DiffManifest> {
+        // hashes from different algorithms can't be compared
+        ensure!(
+            ab.hash_algorithm == bc.hash_algorithm,
+            "The diffs were hashed differently ({:?} and {:?}), upgrade both first",
+            ab.hash_algorithm, bc.hash_algorithm
+        );
+
+        let a = ab.old_state();
+        let mut b = ab.new_state()?;
+        // bc sees these as normal files in its old folder
+        for hl in &ab.hardlinked_files {
+            let target = *b.get(hl.target.as_str()).with_context(|| format!("Hard link {} points to {}, which is not in the diff", hl.path, hl.target))?;
+            b.insert(&hl.path, target);
+        }
+
+        let b_bc = bc.old_state();
+        ensure!(
+            b.len() == b_bc.len() && b_bc.iter().all(|(p, h)| b.get(p).is_some_and(|(h2, _)| h == h2)),
+            "The second diff does not start from the folder that the first diff creates"
+        );
+
+        // sorted so the result is the same every time
+        let c: BTreeMap<_, _> = bc.new_state()?.into_iter().collect();
+
+        let mut merged = DiffManifest {
+            version: bc.version,
+            hash_algorithm: bc.hash_algorithm,
+            ..Default::default()
+        };
+
+        // hash -> paths in C that are copied from A
+        let mut copies: BTreeMap<u64, Vec<&str>> = BTreeMap::new();
+        // hash -> (blob index, paths in C)
+        let mut blobs: BTreeMap<u64, (u64, Vec<&str>)> = BTreeMap::new();
+
+        for (p, (h, src)) in &c {
+            match src {
+                Source::Old(q) => match b[q].1 {
+                    Source::Old(_) => copies.entry(*h).or_default().push(p),
+                    _ => bail!("{p} needs data stored in the first diff, which merging does not support yet"),
+                },
+                Source::NewBlob(i) => blobs.entry(*h).or_insert((*i, Vec::new())).1.push(p),
+                Source::Patch(i) => match b[p].1 {
+                    // the patch is against the same file in A
+                    Source::Old(q) if q == *p => merged.patched_files.push(PatchedFile {
+                        old_hash: a[p],
+                        new_hash: *h,
+                        index: *i,
+                        path: p.to_string(),
+                        compression_level: bc.patched_files.iter().find(|pf| pf.path == *p).and_then(|pf| pf.compression_level),
+                    }),
+                    _ => bail!("{p} is patched on top of a change in the first diff, which merging does not support yet"),
+                },
+            }
+        }
+
+        let mut a_by_hash: BTreeMap<u64, Vec<&str>> = BTreeMap::new();
+        for (p, h) in &a {
+            a_by_hash.entry(*h).or_default().push(p);
+        }
+        for paths in a_by_hash.values_mut() {
+            paths.sort();
+        }
+
+        for (h, new_paths) in copies {
+            let old_paths = &a_by_hash[&h];
+            if old_paths.len() == 1 && new_paths.len() == 1 && old_paths[0] == new_paths[0] {
+                merged.untouched_files.push((h, new_paths[0].to_string()));
+            }
+            else {
+                merged.duplicated_files.push(DuplicatedFile {
+                    hash: h,
+                    idx: u64::MAX,
+                    old_paths: old_paths.iter().map(|p| p.to_string()).collect(),
+                    new_paths: new_paths.iter().map(|p| p.to_string()).collect(),
+                });
+            }
+        }
+
+        for (h, (idx, paths)) in blobs {
+            if let [p] = paths[..] {
+                let compression_level = bc.new_files.iter().find(|nf| nf.index == idx).and_then(|nf| nf.compression_level);
+                merged.new_files.push(NewFile { hash: h, index: idx, path: p.to_string(), compression_level });
+            }
+            else {
+                merged.duplicated_files.push(DuplicatedFile {
+                    hash: h,
+                    idx,
+                    old_paths: Vec::new(),
+                    new_paths: paths.iter().map(|p| p.to_string()).collect(),
+                });
+            }
+        }
+
+        // anything left over in A is gone by C
+        for (h, paths) in &a_by_hash {
+            if merged.duplicated_files.iter().any(|df| df.hash == *h && df.idx == u64::MAX) {
+                continue;
+            }
+            for p in paths {
+                if !c.contains_key(p) {
+                    merged.deleted_files.push((*h, p.to_string()));
+                }
+            }
+        }
+
+        // these all describe C, so only bc knows about them
+        merged.symlinks = bc.symlinks.clone();
+        merged.hardlinked_files = bc.hardlinked_files.clone();
+        merged.file_attributes = bc.file_attributes.clone();
+        merged.empty_dirs_new = bc.empty_dirs_new.clone();
+        // blob indices are bc's, so how the blobs are stored is too
+        merged.uncompressed_blobs = bc.uncompressed_blobs.clone();
+        merged.has_dictionaries = bc.has_dictionaries;
+
+        // dirs removed by either diff, unless bc removed one that ab created
+        let created_by_ab: BTreeSet<_> = ab.empty_dirs_new.iter().collect();
+        let mut deleted_dirs: BTreeSet<_> = ab.empty_dirs_deleted.iter().collect();
+        deleted_dirs.extend(bc.empty_dirs_deleted.iter().filter(|d| !created_by_ab.contains(d)));
+        merged.empty_dirs_deleted = deleted_dirs.into_iter().cloned().collect();
+
+        let sizes: BTreeMap<_, _> = ab.uncompressed_sizes.iter().chain(&bc.uncompressed_sizes).copied().collect();
+        merged.uncompressed_sizes = sizes.into_iter().collect();
+        merged.renamed_dirs = find_renamed_dirs(&merged.duplicated_files);
+
+        Ok(merged)
+    }
+
+    /// a short human-readable summary of what the diff contains, for debugging
+    pub fn display_summary(&self) -> String {
+        self.to_string()
+    }
+
+    /// builds a lookup of file sizes by hash from `uncompressed_sizes`
+    pub fn size_lookup(&self) -> HashMap<u64, u64> {
+        self.uncompressed_sizes.iter().copied().collect()
+    }
+
+    /// how many bytes every file in the new folder adds up to. hard links and symlinks are not counted.
+    /// None if the size of any of them isn't known, e.g. for diffs from before fldf 1.2.0.
+    /// sizes come from the diff, so this saturates at u64::MAX instead of overflowing, which never fits
+    pub fn required_disk_bytes(&self) -> Option<u64> {
+        let sizes = self.size_lookup();
+        let untouched = self.untouched_files.iter().map(|(hash, _)| (*hash, 1));
+        let new = self.new_files.iter().map(|nf| (nf.hash, 1));
+        let duplicated = self.duplicated_files.iter().map(|d| (d.hash, d.new_paths.len() as u64));
+        let patched = self.patched_files.iter().map(|pf| (pf.new_hash, 1));
+
+        untouched.chain(new).chain(duplicated).chain(patched)
+            .try_fold(0u64, |total, (hash, copies)| {
+                sizes.get(&hash).map(|s| total.saturating_add(s.saturating_mul(copies)))
+            })
+    }
+
+    pub fn read_from(reader: impl Read+Seek) -> Result<Self> {
+        Self::read_from_with_key(reader, None).map(|(m, _)| m)
+    }
+
+    /// reads the manifest of a diff that may be encrypted with `key`
+    pub fn read_encrypted_from(reader: impl Read+Seek, key: &EncryptionCfg) -> Result<Self> {
+        Self::read_from_with_key(reader, Some(key)).map(|(m, _)| m)
+    }
+
+    // also returns if the diff is encrypted. diffs that aren't are read the same with or without a key
+    pub(crate) fn read_from_with_key(mut reader: impl Read+Seek, key: Option<&EncryptionCfg>) -> Result<(Self, bool)> {
         let ver = Self::verify_and_read_ver(&mut reader)?;
 
         if ver == VERSION_NUMBER_1_0_0_R {
-            Self::read_100r(reader)
+            Ok((Self::read_100r(reader)?, false))
         }
         else {
-            Self::read_110(reader)
+            Self::read_110(reader, ver, key)
         }
     }
 }
+
+/// Builds a manifest by hand, for tests and other tools.
+/// Does not check that the result makes sense.
+#[derive(Clone, Debug, Default)]
+pub struct DiffManifestBuilder {
+    manifest: DiffManifest,
+}
+
+impl DiffManifestBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_untouched(&mut self, hash: u64, path: impl Into<String>) -> &mut Self {
+        self.manifest.untouched_files.push((hash, path.into()));
+        self
+    }
+
+    pub fn add_deleted(&mut self, hash: u64, path: impl Into<String>) -> &mut Self {
+        self.manifest.deleted_files.push((hash, path.into()));
+        self
+    }
+
+    pub fn add_new(&mut self, hash: u64, path: impl Into<String>, index: u64) -> &mut Self {
+        self.manifest.new_files.push(NewFile { hash, index, path: path.into(), compression_level: None });
+        self
+    }
+
+    pub fn add_patched(&mut self, old_hash: u64, new_hash: u64, path: impl Into<String>, index: u64) -> &mut Self {
+        self.manifest.patched_files.push(PatchedFile { old_hash, new_hash, index, path: path.into(), compression_level: None });
+        self
+    }
+
+    /// `idx` is u64::MAX if the file is not stored in a blob
+    pub fn add_duplicated(&mut self, hash: u64, old_paths: Vec<String>, new_paths: Vec<String>, idx: u64) -> &mut Self {
+        self.manifest.duplicated_files.push(DuplicatedFile { hash, idx, old_paths, new_paths });
+        self
+    }
+
+    pub fn build(&self) -> DiffManifest {
+        self.manifest.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let good = DiffManifestBuilder::new()
+            .add_untouched(1, "a")
+            .add_new(2, "b", 0)
+            .add_patched(3, 4, "c", 0)
+            .add_duplicated(5, vec![], vec!["d".into(), "e".into()], 1)
+            .build();
+        assert!(good.validate(2, 1).is_ok());
+
+        let bad = DiffManifestBuilder::new()
+            .add_untouched(1, "a")
+            .add_new(2, "a", 3)
+            .add_patched(3, 4, "c", 1)
+            .build();
+        let problems = bad.validate(1, 1).unwrap_err();
+        assert_eq!(problems.len(), 3);
+
+        // a file copied somewhere else and then edited is listed twice on the old side, which is fine
+        let copied_and_patched = DiffManifestBuilder::new()
+            .add_duplicated(1, vec!["a".into()], vec!["b".into()], u64::MAX)
+            .add_patched(1, 2, "a", 0)
+            .build();
+        assert!(copied_and_patched.validate(0, 1).is_ok());
+
+        // but it can't have been two different files
+        let conflicting = DiffManifestBuilder::new()
+            .add_deleted(1, "a")
+            .add_patched(3, 2, "a", 0)
+            .build();
+        assert_eq!(conflicting.validate(0, 1).unwrap_err(), ["a has more than one hash in the old folder"]);
+    }
+
+    #[test]
+    fn test_required_disk_bytes() {
+        let mut manifest = DiffManifestBuilder::new()
+            .add_untouched(1, "a")
+            .add_deleted(2, "gone")
+            .add_new(3, "b", 0)
+            .add_patched(4, 5, "c", 0)
+            .add_duplicated(6, vec!["d".into()], vec!["e".into(), "f".into()], u64::MAX)
+            .build();
+        manifest.uncompressed_sizes = vec![(1, 1), (2, 10), (3, 100), (4, 1000), (5, 10_000), (6, 100_000)];
+        // old files that aren't in the new folder aren't counted
+        assert_eq!(manifest.required_disk_bytes(), Some(1 + 100 + 10_000 + 2 * 100_000));
+
+        // a diff can claim any sizes it likes
+        manifest.uncompressed_sizes[5] = (6, u64::MAX / 2 + 1);
+        assert_eq!(manifest.required_disk_bytes(), Some(u64::MAX));
+
+        manifest.uncompressed_sizes.retain(|(hash, _)| *hash != 5);
+        assert_eq!(manifest.required_disk_bytes(), None);
+    }
+
+    #[test]
+    fn test_merge() {
+        // a: x, y, z -> b: x, y2 (renamed), n (new) -> c: x (patched), y2, n, m (new)
+        let ab = DiffManifestBuilder::new()
+            .add_untouched(1, "x")
+            .add_duplicated(2, vec!["y".into()], vec!["y2".into()], u64::MAX)
+            .add_deleted(3, "z")
+            .add_new(4, "n", 0)
+            .build();
+        let mut bc = DiffManifestBuilder::new()
+            .add_patched(1, 5, "x", 0)
+            .add_untouched(2, "y2")
+            .add_deleted(4, "n")
+            .add_new(6, "m", 0)
+            .add_new(7, "raw", 1)
+            .build();
+        bc.uncompressed_blobs = vec![1];
+        bc.has_dictionaries = true;
+
+        let ac = DiffManifest::merge(&ab, &bc).unwrap();
+        assert_eq!(ac.patched_files.len(), 1);
+        assert_eq!(ac.patched_files[0].old_hash, 1);
+        assert_eq!(ac.duplicated_files.len(), 1);
+        assert_eq!(ac.duplicated_files[0].old_paths, ["y"]);
+        assert_eq!(ac.duplicated_files[0].new_paths, ["y2"]);
+        assert_eq!(ac.deleted_files, [(3, "z".to_string())]);
+        assert_eq!(ac.new_files.iter().map(|nf| nf.path.as_str()).collect::<Vec<_>>(), ["m", "raw"]);
+        assert!(ac.untouched_files.is_empty());
+        // still stored the same way as in bc
+        assert_eq!(ac.uncompressed_blobs, [1]);
+        assert!(ac.has_dictionaries);
+
+        // n is new in ab, so c would need its blob
+        let bc = DiffManifestBuilder::new()
+            .add_untouched(1, "x")
+            .add_untouched(2, "y2")
+            .add_untouched(4, "n")
+            .build();
+        assert!(DiffManifest::merge(&ab, &bc).is_err());
+    }
+
+    #[test]
+    fn test_find_renamed_dirs() {
+        assert_eq!(dir_rename("src/a/b.rs", "source/a/b.rs"), Some(("src", "source")));
+        assert_eq!(dir_rename("x/src/b.rs", "x/source/b.rs"), Some(("x/src", "x/source")));
+        assert_eq!(dir_rename("src/a.rs", "src/b.rs"), None);
+        assert_eq!(dir_rename("a.rs", "src/a.rs"), None);
+
+        let mut builder = DiffManifestBuilder::new();
+        for (i, f) in ["a", "b", "c/d"].into_iter().enumerate() {
+            builder.add_duplicated(i as u64, vec![format!("src/{f}")], vec![format!("source/{f}")], u64::MAX);
+        }
+        // not enough of these to count
+        builder.add_duplicated(3, vec!["docs/e".into()], vec!["doc/e".into()], u64::MAX);
+        builder.add_duplicated(4, vec!["docs/f".into()], vec!["doc/f".into()], u64::MAX);
+        let mut manifest = builder.build();
+
+        manifest.renamed_dirs = find_renamed_dirs(&manifest.duplicated_files);
+        assert_eq!(manifest.renamed_dirs, [("src".to_string(), "source".to_string())]);
+        assert!(manifest.duplicated_files[..3].iter().all(|d| manifest.in_renamed_dir(d)));
+        assert!(!manifest.in_renamed_dir(&manifest.duplicated_files[3]));
+    }
+}
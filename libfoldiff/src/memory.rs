@@ -0,0 +1,56 @@
+// peak memory use of the process, so users know how much RAM a diff or apply needs.
+// every platform does this differently, so it's behind the memory-tracking feature,
+// and everything here does nothing without it.
+
+/// starts counting the peak again from how much memory is in use right now.
+/// only linux can do this, elsewhere the peak is for the whole life of the process.
+pub(crate) fn reset_peak_rss() {
+	#[cfg(all(feature = "memory-tracking", target_os = "linux"))]
+	if let Ok(p) = procfs::process::Process::myself() {
+		// needs linux 4.0, if it fails we just get the peak for the whole process
+		let _ = p.clear_refs(procfs::process::ClearRefs::PeakRSS);
+	}
+}
+
+/// the most memory that has been resident at once since the last reset_peak_rss(), in bytes.
+/// None if it can't be found out, or the memory-tracking feature is off.
+#[cfg(all(feature = "memory-tracking", target_os = "linux"))]
+pub(crate) fn peak_rss_bytes() -> Option<u64> {
+	let status = procfs::process::Process::myself().ok()?.status().ok()?;
+	status.vmhwm.map(|kb| kb * 1024)
+}
+
+#[cfg(all(feature = "memory-tracking", windows))]
+pub(crate) fn peak_rss_bytes() -> Option<u64> {
+	use windows_sys::Win32::System::ProcessStatus::{GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS};
+	use windows_sys::Win32::System::Threading::GetCurrentProcess;
+
+	let mut counters: PROCESS_MEMORY_COUNTERS = unsafe { std::mem::zeroed() };
+	let size = std::mem::size_of::<PROCESS_MEMORY_COUNTERS>() as u32;
+	// safety: counters is the size we say it is, and the current process handle is always valid
+	let ok = unsafe { GetProcessMemoryInfo(GetCurrentProcess(), &mut counters, size) };
+	(ok != 0).then_some(counters.PeakWorkingSetSize as u64)
+}
+
+#[cfg(not(all(feature = "memory-tracking", any(target_os = "linux", windows))))]
+pub(crate) fn peak_rss_bytes() -> Option<u64> {
+	None
+}
+
+#[cfg(all(test, feature = "memory-tracking", target_os = "linux"))]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_peak_rss() {
+		reset_peak_rss();
+		let before = peak_rss_bytes().unwrap();
+
+		// touch every page so it is actually resident
+		let big = vec![1u8; 64 << 20];
+		std::hint::black_box(&big);
+		drop(big);
+
+		assert!(peak_rss_bytes().unwrap() >= before + (32 << 20));
+	}
+}
@@ -0,0 +1,79 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+use anyhow::Context;
+use crate::common::FoldiffCfg;
+
+/// writes a line of JSON for every file an operation handles, if `FoldiffCfg::log_path` is set
+pub(crate) struct OpLog(Option<Mutex<BufWriter<File>>>);
+
+impl OpLog {
+	pub fn open(cfg: &FoldiffCfg) -> anyhow::Result<Self> {
+		let Some(path) = &cfg.log_path
+		else { return Ok(Self(None)) };
+
+		let f = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(path)
+			.with_context(|| format!("Failed to open log file {path}"))?;
+		Ok(Self(Some(Mutex::new(BufWriter::new(f)))))
+	}
+
+	pub fn record(&self, op: &str, path: &str, hash: Option<u64>, err: Option<&anyhow::Error>) {
+		let Some(w) = &self.0
+		else { return };
+
+		let mut line = serde_json::json!({
+			"op": op,
+			"path": path,
+			"hash": hash.map(|h| format!("{h:#018x}")),
+			"ok": err.is_none(),
+		});
+		if let Some(e) = err {
+			line["error"] = format!("{e:#}").into();
+		}
+
+		// the log is a record, not part of the operation, so failing to write it shouldn't stop anything
+		if let Ok(mut w) = w.lock() {
+			let _ = writeln!(w, "{line}");
+		}
+	}
+
+	/// runs `f`, which returns an error if it failed like a par_iter filter_map does, and logs the result
+	pub fn wrap(&self, op: &str, path: &str, hash: Option<u64>, f: impl FnOnce() -> Option<anyhow::Error>) -> Option<anyhow::Error> {
+		let err = f();
+		self.record(op, path, hash, err.as_ref());
+		err
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use anyhow::anyhow;
+	use camino::Utf8PathBuf;
+
+	#[test]
+	fn test_oplog_lines() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = Utf8PathBuf::try_from(dir.path().join("log.jsonl")).unwrap();
+		let cfg = FoldiffCfg { log_path: Some(path.clone()), ..FoldiffCfg::default() };
+
+		let log = OpLog::open(&cfg).unwrap();
+		log.record("copy", "a.txt", Some(0x1234), None);
+		let err = log.wrap("new", "b.txt", None, || Some(anyhow!("broken")));
+		assert!(err.is_some());
+		drop(log);
+
+		let text = std::fs::read_to_string(&path).unwrap();
+		let lines: Vec<serde_json::Value> = text.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+		assert_eq!(lines.len(), 2);
+		assert_eq!(lines[0]["op"], "copy");
+		assert_eq!(lines[0]["hash"], "0x0000000000001234");
+		assert_eq!(lines[0]["ok"], true);
+		assert_eq!(lines[1]["ok"], false);
+		assert_eq!(lines[1]["error"], "broken");
+		assert!(lines[1]["hash"].is_null());
+	}
+}
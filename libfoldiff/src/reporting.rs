@@ -1,13 +1,22 @@
+use std::io::Write;
 use std::mem::MaybeUninit;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
 use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use derivative::Derivative;
 
 pub trait ReportingMultiWrapper {
 	fn new() -> Self;
 	// one could provide an implementation if you can get the list of bars,
 	// but we can't assume we can, so, don't.
 	fn suspend<F: FnOnce() -> R, R>(&self, f: F) -> R;
+	/// adds a bar for progress across every stage, shown above the others. `len` is the total across all of them.
+	/// reports nothing by default.
+	fn add_overall_bar(&self, _len: usize) -> impl ReporterSized + Sync {
+		NoopReporter
+	}
 }
 
 // represents a generic progress reporting struct
@@ -20,6 +29,15 @@ pub trait Reporter {
 	fn done_clear(&self);
 	fn done(&self);
 	fn suspend<F: FnOnce() -> R, R>(&self, f: F) -> R;
+	/// how quickly data is being processed, for reporters that want to show it.
+	/// ignored by default.
+	fn set_speed_hint(&self, _bytes_per_sec: f64) {}
+	/// what is being worked on right now, eg the current file. may be called very often.
+	/// ignored by default.
+	fn set_message(&self, _msg: &str) {}
+	/// how long it was from new() until done() or done_clear(), once either has been called.
+	/// None by default, and for reporters that don't keep track.
+	fn stage_elapsed(&self) -> Option<Duration> { None }
 }
 
 // a progress reporter that has a size, eg a bar
@@ -33,10 +51,155 @@ pub trait CanBeWrappedBy<W: ReportingMultiWrapper> : Reporter {
 	fn add_to(self, w: &W) -> Self;
 }
 
+/// reports nothing, for using libfoldiff without a terminal
+pub struct NoopReporter;
+
+impl Reporter for NoopReporter {
+	fn new(_: &str) -> Self { Self }
+	fn incr(&self, _: usize) {}
+	fn count(&self) -> usize { 0 }
+	fn tick(&self) {}
+	fn done_clear(&self) {}
+	fn done(&self) {}
+	fn suspend<F: FnOnce() -> R, R>(&self, f: F) -> R { f() }
+}
+
+impl ReporterSized for NoopReporter {
+	fn new(_: &str, _: usize) -> Self { Self }
+	fn set_len(&self, _: usize) {}
+	fn length(&self) -> usize { 0 }
+}
+
+impl CanBeWrappedBy<NoopMultiWrapper> for NoopReporter {
+	fn add_to(self, _: &NoopMultiWrapper) -> Self { self }
+}
+
+/// groups NoopReporters, by doing nothing
+pub struct NoopMultiWrapper;
+
+impl ReportingMultiWrapper for NoopMultiWrapper {
+	fn new() -> Self { Self }
+	fn suspend<F: FnOnce() -> R, R>(&self, f: F) -> R { f() }
+}
+
+/// reports nothing, but keeps count of progress and how long it took so it can be checked, eg in tests
+#[derive(Debug, Derivative)]
+#[derivative(Default)]
+pub struct CountingReporter {
+	count: AtomicUsize,
+	len: AtomicUsize,
+	#[derivative(Default(value = "Instant::now()"))]
+	started: Instant,
+	elapsed: OnceLock<Duration>,
+}
+
+impl Reporter for CountingReporter {
+	fn new(_: &str) -> Self { Self::default() }
+	fn incr(&self, n: usize) { self.count.fetch_add(n, Ordering::Relaxed); }
+	fn count(&self) -> usize { self.count.load(Ordering::Relaxed) }
+	fn tick(&self) {}
+	fn done_clear(&self) { self.done(); }
+	fn done(&self) { let _ = self.elapsed.set(self.started.elapsed()); }
+	fn suspend<F: FnOnce() -> R, R>(&self, f: F) -> R { f() }
+	fn stage_elapsed(&self) -> Option<Duration> { self.elapsed.get().copied() }
+}
+
+impl ReporterSized for CountingReporter {
+	fn new(_: &str, len: usize) -> Self {
+		Self { len: AtomicUsize::new(len), ..Self::default() }
+	}
+	fn set_len(&self, len: usize) { self.len.store(len, Ordering::Relaxed); }
+	fn length(&self) -> usize { self.len.load(Ordering::Relaxed) }
+}
+
+impl CanBeWrappedBy<NoopMultiWrapper> for CountingReporter {
+	fn add_to(self, _: &NoopMultiWrapper) -> Self { self }
+}
+
+// where JsonReporters write to, if anywhere
+static JSON_TARGET: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+/// reports progress as one line of JSON per event, to the target set with `JsonReporter::set_target`.
+/// if no target is set, reports nothing.
+pub struct JsonReporter {
+	stage: String,
+	pos: AtomicUsize,
+	len: Option<AtomicUsize>,
+}
+
+impl JsonReporter {
+	/// sets where all JsonReporters write their events to
+	pub fn set_target(w: impl Write + Send + 'static) {
+		*JSON_TARGET.lock().unwrap() = Some(Box::new(w));
+	}
+
+	fn emit(&self, event: &str) {
+		let mut target = JSON_TARGET.lock().unwrap();
+		let Some(w) = target.as_mut()
+		else { return };
+
+		let ts_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+		let line = serde_json::json!({
+			"event": event,
+			"stage": self.stage,
+			"pos": self.pos.load(Ordering::Relaxed),
+			"len": self.len.as_ref().map(|l| l.load(Ordering::Relaxed)),
+			"ts_ms": ts_ms,
+		});
+		// progress is best-effort, if whoever is reading it goes away we shouldn't fail
+		let _ = writeln!(w, "{line}");
+	}
+}
+
+impl Reporter for JsonReporter {
+	fn new(msg: &str) -> Self {
+		Self { stage: msg.to_string(), pos: AtomicUsize::new(0), len: None }
+	}
+	fn incr(&self, n: usize) {
+		self.pos.fetch_add(n, Ordering::Relaxed);
+		self.emit("incr");
+	}
+	fn count(&self) -> usize { self.pos.load(Ordering::Relaxed) }
+	fn tick(&self) { self.emit("tick"); }
+	fn done_clear(&self) { self.emit("done"); }
+	fn done(&self) { self.emit("done"); }
+	fn suspend<F: FnOnce() -> R, R>(&self, f: F) -> R { f() }
+}
+
+impl ReporterSized for JsonReporter {
+	fn new(msg: &str, len: usize) -> Self {
+		Self { stage: msg.to_string(), pos: AtomicUsize::new(0), len: Some(AtomicUsize::new(len)) }
+	}
+	fn set_len(&self, len: usize) {
+		if let Some(l) = &self.len {
+			l.store(len, Ordering::Relaxed);
+		}
+	}
+	fn length(&self) -> usize {
+		self.len.as_ref().map_or(0, |l| l.load(Ordering::Relaxed))
+	}
+}
+
+/// groups JsonReporters. events are all written to the same target anyway, so does nothing.
+pub struct JsonMultiWrapper;
+
+impl ReportingMultiWrapper for JsonMultiWrapper {
+	fn new() -> Self { Self }
+	fn suspend<F: FnOnce() -> R, R>(&self, f: F) -> R { f() }
+	fn add_overall_bar(&self, len: usize) -> impl ReporterSized + Sync {
+		<JsonReporter as ReporterSized>::new("Total", len)
+	}
+}
+
+impl CanBeWrappedBy<JsonMultiWrapper> for JsonReporter {
+	fn add_to(self, _: &JsonMultiWrapper) -> Self { self }
+}
+
 pub(crate) struct AutoSpin<'a, R: Reporter+Sync> {
 	run: Box<AtomicBool>,
 	jh: MaybeUninit<JoinHandle<()>>,
 	rep: &'a R, // stored exclusively for all_good().
+	start: Instant,
 	//_ph: PhantomData<&'a R>,
 }
 
@@ -47,6 +210,7 @@ impl<'a, R: Reporter+Sync> AutoSpin<'a, R> {
 			// box so the ptr never moves
 			run: Box::new(AtomicBool::new(true)),
 			rep: r,
+			start: Instant::now(),
 			jh: MaybeUninit::zeroed(),
 			//_ph: PhantomData::default()
 		};
@@ -65,17 +229,19 @@ impl<'a, R: Reporter+Sync> AutoSpin<'a, R> {
 
 			while unsafe { (*run_ptr).load(Ordering::Acquire) } {
 				unsafe { &*rep_ptr }.tick();
-				thread::sleep_ms(50);
+				// parked instead of sleeping, so dropping doesn't have to wait out the rest of the tick
+				thread::park_timeout(Duration::from_millis(50));
 			}
 		}));
 
 		s
 	}
 
-	/// finishes autospinning then calls done() on the internal object.
+	/// finishes autospinning then calls done() on the internal object, and returns how long it was spinning for.
 	/// mainly useful to extend the lifetime of autospin in a neater way than explicit drop().
-	pub fn all_good(self) {
+	pub fn all_good(self) -> Duration {
 		self.rep.done();
+		self.start.elapsed()
 		// self drops here to finish autospinning
 	}
 }
@@ -86,6 +252,8 @@ impl<'a, R: Reporter+Sync> Drop for AutoSpin<'a, R> {
 		self.run.store(false, Ordering::Release);
 		// wait for it to stop
 		let uninit = std::mem::replace(&mut self.jh, MaybeUninit::zeroed());
-		unsafe { uninit.assume_init() }.join().unwrap();
+		let jh = unsafe { uninit.assume_init() };
+		jh.thread().unpark();
+		jh.join().unwrap();
 	}
 }
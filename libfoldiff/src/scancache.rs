@@ -0,0 +1,101 @@
+// remembering file hashes between diffs, so a mostly-static folder that is diffed again and again only has to hash what changed.
+// the cache is kept per dir: if none of the files directly in a dir changed name, size, or modification time since last time,
+// the hashes from last time are trusted. otherwise every file in that dir is hashed again.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::UNIX_EPOCH;
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh3::Xxh3Default;
+use crate::common::HashAlgorithm;
+use crate::vfs::{VfsMetadata, VfsProvider};
+
+/// saved in the root of each scanned folder, and never diffed itself
+pub(crate) const CACHE_FILE_NAME: &str = ".foldiff-cache";
+
+// bump this if what goes into a fingerprint changes, so old caches are thrown away
+const CACHE_VERSION: u32 = 1;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct ScanCache {
+	version: u32,
+	hash_algorithm: HashAlgorithm,
+	// keyed by the dir's path relative to the root
+	dirs: HashMap<String, CachedDir>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CachedDir {
+	fingerprint: u64,
+	// file name -> hash
+	hashes: HashMap<String, u64>,
+}
+
+impl ScanCache {
+	pub fn new(hash_algorithm: HashAlgorithm) -> Self {
+		Self { version: CACHE_VERSION, hash_algorithm, dirs: HashMap::new() }
+	}
+
+	/// loads the cache saved in `root`. a missing cache, or one that can't be used, is the same as an empty one
+	pub fn load(root: &Utf8Path, vfs: &dyn VfsProvider, hash_algorithm: HashAlgorithm) -> Self {
+		let path = root.join(CACHE_FILE_NAME);
+		vfs.open_read(&path).ok()
+			.and_then(|f| rmp_serde::from_read::<_, Self>(f).ok())
+			.filter(|c| c.version == CACHE_VERSION && c.hash_algorithm == hash_algorithm)
+			.unwrap_or_else(|| Self::new(hash_algorithm))
+	}
+
+	pub fn save(&self, root: &Utf8Path, vfs: &dyn VfsProvider) -> Result<()> {
+		let mut f = vfs.create_write(&root.join(CACHE_FILE_NAME))?;
+		rmp_serde::encode::write(&mut f, self).context("Failed to write scan cache")?;
+		f.flush().context("Failed to write scan cache")
+	}
+
+	/// the hash of each file in `dir` from last time, if it had the same fingerprint then
+	pub fn get(&self, dir: &Utf8Path, fingerprint: u64) -> Option<&HashMap<String, u64>> {
+		self.dirs.get(dir.as_str()).filter(|d| d.fingerprint == fingerprint).map(|d| &d.hashes)
+	}
+
+	pub fn insert(&mut self, dir: &Utf8Path, fingerprint: u64, hashes: HashMap<String, u64>) {
+		self.dirs.insert(dir.to_string(), CachedDir { fingerprint, hashes });
+	}
+}
+
+/// fingerprints a dir from the name, size, and modification time of each file in it, in any order.
+/// None if any of them has no modification time, as then changes can't be spotted
+pub(crate) fn fingerprint<'a>(files: impl Iterator<Item = (&'a str, &'a VfsMetadata)>) -> Option<u64> {
+	let mut files: Vec<_> = files.collect();
+	files.sort_unstable_by_key(|(name, _)| *name);
+
+	let mut hasher = Xxh3Default::new();
+	for (name, meta) in files {
+		let mtime = meta.modified?.duration_since(UNIX_EPOCH).ok()?;
+		// lengths first, so names can't run into each other
+		hasher.update(&(name.len() as u64).to_le_bytes());
+		hasher.update(name.as_bytes());
+		hasher.update(&meta.len.to_le_bytes());
+		hasher.update(&mtime.as_nanos().to_le_bytes());
+	}
+	Some(hasher.digest())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::{Duration, SystemTime};
+
+	#[test]
+	fn test_fingerprint() {
+		let meta = |len, secs| VfsMetadata { len, modified: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)), ..Default::default() };
+		let (a, b) = (meta(10, 100), meta(20, 200));
+
+		let fp = fingerprint([("a", &a), ("b", &b)].into_iter()).unwrap();
+		assert_eq!(fingerprint([("b", &b), ("a", &a)].into_iter()), Some(fp));
+		assert_ne!(fingerprint([("a", &a), ("b", &meta(20, 201))].into_iter()), Some(fp));
+		assert_ne!(fingerprint([("a", &a), ("c", &b)].into_iter()), Some(fp));
+		assert_ne!(fingerprint([("a", &a)].into_iter()), Some(fp));
+		assert_eq!(fingerprint([("a", &a), ("b", &VfsMetadata::default())].into_iter()), None);
+	}
+}
@@ -0,0 +1,195 @@
+// fixtures for tests, so test folders are all made the same way, with the same contents every run
+
+use camino::{Utf8Path, Utf8PathBuf};
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use tempfile::TempDir;
+use crate::common::{create_file, FoldiffCfg};
+use crate::hash;
+use crate::reporting::NoopReporter;
+use crate::verify::VerifyMismatch;
+
+#[derive(Clone, Debug)]
+enum Entry {
+	File(Vec<u8>),
+	Dir,
+}
+
+/// what to put in a test folder. paths are relative, and any dirs they need are made for them
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FolderSpec {
+	entries: Vec<(Utf8PathBuf, Entry)>,
+}
+
+impl FolderSpec {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn file(&mut self, path: &str, contents: impl AsRef<[u8]>) -> &mut Self {
+		self.entries.push((path.into(), Entry::File(contents.as_ref().to_vec())));
+		self
+	}
+
+	/// a dir that may have nothing in it, as empty dirs aren't made by anything else
+	pub fn dir(&mut self, path: &str) -> &mut Self {
+		self.entries.push((path.into(), Entry::Dir));
+		self
+	}
+
+	/// `size` random bytes, which are the same every time for the same path
+	pub fn with_random_binary(&mut self, path: &str, size: usize) -> &mut Self {
+		let mut rng = StdRng::seed_from_u64(hash::hash_stream(&mut path.as_bytes()).unwrap());
+		let mut data = vec![0; size];
+		rng.fill_bytes(&mut data);
+		self.entries.push((path.into(), Entry::File(data)));
+		self
+	}
+
+	/// writes everything into `root`, which doesn't have to exist yet
+	pub fn create_in(&self, root: &Utf8Path) {
+		std::fs::create_dir_all(root).unwrap();
+		for (path, entry) in &self.entries {
+			match entry {
+				Entry::File(data) => {
+					create_file(&root.join(path)).unwrap();
+					std::fs::write(root.join(path), data).unwrap();
+				},
+				Entry::Dir => std::fs::create_dir_all(root.join(path)).unwrap(),
+			}
+		}
+	}
+}
+
+/// makes a new temp folder with everything in `spec` in it, which is deleted when dropped
+pub(crate) fn generate_test_folder(spec: &FolderSpec) -> TempDir {
+	let dir = tempfile::tempdir().unwrap();
+	spec.create_in(&root_of(&dir));
+	dir
+}
+
+/// where a temp folder is, as foldiff only takes UTF-8 paths
+pub(crate) fn root_of(dir: &TempDir) -> Utf8PathBuf {
+	Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap()
+}
+
+/// panics listing every difference between the two folders, compared the same way `foldiff verify` does
+#[track_caller]
+pub(crate) fn assert_folders_equal(a: &Utf8Path, b: &Utf8Path) {
+	let res = crate::verify::test_dir_equality::<NoopReporter>(a, b, &FoldiffCfg::default(), Default::default()).unwrap();
+	if res.is_ok() {
+		return;
+	}
+
+	let mut msg = format!("{a} and {b} differ in {} ways:\n", res.mismatches.len());
+	for m in &res.mismatches {
+		msg += &format!("  {m}\n");
+		// "not as expected" doesn't say much when a test fails, so say how
+		if let VerifyMismatch::HashMismatch(p) = m {
+			if let (Ok(da), Ok(db)) = (std::fs::read(a.join(p)), std::fs::read(b.join(p))) {
+				let at = da.iter().zip(&db).position(|(x, y)| x != y).unwrap_or(da.len().min(db.len()));
+				msg += &format!("    {} bytes vs {} bytes, first different at byte {at}\n", da.len(), db.len());
+			}
+		}
+	}
+	panic!("{}", msg.trim_end());
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::vfs::StdVfs;
+	use crate::zstddiff::{self, DiffTuning};
+	use std::io::Cursor;
+
+	#[test]
+	fn test_generate_test_folder() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("a/b.txt", b"content")
+			.dir("c")
+			.file("c/d.txt", "other")
+			.dir("empty")
+			.with_random_binary("bin", 1000)
+		);
+		let root = root_of(&dir);
+		assert_eq!(std::fs::read(root.join("a/b.txt")).unwrap(), b"content");
+		assert_eq!(std::fs::read(root.join("c/d.txt")).unwrap(), b"other");
+		assert_eq!(std::fs::read_dir(root.join("empty")).unwrap().count(), 0);
+
+		// the same every time for the same path, but not for different ones
+		let other = generate_test_folder(FolderSpec::new().with_random_binary("bin", 1000).with_random_binary("bin2", 1000));
+		let bin = std::fs::read(root.join("bin")).unwrap();
+		assert_eq!(bin.len(), 1000);
+		assert_eq!(bin, std::fs::read(root_of(&other).join("bin")).unwrap());
+		assert_ne!(bin, std::fs::read(root_of(&other).join("bin2")).unwrap());
+	}
+
+	#[test]
+	fn test_assert_folders_equal() {
+		let mut spec = FolderSpec::new();
+		spec.file("same.txt", "same").file("a/changed.txt", "hello world").file("only_a", "a");
+		let a = generate_test_folder(&spec);
+		let b = generate_test_folder(&spec);
+		assert_folders_equal(&root_of(&a), &root_of(&b));
+
+		std::fs::write(root_of(&b).join("a/changed.txt"), "hello there").unwrap();
+		std::fs::remove_file(root_of(&b).join("only_a")).unwrap();
+		let err = std::panic::catch_unwind(|| assert_folders_equal(&root_of(&a), &root_of(&b))).unwrap_err();
+		let msg = err.downcast_ref::<String>().unwrap();
+		assert!(msg.contains("differ in 2 ways"), "{msg}");
+		assert!(msg.contains("11 bytes vs 11 bytes, first different at byte 6"), "{msg}");
+		assert!(msg.contains("\"only_a\" only exists in the first folder"), "{msg}");
+	}
+
+	// fills the fuzzers' starting corpora with valid inputs, only run by hand, see CONTRIBUTING.md
+	#[test]
+	#[ignore = "writes the fuzz corpus, run it with --ignored and FOLDIFF_FUZZ_CORPUS set"]
+	fn test_write_fuzz_corpus() {
+		let corpus = std::env::var("FOLDIFF_FUZZ_CORPUS").expect("FOLDIFF_FUZZ_CORPUS should be set to the corpus folder");
+		let corpus = Utf8PathBuf::from(corpus);
+		std::fs::create_dir_all(corpus.join("fuzz_apply")).unwrap();
+		std::fs::create_dir_all(corpus.join("fuzz_manifest_read")).unwrap();
+
+		let text_old = "the quick brown fox\njumps over\nthe lazy dog\n".repeat(50);
+		let text_new = text_old.replacen("brown", "red", 10);
+		let mut bin_old = vec![0; 8192];
+		StdRng::seed_from_u64(0).fill_bytes(&mut bin_old);
+		let mut bin_new = bin_old.clone();
+		bin_new[1000..1100].fill(0);
+
+		// fuzz_apply takes a u32 length, the old file, then the diff
+		let write_apply_seed = |name: &str, old: &[u8], diff: Vec<u8>| {
+			let mut seed = (old.len() as u32).to_be_bytes().to_vec();
+			seed.extend_from_slice(old);
+			seed.extend(diff);
+			std::fs::write(corpus.join("fuzz_apply").join(name), seed).unwrap();
+		};
+		for (name, old, new) in [("text", text_old.as_bytes(), text_new.as_bytes()), ("binary", &bin_old, &bin_new)] {
+			let mut diff = Cursor::new(Vec::new());
+			zstddiff::diff(&mut Cursor::new(old), &mut Cursor::new(new), &mut diff, None, None, None, None).unwrap();
+			write_apply_seed(name, old, diff.into_inner());
+		}
+		let mut diff = Cursor::new(Vec::new());
+		zstddiff::diff_text_with_dict(&mut Cursor::new(&text_old), &mut Cursor::new(&text_new), &mut diff, None, None, None, None, None, &DiffTuning::default()).unwrap();
+		write_apply_seed("text_delta", text_old.as_bytes(), diff.into_inner());
+
+		let old = generate_test_folder(FolderSpec::new()
+			.file("same", "same")
+			.file("edited", &text_old)
+			.file("gone", "gone")
+			.file("copy", "copy")
+		);
+		let new = generate_test_folder(FolderSpec::new()
+			.file("same", "same")
+			.file("edited", &text_new)
+			.file("new", "new")
+			.file("copy", "copy")
+			.file("copy2", "copy")
+		);
+		let cfg = FoldiffCfg::default();
+		let mut diff = crate::diffing::scan_to_diff::<NoopReporter>(root_of(&old), root_of(&new), &cfg, &StdVfs).unwrap();
+		let mut out = Cursor::new(Vec::new());
+		diff.write_to::<NoopReporter, NoopReporter>(&mut out, &cfg, None).unwrap();
+		std::fs::write(corpus.join("fuzz_manifest_read/diff"), out.into_inner()).unwrap();
+	}
+}
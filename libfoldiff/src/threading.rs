@@ -1,3 +1,6 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
 
 
 /// Adds err to errs and returns
@@ -17,7 +20,7 @@ macro_rules! handle_res_async {
 	($errs:expr, $res:expr, $fmt:expr $(, $($arg:tt)+)?) => {{
 		let v = $res;
 		if let Err(e) = v {
-			throw_err_async!($errs, anyhow::anyhow!(format!("{e:?}")).context(format!($fmt, $($($arg)*)?)));
+			throw_err_async!($errs, anyhow::Error::from(e).context(format!($fmt, $($($arg)*)?)));
 		}
 		else {
 			v.unwrap()
@@ -26,7 +29,7 @@ macro_rules! handle_res_async {
 	($errs:expr, $res:expr) => {{
 		let v = $res;
 		if let Err(e) = v {
-			throw_err_async!($errs, anyhow::anyhow!(format!("{e:?}")));
+			throw_err_async!($errs, anyhow::Error::from(e));
 		}
 		else {
 			v.unwrap()
@@ -40,7 +43,7 @@ macro_rules! handle_res_parit {
 	($res:expr, $fmt:expr $(, $($arg:tt)+)?) => {{
 		let v = $res;
 		if let Err(e) = v {
-			return Some(anyhow!(format!("{e:?}")).context(format!($fmt, $($($arg)*)?)));
+			return Some(anyhow::Error::from(e).context(format!($fmt, $($($arg)*)?)));
 		}
 		else {
 			v.unwrap()
@@ -49,7 +52,7 @@ macro_rules! handle_res_parit {
 	($res:expr) => {{
 		let v = $res;
 		if let Err(e) = v {
-			return Some(anyhow!(format!("{e:?}")));
+			return Some(anyhow::Error::from(e));
 		}
 		else {
 			v.unwrap()
@@ -57,6 +60,32 @@ macro_rules! handle_res_parit {
 	}};
 }
 
+/// A handle that can be used to stop a running diff or apply from another thread.
+/// Clones all refer to the same token.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// asks whatever is using this token to stop as soon as it can.
+	/// it will then fail with FoldiffError::Cancelled.
+	pub fn cancel(&self) {
+		self.0.store(true, Ordering::Relaxed);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.0.load(Ordering::Relaxed)
+	}
+}
+
+/// checks an optional token, for when no token means never cancelled
+pub(crate) fn is_cancelled(token: Option<&CancellationToken>) -> bool {
+	token.is_some_and(CancellationToken::is_cancelled)
+}
+
 /// Sets the number of threads in the global thread pool.
 /// Must be called before any tasks are run in it.
 pub fn set_num_threads(thr: usize) -> Result<(), impl std::error::Error> {
@@ -1,9 +1,12 @@
 use crate::manifest::DiffManifest;
-use crate::common::{MAGIC_BYTES, VERSION_NUMBER_1_1_0, VERSION_NUMBER_1_0_0_R, VERSION_NUMBER_LATEST};
+use crate::common::{MAGIC_BYTES, VERSION_NUMBER_1_1_0, VERSION_NUMBER_1_0_0_R, VERSION_NUMBER_1_2_0, VERSION_NUMBER_1_3_0, VERSION_NUMBER_LATEST};
 use anyhow::{bail, Context, Result};
-use std::io::{Read, Seek, Write};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use camino::{Utf8Path, Utf8PathBuf};
+use tempfile::NamedTempFile;
 use zstd::Encoder;
-use crate::reporting::{AutoSpin, Reporter};
+use crate::reporting::{AutoSpin, NoopReporter, Reporter, ReporterSized};
 
 // 1.0.0-r to v1.1.0
 fn upgrade_100r_110<TSpin: Reporter+Sync>(mut src: impl Read+Seek, mut dst: impl Write+Seek) -> Result<()> {
@@ -41,12 +44,424 @@ fn upgrade_100r_110<TSpin: Reporter+Sync>(mut src: impl Read+Seek, mut dst: impl
 	Ok(())
 }
 
-pub fn auto_upgrade<TSpin: Reporter+Sync>(mut src: impl Read+Seek, dst: impl Write+Seek) -> Result<()> {
+// v1.1.0 to v1.2.0
+// v1.2.0 only appends fields to the manifest, which default to empty, so only the version changes
+fn upgrade_110_120<TSpin: Reporter+Sync>(mut src: impl Read, mut dst: impl Write) -> Result<()> {
+	let s = TSpin::new("Upgrading from FLDF 1.1.0 to FLDF 1.2.0");
+	let s = AutoSpin::spin(&s);
+
+	dst.write_all(&MAGIC_BYTES).context("Failed to write to destination file")?;
+	dst.write_all(&VERSION_NUMBER_1_2_0)?;
+
+	// everything after the version is unchanged
+	std::io::copy(&mut src, &mut dst)?;
+
+	s.all_good();
+	Ok(())
+}
+
+// v1.2.0 to v1.3.0
+// v1.3.0 manifests are in the compact format instead of messagepack, so the manifest is rewritten,
+// but the blobs are unchanged. they also say which hash they use, and a 1.2.0 manifest reads as the xxh64 they used
+fn upgrade_120_130<TSpin: Reporter+Sync>(mut src: impl Read, mut dst: impl Write) -> Result<()> {
+	let s = TSpin::new("Upgrading from FLDF 1.2.0 to FLDF 1.3.0");
+	let s = AutoSpin::spin(&s);
+
+	dst.write_all(&MAGIC_BYTES).context("Failed to write to destination file")?;
+	dst.write_all(&VERSION_NUMBER_1_3_0)?;
+
+	let (manifest, _) = DiffManifest::read_110(&mut src, VERSION_NUMBER_1_2_0, None)?;
+	let compressed = manifest.to_compressed()?;
+	dst.write_all(&(compressed.len() as u64).to_be_bytes())?;
+	dst.write_all(&compressed)?;
+
+	std::io::copy(&mut src, &mut dst)?;
+
+	s.all_good();
+	Ok(())
+}
+
+/// Every version of the FLDF format
+// oldest first, so that comparing versions compares how new they are
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FoldiffVersion {
+	V1_0_0R,
+	V1_1_0,
+	V1_2_0,
+	V1_3_0,
+}
+
+impl FoldiffVersion {
+	fn from_bytes(ver: [u8; 4]) -> Self {
+		match ver {
+			VERSION_NUMBER_1_0_0_R => Self::V1_0_0R,
+			VERSION_NUMBER_1_1_0 => Self::V1_1_0,
+			VERSION_NUMBER_1_2_0 => Self::V1_2_0,
+			VERSION_NUMBER_1_3_0 => Self::V1_3_0,
+			// verify_and_read_ver only returns versions we know
+			_ => unreachable!(),
+		}
+	}
+
+	pub fn is_latest(&self) -> bool {
+		Self::from_bytes(VERSION_NUMBER_LATEST) == *self
+	}
+
+	pub fn display_name(&self) -> &'static str {
+		match self {
+			Self::V1_0_0R => "1.0.0-r",
+			Self::V1_1_0 => "v1.1.0",
+			Self::V1_2_0 => "v1.2.0",
+			Self::V1_3_0 => "v1.3.0",
+		}
+	}
+}
+
+/// Which versions of the format code for each version can read, and how to get diffs up to date.
+///
+/// Reading code handles its own version and every older one, but refuses newer ones
+/// instead of risking misreading them, so a diff has to be no newer than whatever reads it.
+pub struct CompatibilityMatrix;
+
+impl CompatibilityMatrix {
+	/// every version of the format, oldest first
+	pub const VERSIONS: [FoldiffVersion; 4] = [
+		FoldiffVersion::V1_0_0R,
+		FoldiffVersion::V1_1_0,
+		FoldiffVersion::V1_2_0,
+		FoldiffVersion::V1_3_0,
+	];
+
+	/// if a diff written as `writer_version` can be read by code that writes `reader_version`
+	pub fn can_read(writer_version: FoldiffVersion, reader_version: FoldiffVersion) -> bool {
+		writer_version <= reader_version
+	}
+
+	/// the versions `auto_upgrade` takes a diff through to bring it up to date, in order.
+	/// empty if it is already the latest.
+	pub fn upgrade_path(from: FoldiffVersion) -> Vec<FoldiffVersion> {
+		Self::VERSIONS.into_iter().filter(|v| *v > from).collect()
+	}
+}
+
+/// Reads just the magic bytes and version of a diff, and checks they are valid
+pub fn detect_version(mut reader: impl Read+Seek) -> Result<FoldiffVersion> {
+	Ok(FoldiffVersion::from_bytes(DiffManifest::verify_and_read_ver(&mut reader)?))
+}
+
+pub fn detect_version_from_file(path: &Utf8Path) -> Result<FoldiffVersion> {
+	let f = File::open(path).context("Failed to open diff to read its version")?;
+	detect_version(f)
+}
+
+/// Checks if a diff is an older version that can be upgraded. Only errors if it can't be read or isn't a valid diff.
+/// Leaves `reader` where it was, so it can be passed straight on to `auto_upgrade`.
+pub fn needs_upgrade(mut reader: impl Read+Seek) -> Result<bool> {
+	let start = reader.stream_position()?;
+	let ver = detect_version(&mut reader)?;
+	reader.seek(SeekFrom::Start(start))?;
+	Ok(!ver.is_latest())
+}
+
+trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+trait WriteSeek: Write + Seek {}
+impl<T: Write + Seek> WriteSeek for T {}
+
+/// upgrades a diff by one version. src is positioned just after the version number.
+type Step = fn(&mut dyn ReadSeek, &mut dyn WriteSeek) -> Result<()>;
+
+/// runs each step in turn, writing everything but the last into a temporary file
+fn run_steps(steps: &[Step], src: &mut dyn ReadSeek, dst: &mut dyn WriteSeek) -> Result<()> {
+	match steps {
+		[] => unreachable!(),
+		[last] => last(src, dst),
+		[first, rest @ ..] => {
+			let mut tmp = tempfile::tempfile().context("Failed to create temporary file for upgrading")?;
+			first(src, &mut tmp)?;
+
+			tmp.rewind()?;
+			DiffManifest::verify_and_read_ver(&mut tmp)?;
+			run_steps(rest, &mut tmp, dst)
+		},
+	}
+}
+
+/// Upgrades `src` to the latest version, writing it to `dst`. Fails if it is already the latest version,
+/// so check with `needs_upgrade` first.
+pub fn auto_upgrade<TSpin: Reporter+Sync>(mut src: impl Read+Seek, mut dst: impl Write+Seek) -> Result<()> {
+	if !needs_upgrade(&mut src)? {
+		bail!("Diff is up to date! (FLDF {})", FoldiffVersion::from_bytes(VERSION_NUMBER_LATEST).display_name());
+	}
 	let ver = DiffManifest::verify_and_read_ver(&mut src)?;
-	
-	match ver {
-		VERSION_NUMBER_LATEST => bail!("Diff is up to date! (FLDF v{}.{}.{})", ver[1], ver[2], ver[3]),
-		VERSION_NUMBER_1_0_0_R => upgrade_100r_110::<TSpin>(src, dst),
-		_ => unreachable!(),
+
+	// the version each step upgrades from, in order. each goes to the version of the one after it.
+	let steps: [([u8; 4], Step); 3] = [
+		(VERSION_NUMBER_1_0_0_R, |s, d| upgrade_100r_110::<TSpin>(s, d)),
+		(VERSION_NUMBER_1_1_0, |s, d| upgrade_110_120::<TSpin>(s, d)),
+		(VERSION_NUMBER_1_2_0, |s, d| upgrade_120_130::<TSpin>(s, d)),
+	];
+
+	let start = steps
+		.iter()
+		.position(|(v, _)| *v == ver)
+		.context("No upgrade path from this diff's version")?;
+	let steps: Vec<_> = steps[start..].iter().map(|(_, step)| *step).collect();
+
+	run_steps(&steps, &mut src, &mut dst)
+}
+
+/// Upgrades the diff at `path`, only replacing it once the upgraded diff has been fully written.
+/// Does nothing if the diff is already the latest version.
+pub fn auto_upgrade_in_place<TSpin: Reporter+Sync>(path: impl AsRef<Utf8Path>) -> Result<()> {
+	let path = path.as_ref();
+	let mut src = File::open(path).context("Failed to open diff to upgrade")?;
+	if !needs_upgrade(&mut src)? {
+		return Ok(());
 	}
-}
\ No newline at end of file
+
+	// next to the source, so that renaming over it can't cross filesystems
+	let dir = path.parent().filter(|p| !p.as_str().is_empty()).unwrap_or(Utf8Path::new("."));
+	let mut tmp = NamedTempFile::new_in(dir).context("Failed to create temporary file for upgrading")?;
+	tmp.as_file().set_permissions(src.metadata()?.permissions())?;
+
+	auto_upgrade::<TSpin>(&mut src, tmp.as_file_mut())?;
+	drop(src);
+
+	tmp.persist(path).context("Failed to replace diff with the upgraded one")?;
+	Ok(())
+}
+
+/// What happened to each diff in a directory passed to `upgrade_directory`
+#[derive(Debug, Default)]
+pub struct DirUpgradeResult {
+	pub upgraded: Vec<Utf8PathBuf>,
+	/// already the latest version, so left alone
+	pub up_to_date: Vec<Utf8PathBuf>,
+	/// couldn't be checked, backed up, or upgraded. these are left as they were.
+	pub failed: Vec<(Utf8PathBuf, anyhow::Error)>,
+}
+
+/// Upgrades every `.fldf` file directly inside `dir` in place, copying each one that needs it into `backup_dir` first.
+/// `backup_dir` defaults to `dir/.foldiff-backup`, and is created if needed.
+/// A diff failing doesn't stop the others, only failing to list `dir` is an error.
+pub fn upgrade_directory<TBar: ReporterSized+Sync>(dir: &Utf8Path, backup_dir: Option<&Utf8Path>) -> Result<DirUpgradeResult> {
+	let backup_dir = backup_dir.map_or_else(|| dir.join(".foldiff-backup"), Utf8Path::to_path_buf);
+	let mut res = DirUpgradeResult::default();
+
+	let mut diffs = Vec::new();
+	for entry in dir.read_dir_utf8().context("Failed to list diffs to upgrade")? {
+		let entry = entry.context("Failed to list diffs to upgrade")?;
+		if entry.path().extension() == Some("fldf") && entry.file_type().is_ok_and(|t| t.is_file()) {
+			diffs.push(entry.into_path());
+		}
+	}
+	// so the order doesn't depend on the filesystem
+	diffs.sort();
+
+	let mut to_upgrade = Vec::new();
+	for p in diffs {
+		match File::open(&p).map_err(anyhow::Error::from).and_then(needs_upgrade) {
+			Ok(true) => to_upgrade.push(p),
+			Ok(false) => res.up_to_date.push(p),
+			Err(e) => res.failed.push((p, e.context("Failed to read diff version"))),
+		}
+	}
+
+	if to_upgrade.is_empty() {
+		return Ok(res);
+	}
+
+	let bar = <TBar as ReporterSized>::new("Upgrading diffs", to_upgrade.len());
+	let abar = AutoSpin::spin(&bar);
+	for p in to_upgrade {
+		bar.set_message(p.as_str());
+		let upgrade = || -> Result<()> {
+			std::fs::create_dir_all(&backup_dir).context("Failed to create backup directory")?;
+			// diffs only ever come from directly inside dir, so always have a file name
+			std::fs::copy(&p, backup_dir.join(p.file_name().unwrap())).context("Failed to back up diff")?;
+			// the bar is enough, one spinner per diff would just flicker past
+			auto_upgrade_in_place::<NoopReporter>(&p)
+		};
+		match upgrade() {
+			Ok(()) => res.upgraded.push(p),
+			Err(e) => res.failed.push((p, e)),
+		}
+		bar.incr(1);
+	}
+	abar.all_good();
+
+	Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::manifest::NewFile;
+	use crate::reporting::{NoopMultiWrapper, NoopReporter};
+	use crate::FoldiffCfg;
+	use camino::Utf8PathBuf;
+	use std::io::Cursor;
+
+	/// a 1.0.0-r diff that creates new.txt with `data`
+	fn make_100r_diff(data: &[u8]) -> Vec<u8> {
+		// diffs this old were all hashed with xxh64
+		let hash = crate::hash::hash_stream_with(&mut &*data, crate::HashAlgorithm::XxHash64).unwrap();
+
+		// 1.0.0-r is the magic bytes followed by an uncompressed manifest, which starts with its version
+		let nf = NewFile { hash, index: 0, path: "new.txt".into(), compression_level: None };
+		let manifest = (VERSION_NUMBER_1_0_0_R, Vec::<(u64, String)>::new(), Vec::<(u64, String)>::new(), vec![nf], Vec::<()>::new(), Vec::<()>::new());
+		let mut old = MAGIC_BYTES.to_vec();
+		old.extend(rmp_serde::to_vec(&manifest).unwrap());
+
+		// one new blob, no patches
+		let blob = zstd::encode_all(data, 3).unwrap();
+		old.extend(1u64.to_be_bytes());
+		old.extend((blob.len() as u64).to_be_bytes());
+		old.extend(blob);
+		old.extend(0u64.to_be_bytes());
+		old
+	}
+
+	#[test]
+	fn test_upgrade_100r_to_latest() {
+		let data = b"a new file from a very old diff";
+		let old = make_100r_diff(data);
+
+		let dir = tempfile::tempdir().unwrap();
+		let root = Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap();
+		let new_path = root.join("new.fldf");
+		auto_upgrade::<NoopReporter>(Cursor::new(old), std::fs::File::create(&new_path).unwrap()).unwrap();
+
+		assert!(detect_version_from_file(&new_path).unwrap().is_latest());
+
+		// and it should still apply
+		std::fs::create_dir(root.join("old")).unwrap();
+		let mut diff = crate::applying::read_diff_from_file(&new_path).unwrap();
+		assert_eq!(diff.manifest().hash_algorithm, crate::HashAlgorithm::XxHash64);
+		assert!(diff.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>(root.join("old"), root.join("out"), &FoldiffCfg::default(), &crate::vfs::StdVfs, None).unwrap().is_ok());
+		assert_eq!(std::fs::read(root.join("out/new.txt")).unwrap(), data);
+	}
+
+	#[test]
+	fn test_upgrade_110_120() {
+		let mut old = Cursor::new(make_100r_diff(b"only the version changes"));
+		DiffManifest::verify_and_read_ver(&mut old).unwrap();
+		let mut v110 = Cursor::new(Vec::new());
+		upgrade_100r_110::<NoopReporter>(&mut old, &mut v110).unwrap();
+
+		v110.set_position(0);
+		DiffManifest::verify_and_read_ver(&mut v110).unwrap();
+		let mut v120 = Vec::new();
+		upgrade_110_120::<NoopReporter>(&mut v110, &mut v120).unwrap();
+
+		assert_eq!(detect_version(Cursor::new(&v120)).unwrap(), FoldiffVersion::V1_2_0);
+		let (v110, v120) = (v110.into_inner(), v120);
+		assert_eq!(v110.len(), v120.len());
+		assert_eq!(v110[8..], v120[8..]);
+
+		// the manifest still reads, with the new fields empty
+		let mut v120 = Cursor::new(v120);
+		DiffManifest::verify_and_read_ver(&mut v120).unwrap();
+		let (manifest, _) = DiffManifest::read_110(&mut v120, VERSION_NUMBER_1_2_0, None).unwrap();
+		assert_eq!(manifest.new_files.len(), 1);
+		assert_eq!(manifest.new_files[0].path, "new.txt");
+		assert!(manifest.symlinks.is_empty() && manifest.uncompressed_sizes.is_empty());
+	}
+
+	#[test]
+	fn test_detect_version() {
+		let old = make_100r_diff(b"hi");
+		assert_eq!(detect_version(Cursor::new(&old)).unwrap(), FoldiffVersion::V1_0_0R);
+		assert!(!FoldiffVersion::V1_0_0R.is_latest());
+
+		let mut v110 = MAGIC_BYTES.to_vec();
+		v110.extend(VERSION_NUMBER_1_1_0);
+		assert_eq!(detect_version(Cursor::new(&v110)).unwrap(), FoldiffVersion::V1_1_0);
+
+		assert!(detect_version(Cursor::new(b"NOPE\0\x01\x02\0")).is_err());
+		assert!(detect_version(Cursor::new(b"FLDF\0\x09\x09\0")).is_err());
+	}
+
+	#[test]
+	fn test_compatibility_matrix() {
+		use FoldiffVersion::*;
+
+		assert!(CompatibilityMatrix::VERSIONS.last().unwrap().is_latest());
+		assert!(CompatibilityMatrix::can_read(V1_0_0R, V1_3_0));
+		assert!(CompatibilityMatrix::can_read(V1_2_0, V1_2_0));
+		assert!(!CompatibilityMatrix::can_read(V1_3_0, V1_2_0));
+
+		assert_eq!(CompatibilityMatrix::upgrade_path(V1_0_0R), [V1_1_0, V1_2_0, V1_3_0]);
+		assert_eq!(CompatibilityMatrix::upgrade_path(V1_2_0), [V1_3_0]);
+		assert!(CompatibilityMatrix::upgrade_path(V1_3_0).is_empty());
+	}
+
+	#[test]
+	fn test_needs_upgrade() {
+		let mut old = Cursor::new(make_100r_diff(b"hi"));
+		assert!(needs_upgrade(&mut old).unwrap());
+		assert_eq!(old.position(), 0);
+
+		let mut latest = Cursor::new(Vec::new());
+		auto_upgrade::<NoopReporter>(&mut old, &mut latest).unwrap();
+		latest.set_position(0);
+		assert!(!needs_upgrade(&mut latest).unwrap());
+
+		let err = auto_upgrade::<NoopReporter>(&mut latest, Cursor::new(Vec::new())).unwrap_err();
+		assert!(err.to_string().starts_with("Diff is up to date!"));
+
+		// not being a diff is still an error
+		assert!(needs_upgrade(Cursor::new(b"NOPE\0\x01\x02\0")).is_err());
+	}
+
+	#[test]
+	fn test_upgrade_directory() {
+		let dir = tempfile::tempdir().unwrap();
+		let root = Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap();
+		let old: Vec<_> = (0..10).map(|i| make_100r_diff(format!("file {i}").as_bytes())).collect();
+		for (i, d) in old.iter().enumerate() {
+			std::fs::write(root.join(format!("{i}.fldf")), d).unwrap();
+		}
+		std::fs::write(root.join("latest.fldf"), std::fs::read(root.join("0.fldf")).unwrap()).unwrap();
+		auto_upgrade_in_place::<NoopReporter>(root.join("latest.fldf")).unwrap();
+		std::fs::write(root.join("broken.fldf"), "not a diff").unwrap();
+		std::fs::write(root.join("notes.txt"), "not a diff either, but not named like one").unwrap();
+
+		let res = upgrade_directory::<NoopReporter>(&root, None).unwrap();
+		assert_eq!(res.upgraded.len(), 10);
+		assert_eq!(res.up_to_date, [root.join("latest.fldf")]);
+		assert_eq!(res.failed.len(), 1);
+		assert_eq!(res.failed[0].0, root.join("broken.fldf"));
+
+		for (i, d) in old.iter().enumerate() {
+			assert!(detect_version_from_file(&root.join(format!("{i}.fldf"))).unwrap().is_latest());
+			assert_eq!(&std::fs::read(root.join(format!(".foldiff-backup/{i}.fldf"))).unwrap(), d);
+		}
+		// only diffs that were upgraded are backed up
+		assert_eq!(std::fs::read_dir(root.join(".foldiff-backup")).unwrap().count(), 10);
+
+		// running it again has nothing to do
+		let res = upgrade_directory::<NoopReporter>(&root, Some(&root.join("elsewhere"))).unwrap();
+		assert_eq!((res.upgraded.len(), res.up_to_date.len(), res.failed.len()), (0, 11, 1));
+		assert!(!root.join("elsewhere").exists());
+	}
+
+	#[test]
+	fn test_upgrade_in_place() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = Utf8PathBuf::try_from(dir.path().join("diff.fldf")).unwrap();
+		std::fs::write(&path, make_100r_diff(b"upgrade me")).unwrap();
+
+		auto_upgrade_in_place::<NoopReporter>(&path).unwrap();
+		assert!(detect_version_from_file(&path).unwrap().is_latest());
+
+		// already up to date is fine, and leaves it alone
+		let before = std::fs::read(&path).unwrap();
+		auto_upgrade_in_place::<NoopReporter>(&path).unwrap();
+		assert_eq!(std::fs::read(&path).unwrap(), before);
+
+		// and nothing else is left behind
+		assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+	}
+}
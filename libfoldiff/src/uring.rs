@@ -0,0 +1,218 @@
+// writing files with io_uring, so the kernel can be writing one chunk while we are still producing the next.
+// only used for files bigger than one buffer, as setting up a ring for a small file costs more than it saves.
+
+use io_uring::{opcode, types, IoUring};
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+
+const BUF_SIZE: usize = 1 << 20;
+const DEPTH: usize = 4;
+
+/// A writer that queues up full buffers to be written by io_uring, instead of waiting for each write.
+/// Falls back to normal writes if a ring can't be set up, eg on kernels older than 5.1.
+///
+/// Writes still in flight are waited for when dropped, but any errors are lost, so call flush() first.
+pub struct UringWriter {
+	file: File,
+	// only created once we have a full buffer to write
+	ring: Option<IoUring>,
+	ring_failed: bool,
+	bufs: Vec<Vec<u8>>,
+	// the file offset each buffer is being written to, if it is in flight
+	in_flight: [Option<u64>; DEPTH],
+	// the buffer being filled
+	cur: usize,
+	// where the next buffer to be submitted goes in the file
+	offset: u64,
+}
+
+impl UringWriter {
+	/// `file` is written from the start, whatever its position is
+	pub fn new(file: File) -> Self {
+		Self {
+			file,
+			ring: None,
+			ring_failed: false,
+			bufs: (0..DEPTH).map(|_| Vec::with_capacity(BUF_SIZE)).collect(),
+			in_flight: [None; DEPTH],
+			cur: 0,
+			offset: 0,
+		}
+	}
+
+	/// sets up a ring if we don't have one yet, unless that already failed
+	fn ring(&mut self) {
+		if self.ring.is_none() && !self.ring_failed {
+			match IoUring::new(DEPTH as u32) {
+				Ok(r) => self.ring = Some(r),
+				Err(_) => self.ring_failed = true,
+			}
+		}
+	}
+
+	/// starts writing the current buffer, and moves on to the next free one
+	fn submit_cur(&mut self) -> std::io::Result<()> {
+		let i = self.cur;
+		let offset = self.offset;
+		self.offset += self.bufs[i].len() as u64;
+
+		let fd = types::Fd(self.file.as_raw_fd());
+		let (ptr, len) = (self.bufs[i].as_ptr(), self.bufs[i].len() as u32);
+		self.ring();
+		if let Some(ring) = self.ring.as_mut() {
+			let entry = opcode::Write::new(fd, ptr, len).offset(offset).build().user_data(i as u64);
+			// safety: the buffer is not touched again until its completion has been reaped,
+			// and it never grows past its capacity, so it is never reallocated
+			unsafe { ring.submission().push(&entry) }.map_err(std::io::Error::other)?;
+			// once it's queued the kernel may pick it up, even if submitting it fails
+			self.in_flight[i] = Some(offset);
+			ring.submit()?;
+		}
+		else {
+			self.file.write_all_at(&self.bufs[i], offset)?;
+			self.bufs[i].clear();
+		}
+
+		self.cur = (self.cur + 1) % DEPTH;
+		if self.in_flight[self.cur].is_some() {
+			self.wait(1)?;
+		}
+		Ok(())
+	}
+
+	/// waits for at least `n` writes to finish, and frees up their buffers
+	fn wait(&mut self, n: usize) -> std::io::Result<()> {
+		let Some(ring) = self.ring.as_mut()
+		else { return Ok(()) };
+		ring.submit_and_wait(n)?;
+
+		// finish them all before returning any errors, so no buffer is left in flight
+		let mut res = Ok(());
+		let done: Vec<_> = ring.completion().map(|c| (c.user_data() as usize, c.result())).collect();
+		for (i, written) in done {
+			let Some(offset) = self.in_flight.get_mut(i).and_then(Option::take)
+			else {
+				res = res.and(Err(std::io::Error::other("io_uring completed a write we never submitted")));
+				continue;
+			};
+			let written = if written < 0 {
+				res = res.and(Err(std::io::Error::from_raw_os_error(-written)));
+				self.bufs[i].len()
+			}
+			else {
+				written as usize
+			};
+
+			// short writes are rare, so just finish them off normally
+			if written < self.bufs[i].len() {
+				res = res.and(self.file.write_all_at(&self.bufs[i][written..], offset + written as u64));
+			}
+			self.bufs[i].clear();
+		}
+		res
+	}
+
+	fn num_in_flight(&self) -> usize {
+		self.in_flight.iter().filter(|f| f.is_some()).count()
+	}
+}
+
+impl Write for UringWriter {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		let space = BUF_SIZE - self.bufs[self.cur].len();
+		let n = buf.len().min(space);
+		self.bufs[self.cur].extend_from_slice(&buf[..n]);
+
+		if self.bufs[self.cur].len() == BUF_SIZE {
+			self.submit_cur()?;
+		}
+		Ok(n)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		if !self.bufs[self.cur].is_empty() {
+			// not worth going through the ring for the last bit
+			self.file.write_all_at(&self.bufs[self.cur], self.offset)?;
+			self.offset += self.bufs[self.cur].len() as u64;
+			self.bufs[self.cur].clear();
+		}
+		while self.num_in_flight() != 0 {
+			self.wait(self.num_in_flight())?;
+		}
+		self.file.flush()
+	}
+}
+
+impl Drop for UringWriter {
+	fn drop(&mut self) {
+		// the kernel may still be reading from our buffers, so we can't free them until it's done
+		let _ = self.flush();
+		// if that failed partway through, there could still be writes going
+		while self.num_in_flight() != 0 {
+			let _ = self.wait(self.num_in_flight());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::hash;
+	use camino::Utf8PathBuf;
+	use rand::RngCore;
+	use std::io::Read;
+	use std::time::Instant;
+
+	/// copies `src` to `dst`, writing through io_uring. returns how many bytes were copied.
+	fn copy(src: &mut impl Read, dst: File) -> std::io::Result<u64> {
+		let mut w = UringWriter::new(dst);
+		let n = std::io::copy(src, &mut w)?;
+		w.flush()?;
+		Ok(n)
+	}
+
+	#[test]
+	fn test_uring_writer() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("out");
+
+		// a few buffers' worth, plus a bit that doesn't fill one
+		let mut data = vec![0u8; BUF_SIZE * (DEPTH + 2) + 1234];
+		rand::thread_rng().fill_bytes(&mut data);
+
+		let n = copy(&mut &data[..], File::create(&path).unwrap()).unwrap();
+		assert_eq!(n, data.len() as u64);
+		assert!(std::fs::read(&path).unwrap() == data);
+	}
+
+	#[test]
+	fn test_uring_copy_large() {
+		// not really a test, but a benchmark: compare against a normal copy through userspace
+		let dir = tempfile::tempdir().unwrap();
+		let dir = Utf8PathBuf::try_from(dir.path().to_path_buf()).unwrap();
+		let src_path = dir.join("src");
+		{
+			let mut f = File::create(&src_path).unwrap();
+			let mut buf = vec![0u8; 64 << 20];
+			for _ in 0..16 {
+				rand::thread_rng().fill_bytes(&mut buf);
+				f.write_all(&buf).unwrap();
+			}
+		}
+		let expected = hash::hash_file(&src_path).unwrap();
+
+		let time = |name: &str, f: &dyn Fn(&mut File, File)| {
+			let dst_path = dir.join(name);
+			let start = Instant::now();
+			f(&mut File::open(&src_path).unwrap(), File::create(&dst_path).unwrap());
+			let secs = start.elapsed().as_secs_f64();
+			eprintln!("{name}: {:.0} MiB/s", 1024.0 / secs);
+			assert_eq!(hash::hash_file(&dst_path).unwrap(), expected);
+		};
+
+		time("std", &|src, mut dst| { std::io::copy(src, &mut dst).unwrap(); });
+		time("uring", &|src, dst| { copy(src, dst).unwrap(); });
+	}
+}
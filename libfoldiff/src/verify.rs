@@ -1,64 +1,191 @@
 use crate::manifest::DiffManifest;
-use crate::hash::hash_file;
+use crate::hash::{hash_file, hash_file_with};
 use crate::aggregate_errors;
+use crate::zstddiff;
 use anyhow::{bail, Context, Result};
 use rayon::prelude::*;
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
 use std::fs;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::sync::Mutex;
 use camino::{Utf8Path, Utf8PathBuf};
 use crate::reporting::{AutoSpin, Reporter};
+use crate::common::{padding_to, FoldiffCfg, HashAlgorithm, SymlinkMode, VerifyMode};
 
-/// Checks if two directories are identical, printing results to stdout
-pub fn test_dir_equality<TSpin: Reporter+Sync>(r1: &Utf8Path, r2: &Utf8Path) -> Result<()> {
+/// A single way in which the checked folders differ from what was expected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerifyMismatch {
+	/// the file should exist, but doesn't
+	Missing(Utf8PathBuf),
+	/// the file exists, but its contents (or symlink target) are wrong
+	HashMismatch(Utf8PathBuf),
+	/// the first path is a file and the second a folder, or vice versa
+	TypeMismatch(Utf8PathBuf, Utf8PathBuf),
+	/// the path, relative to both folders, is in the first folder but not the second
+	OnlyInFirst(Utf8PathBuf),
+	/// the path, relative to both folders, is in the second folder but not the first
+	OnlyInSecond(Utf8PathBuf),
+	/// the path exists in the new folder, but the manifest does not describe it
+	Unexpected(Utf8PathBuf),
+	/// the path is a symlink, but symlinks are denied
+	SymlinkFound(Utf8PathBuf),
+}
+
+impl Display for VerifyMismatch {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			VerifyMismatch::Missing(p) => write!(f, "{p:?} is missing"),
+			VerifyMismatch::HashMismatch(p) => write!(f, "{p:?} is not as expected"),
+			VerifyMismatch::TypeMismatch(p1, p2) => write!(f, "{p1:?} and {p2:?} mismatch, as one is a file and the other is a folder"),
+			VerifyMismatch::OnlyInFirst(p) => write!(f, "{p:?} only exists in the first folder"),
+			VerifyMismatch::OnlyInSecond(p) => write!(f, "{p:?} only exists in the second folder"),
+			VerifyMismatch::Unexpected(p) => write!(f, "{p:?} exists, but is not in the diff"),
+			VerifyMismatch::SymlinkFound(p) => write!(f, "{p:?} is a symlink"),
+		}
+	}
+}
+
+/// How a verification should treat what it finds
+#[derive(Copy, Clone, Debug, Default)]
+pub struct VerifyOptions {
+	/// return Err listing every mismatch, instead of returning them in the result
+	pub strict: bool,
+}
+
+/// The results of a verification
+#[derive(Clone, Debug, Default)]
+pub struct VerifyResult {
+	pub mismatches: Vec<VerifyMismatch>,
+}
+
+impl VerifyResult {
+	/// if no mismatches were found
+	pub fn is_ok(&self) -> bool {
+		self.mismatches.is_empty()
+	}
+
+	/// in strict mode, any mismatches are returned as an error instead
+	fn from_mutex(m: Mutex<Vec<VerifyMismatch>>, opts: VerifyOptions) -> Result<Self> {
+		let mut mismatches = m.into_inner()?;
+		// we find these in parallel, so at least make the order consistent
+		mismatches.sort_by_key(|m| m.to_string());
+		let res = Self { mismatches };
+
+		if opts.strict && !res.is_ok() {
+			bail!("Found {} problems while verifying:\n{}", res.mismatches.len(), res.to_string().trim_end());
+		}
+		Ok(res)
+	}
+}
+
+impl Display for VerifyResult {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		for m in &self.mismatches {
+			writeln!(f, "{m}")?;
+		}
+		Ok(())
+	}
+}
+
+/// checks if a file has the expected contents, only checking the size if we can in fast mode
+fn has_contents(p: &Utf8Path, hash: u64, algo: HashAlgorithm, size: Option<u64>, mode: VerifyMode) -> Result<bool> {
+	if let (VerifyMode::Fast, Some(size)) = (mode, size) {
+		return Ok(fs::metadata(p).context(format!("Failed to get size of file {p:?}"))?.len() == size);
+	}
+	Ok(hash_file_with(p, algo).context(format!("Failed to hash file {p:?}"))? == hash)
+}
+
+/// checks if two files have the same contents, only comparing sizes in fast mode
+fn files_match(p1: &Utf8Path, p2: &Utf8Path, mode: VerifyMode) -> Result<bool> {
+	if mode == VerifyMode::Fast {
+		let len1 = fs::metadata(p1).context(format!("Failed to get size of file {p1:?}"))?.len();
+		let len2 = fs::metadata(p2).context(format!("Failed to get size of file {p2:?}"))?.len();
+		return Ok(len1 == len2);
+	}
+	Ok(hash_file(p1).context(format!("Failed to hash file {p1:?}"))? == hash_file(p2).context(format!("Failed to hash file {p2:?}"))?)
+}
+
+/// Checks if two directories are identical
+pub fn test_dir_equality<TSpin: Reporter+Sync>(r1: &Utf8Path, r2: &Utf8Path, cfg: &FoldiffCfg, opts: VerifyOptions) -> Result<VerifyResult> {
 	let spn = TSpin::new("Scanning folders");
 	let aspn = AutoSpin::spin(&spn);
-	test_equality_internal(r1, r2, "".into(), &spn)?;
+	let mismatches = Mutex::new(Vec::new());
+	test_equality_internal(r1, r2, "".into(), cfg, opts, &spn, &mismatches)?;
 	aspn.all_good();
-	Ok(())
+	VerifyResult::from_mutex(mismatches, opts)
 }
 
-fn test_equality_internal(r1: &Utf8Path, r2: &Utf8Path, p: &Utf8Path, spn: &(impl Reporter+Sync)) -> Result<()> {
+fn test_equality_internal(r1: &Utf8Path, r2: &Utf8Path, p: &Utf8Path, cfg: &FoldiffCfg, opts: VerifyOptions, spn: &(impl Reporter+Sync), mismatches: &Mutex<Vec<VerifyMismatch>>) -> Result<()> {
+	let report = |m| mismatches.lock().unwrap().push(m);
+	let mode = cfg.verify_mode;
+
+	// found enough already, don't bother looking any further
+	if mismatches.lock().unwrap().len() >= cfg.max_errors {
+		return Ok(());
+	}
+
 	// stat both paths
 	let path1 = r1.join(p);
 	let path2 = r2.join(p);
-	let type1 = fs::symlink_metadata(&path1)?;
-	let type2 = fs::symlink_metadata(&path2)?;
+	let mut type1 = fs::symlink_metadata(&path1)?;
+	let mut type2 = fs::symlink_metadata(&path2)?;
 
-	if type1.is_symlink() {
-		bail!("Found a symlink at {:?}", path1);
-	}
-	if type2.is_symlink() {
-		bail!("Found a symlink at {:?}", path2);
+	if type1.is_symlink() || type2.is_symlink() {
+		match cfg.symlinks {
+			SymlinkMode::Deny => {
+				for (path, ty) in [(&path1, &type1), (&path2, &type2)] {
+					if ty.is_symlink() {
+						if opts.strict {
+							bail!("Found a symlink at {:?}", path);
+						}
+						report(VerifyMismatch::SymlinkFound(path.clone()));
+					}
+				}
+				spn.incr(1);
+				return Ok(());
+			},
+			SymlinkMode::Follow => {
+				type1 = fs::metadata(&path1).with_context(|| format!("Failed to follow symlink {path1:?}"))?;
+				type2 = fs::metadata(&path2).with_context(|| format!("Failed to follow symlink {path2:?}"))?;
+			},
+			SymlinkMode::Preserve => {
+				if type1.is_symlink() && type2.is_symlink() {
+					if fs::read_link(&path1)? != fs::read_link(&path2)? {
+						report(VerifyMismatch::HashMismatch(p.to_path_buf()));
+					}
+				}
+				else {
+					report(VerifyMismatch::TypeMismatch(path1, path2));
+				}
+				spn.incr(1);
+				return Ok(());
+			},
+		}
 	}
 
 	spn.incr(1);
 
 	if type1.is_file() {
 		if type2.is_file() {
-			if hash_file(&path1)? != hash_file(&path2)? {
-				spn.suspend(|| {
-					println!("The file {:?} exists in both directories, but has differing contents.", p.to_path_buf());
-				});
+			let differs =
+				if mode == VerifyMode::Fast {
+					type1.len() != type2.len()
+				}
+				else {
+					hash_file(&path1)? != hash_file(&path2)?
+				};
+			if differs {
+				report(VerifyMismatch::HashMismatch(p.to_path_buf()));
 			}
 		}
 		else {
-			spn.suspend(|| {
-				println!(
-					"{:?} is a file, but {:?} is a folder, thus they mismatch.",
-					Utf8Path::new(r1.file_name().unwrap()).join(p),
-					Utf8Path::new(r2.file_name().unwrap()).join(p)
-				);
-			});
+			report(VerifyMismatch::TypeMismatch(r1.join(p), r2.join(p)));
 		}
 	}
 	else if type2.is_file() {
-		spn.suspend(|| {
-			println!(
-				"{:?} is a folder, but {:?} is a file, thus they mismatch.",
-				Utf8Path::new(r1.file_name().unwrap()).join(p),
-				Utf8Path::new(r2.file_name().unwrap()).join(p)
-			);
-		});
+		report(VerifyMismatch::TypeMismatch(r1.join(p), r2.join(p)));
 	}
 	else {
 		// both are directories
@@ -78,14 +205,12 @@ fn test_equality_internal(r1: &Utf8Path, r2: &Utf8Path, p: &Utf8Path, spn: &(imp
 					set1.par_iter()
 						.map(|f| {
 							if !set2.contains(f) {
-								spn.suspend(|| {
-									println!("{:?} only exists in the first folder.", p.join(f));
-								});
+								report(VerifyMismatch::OnlyInFirst(p.join(f)));
 								spn.incr(1);
 							}
 							else {
 								// we have both! recurse.
-								test_equality_internal(r1, r2, &p.join(f), spn)?
+								test_equality_internal(r1, r2, &p.join(f), cfg, opts, spn, mismatches)?
 							}
 							Ok(())
 						})
@@ -95,9 +220,7 @@ fn test_equality_internal(r1: &Utf8Path, r2: &Utf8Path, p: &Utf8Path, spn: &(imp
 				set2.par_iter()
 					.for_each(|f| {
 						if !set1.contains(f) {
-							spn.suspend(|| {
-								println!("{:?} only exists in the second folder.", p.join(f));
-							});
+							report(VerifyMismatch::OnlyInSecond(p.join(f)));
 							spn.incr(1);
 						}
 					}),
@@ -109,11 +232,63 @@ fn test_equality_internal(r1: &Utf8Path, r2: &Utf8Path, p: &Utf8Path, spn: &(imp
 	Ok(())
 }
 
-/// Checks if two directories match the given manifest, printing results to stdout
-pub fn verify_against_diff<TSpin: Reporter+Sync>(r1: &Utf8Path, r2: &Utf8Path, manifest: &DiffManifest) -> Result<()> {
+/// finds every file in `root` that is not in `expected`.
+/// empty directories are unexpected too, unless they are listed.
+/// gives up early once `stop` returns true.
+fn find_unexpected(root: &Utf8Path, expected: &HashSet<&str>, spn: &impl Reporter, report: impl Fn(VerifyMismatch), stop: impl Fn() -> bool) -> Result<()> {
+	// paths in the manifest always use "/", so build the lookup keys the same way
+	let mut queue = VecDeque::from([String::new()]);
+
+	while let Some(dir) = queue.pop_front() {
+
+		let entries = root.join(&dir).read_dir_utf8().with_context(|| format!("Failed to read dir while verifying {dir:?}"))?;
+		let mut is_empty = true;
+
+		for entry in entries {
+			if stop() {
+				return Ok(());
+			}
+			let entry = entry.with_context(|| format!("Failed to read entry while verifying {dir:?}"))?;
+			is_empty = false;
+			spn.incr(1);
+
+			let path =
+				if dir.is_empty() { entry.file_name().to_string() }
+				else { format!("{dir}/{}", entry.file_name()) };
+			if expected.contains(path.as_str()) {
+				continue;
+			}
+
+			// symlinks to folders are followed when diffing with `--symlinks follow`, so do the same here
+			let is_dir = entry.file_type()?.is_dir() || (entry.file_type()?.is_symlink() && entry.path().is_dir());
+			if is_dir {
+				queue.push_back(path);
+			}
+			else {
+				report(VerifyMismatch::Unexpected(root.join(&path)));
+			}
+		}
+
+		if is_empty && !dir.is_empty() && !expected.contains(dir.as_str()) {
+			report(VerifyMismatch::Unexpected(root.join(&dir)));
+		}
+	}
+
+	Ok(())
+}
+
+/// Checks if two directories match the given manifest
+pub fn verify_against_diff<TSpin: Reporter+Sync>(r1: &Utf8Path, r2: &Utf8Path, manifest: &DiffManifest, cfg: &FoldiffCfg, opts: VerifyOptions) -> Result<VerifyResult> {
 	let spn = TSpin::new("Verifying files");
 	let aspn = AutoSpin::spin(&spn);
 
+	let sizes = manifest.size_lookup();
+
+	let mismatches = Mutex::new(Vec::new());
+	let report = |m| mismatches.lock().unwrap().push(m);
+	// once this is true, the rest of the checks are skipped
+	let stop = || mismatches.lock().unwrap().len() >= cfg.max_errors;
+
 	let errors: Vec<_> =
 		manifest.untouched_files
 			.par_iter()
@@ -140,15 +315,14 @@ pub fn verify_against_diff<TSpin: Reporter+Sync>(r1: &Utf8Path, r2: &Utf8Path, m
 					})
 			)
 			.map(|(h, p)| {
+				if stop() {
+					return Ok(());
+				}
 				if !fs::exists(&p).context(format!("Failed to check if {p:?} exists"))? {
-					spn.suspend(|| {
-						println!("{p:?} is missing");
-					})
+					report(VerifyMismatch::Missing(p.clone()))
 				}
-				else if hash_file(&p).context(format!("Failed to hash file {p:?}"))? != h {
-					spn.suspend(|| {
-						println!("{p:?} is not as expected");
-					})
+				else if !has_contents(&p, h, manifest.hash_algorithm, sizes.get(&h).copied(), cfg.verify_mode)? {
+					report(VerifyMismatch::HashMismatch(p.clone()))
 				}
 				spn.incr(1);
 				anyhow::Ok(())
@@ -159,9 +333,337 @@ pub fn verify_against_diff<TSpin: Reporter+Sync>(r1: &Utf8Path, r2: &Utf8Path, m
 			})
 			.collect();
 
+	let symlink_errors: Vec<_> =
+		manifest.symlinks
+			.par_iter()
+			.filter_map(|sl| {
+				if stop() {
+					return None;
+				}
+				let p = r2.join(&sl.path);
+				match fs::read_link(&p) {
+					Ok(target) => {
+						if target.as_os_str() != sl.target.as_str() {
+							report(VerifyMismatch::HashMismatch(p.clone()))
+						}
+					},
+					Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+						report(VerifyMismatch::Missing(p.clone()))
+					},
+					Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+						// exists, but is not a symlink
+						report(VerifyMismatch::HashMismatch(p.clone()))
+					},
+					Err(e) => return Some(anyhow::Error::new(e).context(format!("Failed to read symlink {p:?}"))),
+				}
+				spn.incr(1);
+				None
+			})
+			.collect();
+
+	let hardlink_errors: Vec<_> =
+		manifest.hardlinked_files
+			.par_iter()
+			.map(|hl| {
+				if stop() {
+					return Ok(());
+				}
+				let p = r2.join(&hl.path);
+				if !fs::exists(&p).context(format!("Failed to check if {p:?} exists"))? {
+					report(VerifyMismatch::Missing(p.clone()))
+				}
+				else if !files_match(&p, &r2.join(&hl.target), cfg.verify_mode)? {
+					report(VerifyMismatch::HashMismatch(p.clone()))
+				}
+				spn.incr(1);
+				anyhow::Ok(())
+			})
+			.filter_map(Result::err)
+			.collect();
+
+	// everything the diff says should be in the new folder
+	let expected: HashSet<&str> =
+		manifest.iter_new_paths()
+			.chain(manifest.symlinks.iter().map(|sl| sl.path.as_str()))
+			.chain(manifest.hardlinked_files.iter().map(|hl| hl.path.as_str()))
+			.chain(manifest.empty_dirs_new.iter().map(String::as_str))
+			.collect();
+
+	find_unexpected(r2, &expected, &spn, report, stop)?;
+
+	for (root, dirs) in [(r1, &manifest.empty_dirs_deleted), (r2, &manifest.empty_dirs_new)] {
+		for dir in dirs {
+			let p = root.join(dir);
+			if !p.is_dir() {
+				report(VerifyMismatch::Missing(p.clone()))
+			}
+			spn.incr(1);
+		}
+	}
+
 	aspn.all_good();
 
 	aggregate_errors!(errors);
+	aggregate_errors!(symlink_errors);
+	aggregate_errors!(hardlink_errors);
 
-	Ok(())
-}
\ No newline at end of file
+	VerifyResult::from_mutex(mismatches, opts)
+}
+
+/// What `validate_diff` found wrong with a diff file
+#[derive(Clone, Debug, Default)]
+pub struct DiffValidationReport {
+	/// if nothing at all was wrong
+	pub ok: bool,
+	pub manifest_ok: bool,
+	pub blobs_ok: bool,
+	pub issues: Vec<String>,
+}
+
+impl Display for DiffValidationReport {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		for i in &self.issues {
+			writeln!(f, "{i}")?;
+		}
+		Ok(())
+	}
+}
+
+fn read_u64(f: &mut impl Read, what: &dyn Display) -> std::result::Result<u64, String> {
+	let mut buf = [0u8; 8];
+	f.read_exact(&mut buf).map_err(|_| format!("File ends before the {what}"))?;
+	Ok(u64::from_be_bytes(buf))
+}
+
+fn skip(f: &mut (impl Read + Seek), len: u64, file_len: u64, what: &dyn Display) -> std::result::Result<(), String> {
+	let pos = f.stream_position().map_err(|e| e.to_string())?;
+	if pos.checked_add(len).is_none_or(|end| end > file_len) {
+		return Err(format!("The {what} is {len} bytes long, but the file ends {} bytes after it starts", file_len - pos));
+	}
+	f.seek_relative(len as i64).map_err(|e| e.to_string())
+}
+
+/// walks through every blob, checking that it fits in the file and that nothing is left over at the end.
+/// returns how many new and patch blobs there are, or the first problem, as nothing after a bad length can be found.
+fn check_blobs(f: &mut (impl Read + Seek), file_len: u64, manifest: &DiffManifest) -> std::result::Result<(u64, u64), String> {
+	let pos = f.stream_position().map_err(|e| e.to_string())?;
+	skip(f, padding_to(pos, manifest.blob_alignment), file_len, &"padding before the blobs")?;
+
+	let new_count = read_u64(f, &"new blob count")?;
+	for i in 0..new_count {
+		let len = read_u64(f, &format_args!("length of new blob {i}"))?;
+		skip(f, len, file_len, &format_args!("new blob {i}"))?;
+	}
+
+	let patch_count = read_u64(f, &"patch blob count")?;
+	for i in 0..patch_count {
+		let (chunks, stored) = zstddiff::split_chunk_count(read_u64(f, &format_args!("chunk count of patch blob {i}"))?);
+		if stored {
+			skip(f, chunks.saturating_mul(8), file_len, &format_args!("old chunk lengths of patch blob {i}"))?;
+		}
+		for c in 0..chunks {
+			let len = zstddiff::chunk_data_len(read_u64(f, &format_args!("length of chunk {c} of patch blob {i}"))?);
+			skip(f, len, file_len, &format_args!("chunk {c} of patch blob {i}"))?;
+		}
+	}
+
+	if manifest.has_dictionaries {
+		for name in ["new file dictionary", "diff dictionary"] {
+			let len = read_u64(f, &format_args!("length of the {name}"))?;
+			skip(f, len, file_len, &name)?;
+		}
+	}
+
+	if manifest.has_catalog {
+		let start = f.stream_position().map_err(|e| e.to_string())?;
+		skip(f, 4, file_len, &"catalog magic")?;
+		let count = read_u64(f, &"catalog entry count")?;
+		skip(f, 16 + count.saturating_mul(24), file_len, &"catalog entries")?;
+		if read_u64(f, &"catalog start")? != start {
+			return Err("The catalog at the end of the file doesn't point to itself".to_string());
+		}
+	}
+
+	let end = f.stream_position().map_err(|e| e.to_string())?;
+	if end != file_len {
+		return Err(format!("There are {} unexpected bytes at the end of the file", file_len - end));
+	}
+	Ok((new_count, patch_count))
+}
+
+/// Checks that a diff file is self-consistent without applying it: the manifest must be readable
+/// and only reference blobs that exist, and every blob must fit in the file with nothing left over.
+/// Blob contents are not decompressed, so this is much faster than applying.
+/// Only returns Err if the file can't be read at all, problems with its contents end up in the report.
+pub fn validate_diff(path: &Utf8Path) -> Result<DiffValidationReport> {
+	let mut f = BufReader::new(File::open(path).context("Failed to open diff to validate")?);
+	let file_len = f.get_ref().metadata().context("Failed to get size of diff")?.len();
+	let mut report = DiffValidationReport::default();
+
+	// without a manifest, there's no way to tell where the blobs start
+	let manifest = match DiffManifest::read_from(&mut f) {
+		Ok(m) => m,
+		Err(e) => {
+			report.issues.push(format!("Failed to read manifest: {e:#}"));
+			return Ok(report);
+		},
+	};
+
+	let counts = check_blobs(&mut f, file_len, &manifest);
+	report.blobs_ok = counts.is_ok();
+	// if we couldn't count the blobs, we can still check everything but the indices
+	let (new_count, patch_count) = counts.unwrap_or_else(|issue| {
+		report.issues.push(issue);
+		(u64::MAX, u64::MAX)
+	});
+
+	report.manifest_ok = match manifest.validate(new_count, patch_count) {
+		Ok(()) => true,
+		Err(problems) => {
+			report.issues.extend(problems);
+			false
+		},
+	};
+
+	report.ok = report.manifest_ok && report.blobs_ok;
+	Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::reporting::NoopReporter;
+	use crate::testing::{generate_test_folder, root_of, FolderSpec};
+	use crate::vfs::StdVfs;
+
+	fn make_diff(root: &Utf8Path) -> Utf8PathBuf {
+		FolderSpec::new()
+			.file("old/patched", "before ".repeat(100))
+			.file("new/patched", "after ".repeat(100))
+			.file("new/added", "new file")
+			.create_in(root);
+
+		let cfg = FoldiffCfg::default();
+		let path = root.join("diff.fldf");
+		crate::diffing::scan_to_diff::<NoopReporter>(root.join("old"), root.join("new"), &cfg, &StdVfs).unwrap()
+			.write_to_file::<NoopReporter, NoopReporter>(&path, &cfg).unwrap();
+		path
+	}
+
+	#[test]
+	fn test_validate_diff() {
+		let dir = tempfile::tempdir().unwrap();
+		let root = Utf8Path::from_path(dir.path()).unwrap();
+		let path = make_diff(root);
+
+		let report = validate_diff(&path).unwrap();
+		assert!(report.ok, "{report}");
+
+		// cut off the end of the catalog
+		let original = fs::read(&path).unwrap();
+		File::options().write(true).open(&path).unwrap().set_len(original.len() as u64 - 5).unwrap();
+		let report = validate_diff(&path).unwrap();
+		assert!(!report.ok && !report.blobs_ok && report.manifest_ok);
+
+		// or have too much
+		fs::write(&path, [&original[..], b"junk"].concat()).unwrap();
+		let report = validate_diff(&path).unwrap();
+		assert!(!report.blobs_ok);
+		assert_eq!(report.issues, ["There are 4 unexpected bytes at the end of the file"]);
+
+		fs::write(&path, b"FLDF nonsense").unwrap();
+		let report = validate_diff(&path).unwrap();
+		assert!(!report.ok && !report.manifest_ok);
+	}
+
+	#[test]
+	fn test_verify_result() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("a/same", "same")
+			.file("a/changed", "before")
+			.file("a/only_a", "a")
+			.file("b/same", "same")
+			.file("b/changed", "after")
+			.file("b/only_b", "b")
+		);
+		let root = root_of(&dir);
+		let (a, b) = (root.join("a"), root.join("b"));
+
+		assert!(VerifyResult::default().is_ok());
+		assert_eq!(VerifyResult::default().to_string(), "");
+
+		let res = test_dir_equality::<NoopReporter>(&a, &b, &FoldiffCfg::default(), VerifyOptions::default()).unwrap();
+		assert!(!res.is_ok());
+		// sorted by how they display, so the order doesn't depend on threads
+		assert_eq!(res.mismatches, [
+			VerifyMismatch::HashMismatch("changed".into()),
+			VerifyMismatch::OnlyInFirst("only_a".into()),
+			VerifyMismatch::OnlyInSecond("only_b".into()),
+		]);
+		assert_eq!(res.to_string(), concat!(
+			"\"changed\" is not as expected\n",
+			"\"only_a\" only exists in the first folder\n",
+			"\"only_b\" only exists in the second folder\n",
+		));
+
+		// strict mode returns them as an error instead
+		let (cfg, strict) = (FoldiffCfg::default(), VerifyOptions { strict: true });
+		let err = test_dir_equality::<NoopReporter>(&a, &b, &cfg, strict).unwrap_err();
+		assert!(err.to_string().starts_with("Found 3 problems while verifying:\n"), "{err}");
+		assert!(test_dir_equality::<NoopReporter>(&a, &a, &cfg, strict).unwrap().is_ok());
+	}
+
+	#[test]
+	fn test_verify_nested_files() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("old/sub/dir/file", "before")
+			.file("new/sub/dir/file", "after")
+			.file("new/sub/other/added", "new file")
+		);
+		let root = root_of(&dir);
+
+		let cfg = FoldiffCfg::default();
+		let manifest = crate::diffing::scan_to_diff::<NoopReporter>(root.join("old"), root.join("new"), &cfg, &StdVfs).unwrap()
+			.generate_manifest::<NoopReporter>().unwrap();
+
+		let res = verify_against_diff::<NoopReporter>(&root.join("old"), &root.join("new"), &manifest, &cfg, VerifyOptions::default()).unwrap();
+		assert!(res.is_ok(), "{res}");
+
+		fs::write(root.join("new/sub/dir/stray"), "not in the diff").unwrap();
+		let res = verify_against_diff::<NoopReporter>(&root.join("old"), &root.join("new"), &manifest, &cfg, VerifyOptions::default()).unwrap();
+		assert_eq!(res.mismatches, [VerifyMismatch::Unexpected(root.join("new/sub/dir/stray"))]);
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn test_dir_equality_symlinks() {
+		let dir = generate_test_folder(FolderSpec::new()
+			.file("a/target1", "same")
+			.file("a/target2", "same")
+			.file("b/target1", "same")
+			.file("b/target2", "same")
+		);
+		let root = root_of(&dir);
+		for (side, target) in [("a", "target1"), ("b", "target2")] {
+			std::os::unix::fs::symlink(target, root.join(side).join("link")).unwrap();
+		}
+
+		let check = |symlinks, strict| {
+			let cfg = FoldiffCfg { symlinks, ..FoldiffCfg::default() };
+			test_dir_equality::<NoopReporter>(&root.join("a"), &root.join("b"), &cfg, VerifyOptions { strict })
+		};
+
+		let res = check(SymlinkMode::Deny, false).unwrap();
+		assert_eq!(res.mismatches, [
+			VerifyMismatch::SymlinkFound(root.join("a/link")),
+			VerifyMismatch::SymlinkFound(root.join("b/link")),
+		]);
+		assert!(check(SymlinkMode::Deny, true).is_err());
+
+		// both point at files with the same contents
+		assert!(check(SymlinkMode::Follow, false).unwrap().is_ok());
+
+		let res = check(SymlinkMode::Preserve, false).unwrap();
+		assert_eq!(res.mismatches, [VerifyMismatch::HashMismatch("link".into())]);
+	}
+}
@@ -0,0 +1,179 @@
+use std::fs::File;
+use std::io::{Read, Write};
+use std::time::SystemTime;
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use crate::common::create_file;
+
+/// What foldiff needs to know about a path
+#[derive(Clone, Debug, Default)]
+pub struct VfsMetadata {
+	pub len: u64,
+	pub is_dir: bool,
+	pub is_file: bool,
+	pub is_symlink: bool,
+	/// None if the filesystem doesn't track it
+	pub modified: Option<SystemTime>,
+	/// unix permissions, if there are any
+	pub mode: Option<u32>,
+	/// identifies the underlying file if other paths are hard links to it
+	pub hard_link: Option<(u64, u64)>,
+}
+
+impl From<std::fs::Metadata> for VfsMetadata {
+	fn from(meta: std::fs::Metadata) -> Self {
+		#[cfg(unix)]
+		let (mode, hard_link) = {
+			use std::os::unix::fs::MetadataExt;
+			(Some(meta.mode() & 0o7777), (meta.nlink() > 1).then_some((meta.dev(), meta.ino())))
+		};
+		#[cfg(not(unix))]
+		let (mode, hard_link) = (None, None);
+
+		Self {
+			len: meta.len(),
+			is_dir: meta.is_dir(),
+			is_file: meta.is_file(),
+			is_symlink: meta.is_symlink(),
+			modified: meta.modified().ok(),
+			mode,
+			hard_link,
+		}
+	}
+}
+
+/// Where foldiff reads and writes files.
+/// Symlinks, hard links, and permissions are specific to real filesystems, so are only handled for paths
+/// that report them, and still go through std::fs.
+pub trait VfsProvider: Sync {
+	fn open_read(&self, path: &Utf8Path) -> Result<Box<dyn Read>>;
+	/// creates or truncates a file, and any parent directories it needs
+	fn create_write(&self, path: &Utf8Path) -> Result<Box<dyn Write>>;
+	fn exists(&self, path: &Utf8Path) -> bool;
+	/// lists the full paths of everything in a directory
+	fn read_dir(&self, path: &Utf8Path) -> Result<Vec<Utf8PathBuf>>;
+	/// does not follow symlinks
+	fn metadata(&self, path: &Utf8Path) -> Result<VfsMetadata>;
+}
+
+/// The real filesystem
+#[derive(Copy, Clone, Debug, Default)]
+pub struct StdVfs;
+
+impl VfsProvider for StdVfs {
+	fn open_read(&self, path: &Utf8Path) -> Result<Box<dyn Read>> {
+		Ok(Box::new(File::open(path).with_context(|| format!("Failed to open {path}"))?))
+	}
+
+	fn create_write(&self, path: &Utf8Path) -> Result<Box<dyn Write>> {
+		let f = create_file(path).with_context(|| format!("Failed to create {path}"))?;
+		#[cfg(all(feature = "uring", target_os = "linux"))]
+		return Ok(Box::new(crate::uring::UringWriter::new(f)));
+		#[cfg(not(all(feature = "uring", target_os = "linux")))]
+		return Ok(Box::new(f));
+	}
+
+	fn exists(&self, path: &Utf8Path) -> bool {
+		path.symlink_metadata().is_ok()
+	}
+
+	/// skips anything with a non-UTF-8 name
+	fn read_dir(&self, path: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+		let mut paths = Vec::new();
+		for entry in std::fs::read_dir(path)? {
+			if let Ok(p) = Utf8PathBuf::try_from(entry?.path()) {
+				paths.push(p);
+			}
+		}
+		Ok(paths)
+	}
+
+	fn metadata(&self, path: &Utf8Path) -> Result<VfsMetadata> {
+		Ok(std::fs::symlink_metadata(path)?.into())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::HashMap;
+	use std::io::Cursor;
+	use std::sync::{Arc, Mutex};
+	use crate::FoldiffCfg;
+	use crate::reporting::{NoopMultiWrapper, NoopReporter};
+
+	type Files = Arc<Mutex<HashMap<Utf8PathBuf, Vec<u8>>>>;
+
+	#[derive(Default)]
+	struct MemVfs(Files);
+
+	/// puts the file in place once it's done being written
+	struct MemWriter(Files, Utf8PathBuf, Vec<u8>);
+
+	impl Write for MemWriter {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			self.2.write(buf)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	impl Drop for MemWriter {
+		fn drop(&mut self) {
+			self.0.lock().unwrap().insert(self.1.clone(), std::mem::take(&mut self.2));
+		}
+	}
+
+	impl VfsProvider for MemVfs {
+		fn open_read(&self, path: &Utf8Path) -> Result<Box<dyn Read>> {
+			let data = self.0.lock().unwrap().get(path).cloned().ok_or(anyhow::anyhow!("{path} does not exist"))?;
+			Ok(Box::new(Cursor::new(data)))
+		}
+
+		fn create_write(&self, path: &Utf8Path) -> Result<Box<dyn Write>> {
+			Ok(Box::new(MemWriter(self.0.clone(), path.to_path_buf(), Vec::new())))
+		}
+
+		fn exists(&self, path: &Utf8Path) -> bool {
+			self.0.lock().unwrap().contains_key(path)
+		}
+
+		fn read_dir(&self, path: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+			Ok(self.0.lock().unwrap().keys().filter(|p| p.parent() == Some(path)).cloned().collect())
+		}
+
+		fn metadata(&self, path: &Utf8Path) -> Result<VfsMetadata> {
+			let len = self.0.lock().unwrap().get(path).ok_or(anyhow::anyhow!("{path} does not exist"))?.len() as u64;
+			Ok(VfsMetadata { len, is_file: true, ..Default::default() })
+		}
+	}
+
+	#[test]
+	fn test_apply_to_mem_vfs() {
+		let dir = tempfile::tempdir().unwrap();
+		let root = Utf8Path::from_path(dir.path()).unwrap();
+		std::fs::create_dir_all(root.join("old")).unwrap();
+		std::fs::create_dir_all(root.join("new")).unwrap();
+		std::fs::write(root.join("old/a"), "the quick brown fox".repeat(50)).unwrap();
+		std::fs::write(root.join("new/a"), "the quick brown cat".repeat(50)).unwrap();
+		std::fs::write(root.join("new/b"), "jumps over the lazy dog").unwrap();
+
+		let cfg = FoldiffCfg::default();
+		let mut diff = crate::diffing::scan_to_diff::<NoopReporter>(root.join("old"), root.join("new"), &cfg, &StdVfs).unwrap();
+		diff.write_to_file::<NoopReporter, NoopReporter>(&root.join("diff.fldf"), &cfg).unwrap();
+
+		// the old file only exists in memory, and nothing gets written to disk
+		let vfs = MemVfs::default();
+		vfs.0.lock().unwrap().insert("mem/old/a".into(), "the quick brown fox".repeat(50).into_bytes());
+
+		let mut applying = crate::applying::read_diff_from_file(&root.join("diff.fldf")).unwrap();
+		assert!(applying.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>("mem/old".into(), "mem/new".into(), &cfg, &vfs, None).unwrap().is_ok());
+
+		let files = vfs.0.lock().unwrap();
+		assert_eq!(files[Utf8Path::new("mem/new/a")], "the quick brown cat".repeat(50).into_bytes());
+		assert_eq!(files[Utf8Path::new("mem/new/b")], b"jumps over the lazy dog");
+		assert!(!std::fs::exists("mem").unwrap());
+	}
+}
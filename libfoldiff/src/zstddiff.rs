@@ -1,12 +1,72 @@
 // performs diffing using zstd, similar to the --patch-from cli argument in the zstd cli
 
-use anyhow::Result;
+use anyhow::{bail, ensure, Result};
+use fastcdc::v2020::StreamCDC;
+use std::collections::HashMap;
 use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+use xxhash_rust::xxh3::xxh3_64;
+use zstd::zstd_safe::CParameter;
 use zstd::{Decoder, Encoder};
 
 // bytes
 const CHUNK_SIZE: f64 = ((1u64 << 31)/2) as f64; // 1gb
 
+// limits for reading diffs, so malformed ones fail instead of allocating forever
+const MAX_CHUNKS: u64 = 65536; // 64TiB old files
+const MAX_CHUNK_SIZE: u64 = CHUNK_SIZE as u64;
+
+// set in the chunk count when the length of each chunk of the old file is stored, instead of being
+// worked out from the old file's length. old versions see an absurd chunk count and refuse the diff
+const STORED_CHUNKS_FLAG: u64 = 1 << 63;
+
+// set in the chunk count of a text delta, which is a single chunk that decompresses to a list of lines to copy from the old file
+// and bytes to insert, instead of to the new file itself
+pub(crate) const TEXT_DELTA_FLAG: u64 = 1 << 62;
+
+// written as a chunk's length when the new chunk is exactly the same as the old one, with no data after it.
+// applying copies the old chunk instead of decompressing anything
+pub(crate) const IDENTICAL_CHUNK: u64 = u64::MAX;
+
+// lines shorter than this aren't worth a copy of their own, but can still continue one
+const TEXT_MIN_LINE: usize = 8;
+
+// average size of content-defined chunks. these are only used to find where the old and new files line up,
+// the chunks that get diffed are made of lots of them
+const CDC_AVG_SIZE: u32 = 1 << 20; // 1mb
+
+// the smallest and biggest window logs zstd can use. the biggest is a 2GiB window
+pub const MIN_WINDOW_LOG: u8 = 10;
+pub const MAX_WINDOW_LOG: u8 = 31;
+// the range zstd accepts for the long distance matching hash table size
+pub const MIN_LDM_HASH_LOG: u8 = 6;
+pub const MAX_LDM_HASH_LOG: u8 = 30;
+
+// long distance matching doesn't find anything the normal match finder wouldn't when there's less than this to look through
+const LDM_MIN_LEN: u64 = 32 << 20; // 32mb
+
+// how much of the end of the previous patched file is given to zstd when diffing the next one in a delta chain
+pub(crate) const CHAIN_WINDOW: usize = 8 << 20; // 8mb
+
+/// Information about how well some data compressed
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CompressionStats {
+	pub chunks: u64,
+	pub input_bytes: u64,
+	pub output_bytes: u64,
+	/// output / input, so smaller is better
+	pub ratio: f64,
+	pub elapsed: Duration,
+}
+
+impl CompressionStats {
+	pub(crate) fn new(chunks: u64, input_bytes: u64, output_bytes: u64, elapsed: Duration) -> Self {
+		let ratio = if input_bytes == 0 { 1.0 } else { output_bytes as f64 / input_bytes as f64 };
+		Self { chunks, input_bytes, output_bytes, ratio, elapsed }
+	}
+}
+
 fn length_of(stream: &mut impl Seek) -> Result<u64> {
 	let current_pos = stream.stream_position()?;
 	let length = stream.seek(SeekFrom::End(0))?;
@@ -29,7 +89,8 @@ fn calc_chunk_num(
 	let l1f = l1 as f64;
 	let l2f = l2 as f64;
 	let num_chunks = l1f / CHUNK_SIZE;
-	let num_chunks = num_chunks.ceil(); // round up to ensure the chunk size is <=
+	// round up to ensure the chunk size is <=, and an empty old file still needs a chunk to hold all of new
+	let num_chunks = num_chunks.ceil().max(1.0);
 
 	Ok((num_chunks, l1, l2, l1f, l2f))
 }
@@ -50,6 +111,7 @@ fn read_u64(r: &mut impl Read) -> Result<u64> {
 /// The diff structure (number of blobs, (length of blob, blob)[]) will be written into `dest` at the current seek point.
 /// `level` is the zstd compression level, higher will give smaller diffs.
 /// `old_len_hint` and `new_len_hint` should either not be provided, or MUST be EXACTLY the size of the old and new streams, and allows eliding length determination via SeekFrom::End.
+/// Returns how well the new stream compressed.
 pub fn diff(
 	old: &mut (impl Read + Seek),
 	new: &mut (impl Read + Seek),
@@ -58,7 +120,150 @@ pub fn diff(
 	threads: Option<usize>,
 	old_len_hint: Option<u64>,
 	new_len_hint: Option<u64>,
-) -> Result<()> {
+) -> Result<CompressionStats> {
+	diff_with_dict(old, new, dest, level, threads, old_len_hint, new_len_hint, None, &DiffTuning::default())
+}
+
+/// How zstd is set up when diffing, apart from the level. None of it is needed to apply the diff
+#[derive(Copy, Clone, Debug)]
+pub struct DiffTuning {
+	/// how far back zstd can look, as a power of two. if None, the smallest that covers each chunk and its prefix,
+	/// so small files don't need a 2GiB window to apply
+	pub window_log: Option<u8>,
+	/// look for matches far back in the old file. only used for chunks of at least 32MiB, as smaller ones gain nothing from it
+	pub long_distance_matching: bool,
+	/// how big long distance matching's hash table is, as a power of two. if None, zstd picks it from the window log
+	pub ldm_hash_log: Option<u8>,
+}
+
+impl Default for DiffTuning {
+	fn default() -> Self {
+		Self { window_log: None, long_distance_matching: true, ldm_hash_log: None }
+	}
+}
+
+impl DiffTuning {
+	/// fails if anything is out of the range zstd accepts
+	pub fn check(&self) -> Result<()> {
+		if let Some(w) = self.window_log {
+			ensure!((MIN_WINDOW_LOG..=MAX_WINDOW_LOG).contains(&w), "Window log must be between {MIN_WINDOW_LOG} and {MAX_WINDOW_LOG}, not {w}");
+		}
+		if let Some(h) = self.ldm_hash_log {
+			ensure!((MIN_LDM_HASH_LOG..=MAX_LDM_HASH_LOG).contains(&h), "LDM hash log must be between {MIN_LDM_HASH_LOG} and {MAX_LDM_HASH_LOG}, not {h}");
+		}
+		Ok(())
+	}
+
+	/// sets up `enc` to compress `len` bytes, including the prefix
+	fn apply<W: Write>(&self, enc: &mut Encoder<'_, W>, len: u64) -> Result<()> {
+		enc.window_log(pick_window_log(self.window_log, len))?;
+		if self.long_distance_matching && len >= LDM_MIN_LEN {
+			enc.long_distance_matching(true)?;
+			if let Some(h) = self.ldm_hash_log {
+				enc.set_parameter(CParameter::LdmHashLog(h as u32))?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// the window log to compress `len` bytes with, including the prefix: `window_log` if given,
+/// otherwise just big enough to reach from the end back to the start of the prefix
+fn pick_window_log(window_log: Option<u8>, len: u64) -> u32 {
+	let auto = len.max(1).next_power_of_two().trailing_zeros();
+	window_log.map_or(auto, u32::from).clamp(MIN_WINDOW_LOG as u32, MAX_WINDOW_LOG as u32)
+}
+
+/// splits the chunk count at the start of a diff into the number of chunks, and if the old length of each one is stored after it.
+/// text deltas are laid out the same as any other single chunk diff
+pub(crate) fn split_chunk_count(raw: u64) -> (u64, bool) {
+	(raw & !(STORED_CHUNKS_FLAG | TEXT_DELTA_FLAG), raw & STORED_CHUNKS_FLAG != 0)
+}
+
+/// how many bytes of data follow a chunk with this length
+pub(crate) fn chunk_data_len(len: u64) -> u64 {
+	if len == IDENTICAL_CHUNK { 0 } else { len }
+}
+
+/// if the next `expected.len()` bytes of `r` are exactly `expected`
+fn stream_equals(r: &mut impl Read, expected: &[u8]) -> Result<bool> {
+	let mut buf = vec![0; expected.len().clamp(1, 1 << 20)];
+	for part in expected.chunks(buf.len()) {
+		let buf = &mut buf[..part.len()];
+		r.read_exact(buf)?;
+		if buf != part {
+			return Ok(false);
+		}
+	}
+	Ok(true)
+}
+
+/// reads the next `len` bytes of `old` into memory to use as the prefix for a chunk, after `dict` if there is one
+fn read_prefix(old: &mut impl Read, len: u64, dict: Option<&[u8]>) -> Result<Vec<u8>> {
+	let dict = dict.unwrap_or_default();
+	let mut prefix = vec![0u8; dict.len() + len as usize];
+	prefix[..dict.len()].copy_from_slice(dict);
+	old.read_exact(&mut prefix[dict.len()..])?;
+	Ok(prefix)
+}
+
+/// the dictionary for a patch in a delta chain: the end of the new contents of the patch before it, then `dict` if there is one
+pub(crate) fn chain_dict(prev: &[u8], dict: Option<&[u8]>) -> Vec<u8> {
+	[&prev[prev.len().saturating_sub(CHAIN_WINDOW)..], dict.unwrap_or_default()].concat()
+}
+
+/// passes writes through, keeping the last `window` bytes of them to give to `chain_dict`
+pub(crate) struct TailWriter<W> {
+	inner: W,
+	tail: Vec<u8>,
+	window: usize,
+}
+
+impl<W: Write> TailWriter<W> {
+	pub(crate) fn new(inner: W, window: usize) -> Self {
+		Self { inner, tail: Vec::new(), window }
+	}
+
+	pub(crate) fn into_tail(mut self) -> Vec<u8> {
+		self.tail.drain(..self.tail.len().saturating_sub(self.window));
+		self.tail
+	}
+}
+
+impl<W: Write> Write for TailWriter<W> {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		let n = self.inner.write(buf)?;
+		if self.window != 0 {
+			self.tail.extend_from_slice(&buf[..n]);
+			// only trim once in a while, so it isn't shuffled along on every write
+			if self.tail.len() > 2 * self.window {
+				self.tail.drain(..self.tail.len() - self.window);
+			}
+		}
+		Ok(n)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+/// Same as `diff`, but also lets zstd reference the contents of `dict`, which gives smaller diffs when
+/// the new data is not very similar to the old data but is similar to the dictionary.
+/// The same dictionary must be passed to `apply_with_dict`.
+#[allow(clippy::too_many_arguments)]
+pub fn diff_with_dict(
+	old: &mut (impl Read + Seek),
+	new: &mut (impl Read + Seek),
+	dest: &mut (impl Write + Seek),
+	level: Option<u8>,
+	threads: Option<usize>,
+	old_len_hint: Option<u64>,
+	new_len_hint: Option<u64>,
+	dict: Option<&[u8]>,
+	tuning: &DiffTuning,
+) -> Result<CompressionStats> {
+	let start = Instant::now();
 	let level = level.unwrap_or(3);
 
 	let (num_chunks, old_len, new_len, olf, nlf) =
@@ -70,58 +275,353 @@ pub fn diff(
 
 	// write chunk count
 	dest.write_all(&(num_chunks as u64).to_be_bytes())?;
+	let mut written = 8u64;
 
 	while let Some((co1, cn1)) = chunks.next() {
 		let (co2, cn2) = *chunks.peek().unwrap_or(&(old_len, new_len));
+		written += 8 + diff_chunk(old, new, dest, level, threads, dict, tuning, co1..co2, cn1..cn2)?;
+	}
 
-		// read dictionary into memory
-		let mut dict_chunk = vec![0u8; (co2 - co1) as usize];//.into_boxed_slice();
-		old.seek(SeekFrom::Start(co1))?;
-		old.read_exact(&mut dict_chunk)?;
-
-		// prepare streams
-		new.seek(SeekFrom::Start(cn1))?;
-		let mut throttled_new = new.take(cn2 - cn1);
-		// leave an 8-byte space for the length count
-		dest.seek_relative(8)?;
-		let mut counting_writer = countio::Counter::new(&mut *dest);
-
-		// the results of running GDB on the zstd cli to figure out why --patch-from and -D differ:
-		// we can't use the `with_dictionary` etc functions as those are calling the equivalent of
-		// `ZSTD_CCtx_loadDictionary_byReference`, which writes to cctx.localDict (see following):
-		//    zstd_compress.c:1287:15
-		//    fileio.c:1193:5
-		// whereas we want to use the equivalent of
-		// `ZSTD_CCtx_refPrefix`, which writes to cctx.prefixDict
-		// (see zstd_compress.c:1349:9)
-		// commit hash 6d6d3db in case any lines move around
-		// basically, we want to use a `ref_prefix`, not a dictionary.
-
-		let mut enc = Encoder::with_ref_prefix(&mut counting_writer, level as i32, &dict_chunk)?;
-		enc.long_distance_matching(true)?;
-		enc.window_log(31)?; // 2GiB (2^31)
-		enc.set_pledged_src_size(Some(cn2 - cn1))?;
-		enc.include_dictid(false)?; // not using a trained dictionary
-		enc.include_checksum(false)?; // we do our own redundancy checks
-		enc.include_contentsize(false)?; // not particularly helpful to us
-		if let Some(t) = threads {
-			enc.multithread(t as u32)?;
+	Ok(CompressionStats::new(num_chunks as u64, new_len, written, start.elapsed()))
+}
+
+/// diffs `new_range` of `new` against `old_range` of `old`, writing the length and then the compressed chunk into `dest`.
+/// returns the length of the compressed chunk
+#[allow(clippy::too_many_arguments)]
+fn diff_chunk(
+	old: &mut (impl Read + Seek),
+	new: &mut (impl Read + Seek),
+	dest: &mut (impl Write + Seek),
+	level: u8,
+	threads: Option<usize>,
+	dict: Option<&[u8]>,
+	tuning: &DiffTuning,
+	old_range: Range<u64>,
+	new_range: Range<u64>,
+) -> Result<u64> {
+	// read dictionary into memory
+	// the user's dictionary goes first, as zstd can't use a prefix and a dictionary at the same time
+	old.seek(SeekFrom::Start(old_range.start))?;
+	let dict_chunk = read_prefix(old, old_range.end - old_range.start, dict)?;
+
+	// unchanged chunks are common in big files, and there's no need to compress them
+	new.seek(SeekFrom::Start(new_range.start))?;
+	if new_range.end - new_range.start == old_range.end - old_range.start
+		&& stream_equals(new, &dict_chunk[dict.map_or(0, <[u8]>::len)..])? {
+		dest.write_all(&IDENTICAL_CHUNK.to_be_bytes())?;
+		return Ok(0);
+	}
+
+	// prepare streams
+	new.seek(SeekFrom::Start(new_range.start))?;
+	let mut throttled_new = new.take(new_range.end - new_range.start);
+	// leave an 8-byte space for the length count
+	dest.seek_relative(8)?;
+	let mut counting_writer = countio::Counter::new(&mut *dest);
+
+	// the results of running GDB on the zstd cli to figure out why --patch-from and -D differ:
+	// we can't use the `with_dictionary` etc functions as those are calling the equivalent of
+	// `ZSTD_CCtx_loadDictionary_byReference`, which writes to cctx.localDict (see following):
+	//    zstd_compress.c:1287:15
+	//    fileio.c:1193:5
+	// whereas we want to use the equivalent of
+	// `ZSTD_CCtx_refPrefix`, which writes to cctx.prefixDict
+	// (see zstd_compress.c:1349:9)
+	// commit hash 6d6d3db in case any lines move around
+	// basically, we want to use a `ref_prefix`, not a dictionary.
+
+	let mut enc = Encoder::with_ref_prefix(&mut counting_writer, level as i32, &dict_chunk)?;
+	tuning.apply(&mut enc, dict_chunk.len() as u64 + new_range.end - new_range.start)?;
+	enc.set_pledged_src_size(Some(new_range.end - new_range.start))?;
+	enc.include_dictid(false)?; // not using a trained dictionary
+	enc.include_checksum(false)?; // we do our own redundancy checks
+	enc.include_contentsize(false)?; // not particularly helpful to us
+	if let Some(t) = threads {
+		enc.multithread(t as u32)?;
+	}
+	
+	// run the compression
+	std::io::copy(&mut throttled_new, &mut enc)?;
+	let _ = enc.finish()?;
+
+	let diff_len = counting_writer.writer_bytes() as u64;
+	// seek back
+	dest.seek_relative(-(diff_len as i64) - 8)?;
+	// write length
+	dest.write_all(&diff_len.to_be_bytes())?;
+	// seek forward again
+	dest.seek_relative(diff_len as i64)?;
+	Ok(diff_len)
+}
+
+/// Same as `diff_with_dict`, but splits big files where the old and new data line up, found with content-defined chunking,
+/// instead of into evenly sized chunks. This is much better when data has been inserted or removed near the start of
+/// a file bigger than 1GiB, but reads both files an extra time.
+/// Files that fit in one chunk give exactly the same diff as `diff_with_dict`.
+#[allow(clippy::too_many_arguments)]
+pub fn diff_cdc_with_dict(
+	old: &mut (impl Read + Seek),
+	new: &mut (impl Read + Seek),
+	dest: &mut (impl Write + Seek),
+	level: Option<u8>,
+	threads: Option<usize>,
+	old_len_hint: Option<u64>,
+	new_len_hint: Option<u64>,
+	dict: Option<&[u8]>,
+	tuning: &DiffTuning,
+) -> Result<CompressionStats> {
+	let old_len = resolve_len(old, old_len_hint)?;
+	if old_len <= MAX_CHUNK_SIZE {
+		return diff_with_dict(old, new, dest, level, threads, Some(old_len), new_len_hint, dict, tuning);
+	}
+
+	let start = Instant::now();
+	let new_len = resolve_len(new, new_len_hint)?;
+	old.rewind()?;
+	new.rewind()?;
+	let segments = cdc_segments(old, new, old_len, new_len, MAX_CHUNK_SIZE, CDC_AVG_SIZE)?;
+	let written = diff_segments(old, new, dest, level.unwrap_or(3), threads, dict, tuning, &segments)?;
+	Ok(CompressionStats::new(segments.len() as u64, new_len, written, start.elapsed()))
+}
+
+/// splits `old` and `new` into (old length, new length) pairs to be diffed against each other, with at most `max_old` bytes of old in each.
+/// they're split at chunks with the same contents in both, so that data that has moved is diffed against where it moved from.
+/// reads both streams to the end.
+fn cdc_segments(old: &mut impl Read, new: &mut impl Read, old_len: u64, new_len: u64, max_old: u64, avg_size: u32) -> Result<Vec<(u64, u64)>> {
+	let (min_size, max_size) = (avg_size / 4, avg_size * 4);
+
+	// hash -> where it starts in old. chunks that appear more than once can't tell us where anything lines up
+	let mut old_chunks = HashMap::new();
+	for c in StreamCDC::new(old, min_size, avg_size, max_size) {
+		let c = c?;
+		old_chunks.entry(xxh3_64(&c.data)).and_modify(|o| *o = None).or_insert(Some(c.offset));
+	}
+
+	// (old offset, new offset) of chunks in both, going forwards through both files
+	let mut anchors: Vec<(u64, u64)> = Vec::new();
+	for c in StreamCDC::new(new, min_size, avg_size, max_size) {
+		let c = c?;
+		if let Some(&Some(o)) = old_chunks.get(&xxh3_64(&c.data)) {
+			if anchors.last().is_none_or(|&(last, _)| o > last) {
+				anchors.push((o, c.offset));
+			}
+		}
+	}
+
+	let mut segments = Vec::new();
+	let (mut o, mut n) = (0, 0);
+	let mut anchors = anchors.into_iter().peekable();
+	while old_len - o > max_old {
+		// cut at the furthest anchor we can, to keep as much together as possible
+		let mut cut = None;
+		while let Some(&(ao, an)) = anchors.peek() {
+			if ao > o + max_old {
+				break;
+			}
+			if ao > o && an >= n {
+				cut = Some((ao, an));
+			}
+			anchors.next();
+		}
+
+		// nothing in common close enough, so line up with the next anchor, or the last cut if there are no more
+		let (co, cn) = cut.unwrap_or_else(|| {
+			let co = o + max_old;
+			let (ao, an) = anchors.peek().copied().unwrap_or((o, n));
+			(co, (co + an).saturating_sub(ao).clamp(n, new_len))
+		});
+		segments.push((co - o, cn - n));
+		(o, n) = (co, cn);
+	}
+	segments.push((old_len - o, new_len - n));
+
+	Ok(segments)
+}
+
+/// diffs each (old length, new length) segment, storing the old lengths so applying can find them again
+#[allow(clippy::too_many_arguments)]
+fn diff_segments(
+	old: &mut (impl Read + Seek),
+	new: &mut (impl Read + Seek),
+	dest: &mut (impl Write + Seek),
+	level: u8,
+	threads: Option<usize>,
+	dict: Option<&[u8]>,
+	tuning: &DiffTuning,
+	segments: &[(u64, u64)],
+) -> Result<u64> {
+	dest.write_all(&(segments.len() as u64 | STORED_CHUNKS_FLAG).to_be_bytes())?;
+	for (ol, _) in segments {
+		dest.write_all(&ol.to_be_bytes())?;
+	}
+	let mut written = 8 + 8 * segments.len() as u64;
+
+	let (mut o, mut n) = (0, 0);
+	for (ol, nl) in segments {
+		written += 8 + diff_chunk(old, new, dest, level, threads, dict, tuning, o..(o + ol), n..(n + nl))?;
+		(o, n) = (o + ol, n + nl);
+	}
+	Ok(written)
+}
+
+/// Same as `diff_with_dict`, but first matches up lines of `new` with lines of `old`, and only compresses the lines
+/// that weren't found, still against the old file. Both files are read into memory, so files bigger than 1GiB
+/// give exactly the same diff as `diff_with_dict`.
+#[allow(clippy::too_many_arguments)]
+pub fn diff_text_with_dict(
+	old: &mut (impl Read + Seek),
+	new: &mut (impl Read + Seek),
+	dest: &mut (impl Write + Seek),
+	level: Option<u8>,
+	threads: Option<usize>,
+	old_len_hint: Option<u64>,
+	new_len_hint: Option<u64>,
+	dict: Option<&[u8]>,
+	tuning: &DiffTuning,
+) -> Result<CompressionStats> {
+	let old_len = resolve_len(old, old_len_hint)?;
+	let new_len = resolve_len(new, new_len_hint)?;
+	if old_len > MAX_CHUNK_SIZE || new_len > MAX_CHUNK_SIZE {
+		return diff_with_dict(old, new, dest, level, threads, Some(old_len), Some(new_len), dict, tuning);
+	}
+
+	let start = Instant::now();
+	old.rewind()?;
+	new.rewind()?;
+	let prefix = read_prefix(old, old_len, dict)?;
+	let mut new_data = Vec::with_capacity(new_len as usize);
+	new.take(new_len).read_to_end(&mut new_data)?;
+	ensure!(new_data.len() as u64 == new_len, "New file is shorter than expected");
+
+	let script = text_script(&prefix[dict.map_or(0, <[u8]>::len)..], &new_data);
+
+	dest.write_all(&(1 | TEXT_DELTA_FLAG).to_be_bytes())?;
+	// leave an 8-byte space for the length count
+	dest.seek_relative(8)?;
+	let mut counting_writer = countio::Counter::new(&mut *dest);
+
+	// the inserted lines are often edits of old ones, so still let zstd find those
+	let mut enc = Encoder::with_ref_prefix(&mut counting_writer, level.unwrap_or(3) as i32, &prefix)?;
+	// the script is about as long as the new file
+	tuning.apply(&mut enc, prefix.len() as u64 + new_len)?;
+	enc.include_dictid(false)?;
+	enc.include_checksum(false)?;
+	enc.include_contentsize(false)?;
+	if let Some(t) = threads {
+		enc.multithread(t as u32)?;
+	}
+	for op in script {
+		match op {
+			TextOp::Copy(range) => {
+				enc.write_all(&((range.end - range.start) << 1).to_be_bytes())?;
+				enc.write_all(&range.start.to_be_bytes())?;
+			},
+			TextOp::Insert(range) => {
+				enc.write_all(&(((range.end - range.start) << 1) | 1).to_be_bytes())?;
+				enc.write_all(&new_data[range.start as usize..range.end as usize])?;
+			},
+		}
+	}
+	enc.finish()?;
+
+	let diff_len = counting_writer.writer_bytes() as u64;
+	dest.seek_relative(-(diff_len as i64) - 8)?;
+	dest.write_all(&diff_len.to_be_bytes())?;
+	dest.seek_relative(diff_len as i64)?;
+	Ok(CompressionStats::new(1, new_len, 16 + diff_len, start.elapsed()))
+}
+
+/// one step of rebuilding a new file from a text delta
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum TextOp {
+	/// this range of the old file
+	Copy(Range<u64>),
+	/// this range of the new file, stored in the diff
+	Insert(Range<u64>),
+}
+
+/// works out which lines of `new` can be copied from `old`, and which need storing.
+/// a copy carries on through following lines for as long as they match, so moved blocks only take one copy
+fn text_script(old: &[u8], new: &[u8]) -> Vec<TextOp> {
+	// (start, line) for every line of old, and where each line first appears
+	let mut old_lines = Vec::new();
+	let mut offset = 0u64;
+	for line in old.split_inclusive(|b| *b == b'\n') {
+		old_lines.push((offset, line));
+		offset += line.len() as u64;
+	}
+	let mut first_seen = HashMap::new();
+	for (i, (_, line)) in old_lines.iter().enumerate() {
+		first_seen.entry(*line).or_insert(i);
+	}
+
+	let mut ops: Vec<TextOp> = Vec::new();
+	// the old line after the last one copied
+	let mut next_old = None;
+	let mut pos = 0u64;
+	for line in new.split_inclusive(|b| *b == b'\n') {
+		let range = pos..pos + line.len() as u64;
+		pos = range.end;
+
+		if let (Some(i), Some(TextOp::Copy(copy))) = (next_old, ops.last_mut()) {
+			if old_lines.get(i).is_some_and(|(_, l)| *l == line) {
+				copy.end += line.len() as u64;
+				next_old = Some(i + 1);
+				continue;
+			}
+		}
+		if let Some(&i) = first_seen.get(line).filter(|_| line.len() >= TEXT_MIN_LINE) {
+			let start = old_lines[i].0;
+			ops.push(TextOp::Copy(start..start + line.len() as u64));
+			next_old = Some(i + 1);
+			continue;
+		}
+
+		next_old = None;
+		match ops.last_mut() {
+			Some(TextOp::Insert(insert)) => insert.end = range.end,
+			_ => ops.push(TextOp::Insert(range)),
 		}
-		
-		// run the compression
-		std::io::copy(&mut throttled_new, &mut enc)?;
-		let _ = enc.finish()?;
+	}
+	ops
+}
+
+/// applies a text delta, after its chunk count has been read
+fn apply_text_delta(old: &mut impl Read, diff: &mut impl Read, dest: &mut impl Write, old_len: u64, dict: Option<&[u8]>) -> Result<u64> {
+	ensure!(old_len <= MAX_CHUNK_SIZE, "Text diff is for an old file of more than {MAX_CHUNK_SIZE} bytes");
+	let prefix = read_prefix(old, old_len, dict)?;
+	let old = &prefix[dict.map_or(0, <[u8]>::len)..];
 
-		let diff_len = counting_writer.writer_bytes();
-		// seek back
-		dest.seek_relative(-(diff_len as i64) - 8)?;
-		// write length
-		dest.write_all(&diff_len.to_be_bytes())?;
-		// seek forward again
-		dest.seek_relative(diff_len as i64)?;
+	let diff_c_len = read_u64(diff)?;
+	let mut decoder = Decoder::with_ref_prefix(BufReader::new(diff.take(diff_c_len)), &prefix)?;
+	decoder.window_log_max(MAX_WINDOW_LOG as u32)?;
+
+	let mut written = 0u64;
+	loop {
+		// the end of the script can only come between steps
+		let mut op = [0u8; 8];
+		if decoder.read(&mut op[..1])? == 0 {
+			break;
+		}
+		decoder.read_exact(&mut op[1..])?;
+		let op = u64::from_be_bytes(op);
+		let len = op >> 1;
+
+		if op & 1 == 0 {
+			let start = read_u64(&mut decoder)?;
+			let range = start.checked_add(len).filter(|e| *e <= old_len).map(|e| start as usize..e as usize);
+			let Some(range) = range
+			else { bail!("Text diff copies past the end of the old file") };
+			dest.write_all(&old[range])?;
+		}
+		else {
+			let n = std::io::copy(&mut (&mut decoder).take(len), dest)?;
+			ensure!(n == len, "Text diff ended in the middle of inserted data");
+		}
+		written += len;
 	}
 
-	Ok(())
+	Ok(written)
 }
 
 /// Applies a `diff` from `old`, and writes the new file into `dest`.
@@ -134,24 +634,71 @@ pub fn apply(
 	dest: &mut impl Write,
 	old_len: u64,
 ) -> Result<u64> {
-	// read number of chunks
-	let num_chunks = read_u64(diff)?;
+	apply_streaming(old, diff, dest, old_len)
+}
 
-	let mut chunks = calc_chunks(num_chunks as f64, old_len as f64).peekable();
+/// Same as `apply`, but reads the diff strictly in order, so it can come from a stream such as stdin.
+/// `old_len` must be exactly the size of the old stream, as chunk boundaries are calculated from it.
+/// The diff is left positioned right after the end of the diff structure.
+pub fn apply_streaming(
+	old: &mut impl Read,
+	diff: &mut impl Read,
+	dest: &mut impl Write,
+	old_len: u64,
+) -> Result<u64> {
+	apply_with_dict(old, diff, dest, old_len, None)
+}
 
-	let mut written = 0u64;
+/// Same as `apply_streaming`, for diffs created by `diff_with_dict` with the same `dict`.
+pub fn apply_with_dict(
+	old: &mut impl Read,
+	diff: &mut impl Read,
+	dest: &mut impl Write,
+	old_len: u64,
+	dict: Option<&[u8]>,
+) -> Result<u64> {
+	// read number of chunks
+	let raw_count = read_u64(diff)?;
+	if raw_count & TEXT_DELTA_FLAG != 0 {
+		ensure!(raw_count == 1 | TEXT_DELTA_FLAG, "Text diffs must be made of exactly one chunk");
+		return apply_text_delta(old, diff, dest, old_len, dict);
+	}
+	let (num_chunks, stored) = split_chunk_count(raw_count);
+	ensure!(num_chunks <= MAX_CHUNKS, "Diff has {num_chunks} chunks, which is more than the maximum of {MAX_CHUNKS}");
+
+	// the length of each chunk of old
+	let old_chunks: Vec<u64> = if stored {
+		let lens = (0..num_chunks).map(|_| read_u64(diff)).collect::<Result<Vec<_>>>()?;
+		ensure!(lens.iter().all(|l| *l <= MAX_CHUNK_SIZE), "Diff has a chunk of more than {MAX_CHUNK_SIZE} bytes");
+		ensure!(lens.iter().sum::<u64>() == old_len, "Diff's chunks don't add up to the old file's length of {old_len} bytes");
+		lens
+	}
+	else {
+		ensure!(
+			num_chunks == 0 || old_len / num_chunks <= MAX_CHUNK_SIZE,
+			"Diff has too few chunks ({num_chunks}) for an old file of {old_len} bytes"
+		);
+		let mut starts = calc_chunks(num_chunks as f64, old_len as f64).peekable();
+		let mut lens = Vec::new();
+		while let Some(co1) = starts.next() {
+			lens.push(starts.peek().unwrap_or(&old_len) - co1);
+		}
+		lens
+	};
 
-	while let Some(co1) = chunks.next() {
-		let co2 = *chunks.peek().unwrap_or(&old_len);
+	let mut written = 0u64;
 
+	for chunk_len in old_chunks {
 		// read dictionary into memory
-		let mut dict_chunk = vec![0u8; (co2 - co1) as usize].into_boxed_slice();
-		//debug_assert_eq!(old.stream_position()?, co1);
-		//old.seek(SeekFrom::Start(co1))?;
-		old.read_exact(&mut dict_chunk)?;
+		let dict_chunk = read_prefix(old, chunk_len, dict)?;
 
 		// read length of compressed blob & setup streams
 		let diff_c_len = read_u64(diff)?;
+		if diff_c_len == IDENTICAL_CHUNK {
+			dest.write_all(&dict_chunk[dict.map_or(0, <[u8]>::len)..])?;
+			written += chunk_len;
+			continue;
+		}
 		//diff.seek(SeekFrom::Start(cn1))?;
 		let throttled_diff = BufReader::new(diff.take(diff_c_len));
 
@@ -159,7 +706,8 @@ pub fn apply(
 
 		// decompress diff
 		let mut decoder = Decoder::with_ref_prefix(throttled_diff, &dict_chunk)?;
-		decoder.window_log_max(31)?; // else we OOM
+		// the window is in the frame header, this just stops a malformed one asking for more
+		decoder.window_log_max(MAX_WINDOW_LOG as u32)?;
 		std::io::copy(&mut decoder, &mut counter)?;
 
 		written += counter.writer_bytes() as u64;
@@ -172,6 +720,7 @@ pub fn apply(
 mod tests {
 	use super::*;
 
+	use proptest::prelude::*;
 	use rand::{random, RngCore};
 	use std::fs::{remove_file, File};
 	use std::io::{BufReader, BufWriter, Read, Seek, Write};
@@ -235,7 +784,7 @@ Look at me. Look at me. I'm the captain now.".as_bytes();
 		let mut old_reader = std::io::Cursor::new(&*data_old);
 		let mut new_reader = std::io::Cursor::new(&mut *data_new);
 
-		diff(
+		let stats = diff(
 			&mut old_reader,
 			&mut new_reader,
 			&mut diff_cursor,
@@ -245,6 +794,7 @@ Look at me. Look at me. I'm the captain now.".as_bytes();
 			None,
 		)
 		.unwrap();
+		assert_eq!(stats.output_bytes, diff_cursor.get_ref().len() as u64);
 
 		// now we have a diff, let's apply it
 		let mut final_writer = std::io::Cursor::new(Vec::new());
@@ -257,6 +807,344 @@ Look at me. Look at me. I'm the captain now.".as_bytes();
 		// check if everything is ok
 		assert_eq!(dcsz, 128_000);
 		assert_eq!(*data_new, *final_writer.into_inner());
+
+		// and again, without being able to seek either input
+		let mut final_writer = Vec::new();
+		let diff_bytes = diff_cursor.into_inner();
+		let dcsz = apply_streaming(&mut &*data_old, &mut &*diff_bytes, &mut final_writer, ol).unwrap();
+		assert_eq!(dcsz, 128_000);
+		assert_eq!(data_new, final_writer);
+	}
+
+	#[test]
+	fn test_window_log() {
+		use zstd::zstd_safe::{DCtx, DParameter, InBuffer, OutBuffer};
+
+		let old = (0..2000).map(|i| format!("line {i}\n")).collect::<String>().into_bytes();
+		let new = String::from_utf8(old.clone()).unwrap().replace("line 1000\n", "something else\n").into_bytes();
+
+		// how much memory it takes to apply a diff's one chunk
+		let apply_size = |diff: Vec<u8>| {
+			let mut dctx = DCtx::create();
+			dctx.ref_prefix(&old[..]).unwrap();
+			dctx.set_parameter(DParameter::WindowLogMax(MAX_WINDOW_LOG as u32)).unwrap();
+			let mut out = vec![0u8; new.len() * 2];
+			// skip the chunk count and length
+			dctx.decompress_stream(&mut OutBuffer::around(&mut out[..]), &mut InBuffer::around(&diff[16..])).unwrap();
+			dctx.sizeof()
+		};
+		let text = |window_log: Option<u8>| {
+			let mut diff = std::io::Cursor::new(Vec::new());
+			diff_text_with_dict(&mut std::io::Cursor::new(&old), &mut std::io::Cursor::new(&new), &mut diff, None, None, None, None, None, &DiffTuning { window_log, ..Default::default() }).unwrap();
+			let mut applied = Vec::new();
+			apply_streaming(&mut &old[..], &mut &diff.get_ref()[..], &mut applied, old.len() as u64).unwrap();
+			assert_eq!(applied, new);
+			apply_size(diff.into_inner())
+		};
+		let binary = |window_log: Option<u8>| {
+			let mut diff = std::io::Cursor::new(Vec::new());
+			diff_with_dict(&mut std::io::Cursor::new(&old), &mut std::io::Cursor::new(&new), &mut diff, None, None, None, None, None, &DiffTuning { window_log, ..Default::default() }).unwrap();
+			apply_size(diff.into_inner())
+		};
+
+		// text deltas used to always need a 2GiB window
+		assert!(text(None) < 1 << 20);
+		assert!(text(Some(24)) > 16 << 20);
+		// zstd shrinks the window itself for binary chunks, as it knows how long they are
+		assert!(binary(None) < 1 << 20);
+		assert_eq!(binary(Some(24)), binary(None));
+		// too small to see all of the old file, but still works
+		text(Some(MIN_WINDOW_LOG));
+		assert_eq!(pick_window_log(Some(40), 1), MAX_WINDOW_LOG as u32);
+
+		assert!(DiffTuning::default().check().is_ok());
+		assert!(DiffTuning { window_log: Some(9), ..Default::default() }.check().is_err());
+		assert!(DiffTuning { ldm_hash_log: Some(20), ..Default::default() }.check().is_ok());
+		assert!(DiffTuning { ldm_hash_log: Some(31), ..Default::default() }.check().is_err());
+	}
+
+	#[test]
+	fn test_ldm_large() {
+		// not really a test, but a benchmark: 1GiB made of 1MiB random blocks, which are shuffled around in the new file
+		const BLOCK: usize = 1 << 20;
+		let dir = tempfile::tempdir().unwrap();
+		let (mut old_file, mut new_file) = (File::create(dir.path().join("old")).unwrap(), File::create(dir.path().join("new")).unwrap());
+		let mut rng = rand::thread_rng();
+		let mut block = vec![0u8; BLOCK];
+		let mut seeds = Vec::new();
+		for i in 0..1024u64 {
+			rng.fill_bytes(&mut block);
+			old_file.write_all(&block).unwrap();
+			seeds.push(i);
+		}
+		// every other block goes to the other end of the file, with a few bytes changed
+		seeds.sort_by_key(|i| (i % 2, *i));
+		let mut old = BufReader::new(File::open(dir.path().join("old")).unwrap());
+		for i in seeds {
+			old.seek(SeekFrom::Start(i * BLOCK as u64)).unwrap();
+			old.read_exact(&mut block).unwrap();
+			block[random::<usize>() % BLOCK] = random();
+			new_file.write_all(&block).unwrap();
+		}
+
+		for long_distance_matching in [false, true] {
+			let (mut old, mut new) = (File::open(dir.path().join("old")).unwrap(), File::open(dir.path().join("new")).unwrap());
+			let start = std::time::Instant::now();
+			let tuning = DiffTuning { long_distance_matching, ..Default::default() };
+			let stats = diff_with_dict(&mut old, &mut new, &mut std::io::Cursor::new(Vec::new()), None, None, None, None, None, &tuning).unwrap();
+			eprintln!("ldm {long_distance_matching}: {} bytes in {:?}", stats.output_bytes, start.elapsed());
+		}
+	}
+
+	#[test]
+	fn test_zstddiff_with_dict() {
+		// lots of config files with the same entries but different values.
+		// the entry names are random so they don't compress well on their own.
+		let names: Vec<String> = (0..200).map(|_| format!("{:016x}{:016x}", random::<u64>(), random::<u64>())).collect();
+		let config = |seed: u32| {
+			names.iter().enumerate()
+				.map(|(i, n)| format!("[entity_{n}]\nenabled = {}\nweight = {}\n", i % 2 == 0, (i as u32 * seed) % 97))
+				.collect::<String>()
+				.into_bytes()
+		};
+		let dict = config(3);
+		let data_new = config(7);
+		// the old file has nothing to do with the new one
+		let data_old: Vec<u8> = (0..4096).map(|_| random()).collect();
+
+		let run = |dict: Option<&[u8]>| {
+			let mut diff_cursor = std::io::Cursor::new(Vec::new());
+			let stats = diff_with_dict(
+				&mut std::io::Cursor::new(&*data_old),
+				&mut std::io::Cursor::new(&*data_new),
+				&mut diff_cursor,
+				None,
+				None,
+				None,
+				None,
+				dict,
+				&DiffTuning::default(),
+			)
+			.unwrap();
+
+			let mut final_writer = Vec::new();
+			diff_cursor.rewind().unwrap();
+			apply_with_dict(&mut &*data_old, &mut diff_cursor, &mut final_writer, data_old.len() as u64, dict).unwrap();
+			assert_eq!(data_new, final_writer);
+
+			stats.output_bytes
+		};
+
+		let without = run(None);
+		let with = run(Some(&dict));
+		assert!(with < without / 2, "dictionary should help a lot: {with} bytes with, {without} without");
+	}
+
+	#[test]
+	fn test_zstddiff_cdc() {
+		// small enough to need a few chunks with the limits we pass, and something inserted at the start
+		let mut data_old = vec![0u8; 256 * 1024];
+		rand::thread_rng().fill_bytes(&mut data_old);
+		let mut data_new = vec![7u8; 1000];
+		data_new.extend_from_slice(&data_old);
+		for _ in 0..20 {
+			let oset = (random::<f64>() * data_new.len() as f64) as usize;
+			data_new[oset] = random();
+		}
+
+		let (ol, nl) = (data_old.len() as u64, data_new.len() as u64);
+		let segments = cdc_segments(&mut &*data_old, &mut &*data_new, ol, nl, 64 * 1024, 4096).unwrap();
+		assert!(segments.len() >= 4);
+		assert!(segments.iter().all(|(o, _)| *o <= 64 * 1024));
+		assert_eq!(segments.iter().map(|s| s.0).sum::<u64>(), ol);
+		assert_eq!(segments.iter().map(|s| s.1).sum::<u64>(), nl);
+		// everything after the insert lines up again
+		assert_eq!(segments[0].1, segments[0].0 + 1000);
+		assert!(segments[1..].iter().all(|(o, n)| o == n), "{segments:?}");
+
+		let mut diff_cursor = std::io::Cursor::new(Vec::new());
+		let written = diff_segments(
+			&mut std::io::Cursor::new(&*data_old),
+			&mut std::io::Cursor::new(&*data_new),
+			&mut diff_cursor,
+			3,
+			None,
+			None,
+			&DiffTuning::default(),
+			&segments,
+		)
+		.unwrap();
+		assert_eq!(written, diff_cursor.get_ref().len() as u64);
+
+		let mut final_writer = Vec::new();
+		let diff_bytes = diff_cursor.into_inner();
+		apply_streaming(&mut &*data_old, &mut &*diff_bytes, &mut final_writer, ol).unwrap();
+		assert_eq!(data_new, final_writer);
+
+		// the stored lengths have to cover the old file exactly
+		assert!(apply_streaming(&mut &*data_old, &mut &*diff_bytes, &mut Vec::new(), ol - 1).is_err());
+	}
+
+	#[test]
+	fn test_identical_chunks() {
+		const BLOCK: usize = 64 * 1024;
+		let mut old = vec![0u8; 3 * BLOCK];
+		rand::thread_rng().fill_bytes(&mut old);
+		let mut new = old.clone();
+		new[BLOCK + 100] ^= 1;
+
+		// a whole file that hasn't changed is just the chunk count and the marker
+		let mut diff_cursor = std::io::Cursor::new(Vec::new());
+		diff(&mut std::io::Cursor::new(&old), &mut std::io::Cursor::new(&old), &mut diff_cursor, None, None, None, None).unwrap();
+		assert_eq!(diff_cursor.get_ref()[8..], IDENTICAL_CHUNK.to_be_bytes());
+
+		// only the middle chunk is compressed
+		let mut diff_cursor = std::io::Cursor::new(Vec::new());
+		let segments = [(BLOCK as u64, BLOCK as u64); 3];
+		let written = diff_segments(
+			&mut std::io::Cursor::new(&old),
+			&mut std::io::Cursor::new(&new),
+			&mut diff_cursor,
+			3,
+			None,
+			None,
+			&DiffTuning::default(),
+			&segments,
+		)
+		.unwrap();
+		assert_eq!(written, diff_cursor.get_ref().len() as u64);
+		let diff_bytes = diff_cursor.into_inner();
+		assert_eq!(diff_bytes[32..40], IDENTICAL_CHUNK.to_be_bytes());
+		assert_eq!(diff_bytes[diff_bytes.len() - 8..], IDENTICAL_CHUNK.to_be_bytes());
+
+		let mut final_writer = Vec::new();
+		let written = apply_streaming(&mut &old[..], &mut &diff_bytes[..], &mut final_writer, old.len() as u64).unwrap();
+		assert_eq!(written, new.len() as u64);
+		assert_eq!(final_writer, new);
+	}
+
+	#[test]
+	fn test_zstddiff_text() {
+		let old = b"fn main() {\n\tprintln!(\"hello\");\n}\n\nfn other() {\n\tlet x = 5;\n}\n";
+		let new = b"fn other() {\n\tlet x = 6;\n}\n\nfn main() {\n\tprintln!(\"hello\");\n}\nno newline";
+		// the moved function is one copy, and short lines don't start one
+		assert_eq!(text_script(old, new), vec![
+			TextOp::Copy(35..48),
+			TextOp::Insert(13..28),
+			TextOp::Copy(0..34),
+			TextOp::Insert(62..72),
+		]);
+
+		let dict = b"fn ".repeat(100);
+		for dict in [None, Some(&dict[..])] {
+			let mut diff_cursor = std::io::Cursor::new(Vec::new());
+			let stats = diff_text_with_dict(
+				&mut std::io::Cursor::new(&old[..]),
+				&mut std::io::Cursor::new(&new[..]),
+				&mut diff_cursor,
+				None,
+				None,
+				None,
+				None,
+				dict,
+				&DiffTuning::default(),
+			)
+			.unwrap();
+			assert_eq!(stats.output_bytes, diff_cursor.get_ref().len() as u64);
+			// laid out like any other single chunk
+			assert_eq!(split_chunk_count(read_u64(&mut &diff_cursor.get_ref()[..]).unwrap()), (1, false));
+
+			let mut final_writer = Vec::new();
+			let written = apply_with_dict(&mut &old[..], &mut &diff_cursor.get_ref()[..], &mut final_writer, old.len() as u64, dict).unwrap();
+			assert_eq!(written, new.len() as u64);
+			assert_eq!(&final_writer[..], &new[..]);
+		}
+
+		// copying from past the end of the old file
+		let mut diff_cursor = std::io::Cursor::new(Vec::new());
+		diff_text_with_dict(&mut std::io::Cursor::new(&old[..]), &mut std::io::Cursor::new(&new[..]), &mut diff_cursor, None, None, None, None, None, &DiffTuning::default()).unwrap();
+		assert!(apply_streaming(&mut &old[..10], &mut &diff_cursor.get_ref()[..], &mut Vec::new(), 10).is_err());
+	}
+
+	// the shapes of old and new files that diffs have to handle
+	fn old_and_new() -> impl Strategy<Value = (Vec<u8>, Vec<u8>)> {
+		let bytes = |max| prop::collection::vec(any::<u8>(), 0..max);
+		// lines from a few choices, so text diffs find lines to copy
+		let lines = || prop::collection::vec(
+			prop::sample::select(vec!["fn main() {\n", "\tlet x = 5;\n", "}\n", "\n", "// a comment long enough to be worth copying\n"]),
+			0..100,
+		).prop_map(|l| l.concat().into_bytes());
+
+		prop_oneof![
+			(bytes(4096), bytes(4096)),
+			bytes(4096).prop_map(|new| (Vec::new(), new)),
+			bytes(4096).prop_map(|old| (old, Vec::new())),
+			bytes(4096).prop_map(|old| (old.clone(), old)),
+			(bytes(64), bytes(64 * 1024)),
+			(lines(), lines()),
+			// a few edits to old, like most real changes
+			(bytes(4096), prop::collection::vec((any::<prop::sample::Index>(), any::<u8>(), 0..3u8), 0..20)).prop_map(|(old, edits)| {
+				let mut new = old.clone();
+				for (at, byte, kind) in edits {
+					let at = at.index(new.len() + 1);
+					match kind {
+						0 => new.insert(at, byte),
+						1 if at < new.len() => { new.remove(at); },
+						_ if at < new.len() => new[at] = byte,
+						_ => new.push(byte),
+					}
+				}
+				(old, new)
+			}),
+		]
+	}
+
+	#[test]
+	fn test_zstddiff_empty_old() {
+		// an empty old file used to give zero chunks, which dropped all of new on the floor
+		for new in [&b""[..], b"a whole new file"] {
+			for diff_fn in [diff_with_dict, diff_cdc_with_dict, diff_text_with_dict] {
+				let mut diff_cursor = std::io::Cursor::new(Vec::new());
+				diff_fn(&mut std::io::Cursor::new(&[][..]), &mut std::io::Cursor::new(new), &mut diff_cursor, None, None, None, None, None, &DiffTuning::default()).unwrap();
+
+				let mut final_writer = Vec::new();
+				diff_cursor.rewind().unwrap();
+				let written = apply(&mut &[][..], &mut diff_cursor, &mut final_writer, 0).unwrap();
+				assert_eq!(written, new.len() as u64);
+				assert_eq!(final_writer, new);
+			}
+		}
+	}
+
+	proptest! {
+		#[test]
+		fn test_zstddiff_round_trip((old, new) in old_and_new()) {
+			for diff_fn in [diff_with_dict, diff_cdc_with_dict, diff_text_with_dict] {
+				let mut diff_cursor = std::io::Cursor::new(Vec::new());
+				diff_fn(&mut std::io::Cursor::new(&old[..]), &mut std::io::Cursor::new(&new[..]), &mut diff_cursor, None, None, None, None, None, &DiffTuning::default()).unwrap();
+
+				let mut final_writer = Vec::new();
+				diff_cursor.rewind().unwrap();
+				let written = apply(&mut &old[..], &mut diff_cursor, &mut final_writer, old.len() as u64).unwrap();
+				prop_assert_eq!(written, new.len() as u64);
+				prop_assert_eq!(&final_writer, &new);
+			}
+		}
+	}
+
+	#[test]
+	fn test_zstddiff_malformed() {
+		let mut out = Vec::new();
+
+		// absurd chunk count
+		let bad = u64::MAX.to_be_bytes();
+		assert!(apply_streaming(&mut &[][..], &mut &bad[..], &mut out, 0).is_err());
+
+		// one chunk for a 100GiB old file would need 100GiB of memory
+		let bad = 1u64.to_be_bytes();
+		assert!(apply_streaming(&mut &[][..], &mut &bad[..], &mut out, 100 << 30).is_err());
+
+		assert!(out.is_empty());
 	}
 
 	#[test]
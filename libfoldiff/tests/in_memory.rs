@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use libfoldiff::applying::read_diff_from_bytes;
+use libfoldiff::catalog::seek_to_file;
+use libfoldiff::diffing::scan_to_diff;
+use libfoldiff::reporting::{NoopMultiWrapper, NoopReporter};
+use libfoldiff::vfs::{StdVfs, VfsMetadata, VfsProvider};
+use libfoldiff::FoldiffCfg;
+
+type Files = Arc<Mutex<HashMap<Utf8PathBuf, Vec<u8>>>>;
+
+/// a folder that only exists in memory
+#[derive(Default)]
+struct MemVfs(Files);
+
+/// puts the file in place once it's done being written
+struct MemWriter(Files, Utf8PathBuf, Vec<u8>);
+
+impl Write for MemWriter {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.2.write(buf)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}
+
+impl Drop for MemWriter {
+	fn drop(&mut self) {
+		self.0.lock().unwrap().insert(self.1.clone(), std::mem::take(&mut self.2));
+	}
+}
+
+impl VfsProvider for MemVfs {
+	fn open_read(&self, path: &Utf8Path) -> Result<Box<dyn Read>> {
+		let data = self.0.lock().unwrap().get(path).cloned().ok_or(anyhow!("{path} does not exist"))?;
+		Ok(Box::new(Cursor::new(data)))
+	}
+
+	fn create_write(&self, path: &Utf8Path) -> Result<Box<dyn Write>> {
+		Ok(Box::new(MemWriter(self.0.clone(), path.to_path_buf(), Vec::new())))
+	}
+
+	fn exists(&self, path: &Utf8Path) -> bool {
+		self.0.lock().unwrap().contains_key(path)
+	}
+
+	fn read_dir(&self, path: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+		Ok(self.0.lock().unwrap().keys().filter(|p| p.parent() == Some(path)).cloned().collect())
+	}
+
+	fn metadata(&self, path: &Utf8Path) -> Result<VfsMetadata> {
+		let len = self.0.lock().unwrap().get(path).ok_or(anyhow!("{path} does not exist"))?.len() as u64;
+		Ok(VfsMetadata { len, is_file: true, ..Default::default() })
+	}
+}
+
+/// a diff that patches `a` and adds `b`, made without writing it to disk
+fn make_diff() -> Vec<u8> {
+	let dir = tempfile::tempdir().unwrap();
+	let root = Utf8Path::from_path(dir.path()).unwrap();
+	std::fs::create_dir_all(root.join("old")).unwrap();
+	std::fs::create_dir_all(root.join("new")).unwrap();
+	std::fs::write(root.join("old/a"), "the quick brown fox".repeat(50)).unwrap();
+	std::fs::write(root.join("new/a"), "the quick brown cat".repeat(50)).unwrap();
+	std::fs::write(root.join("new/b"), "jumps over the lazy dog").unwrap();
+
+	let cfg = FoldiffCfg::default();
+	let mut diff = scan_to_diff::<NoopReporter>(root.join("old"), root.join("new"), &cfg, &StdVfs).unwrap();
+	let bytes = diff.write_to_bytes::<NoopReporter, NoopReporter>(&cfg).unwrap();
+
+	// only the folders we diffed
+	assert_eq!(std::fs::read_dir(root).unwrap().count(), 2);
+	bytes
+}
+
+#[test]
+fn test_bytes_round_trip() {
+	let bytes = make_diff();
+	let mut diff = read_diff_from_bytes(&bytes).unwrap();
+	assert_eq!(diff.manifest().new_files.len(), 1);
+	assert_eq!(diff.manifest().patched_files.len(), 1);
+
+	// apply from and to memory
+	let vfs = MemVfs::default();
+	vfs.0.lock().unwrap().insert("mem/old/a".into(), "the quick brown fox".repeat(50).into_bytes());
+	let res = diff.apply::<NoopMultiWrapper, NoopReporter, NoopReporter>("mem/old".into(), "mem/new".into(), &FoldiffCfg::default(), &vfs, None).unwrap();
+	assert!(res.is_ok());
+	// the patched file and the new one
+	assert_eq!(res.io.bytes_written, 19 * 50 + 23);
+	// at least the old file, plus something from the diff
+	assert!(res.io.bytes_read > 19 * 50);
+
+	let files = vfs.0.lock().unwrap();
+	assert_eq!(files[Utf8Path::new("mem/new/a")], "the quick brown cat".repeat(50).into_bytes());
+	assert_eq!(files[Utf8Path::new("mem/new/b")], b"jumps over the lazy dog");
+	assert!(!std::fs::exists("mem").unwrap());
+}
+
+#[test]
+fn test_read_bad_bytes() {
+	assert!(read_diff_from_bytes(b"").is_err());
+	assert!(read_diff_from_bytes(b"not a diff at all").is_err());
+
+	let bytes = make_diff();
+	assert!(read_diff_from_bytes(&bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn test_seek_to_file() {
+	let bytes = make_diff();
+	let mut reader = Cursor::new(&bytes);
+
+	let mut contents = String::new();
+	seek_to_file(&mut reader, "b").unwrap().read_to_string(&mut contents).unwrap();
+	assert_eq!(contents, "jumps over the lazy dog");
+
+	// patched files need the old file, and anything else isn't there
+	assert!(seek_to_file(&mut reader, "a").is_err());
+	assert!(seek_to_file(&mut reader, "c").is_err());
+}